@@ -0,0 +1,600 @@
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use thiserror::Error;
+
+use crate::error_code::ErrorCode;
+use crate::role::Role;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AccountError {
+    #[error("Insufficient funds. Requested {requested} of {available}.")]
+    InsufficientFunds { requested: f64, available: f64 },
+
+    #[error("Account is locked")]
+    Locked,
+
+    #[error("Balance overflow. Can't apply {amount} to a balance of {balance}.")]
+    BalanceOverflow { balance: f64, amount: f64 },
+}
+
+impl AccountError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AccountError::InsufficientFunds { .. } => "insufficient_funds",
+            AccountError::Locked => "locked",
+            AccountError::BalanceOverflow { .. } => "balance_overflow",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AccountError::InsufficientFunds { .. } => ErrorCode::InsufficientFunds,
+            AccountError::Locked => ErrorCode::AccountLocked,
+            AccountError::BalanceOverflow { .. } => ErrorCode::BalanceOverflow,
+        }
+    }
+
+    // The fields of the variant, after the code, kind and message.
+    pub(crate) fn serialize_context<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        match self {
+            AccountError::InsufficientFunds {
+                requested,
+                available,
+            } => {
+                map.serialize_entry("requested", requested)?;
+                map.serialize_entry("available", available)
+            }
+            AccountError::Locked => Ok(()),
+            AccountError::BalanceOverflow { balance, amount } => {
+                map.serialize_entry("balance", balance)?;
+                map.serialize_entry("amount", amount)
+            }
+        }
+    }
+}
+
+// An object with the code, kind, message and the fields of the variant.
+impl Serialize for AccountError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", &self.code())?;
+        map.serialize_entry("kind", self.kind())?;
+        map.serialize_entry("message", &self.to_string())?;
+        self.serialize_context(&mut map)?;
+        map.end()
+    }
+}
+
+pub type AccountResult<T> = Result<T, AccountError>;
+
+fn checked_add(balance: f64, amount: f64) -> AccountResult<f64> {
+    let result = balance + amount;
+    if !result.is_finite() {
+        return Err(AccountError::BalanceOverflow { balance, amount });
+    }
+    Ok(result)
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Account {
+    available: f64,
+    disputed: f64,
+    locked: bool,
+    // Deposits received while locked, neither available nor disputable.
+    #[serde(default)]
+    suspense: f64,
+    // Held for a counterparty until released to it or refunded.
+    #[serde(default)]
+    escrow: f64,
+    // Fees the client owes because the funds didn't cover them. Not part of
+    // the balance.
+    #[serde(default)]
+    receivable: f64,
+    // Applied transactions of the client.
+    #[serde(default)]
+    transactions: u64,
+    // Disputes opened against the account.
+    #[serde(default)]
+    disputes: u64,
+    // Set by opening the account, otherwise the configured role applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    role: Option<Role>,
+}
+
+impl Account {
+    pub fn new() -> Self {
+        Self {
+            available: 0.0,
+            disputed: 0.0,
+            locked: false,
+            suspense: 0.0,
+            escrow: 0.0,
+            receivable: 0.0,
+            transactions: 0,
+            disputes: 0,
+            role: None,
+        }
+    }
+
+    pub fn with_role(role: Role) -> Self {
+        Self {
+            role: Some(role),
+            ..Self::new()
+        }
+    }
+
+    pub fn deposit(&mut self, amount: f64) -> AccountResult<()> {
+        let available = checked_add(self.available, amount)?;
+        checked_add(available, self.held())?;
+
+        self.available = available;
+        Ok(())
+    }
+
+    pub fn deposit_to_suspense(&mut self, amount: f64) -> AccountResult<()> {
+        let suspense = checked_add(self.suspense, amount)?;
+        checked_add(self.available + self.disputed + self.escrow, suspense)?;
+
+        self.suspense = suspense;
+        Ok(())
+    }
+
+    pub fn withdraw(&mut self, amount: f64) -> AccountResult<()> {
+        self.check_locked()?;
+        self.check_sufficient_funds(amount)?;
+
+        self.available = checked_add(self.available, -amount)?;
+        Ok(())
+    }
+
+    pub fn dispute(&mut self, amount: f64) -> AccountResult<()> {
+        self.check_locked()?;
+        self.check_sufficient_funds(amount)?;
+
+        let available = checked_add(self.available, -amount)?;
+        let disputed = checked_add(self.disputed, amount)?;
+
+        self.available = available;
+        self.disputed = disputed;
+        self.disputes += 1;
+        Ok(())
+    }
+
+    pub fn resolve(&mut self, amount: f64) -> AccountResult<()> {
+        let available = checked_add(self.available, amount)?;
+        let disputed = checked_add(self.disputed, -amount)?;
+
+        self.available = available;
+        self.disputed = disputed;
+        Ok(())
+    }
+
+    pub fn chargeback(&mut self, amount: f64, lock: bool) -> AccountResult<()> {
+        self.disputed = checked_add(self.disputed, -amount)?;
+        self.locked |= lock;
+        Ok(())
+    }
+
+    // Fees are charged from the available funds, even of locked accounts.
+    pub fn charge(&mut self, amount: f64) -> AccountResult<()> {
+        self.check_sufficient_funds(amount)?;
+
+        self.available = checked_add(self.available, -amount)?;
+        Ok(())
+    }
+
+    // Manual corrections ignore locks and may leave the account negative.
+    pub fn adjust(&mut self, amount: f64) -> AccountResult<()> {
+        let available = checked_add(self.available, amount)?;
+        checked_add(available, self.held())?;
+
+        self.available = available;
+        Ok(())
+    }
+
+    // Charges as much of the fee as is available, the rest is owed. Returns
+    // the charged amount.
+    pub fn charge_or_owe(&mut self, amount: f64) -> AccountResult<f64> {
+        let charged = amount.min(self.available.max(0.0));
+        let receivable = checked_add(self.receivable, amount - charged)?;
+
+        self.available = checked_add(self.available, -charged)?;
+        self.receivable = receivable;
+        Ok(charged)
+    }
+
+    // A disputed withdrawal is held until the dispute is settled, the client
+    // can't use the funds before.
+    pub fn dispute_withdrawal(&mut self, amount: f64) -> AccountResult<()> {
+        self.check_locked()?;
+
+        let disputed = checked_add(self.disputed, amount)?;
+        checked_add(self.available + self.suspense + self.escrow, disputed)?;
+
+        self.disputed = disputed;
+        self.disputes += 1;
+        Ok(())
+    }
+
+    pub fn resolve_withdrawal(&mut self, amount: f64) -> AccountResult<()> {
+        self.disputed = checked_add(self.disputed, -amount)?;
+        Ok(())
+    }
+
+    pub fn reverse_withdrawal(&mut self, amount: f64, lock: bool) -> AccountResult<()> {
+        let available = checked_add(self.available, amount)?;
+        let disputed = checked_add(self.disputed, -amount)?;
+
+        self.available = available;
+        self.disputed = disputed;
+        self.locked |= lock;
+        Ok(())
+    }
+
+    pub fn hold_in_escrow(&mut self, amount: f64) -> AccountResult<()> {
+        self.check_locked()?;
+        self.check_sufficient_funds(amount)?;
+
+        let available = checked_add(self.available, -amount)?;
+        let escrow = checked_add(self.escrow, amount)?;
+
+        self.available = available;
+        self.escrow = escrow;
+        Ok(())
+    }
+
+    pub fn release_escrow(&mut self, amount: f64) -> AccountResult<()> {
+        self.escrow = checked_add(self.escrow, -amount)?;
+        Ok(())
+    }
+
+    pub fn refund_escrow(&mut self, amount: f64) -> AccountResult<()> {
+        let available = checked_add(self.available, amount)?;
+        let escrow = checked_add(self.escrow, -amount)?;
+
+        self.available = available;
+        self.escrow = escrow;
+        Ok(())
+    }
+
+    pub fn available(&self) -> f64 {
+        self.available
+    }
+
+    pub fn total(&self) -> f64 {
+        self.available + self.held()
+    }
+
+    pub fn disputed(&self) -> f64 {
+        self.disputed
+    }
+
+    pub fn suspense(&self) -> f64 {
+        self.suspense
+    }
+
+    pub fn escrow(&self) -> f64 {
+        self.escrow
+    }
+
+    pub fn receivable(&self) -> f64 {
+        self.receivable
+    }
+
+    // Funds the client can't use: disputed, in suspense and in escrow.
+    pub fn held(&self) -> f64 {
+        self.disputed + self.suspense + self.escrow
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+
+    pub fn transactions(&self) -> u64 {
+        self.transactions
+    }
+
+    pub fn disputes(&self) -> u64 {
+        self.disputes
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    pub fn unlock(&mut self) {
+        self.locked = false;
+    }
+
+    pub(crate) fn count_transaction(&mut self) {
+        self.transactions += 1;
+    }
+
+    fn check_sufficient_funds(&self, requested: f64) -> AccountResult<()> {
+        if requested > self.available {
+            return Err(AccountError::InsufficientFunds {
+                requested,
+                available: self.available,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_locked(&self) -> AccountResult<()> {
+        if self.locked {
+            return Err(AccountError::Locked);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposit_increases_total_and_available_amounts() {
+        let mut account = Account::new();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        assert_eq!(account.disputed(), 0.0);
+
+        let amount = 1.0;
+        assert!(account.deposit(amount).is_ok());
+
+        assert_eq!(account.available(), amount);
+        assert_eq!(account.total(), amount);
+        assert_eq!(account.disputed(), 0.0);
+    }
+
+    #[test]
+    fn withdrawal_fails_if_not_enough_funds() {
+        let mut account = Account::new();
+
+        let amount = 1.0;
+        let err = account.withdraw(amount).unwrap_err();
+        assert_eq!(
+            err,
+            AccountError::InsufficientFunds {
+                requested: amount,
+                available: 0.0
+            }
+        );
+    }
+
+    #[test]
+    fn withdrawal_fails_if_not_enough_funds_due_to_dispute() {
+        let mut account = Account::new();
+
+        let amount = 1.0;
+        assert!(account.deposit(amount).is_ok());
+        let dispute_amount = 0.4;
+        assert!(account.dispute(dispute_amount).is_ok());
+
+        let err = account.withdraw(amount).unwrap_err();
+        let expected_available = 0.6;
+        assert_eq!(
+            err,
+            AccountError::InsufficientFunds {
+                requested: amount,
+                available: expected_available
+            }
+        );
+    }
+
+    #[test]
+    fn withdrawal_succeeds_if_enough_funds() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+
+        let withdrawal_amount = 0.4;
+        assert!(account.withdraw(withdrawal_amount).is_ok());
+
+        let expected_remaining_amount = 0.6;
+        assert_eq!(account.available(), expected_remaining_amount);
+        assert_eq!(account.total(), expected_remaining_amount);
+        assert_eq!(account.disputed(), 0.0);
+    }
+
+    #[test]
+    fn dispute_locks_funds() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+        let dispute_amount = 0.4;
+        assert!(account.dispute(dispute_amount).is_ok());
+
+        let expected_available = 0.6;
+        assert_eq!(account.available(), expected_available);
+        assert_eq!(account.total(), deposit_amount);
+        assert_eq!(account.disputed(), dispute_amount);
+    }
+
+    #[test]
+    fn dispute_fails_if_insufficient_funds() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+        let dispute_amount = 1.4;
+        let err = account.dispute(dispute_amount).unwrap_err();
+        assert_eq!(
+            err,
+            AccountError::InsufficientFunds {
+                requested: dispute_amount,
+                available: deposit_amount
+            }
+        );
+    }
+
+    #[test]
+    fn resolve_unlocks_funds() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+        let dispute_amount = 0.4;
+        assert!(account.dispute(dispute_amount).is_ok());
+        assert!(account.resolve(dispute_amount).is_ok());
+
+        assert_eq!(account.available(), deposit_amount);
+        assert_eq!(account.total(), deposit_amount);
+        assert_eq!(account.disputed(), 0.0);
+    }
+
+    #[test]
+    fn chargeback_removes_disputed_funds() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+        let dispute_amount = 0.4;
+        assert!(account.dispute(dispute_amount).is_ok());
+        assert!(account.chargeback(dispute_amount, true).is_ok());
+
+        let expected_available = 0.6;
+        assert_eq!(account.available(), expected_available);
+        assert_eq!(account.total(), expected_available);
+        assert_eq!(account.disputed(), 0.0);
+        assert!(account.locked());
+    }
+
+    #[test]
+    fn after_chargeback_account_is_locked() {
+        let mut account = Account::new();
+
+        let deposit_amount = 1.0;
+        assert!(account.deposit(deposit_amount).is_ok());
+        let dispute_amount = 0.4;
+        assert!(account.dispute(dispute_amount).is_ok());
+        assert!(account.chargeback(dispute_amount, true).is_ok());
+
+        let err = account.withdraw(deposit_amount).unwrap_err();
+        assert_eq!(err, AccountError::Locked);
+    }
+
+    #[test]
+    fn suspense_deposits_are_held_but_not_available() {
+        let mut account = Account::new();
+        assert!(account.deposit(1.0).is_ok());
+        assert!(account.deposit_to_suspense(2.0).is_ok());
+
+        assert_eq!(account.available(), 1.0);
+        assert_eq!(account.suspense(), 2.0);
+        assert_eq!(account.held(), 2.0);
+        assert_eq!(account.total(), 3.0);
+        assert!(account.withdraw(1.5).is_err());
+    }
+
+    #[test]
+    fn disputed_withdrawal_is_held_until_reversed() {
+        let mut account = Account::new();
+
+        assert!(account.deposit(1.0).is_ok());
+        assert!(account.withdraw(1.0).is_ok());
+        assert!(account.dispute_withdrawal(1.0).is_ok());
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.disputed(), 1.0);
+        assert_eq!(account.total(), 1.0);
+
+        assert!(account.reverse_withdrawal(1.0, false).is_ok());
+        assert_eq!(account.available(), 1.0);
+        assert_eq!(account.disputed(), 0.0);
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn resolved_withdrawal_dispute_pays_out_again() {
+        let mut account = Account::new();
+
+        assert!(account.dispute_withdrawal(1.0).is_ok());
+        assert!(account.resolve_withdrawal(1.0).is_ok());
+        assert_eq!(account.total(), 0.0);
+    }
+
+    #[test]
+    fn adjustments_ignore_locks_and_funds() {
+        let mut account = Account::new();
+        account.lock();
+        assert!(account.adjust(1.0).is_ok());
+        assert!(account.adjust(-3.0).is_ok());
+        assert_eq!(account.available(), -2.0);
+        assert!(account.adjust(f64::MAX).is_ok());
+        assert!(account.adjust(f64::MAX).is_err());
+    }
+
+    #[test]
+    fn fees_beyond_the_available_funds_are_owed() {
+        let mut account = Account::new();
+        assert!(account.deposit(1.0).is_ok());
+        assert_eq!(account.charge_or_owe(0.25), Ok(0.25));
+        assert_eq!(account.charge_or_owe(1.0), Ok(0.75));
+
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        assert_eq!(account.receivable(), 0.25);
+    }
+
+    #[test]
+    fn escrow_is_held_until_refunded() {
+        let mut account = Account::new();
+
+        assert!(account.deposit(3.0).is_ok());
+        assert!(account.hold_in_escrow(2.0).is_ok());
+        assert_eq!(account.available(), 1.0);
+        assert_eq!(account.held(), 2.0);
+        assert!(account.hold_in_escrow(2.0).is_err());
+
+        assert!(account.refund_escrow(2.0).is_ok());
+        assert_eq!(account.available(), 3.0);
+        assert_eq!(account.escrow(), 0.0);
+    }
+
+    #[test]
+    fn deposit_fails_if_balance_overflows() {
+        let mut account = Account::new();
+
+        assert!(account.deposit(f64::MAX).is_ok());
+        let err = account.deposit(f64::MAX).unwrap_err();
+        assert_eq!(
+            err,
+            AccountError::BalanceOverflow {
+                balance: f64::MAX,
+                amount: f64::MAX
+            }
+        );
+        assert_eq!(account.available(), f64::MAX);
+    }
+
+    #[test]
+    fn deposit_fails_if_total_overflows() {
+        let mut account = Account::new();
+
+        assert!(account.deposit(f64::MAX).is_ok());
+        assert!(account.dispute(f64::MAX).is_ok());
+        assert!(account.deposit(f64::MAX).is_err());
+
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.disputed(), f64::MAX);
+        assert_eq!(account.total(), f64::MAX);
+    }
+
+    #[test]
+    fn dispute_and_resolve_succeed_at_maximum_balance() {
+        let mut account = Account::new();
+
+        assert!(account.deposit(f64::MAX).is_ok());
+        assert!(account.dispute(f64::MAX).is_ok());
+        assert!(account.resolve(f64::MAX).is_ok());
+
+        assert_eq!(account.available(), f64::MAX);
+        assert_eq!(account.disputed(), 0.0);
+        assert_eq!(account.total(), f64::MAX);
+    }
+}
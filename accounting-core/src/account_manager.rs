@@ -0,0 +1,3685 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::mem::{size_of, size_of_val};
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use thiserror::Error;
+
+use crate::account::{Account, AccountError};
+use crate::dispute::{CaseStatus, CasesMark, DisputeCase, DisputeCases};
+use crate::error_code::ErrorCode;
+use crate::history::{AsOf, BalanceHistory, BalancePoint};
+use crate::ledger::{Ledger, LedgerAccount};
+use crate::period::{PeriodAdjustment, Periods};
+use crate::policy::{
+    BackdatedPolicy, CacheTtl, LockedDepositPolicy, Policy, PrecisionPolicy, Retention,
+    WithdrawalDisputePolicy, ZeroAmountPolicy,
+};
+use crate::role::Role;
+use crate::rules::{Rule, Subject};
+use crate::stats::{table_bytes, ManagerStats};
+use crate::tier::{Tier, TierLimits, Tiers};
+use crate::tx_cache::{DisputableTransaction, DisputeDirection, TxCache, TxCacheEntry};
+use crate::types::{Action, ClientId, Timestamp, Transaction, TransactionId, MAX_CLIENT_ID};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AccountManagerError {
+    #[error("{0}")]
+    Account(#[from] AccountError),
+
+    #[error("{}", unauthorized_message(.client_id, .owner_id))]
+    Unauthorized {
+        client_id: ClientId,
+        owner_id: ClientId,
+    },
+
+    #[error("Transaction {id} is not disputed")]
+    Undisputed { id: TransactionId },
+
+    #[error("Transaction {id} is already disputed")]
+    AlreadyDisputed { id: TransactionId },
+
+    #[error("Transaction {id} not found")]
+    TransactionNotFound { id: TransactionId },
+
+    #[error("Transaction {id} has an invalid amount of {amount}")]
+    InvalidAmount { id: TransactionId, amount: f64 },
+
+    #[error("Transaction {id} amount {amount} has more than {places} decimal places")]
+    InvalidPrecision {
+        id: TransactionId,
+        amount: f64,
+        places: u32,
+    },
+
+    #[error("Duplicate. Idempotency key {key} was already applied.")]
+    Duplicate { key: String },
+
+    #[error("Limit exceeded. {amount} is above the {limit} of {max} for {tier} accounts.")]
+    LimitExceeded {
+        tier: Tier,
+        limit: &'static str,
+        max: f64,
+        amount: f64,
+    },
+
+    #[error("Not permitted. {role} accounts can't {action}.")]
+    NotPermitted { role: Role, action: &'static str },
+
+    #[error("Escrow {id} not found")]
+    EscrowNotFound { id: TransactionId },
+
+    #[error("Escrow {id} already exists")]
+    DuplicateEscrow { id: TransactionId },
+
+    #[error("Escrow {id} has no counterparty")]
+    MissingCounterparty { id: TransactionId },
+
+    #[error("{}", account_exists_message(.client_id))]
+    AccountExists { client_id: ClientId },
+
+    #[error("Transaction {id} is backdated into the closed period {period}")]
+    Backdated { id: TransactionId, period: u32 },
+
+    #[error("Adjustment or erasure {id} has no reason")]
+    MissingReason { id: TransactionId },
+
+    #[error("Transaction {id} was applied in an earlier run")]
+    AlreadyProcessed { id: TransactionId },
+
+    #[error("Can't roll back {requested} transactions, only the last {available} are logged")]
+    RollbackUnavailable { requested: usize, available: usize },
+
+    #[error("Record at offset {offset} was already applied (committed offset {committed})")]
+    AlreadyApplied { offset: u64, committed: u64 },
+
+    #[error("Rejected by rule '{rule}'")]
+    RuleRejected { id: TransactionId, rule: String },
+
+    #[error("Rejected by the script: {reason}")]
+    ScriptRejected { id: TransactionId, reason: String },
+
+    #[error("Transaction {id} failed the script: {error}")]
+    ScriptFailed { id: TransactionId, error: String },
+
+    #[error("{}", not_settled_message(.client_id))]
+    NotSettled { client_id: ClientId },
+
+    #[error("Not allowed. Transaction {id} is a {action}, admin actions are disabled.")]
+    AdminActionRefused {
+        id: TransactionId,
+        action: &'static str,
+    },
+
+    #[error("Transaction {id} is in the id range of recurring transactions")]
+    ReservedId { id: TransactionId },
+
+    #[error("{}", invariant_message(.subject, .client.as_ref(), .expected, .actual))]
+    InvariantViolation {
+        subject: &'static str,
+        client: Option<ClientId>,
+        expected: f64,
+        actual: f64,
+    },
+}
+
+fn unauthorized_message(client: impl Display, owner: impl Display) -> String {
+    format!("Unauthorized. {client} can't modify transactions of {owner}.")
+}
+
+fn account_exists_message(client: impl Display) -> String {
+    format!("Account of client {client} already exists.")
+}
+
+fn not_settled_message(client: impl Display) -> String {
+    format!("Client {client} can't be erased while it has funds, disputes or escrows or is locked.")
+}
+
+fn invariant_message(
+    subject: &str,
+    client: Option<impl Display>,
+    expected: &f64,
+    actual: &f64,
+) -> String {
+    match client {
+        Some(client) => format!(
+            "Books don't balance. {subject} of client {client}: expected {expected}, found {actual}."
+        ),
+        None => format!("Books don't balance. {subject}: expected {expected}, found {actual}."),
+    }
+}
+
+impl AccountManagerError {
+    // The message with client ids rendered by `label`, e.g. as pseudonyms.
+    pub fn describe_with(&self, label: impl Fn(ClientId) -> String) -> String {
+        match self {
+            AccountManagerError::Unauthorized {
+                client_id,
+                owner_id,
+            } => unauthorized_message(label(*client_id), label(*owner_id)),
+            AccountManagerError::AccountExists { client_id } => {
+                account_exists_message(label(*client_id))
+            }
+            AccountManagerError::NotSettled { client_id } => not_settled_message(label(*client_id)),
+            AccountManagerError::InvariantViolation {
+                subject,
+                client,
+                expected,
+                actual,
+            } => invariant_message(subject, client.map(label), expected, actual),
+            _ => self.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AccountManagerError::Account(err) => err.kind(),
+            AccountManagerError::Unauthorized { .. } => "unauthorized",
+            AccountManagerError::Undisputed { .. } => "undisputed",
+            AccountManagerError::AlreadyDisputed { .. } => "already_disputed",
+            AccountManagerError::TransactionNotFound { .. } => "transaction_not_found",
+            AccountManagerError::InvalidAmount { .. } => "invalid_amount",
+            AccountManagerError::InvalidPrecision { .. } => "invalid_precision",
+            AccountManagerError::Duplicate { .. } => "duplicate",
+            AccountManagerError::LimitExceeded { .. } => "limit_exceeded",
+            AccountManagerError::NotPermitted { .. } => "not_permitted",
+            AccountManagerError::AccountExists { .. } => "account_exists",
+            AccountManagerError::EscrowNotFound { .. } => "escrow_not_found",
+            AccountManagerError::DuplicateEscrow { .. } => "duplicate_escrow",
+            AccountManagerError::MissingCounterparty { .. } => "missing_counterparty",
+            AccountManagerError::Backdated { .. } => "backdated",
+            AccountManagerError::MissingReason { .. } => "missing_reason",
+            AccountManagerError::AlreadyProcessed { .. } => "already_processed",
+            AccountManagerError::RollbackUnavailable { .. } => "rollback_unavailable",
+            AccountManagerError::AlreadyApplied { .. } => "already_applied",
+            AccountManagerError::RuleRejected { .. } => "rule_rejected",
+            AccountManagerError::ScriptRejected { .. } => "script_rejected",
+            AccountManagerError::ScriptFailed { .. } => "script_failed",
+            AccountManagerError::NotSettled { .. } => "not_settled",
+            AccountManagerError::AdminActionRefused { .. } => "admin_action_refused",
+            AccountManagerError::ReservedId { .. } => "reserved_id",
+            AccountManagerError::InvariantViolation { .. } => "invariant_violation",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AccountManagerError::Account(err) => err.code(),
+            AccountManagerError::Unauthorized { .. } => ErrorCode::Unauthorized,
+            AccountManagerError::Undisputed { .. } => ErrorCode::Undisputed,
+            AccountManagerError::AlreadyDisputed { .. } => ErrorCode::AlreadyDisputed,
+            AccountManagerError::TransactionNotFound { .. } => ErrorCode::TransactionNotFound,
+            AccountManagerError::InvalidAmount { .. } => ErrorCode::InvalidAmount,
+            AccountManagerError::InvalidPrecision { .. } => ErrorCode::InvalidPrecision,
+            AccountManagerError::Duplicate { .. } => ErrorCode::Duplicate,
+            AccountManagerError::LimitExceeded { .. } => ErrorCode::LimitExceeded,
+            AccountManagerError::NotPermitted { .. } => ErrorCode::NotPermitted,
+            AccountManagerError::AccountExists { .. } => ErrorCode::AccountExists,
+            AccountManagerError::EscrowNotFound { .. } => ErrorCode::EscrowNotFound,
+            AccountManagerError::DuplicateEscrow { .. } => ErrorCode::DuplicateEscrow,
+            AccountManagerError::MissingCounterparty { .. } => ErrorCode::MissingCounterparty,
+            AccountManagerError::Backdated { .. } => ErrorCode::Backdated,
+            AccountManagerError::MissingReason { .. } => ErrorCode::MissingReason,
+            AccountManagerError::AlreadyProcessed { .. } => ErrorCode::AlreadyProcessed,
+            AccountManagerError::RollbackUnavailable { .. } => ErrorCode::RollbackUnavailable,
+            AccountManagerError::AlreadyApplied { .. } => ErrorCode::AlreadyApplied,
+            AccountManagerError::RuleRejected { .. } => ErrorCode::RuleRejected,
+            AccountManagerError::ScriptRejected { .. } => ErrorCode::ScriptRejected,
+            AccountManagerError::ScriptFailed { .. } => ErrorCode::ScriptFailed,
+            AccountManagerError::NotSettled { .. } => ErrorCode::NotSettled,
+            AccountManagerError::AdminActionRefused { .. } => ErrorCode::AdminActionRefused,
+            AccountManagerError::ReservedId { .. } => ErrorCode::ReservedId,
+            AccountManagerError::InvariantViolation { .. } => ErrorCode::InvariantViolation,
+        }
+    }
+
+    // An object with the code, kind and `message` of the error and the fields
+    // of the variant, with the client ids written by `client`, e.g. as
+    // pseudonyms.
+    pub fn serialize_with<S: Serializer, C: Serialize>(
+        &self,
+        serializer: S,
+        message: &str,
+        client: impl Fn(ClientId) -> C,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", &self.code())?;
+        map.serialize_entry("kind", self.kind())?;
+        map.serialize_entry("message", message)?;
+        match self {
+            AccountManagerError::Account(err) => err.serialize_context(&mut map)?,
+            AccountManagerError::Unauthorized {
+                client_id,
+                owner_id,
+            } => {
+                map.serialize_entry("client", &client(*client_id))?;
+                map.serialize_entry("owner", &client(*owner_id))?;
+            }
+            AccountManagerError::Undisputed { id }
+            | AccountManagerError::AlreadyDisputed { id }
+            | AccountManagerError::TransactionNotFound { id }
+            | AccountManagerError::EscrowNotFound { id }
+            | AccountManagerError::DuplicateEscrow { id }
+            | AccountManagerError::MissingCounterparty { id }
+            | AccountManagerError::MissingReason { id }
+            | AccountManagerError::AlreadyProcessed { id }
+            | AccountManagerError::ReservedId { id } => map.serialize_entry("tx", id)?,
+            AccountManagerError::InvalidAmount { id, amount } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("amount", amount)?;
+            }
+            AccountManagerError::InvalidPrecision { id, amount, places } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("places", places)?;
+            }
+            AccountManagerError::Duplicate { key } => {
+                map.serialize_entry("idempotency_key", key)?
+            }
+            AccountManagerError::LimitExceeded {
+                tier,
+                limit,
+                max,
+                amount,
+            } => {
+                map.serialize_entry("tier", tier)?;
+                map.serialize_entry("limit", limit)?;
+                map.serialize_entry("max", max)?;
+                map.serialize_entry("amount", amount)?;
+            }
+            AccountManagerError::NotPermitted { role, action } => {
+                map.serialize_entry("role", role)?;
+                map.serialize_entry("action", action)?;
+            }
+            AccountManagerError::AdminActionRefused { id, action } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("action", action)?;
+            }
+            AccountManagerError::AccountExists { client_id }
+            | AccountManagerError::NotSettled { client_id } => {
+                map.serialize_entry("client", &client(*client_id))?
+            }
+            AccountManagerError::Backdated { id, period } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("period", period)?;
+            }
+            AccountManagerError::RollbackUnavailable {
+                requested,
+                available,
+            } => {
+                map.serialize_entry("requested", requested)?;
+                map.serialize_entry("available", available)?;
+            }
+            AccountManagerError::AlreadyApplied { offset, committed } => {
+                map.serialize_entry("offset", offset)?;
+                map.serialize_entry("committed", committed)?;
+            }
+            AccountManagerError::RuleRejected { id, rule } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("rule", rule)?;
+            }
+            AccountManagerError::ScriptRejected { id, reason } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("reason", reason)?;
+            }
+            AccountManagerError::ScriptFailed { id, error } => {
+                map.serialize_entry("tx", id)?;
+                map.serialize_entry("error", error)?;
+            }
+            AccountManagerError::InvariantViolation {
+                subject,
+                client: client_id,
+                expected,
+                actual,
+            } => {
+                map.serialize_entry("subject", subject)?;
+                if let Some(client_id) = client_id {
+                    map.serialize_entry("client", &client(*client_id))?;
+                }
+                map.serialize_entry("expected", expected)?;
+                map.serialize_entry("actual", actual)?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl Serialize for AccountManagerError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.serialize_with(serializer, &self.to_string(), |client_id| client_id)
+    }
+}
+
+pub type AccountManagerResult<T> = Result<T, AccountManagerError>;
+
+// The checks of disputes, resolves and chargebacks are shared with AssetBook.
+pub(crate) fn check_authorization<A>(
+    tx: &TxCacheEntry<A>,
+    client_id: ClientId,
+) -> AccountManagerResult<()> {
+    if tx.client_id != client_id {
+        return Err(AccountManagerError::Unauthorized {
+            client_id,
+            owner_id: tx.client_id,
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_disputed<A>(
+    tx: &TxCacheEntry<A>,
+    id: TransactionId,
+) -> AccountManagerResult<()> {
+    if !tx.disputed {
+        return Err(AccountManagerError::Undisputed { id });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_undisputed<A>(
+    tx: &TxCacheEntry<A>,
+    id: TransactionId,
+) -> AccountManagerResult<()> {
+    if tx.disputed {
+        return Err(AccountManagerError::AlreadyDisputed { id });
+    }
+    Ok(())
+}
+
+fn check_limit(
+    tiers: &Tiers,
+    client_id: ClientId,
+    limit: &'static str,
+    max: fn(&TierLimits) -> Option<f64>,
+    amount: f64,
+) -> AccountManagerResult<()> {
+    match tiers.limits(client_id).and_then(max) {
+        Some(max) if amount > max => Err(AccountManagerError::LimitExceeded {
+            tier: tiers.tier(client_id),
+            limit,
+            max,
+            amount,
+        }),
+        _ => Ok(()),
+    }
+}
+
+const BALANCE_TOLERANCE: f64 = 1e-6;
+
+// The most entries AccountManager::reserve preallocates per map, about 800 MB
+// of transaction cache.
+pub const MAX_RESERVED: usize = 1 << 26;
+
+fn check_balance(
+    subject: &'static str,
+    client: Option<ClientId>,
+    expected: f64,
+    actual: f64,
+) -> AccountManagerResult<()> {
+    if (expected - actual).abs() > BALANCE_TOLERANCE * expected.abs().max(1.0) {
+        return Err(AccountManagerError::InvariantViolation {
+            subject,
+            client,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Totals {
+    deposits: f64,
+    withdrawals: f64,
+    chargebacks: f64,
+    // Kept by the business, so they are in cash but in no account.
+    #[serde(default)]
+    fees: f64,
+    // Escrows released into minus out of these books. Zero unless escrows
+    // cross the shards of a ShardedStore.
+    #[serde(default)]
+    transfers: f64,
+    // Manual credits minus debits, not backed by cash.
+    #[serde(default)]
+    adjustments: f64,
+}
+
+impl Totals {
+    fn cash(&self) -> f64 {
+        self.deposits - self.withdrawals - self.chargebacks
+    }
+
+    // The money that should be in the accounts.
+    fn balances(&self) -> f64 {
+        self.cash() - self.fees + self.transfers + self.adjustments
+    }
+}
+
+// The accounts a transaction may change and the totals, taken before it is
+// applied to re-validate the books after it.
+#[cfg(feature = "invariant-checks")]
+struct InvariantMark {
+    accounts: Vec<(ClientId, Option<Account>)>,
+    totals: Totals,
+}
+
+#[cfg(feature = "invariant-checks")]
+fn check_floor(subject: &str, client_id: ClientId, floor: f64, actual: f64) -> Result<(), String> {
+    if !actual.is_finite() || actual < floor - BALANCE_TOLERANCE * floor.abs().max(1.0) {
+        return Err(format!(
+            "{subject} of client {client_id} is {actual}, below {floor}"
+        ));
+    }
+    Ok(())
+}
+
+// A manual credit (positive amount) or debit of a customer balance.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ManualAdjustment {
+    pub tx_id: TransactionId,
+    pub client_id: ClientId,
+    pub amount: f64,
+    pub reason: String,
+    pub timestamp: Option<Timestamp>,
+}
+
+// Funds a client holds for a counterparty.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Escrow {
+    pub client_id: ClientId,
+    pub counterparty: ClientId,
+    pub amount: f64,
+}
+
+// What compactions dropped from the books.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Compacted {
+    pub periods: u64,
+    // Cached deposits and withdrawals, they can't be disputed anymore.
+    pub transactions: u64,
+    pub journal_entries: u64,
+    pub history_points: u64,
+    pub cases: u64,
+    pub adjustments: u64,
+}
+
+impl Compacted {
+    fn add(&mut self, other: &Compacted) {
+        self.periods += other.periods;
+        self.transactions += other.transactions;
+        self.journal_entries += other.journal_entries;
+        self.history_points += other.history_points;
+        self.cases += other.cases;
+        self.adjustments += other.adjustments;
+    }
+}
+
+// Where the details of a closed period end in the logs of the books.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+struct PeriodDetails {
+    // Deposits and withdrawals cached in the period, only with a retention.
+    tx_ids: Vec<TransactionId>,
+    ledger: u64,
+    history: u64,
+    cases: usize,
+    adjustments: usize,
+    period_adjustments: usize,
+}
+
+// The closed periods that weren't compacted yet, oldest first, and the
+// transactions cached in the current period.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Details {
+    cached: Vec<TransactionId>,
+    closed: VecDeque<PeriodDetails>,
+    compacted: Compacted,
+}
+
+// A cached transaction waiting to expire.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct CachedTx {
+    applied: u64,
+    timestamp: Option<Timestamp>,
+    tx_id: TransactionId,
+}
+
+// The cached transactions in the order they were applied, with the clocks of
+// the cache ttl.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+struct Expiry {
+    applied: u64,
+    latest: Option<Timestamp>,
+    queue: VecDeque<CachedTx>,
+    evicted: u64,
+}
+
+impl Expiry {
+    fn is_expired(&self, cached: &CachedTx, ttl: CacheTtl) -> bool {
+        match ttl {
+            CacheTtl::Forever => false,
+            CacheTtl::Transactions(count) => self.applied - cached.applied > count,
+            CacheTtl::Seconds(seconds) => match (cached.timestamp, self.latest) {
+                (Some(timestamp), Some(latest)) => latest.saturating_sub(timestamp) > seconds,
+                _ => false,
+            },
+        }
+    }
+}
+
+// The state an applied transaction changed, taken before it was applied.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct UndoEntry {
+    tx_id: TransactionId,
+    accounts: Vec<(ClientId, Option<Account>)>,
+    tx_cache: Option<TxCacheEntry>,
+    escrow: Option<Escrow>,
+    // Inserted by the transaction.
+    idempotency_key: Option<String>,
+    committed_offset: Option<u64>,
+    totals: Totals,
+    ledger: usize,
+    cases: CasesMark,
+    // Only for period closes, other transactions just add adjustments.
+    periods: Option<Periods>,
+    period_adjustments: usize,
+    adjustments: usize,
+    history: Option<u64>,
+}
+
+// The last `depth` applied transactions, newest last.
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+struct UndoLog {
+    depth: usize,
+    entries: VecDeque<UndoEntry>,
+}
+
+impl UndoLog {
+    fn push(&mut self, entry: UndoEntry) {
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        if self.depth > 0 {
+            self.entries.push_back(entry);
+        }
+    }
+}
+
+fn restore<K: Eq + Hash, V>(map: &mut HashMap<K, V>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+
+fn post(
+    ledger: &mut Option<Ledger>,
+    tx_id: TransactionId,
+    action: Action,
+    debit: LedgerAccount,
+    credit: LedgerAccount,
+    amount: f64,
+) {
+    if let Some(ledger) = ledger {
+        ledger.post(tx_id, action, debit, credit, amount);
+    }
+}
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct AccountManager {
+    accounts: HashMap<ClientId, Account>,
+    tx_cache: TxCache,
+    #[serde(default)]
+    escrows: HashMap<TransactionId, Escrow>,
+    idempotency_keys: HashSet<String>,
+    committed_offset: Option<u64>,
+    totals: Totals,
+    ledger: Option<Ledger>,
+    #[serde(default)]
+    periods: Periods,
+    #[serde(default)]
+    cases: DisputeCases,
+    #[serde(default)]
+    adjustments: Vec<ManualAdjustment>,
+    #[serde(default)]
+    history: Option<BalanceHistory>,
+    #[serde(default)]
+    undo: Option<UndoLog>,
+    #[serde(default)]
+    expiry: Expiry,
+    #[serde(default)]
+    details: Details,
+    #[serde(skip)]
+    policy: Policy,
+}
+
+impl AccountManager {
+    pub fn new() -> Self {
+        Self::with_policy(Policy::default())
+    }
+
+    pub fn with_policy(policy: Policy) -> Self {
+        Self {
+            accounts: HashMap::new(),
+            tx_cache: TxCache::default(),
+            escrows: HashMap::new(),
+            idempotency_keys: HashSet::new(),
+            committed_offset: None,
+            totals: Totals::default(),
+            ledger: None,
+            periods: Periods::new(),
+            cases: DisputeCases::new(),
+            adjustments: Vec::new(),
+            history: None,
+            undo: None,
+            expiry: Expiry::default(),
+            details: Details::default(),
+            policy,
+        }
+    }
+
+    // Preallocates for the expected number of accounts and deposits, so giant
+    // ingests don't rehash the maps over and over.
+    pub fn with_capacity(accounts: usize, transactions: usize) -> Self {
+        let mut account_manager = Self::new();
+        account_manager.reserve(accounts, transactions);
+        account_manager
+    }
+
+    // Grows the maps to hold the expected totals, e.g. after a restore. The
+    // totals are hints: they are capped at MAX_RESERVED (and the accounts at
+    // the number of client ids), and if the memory can't be had the maps
+    // grow as needed instead.
+    pub fn reserve(&mut self, accounts: usize, transactions: usize) {
+        let clients = usize::try_from(MAX_CLIENT_ID).map_or(usize::MAX, |max| max + 1);
+        let accounts = accounts.min(clients).min(MAX_RESERVED);
+        let _ = self
+            .accounts
+            .try_reserve(accounts.saturating_sub(self.accounts.len()));
+        let _ = self.tx_cache.try_reserve(
+            transactions
+                .min(MAX_RESERVED)
+                .saturating_sub(self.tx_cache.len()),
+        );
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub fn committed_offset(&self) -> Option<u64> {
+        self.committed_offset
+    }
+
+    pub fn enable_ledger(&mut self) {
+        self.ledger.get_or_insert_with(Ledger::new);
+    }
+
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    pub fn enable_history(&mut self) {
+        self.history.get_or_insert_with(BalanceHistory::new);
+    }
+
+    pub fn balance_history(&self) -> Option<&BalanceHistory> {
+        self.history.as_ref()
+    }
+
+    // The applied transactions that touched the account of the client, with
+    // its balances after each of them. Needs the history, empty without it.
+    pub fn history(&self, client_id: ClientId) -> impl Iterator<Item = &BalancePoint> + '_ {
+        self.history
+            .iter()
+            .flat_map(move |history| history.points(client_id))
+    }
+
+    // Needs the history, none without it.
+    pub fn balance_as_of(&self, client_id: ClientId, point: AsOf) -> Option<&BalancePoint> {
+        self.history.as_ref()?.as_of(client_id, point)
+    }
+
+    // Keeps what the last `depth` applied transactions changed, so that they
+    // can be rolled back.
+    pub fn enable_undo(&mut self, depth: usize) {
+        let undo = self.undo.get_or_insert_with(UndoLog::default);
+        undo.depth = depth;
+        while undo.entries.len() > depth {
+            undo.entries.pop_front();
+        }
+    }
+
+    // Applied transactions that can be rolled back.
+    pub fn undoable(&self) -> usize {
+        self.undo.as_ref().map_or(0, |undo| undo.entries.len())
+    }
+
+    // Restores the books, including the committed offset, to the state before
+    // the last `count` applied transactions, newest first. Nothing is rolled
+    // back if fewer are logged.
+    pub fn rollback(&mut self, count: usize) -> AccountManagerResult<()> {
+        let available = self.undoable();
+        if count > available {
+            return Err(AccountManagerError::RollbackUnavailable {
+                requested: count,
+                available,
+            });
+        }
+        for _ in 0..count {
+            if let Some(entry) = self.undo.as_mut().and_then(|undo| undo.entries.pop_back()) {
+                self.undo_entry(entry);
+            }
+        }
+        Ok(())
+    }
+
+    // The state `tx` may change. A released escrow also credits the
+    // counterparty.
+    fn undo_snapshot(&self, tx: &Transaction, idempotency_key: Option<String>) -> UndoEntry {
+        let counterparty = match tx.action {
+            Action::ReleaseEscrow => self.escrow(tx.id).map(|escrow| escrow.counterparty),
+            _ => None,
+        };
+        UndoEntry {
+            tx_id: tx.id,
+            accounts: std::iter::once(tx.client_id)
+                .chain(counterparty)
+                .map(|client_id| (client_id, self.accounts.get(&client_id).cloned()))
+                .collect(),
+            tx_cache: self.tx_cache.get(tx.id),
+            escrow: self.escrows.get(&tx.id).cloned(),
+            idempotency_key,
+            committed_offset: self.committed_offset,
+            totals: self.totals.clone(),
+            ledger: self
+                .ledger
+                .as_ref()
+                .map_or(0, |ledger| ledger.entries().len()),
+            cases: self.cases.mark(tx.id),
+            periods: (tx.action == Action::ClosePeriod).then(|| self.periods.clone()),
+            period_adjustments: self.periods.adjustments().len(),
+            adjustments: self.adjustments.len(),
+            history: self.history.as_ref().map(BalanceHistory::sequence),
+        }
+    }
+
+    fn undo_entry(&mut self, entry: UndoEntry) {
+        if let (Some(history), Some(sequence)) = (&mut self.history, entry.history) {
+            history.rewind(
+                sequence,
+                entry.accounts.iter().map(|(client_id, _)| *client_id),
+            );
+        }
+        for (client_id, account) in entry.accounts {
+            restore(&mut self.accounts, client_id, account);
+        }
+        match entry.tx_cache {
+            Some(cached) => self.tx_cache.insert(entry.tx_id, cached),
+            None => _ = self.tx_cache.remove(entry.tx_id),
+        }
+        restore(&mut self.escrows, entry.tx_id, entry.escrow);
+        if let Some(key) = &entry.idempotency_key {
+            self.idempotency_keys.remove(key);
+        }
+        self.committed_offset = entry.committed_offset;
+        self.totals = entry.totals;
+        if let Some(ledger) = &mut self.ledger {
+            ledger.truncate(entry.ledger);
+        }
+        self.cases.restore(entry.tx_id, entry.cases);
+        if let Some(periods) = entry.periods {
+            self.periods = periods;
+            // The transactions of the reopened period are cached in it again.
+            if let Some(period) = self.details.closed.pop_back() {
+                let cached = std::mem::replace(&mut self.details.cached, period.tx_ids);
+                self.details.cached.extend(cached);
+            }
+        }
+        self.periods.truncate_adjustments(entry.period_adjustments);
+        self.adjustments.truncate(entry.adjustments);
+    }
+
+    // The size of the books, cheap enough to ask for during a run. Strings
+    // and other data behind pointers aren't counted.
+    pub fn stats(&self) -> ManagerStats {
+        let ledger = self.ledger.as_ref().map_or(&[][..], Ledger::entries);
+        let history = self.history.as_ref().map_or(0, BalanceHistory::len);
+        let undo = self.undo.as_ref().map_or(0, |undo| undo.entries.len());
+        let logs = size_of_val(ledger)
+            + history * size_of::<BalancePoint>()
+            + undo * size_of::<UndoEntry>()
+            + self.cases.len() * size_of::<DisputeCase>()
+            + self.expiry.queue.len() * size_of::<CachedTx>()
+            + size_of_val(self.adjustments.as_slice());
+        ManagerStats {
+            accounts: self.accounts.len(),
+            cached_transactions: self.tx_cache.len(),
+            open_disputes: self.cases.open_count(),
+            memory_bytes: table_bytes::<(ClientId, Account)>(self.accounts.capacity())
+                + self.tx_cache.memory_bytes()
+                + table_bytes::<(TransactionId, Escrow)>(self.escrows.capacity())
+                + table_bytes::<String>(self.idempotency_keys.capacity())
+                + logs,
+        }
+    }
+
+    // Cached transactions dropped by the cache ttl.
+    pub fn evicted_transactions(&self) -> u64 {
+        self.expiry.evicted
+    }
+
+    // What compactions dropped so far.
+    pub fn compacted(&self) -> Compacted {
+        self.details.compacted
+    }
+
+    // Notes the transaction `tx` cached, to drop it once its period is out of
+    // the retention.
+    fn retain(&mut self, tx: &Transaction) {
+        if self.policy.retention != Retention::Forever
+            && matches!(tx.action, Action::Deposit | Action::Withdrawal)
+            && self.tx_cache.contains(tx.id)
+        {
+            self.details.cached.push(tx.id);
+        }
+    }
+
+    // Drops the details of the closed periods beyond the retention of the
+    // policy: their undisputed cached transactions, journal entries (carried
+    // forward as balances), history points but the last of every client,
+    // closed dispute cases and adjustments. Statement totals and balances
+    // stay. Clears the undo log if anything was dropped.
+    pub fn compact(&mut self) -> Compacted {
+        let Retention::Periods(keep) = self.policy.retention else {
+            return Compacted::default();
+        };
+        let mut compacted = Compacted::default();
+        while self.details.closed.len() > keep as usize {
+            let Some(period) = self.details.closed.pop_front() else {
+                break;
+            };
+            // Disputed transactions move on to the next period.
+            for tx_id in period.tx_ids {
+                match self.tx_cache.get(tx_id) {
+                    Some(entry) if entry.disputed => self
+                        .details
+                        .closed
+                        .front_mut()
+                        .map_or(&mut self.details.cached, |next| &mut next.tx_ids)
+                        .push(tx_id),
+                    Some(_) => {
+                        self.tx_cache.remove(tx_id);
+                        compacted.transactions += 1;
+                    }
+                    None => {}
+                }
+            }
+            if let Some(ledger) = &mut self.ledger {
+                compacted.journal_entries += ledger.compact(period.ledger) as u64;
+            }
+            if let Some(history) = &mut self.history {
+                compacted.history_points += history.compact(period.history) as u64;
+            }
+            let cases = self.cases.compact(period.cases);
+            let adjustments = period.adjustments.min(self.adjustments.len());
+            self.adjustments.drain(..adjustments);
+            self.periods.compact_adjustments(period.period_adjustments);
+            // Erasures may have dropped some of them already.
+            for next in &mut self.details.closed {
+                next.cases = next.cases.saturating_sub(cases);
+                next.adjustments = next.adjustments.saturating_sub(adjustments);
+                next.period_adjustments = next
+                    .period_adjustments
+                    .saturating_sub(period.period_adjustments);
+            }
+            compacted.cases += cases as u64;
+            compacted.adjustments += adjustments as u64;
+            compacted.periods += 1;
+        }
+        if compacted.periods > 0 {
+            if let Some(undo) = &mut self.undo {
+                undo.entries.clear();
+            }
+            self.details.compacted.add(&compacted);
+        }
+        compacted
+    }
+
+    // Queues the cached transaction `tx` created and drops the expired ones.
+    // Disputed transactions stay, they expire again from now on.
+    fn expire(&mut self, tx: &Transaction) {
+        let ttl = self.policy.cache_ttl;
+        if ttl == CacheTtl::Forever {
+            return;
+        }
+        let expiry = &mut self.expiry;
+        expiry.applied += 1;
+        if let Some(timestamp) = tx.timestamp {
+            expiry.latest = Some(
+                expiry
+                    .latest
+                    .map_or(timestamp, |latest| latest.max(timestamp)),
+            );
+        }
+        let now = |expiry: &Expiry, tx_id| CachedTx {
+            applied: expiry.applied,
+            timestamp: expiry.latest,
+            tx_id,
+        };
+        if matches!(tx.action, Action::Deposit | Action::Withdrawal)
+            && self.tx_cache.contains(tx.id)
+        {
+            let cached = CachedTx {
+                timestamp: tx.timestamp.or(expiry.latest),
+                ..now(expiry, tx.id)
+            };
+            expiry.queue.push_back(cached);
+        }
+        while let Some(cached) = expiry.queue.front() {
+            if !expiry.is_expired(cached, ttl) {
+                break;
+            }
+            let tx_id = cached.tx_id;
+            expiry.queue.pop_front();
+            match self.tx_cache.get(tx_id) {
+                Some(entry) if entry.disputed => {
+                    let cached = now(expiry, tx_id);
+                    expiry.queue.push_back(cached);
+                }
+                Some(_) => {
+                    self.tx_cache.remove(tx_id);
+                    expiry.evicted += 1;
+                    // Rollbacks couldn't bring the evicted transaction back.
+                    if let Some(undo) = &mut self.undo {
+                        undo.entries.clear();
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    // Adds a point for every account the applied transaction touched.
+    fn record_history(&mut self, tx: &Transaction, counterparty: Option<ClientId>) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        history.next_sequence();
+        for client_id in std::iter::once(tx.client_id).chain(counterparty) {
+            if let Some(account) = self.accounts.get(&client_id) {
+                history.record(client_id, account, tx);
+            }
+        }
+    }
+
+    // The history of a counterparty credited by a release in other books.
+    fn record_received(&mut self, tx: &Transaction, counterparty: ClientId) {
+        let Some(history) = &mut self.history else {
+            return;
+        };
+        history.next_sequence();
+        if let Some(account) = self.accounts.get(&counterparty) {
+            history.record(counterparty, account, tx);
+        }
+    }
+
+    pub fn periods(&self) -> &Periods {
+        &self.periods
+    }
+
+    // Every applied adjustment, in order.
+    pub fn manual_adjustments(&self) -> &[ManualAdjustment] {
+        &self.adjustments
+    }
+
+    // Credits or debits the available funds of the client, regardless of
+    // locks, limits and funds. Only the reason is required.
+    pub fn adjust(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+        amount: f64,
+        reason: Option<&str>,
+        timestamp: Option<Timestamp>,
+    ) -> AccountManagerResult<()> {
+        let reason = reason
+            .filter(|reason| !reason.trim().is_empty())
+            .ok_or(AccountManagerError::MissingReason { id: tx_id })?;
+        self.accounts.entry(client_id).or_default().adjust(amount)?;
+        self.totals.adjustments += amount;
+        let (debit, credit) = match amount < 0.0 {
+            true => (
+                LedgerAccount::CustomerAvailable(client_id),
+                LedgerAccount::Adjustments,
+            ),
+            false => (
+                LedgerAccount::Adjustments,
+                LedgerAccount::CustomerAvailable(client_id),
+            ),
+        };
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::Adjustment,
+            debit,
+            credit,
+            amount.abs(),
+        );
+        self.adjustments.push(ManualAdjustment {
+            tx_id,
+            client_id,
+            amount,
+            reason: reason.to_string(),
+            timestamp,
+        });
+        Ok(())
+    }
+
+    // Removes everything that links the client to its transactions: the
+    // account, the cached transactions, the dispute cases, the adjustments
+    // and the statement and history entries. The ledger keeps the amounts on
+    // the erased customer account, so the books still balance. Only settled
+    // clients can be erased, and only with a reason. The erasure clears the
+    // undo log, the transactions before it can't be rolled back anymore.
+    pub fn erase_client(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+        reason: Option<&str>,
+    ) -> AccountManagerResult<()> {
+        if reason.is_none_or(|reason| reason.trim().is_empty()) {
+            return Err(AccountManagerError::MissingReason { id: tx_id });
+        }
+        let unsettled = self.accounts.get(&client_id).is_some_and(|account| {
+            [
+                account.available(),
+                account.disputed(),
+                account.suspense(),
+                account.escrow(),
+                account.receivable(),
+            ]
+            .iter()
+            .any(|amount| *amount != 0.0)
+                // Erasing a locked client would unlock it with its next row.
+                || account.locked()
+        });
+        let escrows = self
+            .escrows
+            .values()
+            .any(|escrow| escrow.client_id == client_id || escrow.counterparty == client_id);
+        if unsettled
+            || escrows
+            || self
+                .cases
+                .open_cases()
+                .any(|case| case.client_id == client_id)
+        {
+            return Err(AccountManagerError::NotSettled { client_id });
+        }
+
+        self.accounts.remove(&client_id);
+        let tx_ids: Vec<_> = self
+            .tx_cache
+            .client_entries(client_id)
+            .map(|(tx_id, _)| tx_id)
+            .collect();
+        for tx_id in tx_ids {
+            self.tx_cache.remove(tx_id);
+        }
+        self.cases.erase_client(client_id);
+        self.adjustments
+            .retain(|adjustment| adjustment.client_id != client_id);
+        self.periods.erase_client(client_id);
+        if let Some(history) = &mut self.history {
+            history.erase_client(client_id);
+        }
+        if let Some(ledger) = &mut self.ledger {
+            ledger.erase_client(client_id);
+        }
+        if let Some(undo) = &mut self.undo {
+            undo.entries.clear();
+        }
+        Ok(())
+    }
+
+    pub fn dispute_cases(&self) -> &DisputeCases {
+        &self.cases
+    }
+
+    // Adds evidence to the open dispute case of the transaction.
+    pub fn attach_evidence(
+        &mut self,
+        tx_id: TransactionId,
+        evidence: String,
+    ) -> AccountManagerResult<()> {
+        match self.cases.attach_evidence(tx_id, evidence) {
+            true => Ok(()),
+            false => Err(AccountManagerError::Undisputed { id: tx_id }),
+        }
+    }
+
+    // Opens or closes the dispute case of an applied dispute, resolve or
+    // chargeback. Disputes that didn't hold any funds open no case.
+    fn record_case(&mut self, tx: &Transaction) {
+        let status = match tx.action {
+            Action::Dispute => {
+                let Some(entry) = self.tx_cache.get(tx.id).filter(|entry| entry.disputed) else {
+                    return;
+                };
+                self.cases.open(DisputeCase {
+                    opened_at: tx.timestamp,
+                    reason: tx.description.clone(),
+                    evidence: tx.reference.iter().cloned().collect(),
+                    ..DisputeCase::new(tx.id, tx.client_id, entry.amount)
+                });
+                return;
+            }
+            Action::Resolve => CaseStatus::Resolved,
+            Action::Chargeback => CaseStatus::ChargedBack,
+            _ => return,
+        };
+        if let Some(reference) = &tx.reference {
+            self.cases.attach_evidence(tx.id, reference.clone());
+        }
+        self.cases.close(tx.id, status, tx.timestamp);
+    }
+
+    // The closed period the timestamp of the transaction falls into.
+    pub fn backdated_period(&self, tx: &Transaction) -> Option<u32> {
+        match tx.action {
+            Action::ClosePeriod
+            | Action::EraseClient
+            | Action::Freeze
+            | Action::Unfreeze
+            | Action::Balance => None,
+            _ => tx
+                .timestamp
+                .and_then(|timestamp| self.periods.period_of(timestamp)),
+        }
+    }
+
+    // Freezes the statement totals of the current period, starts the next and
+    // compacts the periods beyond the retention.
+    pub fn close_period(
+        &mut self,
+        label: Option<String>,
+        closed_at: Option<Timestamp>,
+    ) -> Compacted {
+        let ledger = self.ledger.as_ref().map_or(0, Ledger::sequence);
+        self.periods
+            .close(self.accounts.iter(), label, closed_at, ledger);
+        self.details.closed.push_back(PeriodDetails {
+            tx_ids: std::mem::take(&mut self.details.cached),
+            ledger,
+            history: self.history.as_ref().map_or(0, BalanceHistory::sequence),
+            cases: self.cases.len(),
+            adjustments: self.adjustments.len(),
+            period_adjustments: self.periods.adjustments().len(),
+        });
+        self.compact()
+    }
+
+    fn check_idempotency_key(&self, key: &str) -> AccountManagerResult<()> {
+        if self.idempotency_keys.contains(key) {
+            return Err(AccountManagerError::Duplicate {
+                key: key.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // Checks that the sum of all account balances equals the money that moved
+    // through the engine and, with the ledger enabled, that every account
+    // agrees with its ledger accounts.
+    pub fn verify_invariants(&self) -> AccountManagerResult<()> {
+        let cash = self.totals.cash();
+        let expected = self.totals.balances();
+        let actual: f64 = self.accounts.values().map(Account::total).sum();
+        check_balance("sum of account balances", None, expected, actual)?;
+
+        if let Some(ledger) = &self.ledger {
+            let balances = ledger.balances();
+            let balance = |account| -balances.get(&account).copied().unwrap_or_default();
+            check_balance("cash", None, cash, -balance(LedgerAccount::Cash))?;
+            for (client_id, account) in &self.accounts {
+                check_balance(
+                    "available",
+                    Some(*client_id),
+                    account.available(),
+                    balance(LedgerAccount::CustomerAvailable(*client_id)),
+                )?;
+                check_balance(
+                    "held",
+                    Some(*client_id),
+                    account.disputed(),
+                    balance(LedgerAccount::CustomerHeld(*client_id)),
+                )?;
+                check_balance(
+                    "suspense",
+                    Some(*client_id),
+                    account.suspense(),
+                    balance(LedgerAccount::CustomerSuspense(*client_id)),
+                )?;
+                check_balance(
+                    "escrow",
+                    Some(*client_id),
+                    account.escrow(),
+                    balance(LedgerAccount::CustomerEscrow(*client_id)),
+                )?;
+                check_balance(
+                    "receivable",
+                    Some(*client_id),
+                    account.receivable(),
+                    -balance(LedgerAccount::CustomerReceivable(*client_id)),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "invariant-checks")]
+    fn invariant_mark(&self, client_id: ClientId, counterparty: Option<ClientId>) -> InvariantMark {
+        let clients = std::iter::once(client_id).chain(counterparty.filter(|id| *id != client_id));
+        InvariantMark {
+            accounts: clients
+                .map(|id| (id, self.accounts.get(&id).cloned()))
+                .collect(),
+            totals: self.totals.clone(),
+        }
+    }
+
+    // Checks the accounts a transaction changed: no funds below their floor,
+    // the disputed funds and escrows agree with the tx cache and the escrows,
+    // and the totals changed by as much as the balances.
+    #[cfg(feature = "invariant-checks")]
+    fn check_account_invariants(
+        &self,
+        tx: &Transaction,
+        mark: &InvariantMark,
+    ) -> Result<(), String> {
+        let mut change = 0.0;
+        for (client_id, before) in &mark.accounts {
+            let Some(account) = self.accounts.get(client_id) else {
+                continue;
+            };
+            let before = before.clone().unwrap_or_default();
+            change += account.total() - before.total();
+            // Only manual adjustments overdraw an account, and an overdrawn
+            // one can't drop further.
+            let floor = match tx.action {
+                Action::Adjustment => f64::NEG_INFINITY,
+                _ => before.available().min(0.0),
+            };
+            check_floor("available", *client_id, floor, account.available())?;
+            let held = [
+                ("disputed", account.disputed()),
+                ("suspense", account.suspense()),
+                ("escrow", account.escrow()),
+                ("receivable", account.receivable()),
+            ];
+            for (subject, amount) in held {
+                check_floor(subject, *client_id, 0.0, amount)?;
+            }
+            let disputed = self
+                .tx_cache
+                .client_entries(*client_id)
+                .filter(|(_, entry)| entry.disputed)
+                .map(|(_, entry)| entry.amount)
+                .sum();
+            let escrow = self
+                .escrows
+                .values()
+                .filter(|escrow| escrow.client_id == *client_id)
+                .map(|escrow| escrow.amount)
+                .sum();
+            let client = Some(*client_id);
+            check_balance(
+                "disputed transactions",
+                client,
+                disputed,
+                account.disputed(),
+            )
+            .and_then(|()| check_balance("escrows", client, escrow, account.escrow()))
+            .map_err(|err| err.to_string())?;
+        }
+        let expected = self.totals.balances() - mark.totals.balances();
+        check_balance("change of the balances", None, expected, change)
+            .map_err(|err| err.to_string())
+    }
+
+    // Panics if the transaction broke an invariant of the books, so CI runs
+    // and fuzzing catch the operation that did.
+    #[cfg(feature = "invariant-checks")]
+    fn assert_invariants(&self, tx: &Transaction, mark: InvariantMark) {
+        if let Err(violation) = self.check_account_invariants(tx, &mark) {
+            let after: Vec<_> = mark
+                .accounts
+                .iter()
+                .map(|(client_id, _)| (client_id, self.accounts.get(client_id)))
+                .collect();
+            panic!(
+                "Invariant violated by {} {} of client {}: {violation}\nbefore: {:?}\nafter: {after:?}",
+                tx.action.as_str(),
+                tx.id,
+                tx.client_id,
+                mark.accounts
+            );
+        }
+    }
+
+    // The book of one client with its account and the cached transactions of
+    // the client, e.g. loaded from a SharedBook.
+    pub(crate) fn client_book(
+        policy: Policy,
+        client_id: ClientId,
+        account: Option<Account>,
+        tx_cache: TxCache,
+    ) -> Self {
+        let mut account_manager = Self::with_policy(policy);
+        account_manager
+            .accounts
+            .extend(account.map(|account| (client_id, account)));
+        account_manager.tx_cache = tx_cache;
+        account_manager
+    }
+
+    pub(crate) fn into_client_book(mut self, client_id: ClientId) -> (Option<Account>, TxCache) {
+        (self.accounts.remove(&client_id), self.tx_cache)
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    // The deposits and withdrawals of the client that can still be disputed,
+    // ordered by tx id. Charged back transactions aren't disputable anymore.
+    pub fn transactions_for(
+        &self,
+        client_id: ClientId,
+    ) -> impl Iterator<Item = DisputableTransaction> + '_ {
+        self.tx_cache
+            .client_entries(client_id)
+            .map(|(id, entry)| DisputableTransaction {
+                id,
+                action: match entry.direction {
+                    DisputeDirection::Incoming => Action::Deposit,
+                    DisputeDirection::Outgoing => Action::Withdrawal,
+                },
+                amount: entry.amount,
+                disputed: entry.disputed,
+            })
+    }
+
+    // The role the client opened the account with, or the configured one.
+    pub fn role(&self, client_id: ClientId) -> Role {
+        self.accounts
+            .get(&client_id)
+            .and_then(Account::role)
+            .unwrap_or_else(|| self.policy.roles.role(client_id))
+    }
+
+    // The first matching reject and freeze rules of the policy.
+    fn matched_rules(&self, tx: &Transaction) -> (Option<&Rule>, Option<&Rule>) {
+        if self.policy.rules.is_empty()
+            || matches!(
+                tx.action,
+                Action::ClosePeriod
+                    | Action::EraseClient
+                    | Action::Freeze
+                    | Action::Unfreeze
+                    | Action::Balance
+            )
+        {
+            return (None, None);
+        }
+        let disputes = self.account(tx.client_id).map_or(0, Account::disputes);
+        let subject = Subject {
+            tx,
+            tier: self.policy.tiers.tier(tx.client_id),
+            role: self.role(tx.client_id),
+            disputes: disputes + u64::from(tx.action == Action::Dispute),
+        };
+        self.policy.rules.evaluate(&subject)
+    }
+
+    // Client of a transaction that can still be disputed.
+    pub(crate) fn transaction_owner(&self, tx_id: TransactionId) -> Option<ClientId> {
+        self.tx_cache.get(tx_id).map(|tx| tx.client_id)
+    }
+
+    pub fn escrow(&self, tx_id: TransactionId) -> Option<&Escrow> {
+        self.escrows.get(&tx_id)
+    }
+
+    pub fn accounts(&self) -> Vec<(ClientId, Account)> {
+        self.accounts.clone().into_iter().collect()
+    }
+
+    // Borrows the accounts, e.g. to write a report without copying them.
+    pub fn iter_accounts(&self) -> impl Iterator<Item = (&ClientId, &Account)> + Clone {
+        self.accounts.iter()
+    }
+
+    // Locks the account like a chargeback does, e.g. on a manual decision of
+    // risk. Clients without an account get a locked one.
+    pub fn freeze(&mut self, client_id: ClientId) {
+        let role = self.role(client_id);
+        self.accounts
+            .entry(client_id)
+            .or_insert_with(|| Account::with_role(role))
+            .lock();
+    }
+
+    // Unlocks the account, also one locked by a chargeback or a rule.
+    pub fn unfreeze(&mut self, client_id: ClientId) {
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            account.unlock();
+        }
+    }
+
+    pub fn open_account(
+        &mut self,
+        client_id: ClientId,
+        role: Option<Role>,
+    ) -> AccountManagerResult<()> {
+        if self.accounts.contains_key(&client_id) {
+            return Err(AccountManagerError::AccountExists { client_id });
+        }
+        let role = role.unwrap_or_else(|| self.policy.roles.role(client_id));
+        self.accounts.insert(client_id, Account::with_role(role));
+        Ok(())
+    }
+
+    pub fn deposit(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+        amount: f64,
+    ) -> AccountManagerResult<()> {
+        let role = self.role(client_id);
+        let account = self.accounts.entry(client_id).or_default();
+        check_limit(
+            &self.policy.tiers,
+            client_id,
+            "max balance",
+            |limits| limits.max_balance,
+            account.total() + amount,
+        )?;
+        if account.locked() {
+            match self.policy.locked_deposit {
+                LockedDepositPolicy::Accept => {}
+                LockedDepositPolicy::Reject => return Err(AccountError::Locked.into()),
+                LockedDepositPolicy::Suspense => {
+                    // Not cached, so the deposit can't be disputed.
+                    account.deposit_to_suspense(amount)?;
+                    self.totals.deposits += amount;
+                    post(
+                        &mut self.ledger,
+                        tx_id,
+                        Action::Deposit,
+                        LedgerAccount::Cash,
+                        LedgerAccount::CustomerSuspense(client_id),
+                        amount,
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        // Credited and charged together, so a rejected fee leaves the account,
+        // totals and ledger as they were.
+        let fee = self.policy.roles.fee(role, amount, &self.policy.currency);
+        let mut credited = account.clone();
+        credited.deposit(amount)?;
+        if fee > 0.0 {
+            credited.charge(fee)?;
+        }
+        *account = credited;
+        self.totals.deposits += amount;
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::Deposit,
+            LedgerAccount::Cash,
+            LedgerAccount::CustomerAvailable(client_id),
+            amount,
+        );
+        // Only the credited amount can be disputed, the fee is kept.
+        if fee > 0.0 {
+            self.totals.fees += fee;
+            post(
+                &mut self.ledger,
+                tx_id,
+                Action::Deposit,
+                LedgerAccount::CustomerAvailable(client_id),
+                LedgerAccount::FeeIncome,
+                fee,
+            );
+        }
+        self.tx_cache.insert(
+            tx_id,
+            TxCacheEntry::new(client_id, amount - fee, DisputeDirection::Incoming),
+        );
+        Ok(())
+    }
+
+    pub fn withdraw(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+        amount: f64,
+    ) -> AccountManagerResult<()> {
+        check_limit(
+            &self.policy.tiers,
+            client_id,
+            "max withdrawal",
+            |limits| limits.max_withdrawal,
+            amount,
+        )?;
+        self.accounts
+            .entry(client_id)
+            .or_default()
+            .withdraw(amount)?;
+        self.totals.withdrawals += amount;
+        if self.policy.withdrawal_dispute != WithdrawalDisputePolicy::Reject {
+            self.tx_cache.insert(
+                tx_id,
+                TxCacheEntry::new(client_id, amount, DisputeDirection::Outgoing),
+            );
+        }
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::Withdrawal,
+            LedgerAccount::CustomerAvailable(client_id),
+            LedgerAccount::Cash,
+            amount,
+        );
+        Ok(())
+    }
+
+    pub fn dispute(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let role = self.role(client_id);
+        let mut tx = self
+            .tx_cache
+            .get(tx_id)
+            .ok_or(AccountManagerError::TransactionNotFound { id: tx_id })?;
+        check_authorization(&tx, client_id)?;
+        check_undisputed(&tx, tx_id)?;
+        // Chargebacks of payments to merchants come from the card networks.
+        if role == Role::Merchant {
+            return Err(AccountManagerError::NotPermitted {
+                role,
+                action: "initiate disputes",
+            });
+        }
+
+        let account = self.accounts.entry(client_id).or_default();
+        match tx.direction {
+            DisputeDirection::Incoming => {
+                tx.disputed = account.dispute(tx.amount).is_ok();
+                if tx.disputed {
+                    post(
+                        &mut self.ledger,
+                        tx_id,
+                        Action::Dispute,
+                        LedgerAccount::CustomerAvailable(client_id),
+                        LedgerAccount::CustomerHeld(client_id),
+                        tx.amount,
+                    );
+                }
+            }
+            // The payout is recalled into the held funds until the dispute is
+            // settled.
+            DisputeDirection::Outgoing => {
+                tx.disputed = account.dispute_withdrawal(tx.amount).is_ok();
+                if tx.disputed {
+                    self.totals.withdrawals -= tx.amount;
+                    post(
+                        &mut self.ledger,
+                        tx_id,
+                        Action::Dispute,
+                        LedgerAccount::Cash,
+                        LedgerAccount::CustomerHeld(client_id),
+                        tx.amount,
+                    );
+                }
+            }
+        }
+        let freeze = self
+            .policy
+            .tiers
+            .limits(client_id)
+            .is_some_and(|limits| limits.freeze_on_dispute);
+        if tx.disputed && freeze {
+            account.lock();
+        }
+        self.tx_cache.insert(tx_id, tx);
+        Ok(())
+    }
+
+    pub fn resolve(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let mut tx = self
+            .tx_cache
+            .get(tx_id)
+            .ok_or(AccountManagerError::TransactionNotFound { id: tx_id })?;
+        check_authorization(&tx, client_id)?;
+        check_disputed(&tx, tx_id)?;
+
+        let account = self.accounts.entry(client_id).or_default();
+        match tx.direction {
+            DisputeDirection::Incoming => {
+                account.resolve(tx.amount)?;
+                post(
+                    &mut self.ledger,
+                    tx_id,
+                    Action::Resolve,
+                    LedgerAccount::CustomerHeld(client_id),
+                    LedgerAccount::CustomerAvailable(client_id),
+                    tx.amount,
+                );
+            }
+            // The withdrawal stands and is paid out again.
+            DisputeDirection::Outgoing => {
+                account.resolve_withdrawal(tx.amount)?;
+                self.totals.withdrawals += tx.amount;
+                post(
+                    &mut self.ledger,
+                    tx_id,
+                    Action::Resolve,
+                    LedgerAccount::CustomerHeld(client_id),
+                    LedgerAccount::Cash,
+                    tx.amount,
+                );
+            }
+        }
+        tx.disputed = false;
+        self.tx_cache.insert(tx_id, tx);
+        Ok(())
+    }
+
+    pub fn chargeback(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let role = self.role(client_id);
+        let fee = self.chargeback_fee(client_id, role);
+        let tx = self
+            .tx_cache
+            .get(tx_id)
+            .ok_or(AccountManagerError::TransactionNotFound { id: tx_id })?;
+        check_authorization(&tx, client_id)?;
+        // Payments to merchants are charged back without a dispute of theirs.
+        let direct = role == Role::Merchant && tx.direction == DisputeDirection::Incoming;
+        if !direct {
+            check_disputed(&tx, tx_id)?;
+        }
+
+        let account = self.accounts.entry(client_id).or_default();
+        if direct && !tx.disputed {
+            account.dispute(tx.amount)?;
+            post(
+                &mut self.ledger,
+                tx_id,
+                Action::Dispute,
+                LedgerAccount::CustomerAvailable(client_id),
+                LedgerAccount::CustomerHeld(client_id),
+                tx.amount,
+            );
+        }
+        if tx.direction == DisputeDirection::Outgoing {
+            let lock = self.policy.withdrawal_dispute == WithdrawalDisputePolicy::ReverseAndLock;
+            account.reverse_withdrawal(tx.amount, lock)?;
+            post(
+                &mut self.ledger,
+                tx_id,
+                Action::Chargeback,
+                LedgerAccount::CustomerHeld(client_id),
+                LedgerAccount::CustomerAvailable(client_id),
+                tx.amount,
+            );
+            self.tx_cache.remove(tx_id);
+            return Ok(());
+        }
+        // Merchants bear the loss of a chargeback but keep trading.
+        account.chargeback(tx.amount, role != Role::Merchant)?;
+        self.totals.chargebacks += tx.amount;
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::Chargeback,
+            LedgerAccount::ChargebackLoss,
+            LedgerAccount::Cash,
+            tx.amount,
+        );
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::Chargeback,
+            LedgerAccount::CustomerHeld(client_id),
+            LedgerAccount::ChargebackLoss,
+            tx.amount,
+        );
+        self.tx_cache.remove(tx_id);
+        if fee > 0.0 {
+            let charged = account.charge_or_owe(fee)?;
+            self.totals.fees += charged;
+            if charged > 0.0 {
+                post(
+                    &mut self.ledger,
+                    tx_id,
+                    Action::Chargeback,
+                    LedgerAccount::CustomerAvailable(client_id),
+                    LedgerAccount::FeeIncome,
+                    charged,
+                );
+            }
+            if fee > charged {
+                post(
+                    &mut self.ledger,
+                    tx_id,
+                    Action::Chargeback,
+                    LedgerAccount::CustomerReceivable(client_id),
+                    LedgerAccount::FeeIncome,
+                    fee - charged,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The penalty of a deposit chargeback, the one of the client's tier if it
+    // has one, otherwise the one of the role.
+    fn chargeback_fee(&self, client_id: ClientId, role: Role) -> f64 {
+        let fee = self
+            .policy
+            .tiers
+            .limits(client_id)
+            .and_then(|limits| limits.chargeback_fee)
+            .unwrap_or_else(|| self.policy.roles.chargeback_fee(role));
+        self.policy.currency.round(fee)
+    }
+
+    pub fn hold_in_escrow(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+        counterparty: Option<ClientId>,
+        amount: f64,
+    ) -> AccountManagerResult<()> {
+        let counterparty =
+            counterparty.ok_or(AccountManagerError::MissingCounterparty { id: tx_id })?;
+        if self.escrows.contains_key(&tx_id) {
+            return Err(AccountManagerError::DuplicateEscrow { id: tx_id });
+        }
+        self.accounts
+            .entry(client_id)
+            .or_default()
+            .hold_in_escrow(amount)?;
+        self.escrows.insert(
+            tx_id,
+            Escrow {
+                client_id,
+                counterparty,
+                amount,
+            },
+        );
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::HoldInEscrow,
+            LedgerAccount::CustomerAvailable(client_id),
+            LedgerAccount::CustomerEscrow(client_id),
+            amount,
+        );
+        Ok(())
+    }
+
+    // The escrow of tx_id if the client holds it.
+    pub(crate) fn owned_escrow(
+        &self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<&Escrow> {
+        let escrow = self
+            .escrows
+            .get(&tx_id)
+            .ok_or(AccountManagerError::EscrowNotFound { id: tx_id })?;
+        if escrow.client_id != client_id {
+            return Err(AccountManagerError::Unauthorized {
+                client_id,
+                owner_id: escrow.client_id,
+            });
+        }
+        Ok(escrow)
+    }
+
+    pub fn release_escrow(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let escrow = self.owned_escrow(tx_id, client_id)?.clone();
+        self.receive_escrow(&escrow)?;
+        self.take_escrow(tx_id, &escrow)
+    }
+
+    // Releases an escrow to a counterparty whose account is in the books of
+    // `receiver`.
+    pub(crate) fn release_escrow_into(
+        &mut self,
+        receiver: &mut AccountManager,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let escrow = self.owned_escrow(tx_id, client_id)?.clone();
+        receiver.receive_escrow(&escrow)?;
+        self.take_escrow(tx_id, &escrow)
+    }
+
+    // Credits a released escrow to the counterparty. The counterparty's
+    // account may be in other books than the escrow, see ShardedStore.
+    pub(crate) fn receive_escrow(&mut self, escrow: &Escrow) -> AccountManagerResult<()> {
+        self.accounts
+            .entry(escrow.counterparty)
+            .or_default()
+            .deposit(escrow.amount)?;
+        self.totals.transfers += escrow.amount;
+        Ok(())
+    }
+
+    pub(crate) fn take_escrow(
+        &mut self,
+        tx_id: TransactionId,
+        escrow: &Escrow,
+    ) -> AccountManagerResult<()> {
+        self.accounts
+            .entry(escrow.client_id)
+            .or_default()
+            .release_escrow(escrow.amount)?;
+        self.totals.transfers -= escrow.amount;
+        self.escrows.remove(&tx_id);
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::ReleaseEscrow,
+            LedgerAccount::CustomerEscrow(escrow.client_id),
+            LedgerAccount::CustomerAvailable(escrow.counterparty),
+            escrow.amount,
+        );
+        Ok(())
+    }
+
+    pub fn refund_escrow(
+        &mut self,
+        tx_id: TransactionId,
+        client_id: ClientId,
+    ) -> AccountManagerResult<()> {
+        let amount = self.owned_escrow(tx_id, client_id)?.amount;
+        self.accounts
+            .entry(client_id)
+            .or_default()
+            .refund_escrow(amount)?;
+        self.escrows.remove(&tx_id);
+        post(
+            &mut self.ledger,
+            tx_id,
+            Action::RefundEscrow,
+            LedgerAccount::CustomerEscrow(client_id),
+            LedgerAccount::CustomerAvailable(client_id),
+            amount,
+        );
+        Ok(())
+    }
+}
+
+fn validate_amount(policy: &Policy, tx: &Transaction) -> AccountManagerResult<()> {
+    let Some(amount) = tx.amount else {
+        return Ok(());
+    };
+    // Adjustments debit with negative amounts.
+    let valid = match (&tx.action, policy.zero_amount) {
+        (Action::Adjustment, _) => amount != 0.0,
+        (_, ZeroAmountPolicy::Reject) => amount > 0.0,
+        (_, ZeroAmountPolicy::Accept) => amount >= 0.0,
+    };
+    if !valid {
+        return Err(AccountManagerError::InvalidAmount { id: tx.id, amount });
+    }
+    Ok(())
+}
+
+fn decimal_places(amount: f64) -> usize {
+    // Display prints the shortest representation that round-trips, i.e. the
+    // digits of the parsed input, and never switches to exponent notation.
+    let formatted = amount.to_string();
+    formatted
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len())
+}
+
+fn validate_precision(policy: &Policy, tx: &mut Transaction) -> AccountManagerResult<()> {
+    let Some(amount) = tx.amount else {
+        return Ok(());
+    };
+    let places = policy.currency.minor_units;
+    if decimal_places(amount) <= places as usize {
+        return Ok(());
+    }
+    match policy.precision {
+        PrecisionPolicy::Reject => Err(AccountManagerError::InvalidPrecision {
+            id: tx.id,
+            amount,
+            places,
+        }),
+        PrecisionPolicy::Round => {
+            tx.amount = Some(policy.currency.round(amount));
+            Ok(())
+        }
+    }
+}
+
+// Checks the amount of a transaction against the policy, rounding it if the
+// policy allows.
+pub(crate) fn validate_transaction(
+    policy: &Policy,
+    tx: &mut Transaction,
+) -> AccountManagerResult<()> {
+    validate_precision(policy, tx)?;
+    validate_amount(policy, tx)
+}
+
+pub fn process_transaction(
+    account_manager: &mut AccountManager,
+    mut tx: Transaction,
+) -> AccountManagerResult<()> {
+    validate_transaction(account_manager.policy(), &mut tx)?;
+
+    let idempotency_key = tx.idempotency_key.take();
+    if let Some(key) = &idempotency_key {
+        account_manager.check_idempotency_key(key)?;
+    }
+
+    // Erasures, closes that compact and transactions that evict expired ones
+    // clear the undo log, they can't be rolled back. Balance inquiries change
+    // nothing to roll back.
+    let compacts =
+        tx.action == Action::ClosePeriod && account_manager.policy.retention != Retention::Forever;
+    let undoable = !compacts && !matches!(tx.action, Action::EraseClient | Action::Balance);
+    let undo = (account_manager.undo.is_some() && undoable)
+        .then(|| account_manager.undo_snapshot(&tx, idempotency_key.clone()));
+    let evicted = account_manager.expiry.evicted;
+    apply_transaction(account_manager, tx)?;
+    if let Some(key) = idempotency_key {
+        account_manager.idempotency_keys.insert(key);
+    }
+    if account_manager.expiry.evicted != evicted {
+        return Ok(());
+    }
+    if let (Some(log), Some(entry)) = (&mut account_manager.undo, undo) {
+        log.push(entry);
+    }
+    Ok(())
+}
+
+// Processes a record read at a monotonically increasing source offset (e.g. the
+// byte offset in a file). Records at or before the committed offset were already
+// consumed and are refused, which makes replaying a source after a restart safe.
+pub fn process_transaction_at(
+    account_manager: &mut AccountManager,
+    offset: u64,
+    tx: Transaction,
+) -> AccountManagerResult<()> {
+    check_offset(account_manager, offset)?;
+    let result = process_transaction(account_manager, tx);
+    account_manager.committed_offset = Some(offset);
+    result
+}
+
+// Consumes a record without applying it, e.g. one that is scheduled for later.
+pub(crate) fn commit_offset(
+    account_manager: &mut AccountManager,
+    offset: u64,
+) -> AccountManagerResult<()> {
+    check_offset(account_manager, offset)?;
+    account_manager.committed_offset = Some(offset);
+    Ok(())
+}
+
+fn check_offset(account_manager: &AccountManager, offset: u64) -> AccountManagerResult<()> {
+    match account_manager.committed_offset {
+        Some(committed) if offset <= committed => {
+            Err(AccountManagerError::AlreadyApplied { offset, committed })
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn apply_transaction(
+    account_manager: &mut AccountManager,
+    tx: Transaction,
+) -> AccountManagerResult<()> {
+    apply_transaction_into(account_manager, None, tx)
+}
+
+// Like apply_transaction, but an escrow release credits the counterparty in
+// the books of `receiver`, see ShardedStore.
+pub(crate) fn apply_transaction_into(
+    account_manager: &mut AccountManager,
+    mut receiver: Option<&mut AccountManager>,
+    tx: Transaction,
+) -> AccountManagerResult<()> {
+    if tx.action.is_admin() && !account_manager.policy.allow_admin_actions {
+        return Err(AccountManagerError::AdminActionRefused {
+            id: tx.id,
+            action: tx.action.as_str(),
+        });
+    }
+    let backdated = account_manager.backdated_period(&tx);
+    let adjusted = match (backdated, account_manager.policy.backdated) {
+        (Some(period), BackdatedPolicy::Reject) => {
+            return Err(AccountManagerError::Backdated { id: tx.id, period });
+        }
+        (Some(period), BackdatedPolicy::Adjust) => Some(period),
+        _ => None,
+    };
+    let freeze = match account_manager.matched_rules(&tx) {
+        (Some(rule), _) => {
+            return Err(AccountManagerError::RuleRejected {
+                id: tx.id,
+                rule: rule.name.clone(),
+            });
+        }
+        (None, freeze) => freeze.is_some(),
+    };
+    let (client_id, tx_id) = (tx.client_id, tx.id);
+    let total = |account_manager: &AccountManager| {
+        account_manager
+            .account(client_id)
+            .map_or(0.0, Account::total)
+    };
+    let before = total(account_manager);
+    let posted = account_manager
+        .ledger
+        .as_ref()
+        .map_or(0, |ledger| ledger.entries().len());
+    let counterparty = match tx.action {
+        Action::ReleaseEscrow => account_manager
+            .escrow(tx.id)
+            .map(|escrow| escrow.counterparty),
+        _ => None,
+    };
+    #[cfg(feature = "invariant-checks")]
+    let mark = account_manager.invariant_mark(client_id, counterparty);
+    let result = match tx.action {
+        Action::Deposit => {
+            if let Some(amount) = tx.amount {
+                account_manager.deposit(tx.id, tx.client_id, amount)
+            } else {
+                Ok(())
+            }
+        }
+        Action::Withdrawal => {
+            if let Some(amount) = tx.amount {
+                account_manager.withdraw(tx.id, tx.client_id, amount)
+            } else {
+                Ok(())
+            }
+        }
+        Action::Dispute => account_manager.dispute(tx.id, tx.client_id),
+        Action::Resolve => account_manager.resolve(tx.id, tx.client_id),
+        Action::Chargeback => account_manager.chargeback(tx.id, tx.client_id),
+        Action::OpenAccount => account_manager.open_account(tx.client_id, tx.role),
+        Action::HoldInEscrow => {
+            if let Some(amount) = tx.amount {
+                account_manager.hold_in_escrow(tx.id, tx.client_id, tx.counterparty, amount)
+            } else {
+                Ok(())
+            }
+        }
+        Action::ReleaseEscrow => match receiver.as_deref_mut() {
+            Some(receiver) => account_manager.release_escrow_into(receiver, tx.id, tx.client_id),
+            None => account_manager.release_escrow(tx.id, tx.client_id),
+        },
+        Action::RefundEscrow => account_manager.refund_escrow(tx.id, tx.client_id),
+        Action::Adjustment => {
+            if let Some(amount) = tx.amount {
+                account_manager.adjust(
+                    tx.id,
+                    tx.client_id,
+                    amount,
+                    tx.description.as_deref(),
+                    tx.timestamp,
+                )
+            } else {
+                Ok(())
+            }
+        }
+        // Not a transaction of the client, so it doesn't count for the account.
+        Action::ClosePeriod => {
+            account_manager.close_period(tx.reference, tx.timestamp);
+            return Ok(());
+        }
+        // Nothing of the client is left to count or record.
+        Action::EraseClient => {
+            return account_manager.erase_client(tx.id, tx.client_id, tx.description.as_deref());
+        }
+        // Decisions about the client, not transactions of it.
+        Action::Freeze => {
+            account_manager.freeze(tx.client_id);
+            return Ok(());
+        }
+        Action::Unfreeze => {
+            account_manager.unfreeze(tx.client_id);
+            return Ok(());
+        }
+        // Only asks for the balances, the caller reads them off the account.
+        Action::Balance => return Ok(()),
+    };
+    if let Some(ledger) = &mut account_manager.ledger {
+        ledger.annotate(posted, &tx);
+    }
+    if let (Ok(()), Some(account)) = (&result, account_manager.accounts.get_mut(&tx.client_id)) {
+        account.count_transaction();
+        if freeze {
+            account.lock();
+        }
+    }
+    if result.is_ok() {
+        account_manager.record_case(&tx);
+        account_manager.record_history(&tx, counterparty);
+        if let (Some(receiver), Some(counterparty)) = (receiver, counterparty) {
+            receiver.record_received(&tx, counterparty);
+        }
+        account_manager.expire(&tx);
+        account_manager.retain(&tx);
+    }
+    if let (Ok(()), Some(period)) = (&result, adjusted) {
+        let change = total(account_manager) - before;
+        account_manager.periods.adjust(PeriodAdjustment {
+            period,
+            client_id,
+            tx_id,
+            change,
+        });
+    }
+    #[cfg(feature = "invariant-checks")]
+    account_manager.assert_invariants(&tx, mark);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::currency::{Currency, Rounding};
+    use crate::role::Roles;
+    use crate::rules::{Consequence, Rules};
+
+    #[test]
+    fn negative_amounts_are_rejected() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        let deposit = Transaction::new(Action::Deposit, client_id, 1, Some(-5.0));
+        let err = process_transaction(&mut account_manager, deposit).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::InvalidAmount {
+                id: 1,
+                amount: -5.0
+            }
+        );
+
+        let withdrawal = Transaction::new(Action::Withdrawal, client_id, 2, Some(-5.0));
+        let err = process_transaction(&mut account_manager, withdrawal).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::InvalidAmount {
+                id: 2,
+                amount: -5.0
+            }
+        );
+
+        assert!(account_manager.accounts().is_empty());
+    }
+
+    #[test]
+    fn zero_amounts_are_rejected_by_default() {
+        let mut account_manager = AccountManager::new();
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(0.0));
+        let err = process_transaction(&mut account_manager, deposit).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::InvalidAmount { id: 1, amount: 0.0 }
+        );
+        assert!(account_manager.accounts().is_empty());
+    }
+
+    #[test]
+    fn iter_accounts_borrows_the_same_accounts() {
+        let mut account_manager = AccountManager::new();
+        for (client_id, id) in [(1, 1), (2, 2), (1, 3)] {
+            let deposit = Transaction::new(Action::Deposit, client_id, id, Some(1.0));
+            assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        }
+
+        let mut borrowed: Vec<_> = account_manager
+            .iter_accounts()
+            .map(|(client_id, account)| (*client_id, account.total()))
+            .collect();
+        borrowed.sort_by_key(|(client_id, _)| *client_id);
+        assert_eq!(borrowed, vec![(1, 2.0), (2, 1.0)]);
+        assert_eq!(account_manager.accounts().len(), 2);
+    }
+
+    #[test]
+    fn capacity_hints_preallocate_the_maps() {
+        let mut account_manager = AccountManager::with_capacity(100, 1000);
+        assert!(account_manager.accounts.capacity() >= 100);
+        assert!(account_manager.tx_cache.capacity() >= 1000);
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        account_manager.reserve(5000, 0);
+        assert!(account_manager.accounts.capacity() >= 5000);
+
+        // Hints beyond what could ever be used are capped.
+        account_manager.reserve(usize::MAX, usize::MAX / 2);
+        assert!(account_manager.tx_cache.capacity() < 2 * MAX_RESERVED);
+    }
+
+    #[test]
+    fn zero_amounts_are_accepted_if_configured() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            zero_amount: ZeroAmountPolicy::Accept,
+            ..Policy::default()
+        });
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(0.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.total(), 0.0);
+    }
+
+    #[test]
+    fn amounts_with_more_than_four_decimal_places_are_rejected() {
+        let mut account_manager = AccountManager::new();
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.2345));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+
+        let deposit = Transaction::new(Action::Deposit, 1, 2, Some(0.00001));
+        let err = process_transaction(&mut account_manager, deposit).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::InvalidPrecision {
+                id: 2,
+                amount: 0.00001,
+                places: 4
+            }
+        );
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts[0].1.total(), 1.2345);
+    }
+
+    #[test]
+    fn amounts_with_more_than_four_decimal_places_are_rounded_if_configured() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            precision: PrecisionPolicy::Round,
+            ..Policy::default()
+        });
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.23456));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts[0].1.total(), 1.2346);
+    }
+
+    #[test]
+    fn amounts_follow_the_minor_units_of_the_currency() {
+        let mut roles = Roles::new().with_merchant_fee(0.029);
+        roles.assign(1, Role::Merchant);
+        let mut account_manager = AccountManager::with_policy(Policy {
+            currency: Currency::new("JPY", 0),
+            roles: Arc::new(roles),
+            ..Policy::default()
+        });
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1000.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let deposit = Transaction::new(Action::Deposit, 1, 2, Some(0.5));
+        assert_eq!(
+            process_transaction(&mut account_manager, deposit),
+            Err(AccountManagerError::InvalidPrecision {
+                id: 2,
+                amount: 0.5,
+                places: 0
+            })
+        );
+        // The fee of 29 yen, not 29.0 rounded to 4 decimal places.
+        assert_eq!(account_manager.account(1).unwrap().available(), 971.0);
+
+        account_manager.set_policy(Policy {
+            precision: PrecisionPolicy::Round,
+            currency: Currency::new("BHD", 3).with_rounding(Rounding::HalfEven),
+            ..Policy::default()
+        });
+        let deposit = Transaction::new(Action::Deposit, 2, 3, Some(1.0625));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        assert_eq!(account_manager.account(2).unwrap().available(), 1.062);
+    }
+
+    #[test]
+    fn transactions_with_applied_idempotency_key_are_skipped() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        let mut deposit = Transaction::new(Action::Deposit, client_id, 1, Some(1.0));
+        deposit.idempotency_key = Some("abc".to_string());
+        let mut retry = Transaction::new(Action::Deposit, client_id, 2, Some(1.0));
+        retry.idempotency_key = Some("abc".to_string());
+
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let err = process_transaction(&mut account_manager, retry).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::Duplicate {
+                key: "abc".to_string()
+            }
+        );
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts[0].1.total(), 1.0);
+    }
+
+    #[test]
+    fn idempotency_key_of_rejected_transaction_can_be_retried() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        let mut withdrawal = Transaction::new(Action::Withdrawal, client_id, 1, Some(1.0));
+        withdrawal.idempotency_key = Some("abc".to_string());
+        assert!(process_transaction(&mut account_manager, withdrawal.clone()).is_err());
+
+        assert!(account_manager.deposit(2, client_id, 1.0).is_ok());
+        withdrawal.id = 3;
+        assert!(process_transaction(&mut account_manager, withdrawal).is_ok());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts[0].1.total(), 0.0);
+    }
+
+    #[test]
+    fn records_before_committed_offset_are_refused() {
+        let mut account_manager = AccountManager::new();
+
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.0));
+        assert!(process_transaction_at(&mut account_manager, 10, deposit.clone()).is_ok());
+        assert_eq!(account_manager.committed_offset(), Some(10));
+
+        let err = process_transaction_at(&mut account_manager, 10, deposit).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::AlreadyApplied {
+                offset: 10,
+                committed: 10
+            }
+        );
+
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(2.0));
+        assert!(process_transaction_at(&mut account_manager, 20, withdrawal).is_err());
+        assert_eq!(account_manager.committed_offset(), Some(20));
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts[0].1.total(), 1.0);
+    }
+
+    #[test]
+    fn ledger_stays_balanced() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+
+        let client_id = 1;
+        assert!(account_manager.deposit(1, client_id, 3.0).is_ok());
+        assert!(account_manager.deposit(2, client_id, 2.0).is_ok());
+        assert!(account_manager.withdraw(3, client_id, 1.0).is_ok());
+        assert!(account_manager.dispute(1, client_id).is_ok());
+        assert!(account_manager.resolve(1, client_id).is_ok());
+        assert!(account_manager.dispute(2, client_id).is_ok());
+        assert!(account_manager.chargeback(2, client_id).is_ok());
+
+        let ledger = account_manager.ledger().unwrap();
+        assert_eq!(ledger.entries().len(), 8);
+        assert_eq!(ledger.balance(LedgerAccount::Cash), 2.0);
+        assert_eq!(
+            ledger.balance(LedgerAccount::CustomerAvailable(client_id)),
+            -2.0
+        );
+        assert_eq!(ledger.balance(LedgerAccount::CustomerHeld(client_id)), 0.0);
+        assert_eq!(ledger.balance(LedgerAccount::ChargebackLoss), 0.0);
+        assert_eq!(ledger.balances().values().sum::<f64>(), 0.0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    fn locked_account_manager(locked_deposit: LockedDepositPolicy) -> AccountManager {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            locked_deposit,
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        assert!(account_manager.deposit(1, 1, 3.0).is_ok());
+        assert!(account_manager.dispute(1, 1).is_ok());
+        assert!(account_manager.chargeback(1, 1).is_ok());
+        account_manager
+    }
+
+    #[test]
+    fn deposits_into_locked_accounts_are_accepted_by_default() {
+        let mut account_manager = locked_account_manager(LockedDepositPolicy::default());
+        assert!(account_manager.deposit(2, 1, 2.0).is_ok());
+
+        let account = account_manager.account(1).unwrap();
+        assert_eq!(account.available(), 2.0);
+        assert!(account.locked());
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn deposits_into_locked_accounts_are_rejected_if_configured() {
+        let mut account_manager = locked_account_manager(LockedDepositPolicy::Reject);
+        assert_eq!(
+            account_manager.deposit(2, 1, 2.0),
+            Err(AccountManagerError::Account(AccountError::Locked))
+        );
+
+        assert_eq!(account_manager.account(1).unwrap().total(), 0.0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn deposits_into_locked_accounts_are_held_in_suspense_if_configured() {
+        let mut account_manager = locked_account_manager(LockedDepositPolicy::Suspense);
+        assert!(account_manager.deposit(2, 1, 2.0).is_ok());
+
+        let account = account_manager.account(1).unwrap();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.suspense(), 2.0);
+        assert_eq!(account.total(), 2.0);
+        assert_eq!(
+            account_manager.dispute(2, 1),
+            Err(AccountManagerError::TransactionNotFound { id: 2 })
+        );
+        let ledger = account_manager.ledger().unwrap();
+        assert_eq!(ledger.balance(LedgerAccount::CustomerSuspense(1)), -2.0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    fn tiered_account_manager() -> AccountManager {
+        let mut tiers = Tiers::new();
+        let limits = TierLimits {
+            max_balance: Some(10.0),
+            max_withdrawal: Some(2.0),
+            freeze_on_dispute: true,
+            chargeback_fee: None,
+        };
+        tiers.set_limits(Tier::Basic, limits);
+        tiers.assign(2, Tier::Premium);
+        AccountManager::with_policy(Policy {
+            tiers: Arc::new(tiers),
+            ..Policy::default()
+        })
+    }
+
+    #[test]
+    fn tier_limits_are_enforced() {
+        let mut account_manager = tiered_account_manager();
+        assert!(account_manager.deposit(1, 1, 8.0).is_ok());
+        assert_eq!(
+            account_manager.deposit(2, 1, 3.0),
+            Err(AccountManagerError::LimitExceeded {
+                tier: Tier::Basic,
+                limit: "max balance",
+                max: 10.0,
+                amount: 11.0
+            })
+        );
+        assert_eq!(
+            account_manager.withdraw(3, 1, 2.5).unwrap_err().kind(),
+            "limit_exceeded"
+        );
+        assert!(account_manager.withdraw(4, 1, 2.0).is_ok());
+
+        assert!(account_manager.deposit(5, 2, 100.0).is_ok());
+        assert!(account_manager.withdraw(6, 2, 50.0).is_ok());
+    }
+
+    #[test]
+    fn disputes_freeze_accounts_of_tiers_configured_so() {
+        let mut account_manager = tiered_account_manager();
+        assert!(account_manager.deposit(1, 1, 2.0).is_ok());
+        assert!(account_manager.deposit(2, 2, 2.0).is_ok());
+        assert!(account_manager.dispute(1, 1).is_ok());
+        assert!(account_manager.dispute(2, 2).is_ok());
+
+        assert!(account_manager.account(1).unwrap().locked());
+        assert!(!account_manager.account(2).unwrap().locked());
+    }
+
+    fn ruled_account_manager() -> AccountManager {
+        let rule = |name: &str, then| Rule {
+            name: name.to_string(),
+            action: None,
+            tier: None,
+            role: None,
+            amount_above: None,
+            disputes_at_least: None,
+            then,
+        };
+        let rules = Rules::new(vec![
+            Rule {
+                action: Some(Action::Withdrawal),
+                tier: Some(Tier::Basic),
+                amount_above: Some(10000.0),
+                ..rule("large basic withdrawals", Consequence::Reject)
+            },
+            Rule {
+                action: Some(Action::Dispute),
+                disputes_at_least: Some(3),
+                ..rule("dispute abuse", Consequence::Freeze)
+            },
+        ])
+        .unwrap();
+        let mut tiers = Tiers::new();
+        tiers.assign(2, Tier::Premium);
+        AccountManager::with_policy(Policy {
+            tiers: Arc::new(tiers),
+            rules: Arc::new(rules),
+            ..Policy::default()
+        })
+    }
+
+    #[test]
+    fn rules_reject_matching_transactions() {
+        let mut account_manager = ruled_account_manager();
+        for (client_id, id) in [(1, 1), (2, 2)] {
+            let deposit = Transaction::new(Action::Deposit, client_id, id, Some(20000.0));
+            assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        }
+
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 3, Some(15000.0));
+        assert_eq!(
+            process_transaction(&mut account_manager, withdrawal),
+            Err(AccountManagerError::RuleRejected {
+                id: 3,
+                rule: "large basic withdrawals".to_string()
+            })
+        );
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 4, Some(10000.0));
+        assert!(process_transaction(&mut account_manager, withdrawal).is_ok());
+        let withdrawal = Transaction::new(Action::Withdrawal, 2, 5, Some(15000.0));
+        assert!(process_transaction(&mut account_manager, withdrawal).is_ok());
+
+        assert_eq!(account_manager.account(1).unwrap().available(), 10000.0);
+        assert_eq!(account_manager.account(1).unwrap().transactions(), 2);
+    }
+
+    #[test]
+    fn rules_freeze_accounts_after_the_applied_transaction() {
+        let mut account_manager = ruled_account_manager();
+        for id in 1..=4 {
+            let deposit = Transaction::new(Action::Deposit, 1, id, Some(1.0));
+            assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        }
+        for id in 1..=2 {
+            let dispute = Transaction::new(Action::Dispute, 1, id, None);
+            assert!(process_transaction(&mut account_manager, dispute).is_ok());
+            let resolve = Transaction::new(Action::Resolve, 1, id, None);
+            assert!(process_transaction(&mut account_manager, resolve).is_ok());
+        }
+        assert!(!account_manager.account(1).unwrap().locked());
+
+        let dispute = Transaction::new(Action::Dispute, 1, 3, None);
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+        let account = account_manager.account(1).unwrap();
+        assert_eq!((account.disputes(), account.disputed()), (3, 1.0));
+        assert!(account.locked());
+    }
+
+    fn merchant_account_manager() -> AccountManager {
+        let mut roles = Roles::new().with_merchant_fee(0.1);
+        roles.assign(2, Role::Merchant);
+        let mut account_manager = AccountManager::with_policy(Policy {
+            withdrawal_dispute: WithdrawalDisputePolicy::Reverse,
+            roles: Arc::new(roles),
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        account_manager
+    }
+
+    #[test]
+    fn chargebacks_charge_the_penalty_of_the_tier_or_role() {
+        let mut tiers = Tiers::new();
+        let limits = TierLimits {
+            chargeback_fee: Some(1.0),
+            ..TierLimits::default()
+        };
+        tiers.set_limits(Tier::Premium, limits);
+        tiers.assign(3, Tier::Premium);
+        let mut roles = Roles::new().with_chargeback_fee(Role::Merchant, 15.0);
+        roles.assign(2, Role::Merchant);
+        roles.assign(3, Role::Merchant);
+        let mut account_manager = AccountManager::with_policy(Policy {
+            tiers: Arc::new(tiers),
+            roles: Arc::new(roles),
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+
+        for (tx_id, client_id) in (1..=3).zip(1..) {
+            assert!(account_manager.deposit(tx_id, client_id, 10.0).is_ok());
+            assert!(account_manager.deposit(tx_id + 10, client_id, 5.0).is_ok());
+            if client_id == 1 {
+                assert!(account_manager.dispute(tx_id, client_id).is_ok());
+            }
+            assert!(account_manager.chargeback(tx_id, client_id).is_ok());
+        }
+
+        let balances = |client_id| {
+            let account = account_manager.account(client_id).unwrap();
+            (account.available(), account.receivable())
+        };
+        assert_eq!(balances(1), (5.0, 0.0));
+        assert_eq!(balances(2), (0.0, 10.0));
+        assert_eq!(balances(3), (4.0, 0.0));
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn rejected_fees_leave_the_deposit_unapplied() {
+        let mut roles = Roles::new().with_merchant_fee(f64::MAX);
+        roles.assign(2, Role::Merchant);
+        let mut account_manager = AccountManager::with_policy(Policy {
+            roles: Arc::new(roles),
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        assert!(account_manager
+            .adjust(1, 2, 5.0, Some("opening balance"), None)
+            .is_ok());
+        let ledger = account_manager.ledger().unwrap().entries().len();
+
+        assert!(account_manager.deposit(2, 2, 10.0).is_err());
+        let account = account_manager.account(2).unwrap();
+        assert_eq!((account.available(), account.total()), (5.0, 5.0));
+        assert_eq!(account_manager.totals.deposits, 0.0);
+        assert_eq!(account_manager.totals.fees, 0.0);
+        assert_eq!(account_manager.ledger().unwrap().entries().len(), ledger);
+        assert!(account_manager.dispute(2, 2).is_err());
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn merchants_pay_fees_and_keep_trading_after_chargebacks() {
+        let mut account_manager = merchant_account_manager();
+        assert!(account_manager.deposit(1, 1, 10.0).is_ok());
+        assert!(account_manager.deposit(2, 2, 10.0).is_ok());
+        assert!(account_manager.deposit(3, 2, 10.0).is_ok());
+        assert_eq!(account_manager.account(1).unwrap().available(), 10.0);
+        assert_eq!(account_manager.account(2).unwrap().available(), 18.0);
+
+        // Charged back by the card network, without a dispute of theirs.
+        assert!(account_manager.chargeback(2, 2).is_ok());
+        let merchant = account_manager.account(2).unwrap();
+        assert_eq!(merchant.total(), 9.0);
+        assert!(!merchant.locked());
+        assert!(account_manager.verify_invariants().is_ok());
+        assert_eq!(
+            account_manager
+                .ledger()
+                .unwrap()
+                .balance(LedgerAccount::FeeIncome),
+            -2.0
+        );
+    }
+
+    #[test]
+    fn merchants_cant_initiate_disputes() {
+        let mut account_manager = merchant_account_manager();
+        assert!(account_manager.deposit(1, 2, 10.0).is_ok());
+        assert!(account_manager.withdraw(2, 2, 5.0).is_ok());
+        for tx_id in [1, 2] {
+            assert_eq!(
+                account_manager.dispute(tx_id, 2),
+                Err(AccountManagerError::NotPermitted {
+                    role: Role::Merchant,
+                    action: "initiate disputes",
+                })
+            );
+        }
+        assert_eq!(
+            account_manager.chargeback(2, 2),
+            Err(AccountManagerError::Undisputed { id: 2 })
+        );
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn opened_accounts_keep_their_role() {
+        let mut account_manager = merchant_account_manager();
+        let open = Transaction {
+            role: Some(Role::Merchant),
+            ..Transaction::new(Action::OpenAccount, 3, 1, None)
+        };
+        assert!(process_transaction(&mut account_manager, open.clone()).is_ok());
+        assert_eq!(account_manager.role(3), Role::Merchant);
+        assert_eq!(
+            process_transaction(&mut account_manager, open),
+            Err(AccountManagerError::AccountExists { client_id: 3 })
+        );
+
+        let open = Transaction::new(Action::OpenAccount, 2, 2, None);
+        assert!(process_transaction(&mut account_manager, open).is_ok());
+        assert_eq!(account_manager.role(2), Role::Merchant);
+        assert_eq!(account_manager.role(1), Role::Customer);
+    }
+
+    fn escrow(client_id: ClientId, id: TransactionId, counterparty: ClientId) -> Transaction {
+        Transaction {
+            counterparty: Some(counterparty),
+            ..Transaction::new(Action::HoldInEscrow, client_id, id, Some(2.0))
+        }
+    }
+
+    #[test]
+    fn escrows_are_released_to_the_counterparty_or_refunded() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(5.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        assert!(process_transaction(&mut account_manager, escrow(1, 2, 7)).is_ok());
+        assert!(process_transaction(&mut account_manager, escrow(1, 3, 7)).is_ok());
+        assert_eq!(account_manager.account(1).unwrap().held(), 4.0);
+
+        let release = Transaction::new(Action::ReleaseEscrow, 1, 2, None);
+        assert!(process_transaction(&mut account_manager, release).is_ok());
+        let refund = Transaction::new(Action::RefundEscrow, 1, 3, None);
+        assert!(process_transaction(&mut account_manager, refund).is_ok());
+
+        assert_eq!(account_manager.account(1).unwrap().available(), 3.0);
+        assert_eq!(account_manager.account(1).unwrap().held(), 0.0);
+        assert_eq!(account_manager.account(7).unwrap().available(), 2.0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn escrows_are_settled_once_by_their_holder() {
+        let mut account_manager = AccountManager::new();
+        assert!(account_manager.deposit(1, 1, 5.0).is_ok());
+        assert_eq!(
+            account_manager.hold_in_escrow(2, 1, None, 1.0),
+            Err(AccountManagerError::MissingCounterparty { id: 2 })
+        );
+        assert!(process_transaction(&mut account_manager, escrow(1, 2, 7)).is_ok());
+        assert_eq!(
+            process_transaction(&mut account_manager, escrow(1, 2, 7)),
+            Err(AccountManagerError::DuplicateEscrow { id: 2 })
+        );
+
+        assert_eq!(
+            account_manager.release_escrow(2, 7),
+            Err(AccountManagerError::Unauthorized {
+                client_id: 7,
+                owner_id: 1
+            })
+        );
+        assert!(account_manager.refund_escrow(2, 1).is_ok());
+        assert_eq!(
+            account_manager.release_escrow(2, 1),
+            Err(AccountManagerError::EscrowNotFound { id: 2 })
+        );
+    }
+
+    #[test]
+    fn applied_transactions_are_counted_per_account() {
+        let mut account_manager = AccountManager::new();
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(2.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(3.0));
+        assert!(process_transaction(&mut account_manager, withdrawal).is_err());
+        let dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+
+        assert_eq!(account_manager.account(1).unwrap().transactions(), 2);
+    }
+
+    #[test]
+    fn lists_the_disputable_transactions_of_a_client() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            withdrawal_dispute: WithdrawalDisputePolicy::Reverse,
+            ..Policy::default()
+        });
+        let txs = [
+            Transaction::new(Action::Deposit, 1, 7, Some(1.0)),
+            Transaction::new(Action::Deposit, 2, 2, Some(1.0)),
+            Transaction::new(Action::Deposit, 1, 4, Some(5.0)),
+            Transaction::new(Action::Withdrawal, 1, 3, Some(2.0)),
+            Transaction::new(Action::Dispute, 1, 7, None),
+            Transaction::new(Action::Dispute, 2, 2, None),
+            Transaction::new(Action::Chargeback, 2, 2, None),
+        ];
+        for tx in txs {
+            assert!(process_transaction(&mut account_manager, tx).is_ok());
+        }
+        let listed: Vec<_> = account_manager
+            .transactions_for(1)
+            .map(|tx| (tx.id, tx.action, tx.amount, tx.disputed))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (3, Action::Withdrawal, 2.0, false),
+                (4, Action::Deposit, 5.0, false),
+                (7, Action::Deposit, 1.0, true),
+            ]
+        );
+        // Charged back transactions can't be disputed anymore.
+        assert_eq!(account_manager.transactions_for(2).count(), 0);
+    }
+
+    #[test]
+    fn backdated_transactions_follow_the_policy() {
+        let at = |action: Action, id: TransactionId, amount: Option<f64>, timestamp: Timestamp| {
+            Transaction {
+                timestamp: Some(timestamp),
+                ..Transaction::new(action, 1, id, amount)
+            }
+        };
+        for (backdated, expected) in [
+            (BackdatedPolicy::Apply, Ok(())),
+            (
+                BackdatedPolicy::Reject,
+                Err(AccountManagerError::Backdated { id: 3, period: 1 }),
+            ),
+            (BackdatedPolicy::Adjust, Ok(())),
+        ] {
+            let mut account_manager = AccountManager::with_policy(Policy {
+                backdated,
+                ..Policy::default()
+            });
+            let deposit = at(Action::Deposit, 1, Some(2.0), 50);
+            assert!(process_transaction(&mut account_manager, deposit).is_ok());
+            let close = at(Action::ClosePeriod, 2, None, 100);
+            assert!(process_transaction(&mut account_manager, close).is_ok());
+
+            let late = at(Action::Withdrawal, 3, Some(0.5), 99);
+            assert_eq!(process_transaction(&mut account_manager, late), expected);
+            let current = at(Action::Withdrawal, 4, Some(0.5), 100);
+            assert!(process_transaction(&mut account_manager, current).is_ok());
+
+            let adjustments = account_manager.periods().adjustments();
+            match backdated {
+                BackdatedPolicy::Adjust => assert_eq!(
+                    adjustments,
+                    [PeriodAdjustment {
+                        period: 1,
+                        client_id: 1,
+                        tx_id: 3,
+                        change: -0.5,
+                    }]
+                ),
+                _ => assert!(adjustments.is_empty()),
+            }
+        }
+    }
+
+    #[test]
+    fn adjustments_need_a_reason_and_bypass_validation() {
+        let mut account_manager = locked_account_manager(LockedDepositPolicy::Reject);
+        let adjustment = |amount: f64, reason: Option<&str>| Transaction {
+            description: reason.map(str::to_string),
+            ..Transaction::new(Action::Adjustment, 1, 9, Some(amount))
+        };
+        // Input rows can't adjust balances unless admin actions are allowed.
+        assert_eq!(
+            process_transaction(&mut account_manager, adjustment(1.0, Some("Typo"))),
+            Err(AccountManagerError::AdminActionRefused {
+                id: 9,
+                action: "adjustment"
+            })
+        );
+        account_manager.policy.allow_admin_actions = true;
+        assert_eq!(
+            process_transaction(&mut account_manager, adjustment(1.0, None)),
+            Err(AccountManagerError::MissingReason { id: 9 })
+        );
+        assert_eq!(
+            process_transaction(&mut account_manager, adjustment(0.0, Some("Typo"))),
+            Err(AccountManagerError::InvalidAmount { id: 9, amount: 0.0 })
+        );
+        let available = account_manager.account(1).unwrap().available();
+        let debit = adjustment(-(available + 1.0), Some("Incident 42"));
+        assert!(process_transaction(&mut account_manager, debit).is_ok());
+
+        assert_eq!(account_manager.account(1).unwrap().available(), -1.0);
+        assert_eq!(account_manager.manual_adjustments().len(), 1);
+        assert_eq!(
+            account_manager.manual_adjustments()[0].reason,
+            "Incident 42"
+        );
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn erasure_removes_settled_clients_and_keeps_the_books_balanced() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            allow_admin_actions: true,
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        account_manager.enable_history();
+        account_manager.enable_undo(10);
+        let txs = [
+            Transaction::new(Action::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(Action::Dispute, 1, 1, None),
+            Transaction::new(Action::Resolve, 1, 1, None),
+            Transaction::new(Action::Withdrawal, 1, 2, Some(5.0)),
+            Transaction::new(Action::Deposit, 2, 3, Some(3.0)),
+            Transaction::new(Action::Deposit, 3, 5, Some(1.0)),
+            Transaction::new(Action::Dispute, 3, 5, None),
+            Transaction::new(Action::Chargeback, 3, 5, None),
+        ];
+        for tx in txs {
+            assert!(process_transaction(&mut account_manager, tx).is_ok());
+        }
+        let erase = |client_id, reason: Option<&str>| Transaction {
+            description: reason.map(str::to_string),
+            ..Transaction::new(Action::EraseClient, client_id, 4, None)
+        };
+        // A client locked after a chargeback keeps its lock.
+        assert_eq!(account_manager.account(3).unwrap().total(), 0.0);
+        assert_eq!(
+            process_transaction(&mut account_manager, erase(3, Some("Request 6"))),
+            Err(AccountManagerError::NotSettled { client_id: 3 })
+        );
+        assert_eq!(
+            process_transaction(&mut account_manager, erase(1, Some(" "))),
+            Err(AccountManagerError::MissingReason { id: 4 })
+        );
+        assert_eq!(
+            process_transaction(&mut account_manager, erase(2, Some("Request 7"))),
+            Err(AccountManagerError::NotSettled { client_id: 2 })
+        );
+        assert!(process_transaction(&mut account_manager, erase(1, Some("Request 8"))).is_ok());
+
+        assert!(account_manager.account(1).is_none());
+        assert_eq!(account_manager.transactions_for(1).count(), 0);
+        assert_eq!(account_manager.history(1).count(), 0);
+        assert!(account_manager
+            .dispute_cases()
+            .iter()
+            .all(|case| case.client_id != 1));
+        assert_eq!(account_manager.undoable(), 0);
+        let ledger = account_manager.ledger().unwrap();
+        assert!(
+            ledger
+                .entries()
+                .iter()
+                .all(|entry| entry.debit.client_id() != Some(1)
+                    && entry.credit.client_id() != Some(1))
+        );
+        assert_eq!(ledger.balance(LedgerAccount::ErasedCustomer), 0.0);
+        assert_eq!(ledger.balance(LedgerAccount::Cash), 3.0);
+        assert!(account_manager.verify_invariants().is_ok());
+        assert_eq!(
+            process_transaction(
+                &mut account_manager,
+                Transaction::new(Action::Dispute, 1, 1, None)
+            ),
+            Err(AccountManagerError::TransactionNotFound { id: 1 })
+        );
+        assert_eq!(account_manager.account(2).unwrap().total(), 3.0);
+
+        let mut account_manager = AccountManager::new();
+        assert_eq!(
+            process_transaction(&mut account_manager, erase(1, Some("Request 9"))),
+            Err(AccountManagerError::AdminActionRefused {
+                id: 4,
+                action: "erase_client"
+            })
+        );
+    }
+
+    #[test]
+    fn admin_actions_freeze_and_unfreeze_accounts_when_allowed() {
+        let freeze = Transaction::new(Action::Freeze, 1, 2, None);
+        let mut account_manager = AccountManager::new();
+        assert_eq!(
+            process_transaction(&mut account_manager, freeze.clone()),
+            Err(AccountManagerError::AdminActionRefused {
+                id: 2,
+                action: "freeze"
+            })
+        );
+
+        let mut account_manager = AccountManager::with_policy(Policy {
+            allow_admin_actions: true,
+            ..Policy::default()
+        });
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(5.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        assert!(process_transaction(&mut account_manager, freeze).is_ok());
+        let withdrawal = |id| Transaction::new(Action::Withdrawal, 1, id, Some(1.0));
+        assert_eq!(
+            process_transaction(&mut account_manager, withdrawal(3)),
+            Err(AccountManagerError::Account(AccountError::Locked))
+        );
+        let unfreeze = Transaction::new(Action::Unfreeze, 1, 4, None);
+        assert!(process_transaction(&mut account_manager, unfreeze).is_ok());
+        assert!(process_transaction(&mut account_manager, withdrawal(5)).is_ok());
+        let account = account_manager.account(1).unwrap();
+        assert!(!account.locked());
+        assert_eq!((account.total(), account.transactions()), (4.0, 2));
+
+        // Clients can be frozen before their first transaction.
+        let freeze = Transaction::new(Action::Freeze, 2, 6, None);
+        assert!(process_transaction(&mut account_manager, freeze).is_ok());
+        assert!(account_manager.account(2).unwrap().locked());
+    }
+
+    #[test]
+    fn balance_inquiries_change_nothing() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+        account_manager.enable_undo(10);
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(5.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let before = account_manager.account(1).cloned().unwrap();
+        for client_id in [1, 2] {
+            let inquiry = Transaction::new(Action::Balance, client_id, 2, None);
+            assert!(process_transaction(&mut account_manager, inquiry).is_ok());
+        }
+        let after = account_manager.account(1).unwrap();
+        assert_eq!((after.total(), after.transactions()), (before.total(), 1));
+        assert!(account_manager.account(2).is_none());
+        assert_eq!(account_manager.undoable(), 1);
+        assert_eq!(account_manager.ledger().unwrap().entries().len(), 1);
+    }
+
+    #[test]
+    fn history_records_the_balances_after_every_transaction() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_history();
+        let transactions = [
+            Transaction::new(Action::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(Action::Withdrawal, 1, 2, Some(9.0)),
+            Transaction {
+                counterparty: Some(2),
+                ..Transaction::new(Action::HoldInEscrow, 1, 3, Some(2.0))
+            },
+            Transaction::new(Action::ReleaseEscrow, 1, 3, None),
+        ];
+        for tx in transactions {
+            let _ = process_transaction(&mut account_manager, tx);
+        }
+
+        let history = account_manager.balance_history().unwrap();
+        assert_eq!(history.sequence(), 3);
+        let balances: Vec<_> = history
+            .points(1)
+            .iter()
+            .map(|point| (point.sequence, point.available, point.held))
+            .collect();
+        assert_eq!(balances, vec![(1, 5.0, 0.0), (2, 3.0, 2.0), (3, 3.0, 0.0)]);
+        assert_eq!(history.points(2)[0].sequence, 3);
+        assert_eq!(history.points(2)[0].available, 2.0);
+        let actions: Vec<_> = account_manager
+            .history(1)
+            .map(|point| (point.tx_id, point.action.clone().unwrap()))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                (1, Action::Deposit),
+                (3, Action::HoldInEscrow),
+                (3, Action::ReleaseEscrow)
+            ]
+        );
+
+        let before_release = account_manager.balance_as_of(1, AsOf::Sequence(2)).unwrap();
+        assert_eq!((before_release.held, before_release.transactions), (2.0, 2));
+        assert!(account_manager
+            .balance_as_of(2, AsOf::Sequence(2))
+            .is_none());
+    }
+
+    #[test]
+    fn expired_transactions_are_evicted_unless_disputed() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            cache_ttl: CacheTtl::Transactions(2),
+            ..Policy::default()
+        });
+        let deposit = |id| Transaction::new(Action::Deposit, 1, id, Some(1.0));
+        for id in 1..=4 {
+            assert!(process_transaction(&mut account_manager, deposit(id)).is_ok());
+        }
+        assert_eq!(account_manager.evicted_transactions(), 1);
+        let dispute = |id| Transaction::new(Action::Dispute, 1, id, None);
+        assert_eq!(
+            process_transaction(&mut account_manager, dispute(1)),
+            Err(AccountManagerError::TransactionNotFound { id: 1 })
+        );
+        assert!(process_transaction(&mut account_manager, dispute(2)).is_ok());
+        for id in 5..=7 {
+            assert!(process_transaction(&mut account_manager, deposit(id)).is_ok());
+        }
+        assert_eq!(account_manager.evicted_transactions(), 3);
+        let resolve = Transaction::new(Action::Resolve, 1, 2, None);
+        assert!(process_transaction(&mut account_manager, resolve).is_ok());
+
+        let mut account_manager = AccountManager::with_policy(Policy {
+            cache_ttl: CacheTtl::Seconds(100),
+            ..Policy::default()
+        });
+        for (id, timestamp) in [(1, 0), (2, 100), (3, 150)] {
+            let tx = Transaction {
+                timestamp: Some(timestamp),
+                ..deposit(id)
+            };
+            assert!(process_transaction(&mut account_manager, tx).is_ok());
+        }
+        assert!(process_transaction(&mut account_manager, dispute(1)).is_err());
+        assert!(process_transaction(&mut account_manager, dispute(2)).is_ok());
+    }
+
+    #[test]
+    fn evictions_clear_the_undo_log() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            cache_ttl: CacheTtl::Transactions(2),
+            ..Policy::default()
+        });
+        account_manager.enable_undo(10);
+        let deposit = |id| Transaction::new(Action::Deposit, 1, id, Some(1.0));
+        for id in 1..=3 {
+            assert!(process_transaction(&mut account_manager, deposit(id)).is_ok());
+        }
+        assert_eq!(account_manager.undoable(), 3);
+        assert!(process_transaction(&mut account_manager, deposit(4)).is_ok());
+        assert_eq!(account_manager.evicted_transactions(), 1);
+        assert_eq!(account_manager.undoable(), 0);
+        assert_eq!(
+            account_manager.rollback(1),
+            Err(AccountManagerError::RollbackUnavailable {
+                requested: 1,
+                available: 0
+            })
+        );
+        assert!(process_transaction(&mut account_manager, deposit(5)).is_ok());
+        assert_eq!(account_manager.evicted_transactions(), 2);
+        assert_eq!(account_manager.undoable(), 0);
+    }
+
+    #[test]
+    fn rollback_restores_the_books_before_the_last_transactions() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+        account_manager.enable_history();
+        account_manager.enable_undo(10);
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(5.0));
+        assert!(process_transaction_at(&mut account_manager, 10, deposit).is_ok());
+        let posted = account_manager.ledger().unwrap().entries().len();
+
+        let batch = [
+            Transaction {
+                idempotency_key: Some("abc".to_string()),
+                ..Transaction::new(Action::Deposit, 1, 2, Some(3.0))
+            },
+            Transaction::new(Action::Dispute, 1, 1, None),
+            Transaction {
+                counterparty: Some(2),
+                ..Transaction::new(Action::HoldInEscrow, 1, 3, Some(2.0))
+            },
+            Transaction::new(Action::ReleaseEscrow, 1, 3, None),
+        ];
+        for (offset, tx) in (20..).step_by(10).zip(batch.clone()) {
+            assert!(process_transaction_at(&mut account_manager, offset, tx).is_ok());
+        }
+        assert!(account_manager.rollback(4).is_ok());
+
+        let account = account_manager.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (5.0, 0.0));
+        assert_eq!(account.transactions(), 1);
+        assert!(account_manager.account(2).is_none());
+        assert!(account_manager.escrow(3).is_none());
+        assert_eq!(account_manager.ledger().unwrap().entries().len(), posted);
+        assert_eq!(account_manager.dispute_cases().iter().count(), 0);
+        assert_eq!(account_manager.balance_history().unwrap().sequence(), 1);
+        assert!(account_manager.verify_invariants().is_ok());
+
+        // The corrected batch can be applied again at the same offsets.
+        for (offset, tx) in (20..).step_by(10).zip(batch) {
+            assert!(process_transaction_at(&mut account_manager, offset, tx).is_ok());
+        }
+        assert_eq!(account_manager.account(2).unwrap().available(), 2.0);
+        assert_eq!(
+            account_manager.rollback(6),
+            Err(AccountManagerError::RollbackUnavailable {
+                requested: 6,
+                available: 5
+            })
+        );
+    }
+
+    #[test]
+    fn disputes_are_tracked_as_cases() {
+        let mut account_manager = AccountManager::new();
+        assert!(account_manager.deposit(1, 1, 2.0).is_ok());
+        let dispute = Transaction {
+            description: Some("Card fraud".to_string()),
+            reference: Some("CASE-7".to_string()),
+            timestamp: Some(100),
+            ..Transaction::new(Action::Dispute, 1, 1, None)
+        };
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+        assert!(account_manager
+            .attach_evidence(1, "receipt.pdf".to_string())
+            .is_ok());
+        let cases = account_manager.dispute_cases();
+        assert_eq!(cases.open_cases().count(), 1);
+        assert_eq!(cases.open_case(1).unwrap().age(Some(160)), Some(60));
+
+        let chargeback = Transaction {
+            timestamp: Some(200),
+            ..Transaction::new(Action::Chargeback, 1, 1, None)
+        };
+        assert!(process_transaction(&mut account_manager, chargeback).is_ok());
+        let case = account_manager.dispute_cases().history(1).next().unwrap();
+        assert_eq!(case.status, CaseStatus::ChargedBack);
+        assert_eq!(case.reason.as_deref(), Some("Card fraud"));
+        assert_eq!(case.evidence, vec!["CASE-7", "receipt.pdf"]);
+        assert_eq!(case.age(None), Some(100));
+        assert_eq!(
+            account_manager.attach_evidence(1, "late.pdf".to_string()),
+            Err(AccountManagerError::Undisputed { id: 1 })
+        );
+    }
+
+    #[test]
+    fn closed_periods_keep_their_totals() {
+        let mut account_manager = AccountManager::new();
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(2.0));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let close = Transaction {
+            reference: Some("2024-01".to_string()),
+            timestamp: Some(100),
+            ..Transaction::new(Action::ClosePeriod, 1, 0, None)
+        };
+        assert!(process_transaction(&mut account_manager, close).is_ok());
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(0.5));
+        assert!(process_transaction(&mut account_manager, withdrawal).is_ok());
+
+        let periods = account_manager.periods();
+        assert_eq!(periods.current(), 2);
+        let closed = &periods.closed()[0];
+        assert_eq!(closed.closed_at, Some(100));
+        assert_eq!(closed.clients[0].closing, 2.0);
+        assert_eq!(closed.clients[0].transactions, 1);
+        assert_eq!(account_manager.account(1).unwrap().transactions(), 2);
+    }
+
+    #[test]
+    fn periods_beyond_the_retention_are_compacted() {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            retention: Retention::Periods(1),
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        account_manager.enable_history();
+        let close = || Transaction::new(Action::ClosePeriod, 1, 0, None);
+        let txs = [
+            Transaction::new(Action::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(Action::Deposit, 2, 2, Some(3.0)),
+            Transaction::new(Action::Dispute, 1, 1, None),
+            Transaction::new(Action::Resolve, 1, 1, None),
+            Transaction::new(Action::Dispute, 2, 2, None),
+            close(),
+            Transaction::new(Action::Deposit, 1, 3, Some(1.0)),
+            Transaction::new(Action::Withdrawal, 1, 4, Some(2.0)),
+        ];
+        for tx in txs {
+            assert!(process_transaction(&mut account_manager, tx).is_ok());
+        }
+        assert_eq!(account_manager.compacted(), Compacted::default());
+        assert!(process_transaction(&mut account_manager, close()).is_ok());
+
+        let compacted = account_manager.compacted();
+        assert_eq!(compacted.periods, 1);
+        assert_eq!(compacted.transactions, 1);
+        assert_eq!(compacted.cases, 1);
+        assert_eq!(compacted.history_points, 3);
+        let dispute = |client_id, id| Transaction::new(Action::Dispute, client_id, id, None);
+        assert_eq!(
+            process_transaction(&mut account_manager, dispute(1, 1)),
+            Err(AccountManagerError::TransactionNotFound { id: 1 })
+        );
+        assert!(process_transaction(&mut account_manager, dispute(1, 3)).is_ok());
+        // The dispute of the compacted period is still open.
+        let resolve = Transaction::new(Action::Resolve, 2, 2, None);
+        assert!(process_transaction(&mut account_manager, resolve).is_ok());
+
+        assert_eq!(account_manager.periods().closed().len(), 2);
+        assert_eq!(account_manager.account(1).unwrap().total(), 4.0);
+        assert_eq!(account_manager.history(1).count(), 4);
+        assert_eq!(account_manager.ledger().unwrap().entries()[0].sequence, 0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn metadata_is_attached_to_the_posted_entries() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+        let deposit = Transaction {
+            description: Some("Salary".to_string()),
+            reference: Some("00123".to_string()),
+            ..Transaction::new(Action::Deposit, 1, 1, Some(2.0))
+        };
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+
+        let entries = account_manager.ledger().unwrap().entries();
+        assert_eq!(entries[0].description.as_deref(), Some("Salary"));
+        assert_eq!(entries[0].reference.as_deref(), Some("00123"));
+        assert_eq!(entries[1].description, None);
+    }
+
+    fn withdrawn_account_manager(withdrawal_dispute: WithdrawalDisputePolicy) -> AccountManager {
+        let mut account_manager = AccountManager::with_policy(Policy {
+            withdrawal_dispute,
+            ..Policy::default()
+        });
+        account_manager.enable_ledger();
+        assert!(account_manager.deposit(1, 1, 3.0).is_ok());
+        assert!(account_manager.withdraw(2, 1, 2.0).is_ok());
+        account_manager
+    }
+
+    #[test]
+    fn withdrawals_can_not_be_disputed_by_default() {
+        let mut account_manager = withdrawn_account_manager(WithdrawalDisputePolicy::default());
+        assert_eq!(
+            account_manager.dispute(2, 1),
+            Err(AccountManagerError::TransactionNotFound { id: 2 })
+        );
+    }
+
+    #[test]
+    fn disputed_withdrawal_is_held_and_paid_out_again_on_resolve() {
+        let mut account_manager = withdrawn_account_manager(WithdrawalDisputePolicy::Reverse);
+        assert!(account_manager.dispute(2, 1).is_ok());
+        let account = account_manager.account(1).unwrap();
+        assert_eq!(account.available(), 1.0);
+        assert_eq!(account.disputed(), 2.0);
+        assert_eq!(account.total(), 3.0);
+        assert!(account_manager.verify_invariants().is_ok());
+
+        assert!(account_manager.resolve(2, 1).is_ok());
+        let account = account_manager.account(1).unwrap();
+        assert_eq!(account.available(), 1.0);
+        assert_eq!(account.total(), 1.0);
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn chargeback_of_withdrawal_credits_the_funds_back() {
+        for (policy, locked) in [
+            (WithdrawalDisputePolicy::Reverse, false),
+            (WithdrawalDisputePolicy::ReverseAndLock, true),
+        ] {
+            let mut account_manager = withdrawn_account_manager(policy);
+            assert!(account_manager.dispute(2, 1).is_ok());
+            assert!(account_manager.chargeback(2, 1).is_ok());
+
+            let account = account_manager.account(1).unwrap();
+            assert_eq!(account.available(), 3.0);
+            assert_eq!(account.disputed(), 0.0);
+            assert_eq!(account.locked(), locked);
+            assert_eq!(
+                account_manager.dispute(2, 1),
+                Err(AccountManagerError::TransactionNotFound { id: 2 })
+            );
+            assert!(account_manager.verify_invariants().is_ok());
+        }
+    }
+
+    #[test]
+    fn invariants_hold_after_processing() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        assert!(account_manager.deposit(1, client_id, 3.0).is_ok());
+        assert!(account_manager.deposit(2, client_id, 1.0).is_ok());
+        assert!(account_manager.withdraw(3, client_id, 1.0).is_ok());
+        assert!(account_manager.dispute(1, client_id).is_ok());
+        assert!(account_manager.chargeback(1, client_id).is_ok());
+        assert!(account_manager.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn invariant_violation_is_detected() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        assert!(account_manager.deposit(1, client_id, 3.0).is_ok());
+        account_manager.totals.deposits = 2.0;
+
+        let err = account_manager.verify_invariants().unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::InvariantViolation {
+                subject: "sum of account balances",
+                client: None,
+                expected: 2.0,
+                actual: 3.0
+            }
+        );
+    }
+
+    #[cfg(feature = "invariant-checks")]
+    #[test]
+    #[should_panic(
+        expected = "Invariant violated by deposit 2 of client 1: Books don't balance. \
+                               disputed transactions of client 1: expected 3, found 0."
+    )]
+    fn transactions_breaking_an_invariant_panic() {
+        let mut account_manager = AccountManager::new();
+        let deposit = |tx_id| Transaction::new(Action::Deposit, 1, tx_id, Some(3.0));
+        assert!(process_transaction(&mut account_manager, deposit(1)).is_ok());
+        // The dispute flag is set without holding the funds.
+        let mut entry = account_manager.tx_cache.get(1).unwrap();
+        entry.disputed = true;
+        account_manager.tx_cache.insert(1, entry);
+
+        let _ = process_transaction(&mut account_manager, deposit(2));
+    }
+
+    #[test]
+    fn dispute_fails_if_transaction_is_not_owned_by_client() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id = 2;
+        let client_id = 1;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id, client_id, amount).is_ok());
+
+        let other_tx_id = 3;
+        let other_client_id = 2;
+        assert!(account_manager
+            .deposit(other_tx_id, other_client_id, amount)
+            .is_ok());
+
+        assert!(account_manager.dispute(tx_id, other_client_id).is_err());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].1.available(), amount);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), 0.0);
+        assert_eq!(accounts[1].1.available(), amount);
+        assert_eq!(accounts[1].1.total(), amount);
+        assert_eq!(accounts[1].1.disputed(), 0.0);
+    }
+
+    #[test]
+    fn dispute_transaction() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id = 2;
+        let client_id = 1;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id, client_id, amount).is_ok());
+        assert!(account_manager.dispute(tx_id, client_id).is_ok());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), 0.0);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), amount);
+    }
+
+    #[test]
+    fn resolve_disputed_transaction() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id = 2;
+        let client_id = 1;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id, client_id, amount).is_ok());
+        assert!(account_manager.dispute(tx_id, client_id).is_ok());
+        assert!(account_manager.resolve(tx_id, client_id).is_ok());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), amount);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), 0.0);
+    }
+
+    #[test]
+    fn resolution_fails_if_dispute_failed() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id1 = 2;
+        let tx_id2 = 3;
+        let client_id = 1;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id1, client_id, amount).is_ok());
+        assert!(account_manager.withdraw(4, client_id, amount).is_ok());
+        assert!(account_manager.deposit(tx_id2, client_id, amount).is_ok());
+        let err = account_manager.resolve(tx_id1, client_id).unwrap_err();
+        assert_eq!(err, AccountManagerError::Undisputed { id: tx_id1 });
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), amount);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), 0.0);
+    }
+
+    #[test]
+    fn resolve_fails_if_transaction_is_from_other_client() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id = 2;
+        let client_id = 1;
+        let other_client_id = 2;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id, client_id, amount).is_ok());
+        assert!(account_manager.dispute(tx_id, client_id).is_ok());
+        assert!(account_manager.resolve(tx_id, other_client_id).is_err());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), 0.0);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), amount);
+    }
+
+    #[test]
+    fn resolve_fails_if_transaction_is_not_disputed() {
+        let mut account_manager = AccountManager::new();
+
+        let tx_id = 2;
+        let client_id = 1;
+        let amount = 1.0;
+        assert!(account_manager.deposit(tx_id, client_id, amount).is_ok());
+        assert!(account_manager.resolve(tx_id, client_id).is_err());
+
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), amount);
+        assert_eq!(accounts[0].1.total(), amount);
+        assert_eq!(accounts[0].1.disputed(), 0.0);
+    }
+
+    #[test]
+    fn deposit_fails_if_balance_overflows() {
+        let mut account_manager = AccountManager::new();
+
+        let client_id = 1;
+        assert!(account_manager.deposit(1, client_id, f64::MAX).is_ok());
+        let err = account_manager.deposit(2, client_id, f64::MAX).unwrap_err();
+        assert_eq!(
+            err,
+            AccountManagerError::Account(AccountError::BalanceOverflow {
+                balance: f64::MAX,
+                amount: f64::MAX
+            })
+        );
+
+        let err = account_manager.dispute(2, client_id).unwrap_err();
+        assert_eq!(err, AccountManagerError::TransactionNotFound { id: 2 });
+    }
+
+    #[test]
+    fn stats_count_accounts_cached_transactions_and_open_disputes() {
+        let mut account_manager = AccountManager::new();
+        for (tx_id, client_id) in [(1, 1), (2, 1), (3, 2)] {
+            let deposit = Transaction::new(Action::Deposit, client_id, tx_id, Some(1.0));
+            assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        }
+        let dispute = Transaction::new(Action::Dispute, 1, 2, None);
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+
+        let stats = account_manager.stats();
+        assert_eq!(
+            (
+                stats.accounts,
+                stats.cached_transactions,
+                stats.open_disputes
+            ),
+            (2, 3, 1)
+        );
+        assert!(stats.memory_bytes > AccountManager::new().stats().memory_bytes);
+    }
+}
@@ -0,0 +1,446 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::Visitor;
+use serde::{Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AmountFormatError {
+    #[error("invalid amount `{0}`")]
+    Invalid(String),
+
+    #[error("invalid digit grouping in amount `{0}`")]
+    Grouping(String),
+
+    #[error("ambiguous amount `{0}`, use a decimal separator or no thousands separator")]
+    Ambiguous(String),
+}
+
+pub type AmountFormatResult<T> = Result<T, AmountFormatError>;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AmountParseError {
+    #[error("amount `{0}` is not a decimal number")]
+    Malformed(String),
+
+    #[error("amount must be a finite number, got {0}")]
+    NonFinite(String),
+
+    #[error("amount `{value}` has more than {max} digits and can't be kept exactly")]
+    TooManyDigits { value: String, max: usize },
+}
+
+pub type AmountParseResult<T> = Result<T, AmountParseError>;
+
+// Digits of a decimal that every f64 keeps exactly.
+pub const MAX_EXACT_DIGITS: usize = 15;
+
+pub fn format_amount(amount: f64, places: u32) -> String {
+    format!("{amount:.*}", places as usize)
+}
+
+// An amount of a report row, written with the minor units of the currency of
+// the books (Policy::currency), i.e. 4 decimal places without one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportAmount {
+    pub amount: f64,
+    pub places: u32,
+}
+
+impl ReportAmount {
+    pub fn new(amount: f64, places: u32) -> Self {
+        Self { amount, places }
+    }
+}
+
+impl fmt::Display for ReportAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.*}", self.places as usize, self.amount)
+    }
+}
+
+impl Serialize for ReportAmount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// An amount as written in the input: units of 10^-scale, e.g. 1.25 is 125
+// with scale 2. Parsing checks the digits, so an amount that an f64 can't
+// hold is rejected instead of being rounded to a neighbour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExactAmount {
+    pub units: i64,
+    pub scale: u32,
+}
+
+impl ExactAmount {
+    // Plain decimals like -1.5, .5 or 2., optionally with an exponent like
+    // 1e3. The digits are the integer digits without leading zeros and the
+    // fraction digits without trailing zeros, at most MAX_EXACT_DIGITS.
+    pub fn parse(value: &str) -> AmountParseResult<Self> {
+        let malformed = || AmountParseError::Malformed(value.to_string());
+        let (negative, unsigned) = match value.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        if ["inf", "infinity", "nan"]
+            .iter()
+            .any(|name| unsigned.eq_ignore_ascii_case(name))
+        {
+            return Err(AmountParseError::NonFinite(value.to_string()));
+        }
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => {
+                (mantissa, exponent.parse::<i32>().map_err(|_| malformed())?)
+            }
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if integer.is_empty() && fraction.is_empty() || !is_digits(integer) || !is_digits(fraction)
+        {
+            return Err(malformed());
+        }
+
+        let fraction = fraction.trim_end_matches('0');
+        let digits = format!("{integer}{fraction}");
+        let digits = digits.trim_start_matches('0');
+        if digits.is_empty() {
+            return Ok(Self { units: 0, scale: 0 });
+        }
+        let scale = fraction.len() as i64 - i64::from(exponent);
+        // Positive exponents append zeros to the units.
+        let significant = (digits.len() as i64 + (-scale).max(0)).max(scale);
+        if significant > MAX_EXACT_DIGITS as i64 {
+            return Err(AmountParseError::TooManyDigits {
+                value: value.to_string(),
+                max: MAX_EXACT_DIGITS,
+            });
+        }
+        let units = digits.parse::<i64>().unwrap_or(0) * 10i64.pow((-scale).max(0) as u32);
+        Ok(Self {
+            units: if negative { -units } else { units },
+            scale: scale.max(0) as u32,
+        })
+    }
+
+    // The nearest f64, the same one str::parse finds: units and the power of
+    // ten are exact, so the division rounds once.
+    pub fn to_f64(self) -> f64 {
+        self.units as f64 / 10f64.powi(self.scale as i32)
+    }
+}
+
+// Amounts are parsed from the digits as written by ExactAmount. CSV fields
+// that look like numbers and numbers of JSON arrive as f64 and are checked
+// by their shortest representation, i.e. the digits of the input unless it
+// had more than an f64 keeps.
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Option<f64>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a decimal amount")
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        ExactAmount::parse(value)
+            .map(|amount| Some(amount.to_f64()))
+            .map_err(E::custom)
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, value: f64) -> Result<Self::Value, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u128<E: serde::de::Error>(self, value: u128) -> Result<Self::Value, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_i128<E: serde::de::Error>(self, value: i128) -> Result<Self::Value, E> {
+        self.visit_str(&value.to_string())
+    }
+}
+
+pub fn deserialize_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+// How amounts are written in the input. Localized amounts are normalized to
+// the plain format before they are deserialized, so the amount deserializer
+// stays the only place that turns them into numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AmountFormat {
+    // 1234.56
+    #[default]
+    Plain,
+    // 1,234.56
+    DecimalPoint,
+    // 1.234,56
+    DecimalComma,
+    // 1'234.56, e.g. in Switzerland
+    Apostrophe,
+    // 1 234,56, e.g. in France, also with no-break spaces
+    SpaceComma,
+}
+
+impl FromStr for AmountFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Self::Plain),
+            "decimal-point" => Ok(Self::DecimalPoint),
+            "decimal-comma" => Ok(Self::DecimalComma),
+            "apostrophe" => Ok(Self::Apostrophe),
+            "space-comma" => Ok(Self::SpaceComma),
+            _ => Err(format!(
+                "unknown amount format '{s}', expected plain, decimal-point, decimal-comma, apostrophe or space-comma"
+            )),
+        }
+    }
+}
+
+impl AmountFormat {
+    // The decimal separator and the thousands separators, the first one is
+    // written.
+    fn separators(&self) -> Option<(char, &'static [char])> {
+        match self {
+            AmountFormat::Plain => None,
+            AmountFormat::DecimalPoint => Some(('.', &[','])),
+            AmountFormat::DecimalComma => Some((',', &['.'])),
+            AmountFormat::Apostrophe => Some(('.', &['\'', '\u{2019}'])),
+            AmountFormat::SpaceComma => Some((',', &[' ', '\u{a0}', '\u{202f}'])),
+        }
+    }
+
+    // Rewrites an amount to the plain format. Thousands separators must
+    // group the integer digits by three. A single thousands separator without
+    // a decimal separator, like `1.234` with decimal commas, is rejected
+    // because senders using the other format mean a fraction by it.
+    pub fn normalize<'a>(&self, value: &'a str) -> AmountFormatResult<Cow<'a, str>> {
+        let Some((decimal, thousands)) = self.separators() else {
+            return Ok(Cow::Borrowed(value));
+        };
+        if !value.contains(|c| c == decimal || thousands.contains(&c)) {
+            return Ok(Cow::Borrowed(value));
+        }
+
+        let invalid = || AmountFormatError::Invalid(value.to_string());
+        let (sign, digits) = match value.strip_prefix(['-', '+']) {
+            Some(digits) => (&value[..1], digits),
+            None => ("", value),
+        };
+        let (integer, fraction) = match digits.split_once(decimal) {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (digits, None),
+        };
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+
+        let groups: Vec<_> = integer.split(thousands).collect();
+        if !groups.iter().copied().all(is_digits) {
+            return Err(invalid());
+        }
+        if groups.len() > 1
+            && (groups[0].is_empty()
+                || groups[0].len() > 3
+                || groups[1..].iter().any(|group| group.len() != 3))
+        {
+            return Err(AmountFormatError::Grouping(value.to_string()));
+        }
+        // Apostrophes and spaces never separate decimals.
+        let ambiguous = thousands.iter().any(|c| matches!(c, '.' | ','));
+        if ambiguous && groups.len() == 2 && fraction.is_none() {
+            return Err(AmountFormatError::Ambiguous(value.to_string()));
+        }
+
+        let mut plain = format!("{sign}{}", groups.concat());
+        if let Some(fraction) = fraction {
+            if fraction.is_empty() || !is_digits(fraction) {
+                return Err(invalid());
+            }
+            plain.push('.');
+            plain.push_str(fraction);
+        }
+        Ok(Cow::Owned(plain))
+    }
+
+    // The reverse of normalize for reports, e.g. 1,234.5678.
+    pub fn format(&self, amount: f64, decimals: usize) -> String {
+        let plain = format!("{:.decimals$}", amount.abs());
+        // No minus sign for amounts that round to zero.
+        let sign = match amount < 0.0 && plain.bytes().any(|byte| matches!(byte, b'1'..=b'9')) {
+            true => "-",
+            false => "",
+        };
+        let Some((decimal, thousands)) = self.separators() else {
+            return format!("{sign}{plain}");
+        };
+        let (integer, fraction) = plain.split_once('.').unwrap_or((&plain, ""));
+        let mut formatted = String::with_capacity(plain.len() + integer.len() / 3 + 1);
+        formatted.push_str(sign);
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                formatted.push(thousands[0]);
+            }
+            formatted.push(digit);
+        }
+        if !fraction.is_empty() {
+            formatted.push(decimal);
+            formatted.push_str(fraction);
+        }
+        formatted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decimal_comma_amounts_are_normalized() {
+        let format = AmountFormat::DecimalComma;
+        assert_eq!(format.normalize("1.234,56").unwrap(), "1234.56");
+        assert_eq!(format.normalize("-12.345.678,9").unwrap(), "-12345678.9");
+        assert_eq!(format.normalize("0,5").unwrap(), "0.5");
+        assert_eq!(format.normalize("1234").unwrap(), "1234");
+        assert_eq!(
+            AmountFormat::DecimalPoint.normalize("1,234.56").unwrap(),
+            "1234.56"
+        );
+    }
+
+    #[test]
+    fn ambiguous_and_misgrouped_amounts_are_rejected() {
+        let format = AmountFormat::DecimalComma;
+        assert_eq!(
+            format.normalize("1.234"),
+            Err(AmountFormatError::Ambiguous("1.234".to_string()))
+        );
+        assert_eq!(
+            format.normalize("1234.56"),
+            Err(AmountFormatError::Grouping("1234.56".to_string()))
+        );
+        assert_eq!(
+            format.normalize("1,234,56"),
+            Err(AmountFormatError::Invalid("1,234,56".to_string()))
+        );
+        assert_eq!(
+            AmountFormat::DecimalPoint.normalize("1,234,5"),
+            Err(AmountFormatError::Grouping("1,234,5".to_string()))
+        );
+    }
+
+    #[test]
+    fn amounts_are_formatted_with_grouping() {
+        assert_eq!(
+            AmountFormat::DecimalPoint.format(1234.5678, 4),
+            "1,234.5678"
+        );
+        assert_eq!(
+            AmountFormat::DecimalComma.format(-1234567.5, 4),
+            "-1.234.567,5000"
+        );
+        assert_eq!(AmountFormat::DecimalPoint.format(123.0, 0), "123");
+        assert_eq!(AmountFormat::Plain.format(-1234.5, 2), "-1234.50");
+    }
+
+    #[test]
+    fn apostrophe_and_space_grouped_amounts_are_normalized() {
+        let format = AmountFormat::Apostrophe;
+        assert_eq!(format.normalize("1'234.56").unwrap(), "1234.56");
+        assert_eq!(format.normalize("-12\u{2019}345").unwrap(), "-12345");
+        assert_eq!(
+            format.normalize("1'23.5"),
+            Err(AmountFormatError::Grouping("1'23.5".to_string()))
+        );
+
+        let format = AmountFormat::SpaceComma;
+        assert_eq!(format.normalize("1 234,56").unwrap(), "1234.56");
+        assert_eq!(
+            format.normalize("1\u{a0}234\u{202f}567").unwrap(),
+            "1234567"
+        );
+        assert_eq!(format.normalize("0,5").unwrap(), "0.5");
+        assert_eq!(format.format(1234.5, 2), "1 234,50");
+    }
+
+    #[test]
+    fn plain_amounts_are_untouched() {
+        assert_eq!(AmountFormat::Plain.normalize("1,5").unwrap(), "1,5");
+    }
+
+    #[test]
+    fn exact_amounts_keep_their_digits() {
+        let parse =
+            |value: &str| ExactAmount::parse(value).map(|amount| (amount.units, amount.scale));
+        assert_eq!(parse("1.25"), Ok((125, 2)));
+        assert_eq!(parse("-.5"), Ok((-5, 1)));
+        assert_eq!(parse("+2."), Ok((2, 0)));
+        assert_eq!(parse("0.000100"), Ok((1, 4)));
+        assert_eq!(parse("1.5e3"), Ok((1500, 0)));
+        assert_eq!(parse("15e-4"), Ok((15, 4)));
+        assert_eq!(parse("0e999"), Ok((0, 0)));
+        assert_eq!(parse("999999999999999"), Ok((999999999999999, 0)));
+        for value in ["0.1", "123456789.0001", "0.3", "-7.25", "1e-15"] {
+            assert_eq!(
+                ExactAmount::parse(value).unwrap().to_f64(),
+                value.parse::<f64>().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn inexact_and_malformed_amounts_are_rejected() {
+        for value in [
+            "0.30000000000000004",
+            "1.00000000000000001",
+            "1e16",
+            "1e-16",
+        ] {
+            assert_eq!(
+                ExactAmount::parse(value),
+                Err(AmountParseError::TooManyDigits {
+                    value: value.to_string(),
+                    max: MAX_EXACT_DIGITS
+                })
+            );
+        }
+        for value in ["", ".", "1.2.3", "1,5", "0x10", "1e", "--1", "1 000"] {
+            assert_eq!(
+                ExactAmount::parse(value),
+                Err(AmountParseError::Malformed(value.to_string()))
+            );
+        }
+        assert_eq!(
+            ExactAmount::parse("-Infinity"),
+            Err(AmountParseError::NonFinite("-Infinity".to_string()))
+        );
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Write;
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::{StringRecord, Writer};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::account_manager::{
+    check_authorization, check_disputed, check_undisputed, AccountManagerError,
+};
+use crate::tx_cache::{DisputeDirection, TxCacheEntry};
+use crate::types::{Action, ClientId, TransactionId};
+#[cfg(feature = "csv")]
+use crate::types::{RecordError, Transaction};
+
+// Wei, the smallest unit of ether, has 18 decimals.
+pub const MAX_ASSET_DECIMALS: u32 = 18;
+
+// An amount in the smallest unit of an asset, e.g. wei or satoshi. With 18
+// decimals it holds about 170 quintillion whole units.
+pub type MinorUnits = i128;
+
+// Decimals of the assets known without a `CODE:DECIMALS`.
+const KNOWN_ASSETS: &[(&str, u32)] = &[
+    ("BTC", 8),
+    ("ETH", 18),
+    ("SOL", 9),
+    ("USDC", 6),
+    ("USDT", 6),
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AssetError {
+    #[error("amount `{value}` is not a decimal number")]
+    Malformed { value: String },
+
+    #[error("amount `{value}` has more than the {decimals} decimal places of the asset")]
+    TooManyDecimals { value: String, decimals: u32 },
+
+    #[error("amount `{value}` is too large for the asset")]
+    TooLarge { value: String },
+
+    #[cfg(feature = "csv")]
+    #[error("{0}")]
+    Record(#[from] RecordError),
+
+    #[error("Transaction {id} has an invalid amount of {amount}")]
+    InvalidAmount { id: TransactionId, amount: String },
+
+    #[error("Insufficient funds. Requested {requested} of {available}.")]
+    InsufficientFunds {
+        id: TransactionId,
+        requested: String,
+        available: String,
+    },
+
+    #[error("Account is locked")]
+    Locked { id: TransactionId },
+
+    #[error("Balance overflow. Can't apply transaction {id}.")]
+    BalanceOverflow { id: TransactionId },
+
+    #[error("Transaction {id} already exists")]
+    DuplicateTransaction { id: TransactionId },
+
+    // Rejections of disputes, resolves and chargebacks, like the ones of an
+    // AccountManager.
+    #[error("{0}")]
+    Dispute(#[from] AccountManagerError),
+
+    #[error("Asset books don't support {action} transactions")]
+    Unsupported { action: &'static str },
+}
+
+impl AssetError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AssetError::Malformed { .. }
+            | AssetError::TooManyDecimals { .. }
+            | AssetError::TooLarge { .. } => "invalid_row",
+            #[cfg(feature = "csv")]
+            AssetError::Record(_) => "invalid_row",
+            AssetError::InvalidAmount { .. } => "invalid_amount",
+            AssetError::InsufficientFunds { .. } => "insufficient_funds",
+            AssetError::Locked { .. } => "locked",
+            AssetError::BalanceOverflow { .. } => "balance_overflow",
+            AssetError::DuplicateTransaction { .. } => "duplicate_transaction",
+            AssetError::Dispute(err) => err.kind(),
+            AssetError::Unsupported { .. } => "unsupported",
+        }
+    }
+}
+
+pub type AssetResult<T> = Result<T, AssetError>;
+
+// An asset with its decimals. Amounts are exact integers of its minor units,
+// so 18 decimal amounts are parsed, summed and written without the rounding
+// of the f64 balances of an AccountManager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Asset {
+    pub code: String,
+    pub decimals: u32,
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code)
+    }
+}
+
+// A known code like ETH or CODE:DECIMALS.
+impl FromStr for Asset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = s.to_ascii_uppercase();
+        let (code, decimals) = match code.split_once(':') {
+            Some((code, decimals)) => (
+                code,
+                decimals
+                    .parse()
+                    .map_err(|_| format!("invalid decimals in asset '{s}'"))?,
+            ),
+            None => match KNOWN_ASSETS.iter().find(|(known, _)| *known == code) {
+                Some((code, decimals)) => (*code, *decimals),
+                None => return Err(format!("unknown asset '{s}', use CODE:DECIMALS")),
+            },
+        };
+        if code.is_empty() || decimals > MAX_ASSET_DECIMALS {
+            return Err(format!(
+                "invalid asset '{s}', expected a code with at most {MAX_ASSET_DECIMALS} decimals"
+            ));
+        }
+        Ok(Self::new(code, decimals))
+    }
+}
+
+impl Asset {
+    pub fn new(code: &str, decimals: u32) -> Self {
+        Self {
+            code: code.to_ascii_uppercase(),
+            decimals: decimals.min(MAX_ASSET_DECIMALS),
+        }
+    }
+
+    fn scale(&self) -> MinorUnits {
+        10i128.pow(self.decimals)
+    }
+
+    // A plain decimal like 1.5 or -.000000000000000001, with at most the
+    // decimals of the asset.
+    pub fn parse(&self, value: &str) -> AssetResult<MinorUnits> {
+        let malformed = || AssetError::Malformed {
+            value: value.to_string(),
+        };
+        let (negative, unsigned) = match value.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if integer.is_empty() && fraction.is_empty() || !is_digits(integer) || !is_digits(fraction)
+        {
+            return Err(malformed());
+        }
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > self.decimals as usize {
+            return Err(AssetError::TooManyDecimals {
+                value: value.to_string(),
+                decimals: self.decimals,
+            });
+        }
+        let padding = 10i128.pow(self.decimals - fraction.len() as u32);
+        let units = format!("{integer}{fraction}")
+            .bytes()
+            .try_fold(0i128, |units, byte| {
+                units
+                    .checked_mul(10)?
+                    .checked_add(MinorUnits::from(byte - b'0'))
+            })
+            .and_then(|units| units.checked_mul(padding))
+            .ok_or_else(|| AssetError::TooLarge {
+                value: value.to_string(),
+            })?;
+        Ok(if negative { -units } else { units })
+    }
+
+    // All decimals of the asset, e.g. 1.500000000000000000 for ETH.
+    pub fn format(&self, units: MinorUnits) -> String {
+        let sign = if units < 0 { "-" } else { "" };
+        let magnitude = units.unsigned_abs();
+        let scale = self.scale().unsigned_abs();
+        match self.decimals {
+            0 => format!("{sign}{magnitude}"),
+            decimals => format!(
+                "{sign}{}.{:0width$}",
+                magnitude / scale,
+                magnitude % scale,
+                width = decimals as usize
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AssetAccount {
+    available: MinorUnits,
+    held: MinorUnits,
+    locked: bool,
+}
+
+impl AssetAccount {
+    pub fn available(&self) -> MinorUnits {
+        self.available
+    }
+
+    pub fn held(&self) -> MinorUnits {
+        self.held
+    }
+
+    pub fn total(&self) -> MinorUnits {
+        self.available + self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+}
+
+// A transaction with its amount in minor units of the asset of the books.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetTransaction {
+    pub action: Action,
+    pub client_id: ClientId,
+    pub id: TransactionId,
+    pub amount: Option<MinorUnits>,
+}
+
+#[cfg(feature = "csv")]
+impl AssetTransaction {
+    // The columns type, client, tx and amount of a record with the headers.
+    pub fn from_record(
+        record: &StringRecord,
+        headers: &StringRecord,
+        asset: &Asset,
+    ) -> AssetResult<Self> {
+        let field = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name)
+                .and_then(|index| record.get(index))
+        };
+        // The amount of the transaction would be an f64, it is parsed here.
+        let tx = Transaction::from_fields(|name| match name {
+            "amount" => None,
+            name => field(name),
+        })?;
+        Ok(Self {
+            action: tx.action,
+            client_id: tx.client_id,
+            id: tx.id,
+            amount: field("amount")
+                .filter(|value| !value.is_empty())
+                .map(|value| asset.parse(value))
+                .transpose()?,
+        })
+    }
+}
+
+// Books of one asset with exact balances in its minor units, for assets with
+// more decimals than an f64 keeps, like ETH. They apply deposits, withdrawals,
+// disputes, resolves and chargebacks of deposits like the default policy of
+// an AccountManager, with the same checks of the disputed deposits; fees,
+// limits, escrows, tenants and the other books aren't supported.
+#[derive(Debug, Clone)]
+pub struct AssetBook {
+    asset: Asset,
+    accounts: HashMap<ClientId, AssetAccount>,
+    deposits: HashMap<TransactionId, TxCacheEntry<MinorUnits>>,
+}
+
+impl AssetBook {
+    pub fn new(asset: Asset) -> Self {
+        Self {
+            asset,
+            accounts: HashMap::new(),
+            deposits: HashMap::new(),
+        }
+    }
+
+    pub fn asset(&self) -> &Asset {
+        &self.asset
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&AssetAccount> {
+        self.accounts.get(&client_id)
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = (&ClientId, &AssetAccount)> {
+        self.accounts.iter()
+    }
+
+    pub fn process_transaction(&mut self, tx: &AssetTransaction) -> AssetResult<()> {
+        match tx.action {
+            Action::Deposit => self.deposit(tx),
+            Action::Withdrawal => self.withdraw(tx),
+            Action::Dispute | Action::Resolve | Action::Chargeback => self.settle_dispute(tx),
+            _ => Err(AssetError::Unsupported {
+                action: tx.action.as_str(),
+            }),
+        }
+    }
+
+    fn amount(&self, tx: &AssetTransaction) -> AssetResult<MinorUnits> {
+        match tx.amount {
+            Some(amount) if amount > 0 => Ok(amount),
+            amount => Err(AssetError::InvalidAmount {
+                id: tx.id,
+                amount: amount.map_or_else(String::new, |a| self.asset.format(a)),
+            }),
+        }
+    }
+
+    // Deposits to locked accounts are credited, like by default.
+    fn deposit(&mut self, tx: &AssetTransaction) -> AssetResult<()> {
+        let (id, amount) = (tx.id, self.amount(tx)?);
+        if self.deposits.contains_key(&id) {
+            return Err(AssetError::DuplicateTransaction { id });
+        }
+        let total = self.account(tx.client_id).map_or(0, AssetAccount::total);
+        if total.checked_add(amount).is_none() {
+            return Err(AssetError::BalanceOverflow { id });
+        }
+        self.accounts.entry(tx.client_id).or_default().available += amount;
+        self.deposits.insert(
+            id,
+            TxCacheEntry::new(tx.client_id, amount, DisputeDirection::Incoming),
+        );
+        Ok(())
+    }
+
+    // Rejected withdrawals open no account.
+    fn withdraw(&mut self, tx: &AssetTransaction) -> AssetResult<()> {
+        let (id, amount) = (tx.id, self.amount(tx)?);
+        let account = self.accounts.get_mut(&tx.client_id);
+        if account.as_ref().is_some_and(|account| account.locked) {
+            return Err(AssetError::Locked { id });
+        }
+        match account {
+            Some(account) if amount <= account.available => {
+                account.available -= amount;
+                Ok(())
+            }
+            account => Err(AssetError::InsufficientFunds {
+                id,
+                requested: self.asset.format(amount),
+                available: self
+                    .asset
+                    .format(account.map_or(0, |account| account.available)),
+            }),
+        }
+    }
+
+    fn settle_dispute(&mut self, tx: &AssetTransaction) -> AssetResult<()> {
+        let id = tx.id;
+        let deposit = self
+            .deposits
+            .get_mut(&id)
+            .ok_or(AccountManagerError::TransactionNotFound { id })?;
+        check_authorization(deposit, tx.client_id)?;
+        match tx.action {
+            Action::Dispute => check_undisputed(deposit, id)?,
+            _ => check_disputed(deposit, id)?,
+        }
+        // The deposit created the account.
+        let account = self
+            .accounts
+            .get_mut(&tx.client_id)
+            .ok_or(AccountManagerError::TransactionNotFound { id })?;
+        match tx.action {
+            // Like by default, a dispute of a locked account or of spent
+            // funds holds nothing and leaves the deposit undisputed.
+            Action::Dispute => {
+                if !account.locked && deposit.amount <= account.available {
+                    account.available -= deposit.amount;
+                    account.held += deposit.amount;
+                    deposit.disputed = true;
+                }
+            }
+            Action::Resolve => {
+                account.held -= deposit.amount;
+                account.available += deposit.amount;
+                deposit.disputed = false;
+            }
+            _ => {
+                account.held -= deposit.amount;
+                account.locked = true;
+                // A charged back deposit can't be disputed again.
+                self.deposits.remove(&id);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "csv")]
+#[derive(Serialize)]
+struct AssetAccountRecord {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+// The accounts ordered by client, amounts with all decimals of the asset.
+#[cfg(feature = "csv")]
+pub fn write_asset_accounts<W: Write>(writer: W, book: &AssetBook) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    let mut accounts: Vec<_> = book.accounts().collect();
+    accounts.sort_by_key(|(client_id, _)| **client_id);
+    for (client_id, account) in accounts {
+        writer.serialize(AssetAccountRecord {
+            client: *client_id,
+            available: book.asset.format(account.available),
+            held: book.asset.format(account.held),
+            total: book.asset.format(account.total()),
+            locked: account.locked,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "csv")]
+    use crate::types::MAX_CLIENT_ID;
+
+    fn tx(
+        action: Action,
+        client_id: ClientId,
+        id: TransactionId,
+        amount: &str,
+    ) -> AssetTransaction {
+        let eth: Asset = "ETH".parse().unwrap();
+        AssetTransaction {
+            action,
+            client_id,
+            id,
+            amount: (!amount.is_empty()).then(|| eth.parse(amount).unwrap()),
+        }
+    }
+
+    #[test]
+    fn amounts_round_trip_with_all_decimals() {
+        let eth: Asset = "eth".parse().unwrap();
+        assert_eq!(eth.decimals, 18);
+        let wei = eth.parse("1.000000000000000001").unwrap();
+        assert_eq!(wei, 1_000_000_000_000_000_001);
+        assert_eq!(eth.format(wei), "1.000000000000000001");
+        assert_eq!(
+            eth.format(eth.parse("-.5").unwrap()),
+            "-0.500000000000000000"
+        );
+        let token = Asset::from_str("tok:0").unwrap();
+        assert_eq!(token.format(token.parse("42.000").unwrap()), "42");
+
+        assert_eq!(
+            eth.parse("0.0000000000000000001"),
+            Err(AssetError::TooManyDecimals {
+                value: "0.0000000000000000001".to_string(),
+                decimals: 18
+            })
+        );
+        assert!(matches!(
+            eth.parse("1e3"),
+            Err(AssetError::Malformed { .. })
+        ));
+        assert!(matches!(
+            eth.parse("1000000000000000000000"),
+            Err(AssetError::TooLarge { .. })
+        ));
+        assert!(Asset::from_str("XYZ").is_err());
+        assert!(Asset::from_str("XYZ:19").is_err());
+    }
+
+    #[test]
+    fn applies_transactions_exactly() {
+        let mut book = AssetBook::new("ETH".parse().unwrap());
+        let txs = [
+            tx(Action::Deposit, 1, 1, "0.1"),
+            tx(Action::Deposit, 1, 2, "0.2"),
+            tx(Action::Withdrawal, 1, 3, "0.300000000000000001"),
+            tx(Action::Withdrawal, 1, 4, "0.000000000000000001"),
+            tx(Action::Dispute, 1, 2, ""),
+        ];
+        let results: Vec<_> = txs
+            .iter()
+            .map(|tx| book.process_transaction(tx).map_err(|err| err.kind()))
+            .collect();
+        assert_eq!(
+            results,
+            vec![Ok(()), Ok(()), Err("insufficient_funds"), Ok(()), Ok(())]
+        );
+        let account = book.account(1).unwrap();
+        assert_eq!(
+            book.asset().format(account.available()),
+            "0.099999999999999999"
+        );
+        assert_eq!(account.held(), 200_000_000_000_000_000);
+
+        assert!(book
+            .process_transaction(&tx(Action::Chargeback, 1, 2, ""))
+            .is_ok());
+        assert!(book.account(1).unwrap().locked());
+        assert_eq!(
+            book.process_transaction(&tx(Action::Dispute, 1, 2, "")),
+            Err(AccountManagerError::TransactionNotFound { id: 2 }.into())
+        );
+        assert_eq!(
+            book.process_transaction(&tx(Action::OpenAccount, 2, 5, "")),
+            Err(AssetError::Unsupported {
+                action: "open_account"
+            })
+        );
+    }
+
+    #[test]
+    fn checks_disputes_like_an_account_manager() {
+        let mut book = AssetBook::new("ETH".parse().unwrap());
+        assert!(book
+            .process_transaction(&tx(Action::Withdrawal, 2, 1, "1"))
+            .is_err());
+        assert!(book.account(2).is_none());
+
+        assert!(book
+            .process_transaction(&tx(Action::Deposit, 1, 2, "1"))
+            .is_ok());
+        assert_eq!(
+            book.process_transaction(&tx(Action::Dispute, 2, 2, "")),
+            Err(AccountManagerError::Unauthorized {
+                client_id: 2,
+                owner_id: 1
+            }
+            .into())
+        );
+        assert_eq!(
+            book.process_transaction(&tx(Action::Resolve, 1, 2, ""))
+                .map_err(|err| err.kind()),
+            Err("undisputed")
+        );
+
+        // Spent funds can't be held, the dispute leaves the deposit undisputed.
+        assert!(book
+            .process_transaction(&tx(Action::Withdrawal, 1, 3, "0.5"))
+            .is_ok());
+        assert!(book
+            .process_transaction(&tx(Action::Dispute, 1, 2, ""))
+            .is_ok());
+        assert_eq!(book.account(1).unwrap().held(), 0);
+        assert_eq!(
+            book.process_transaction(&tx(Action::Chargeback, 1, 2, "")),
+            Err(AccountManagerError::Undisputed { id: 2 }.into())
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn reads_records_and_writes_the_accounts() {
+        let eth: Asset = "ETH".parse().unwrap();
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let record = StringRecord::from(vec!["deposit", "2", "1", "12.3456789012345678901"]);
+        assert!(matches!(
+            AssetTransaction::from_record(&record, &headers, &eth),
+            Err(AssetError::TooManyDecimals { .. })
+        ));
+        let record = StringRecord::from(vec!["deposit", "2", "1", "12.345678901234567890"]);
+        let deposit = AssetTransaction::from_record(&record, &headers, &eth).unwrap();
+        let too_large = (u128::from(MAX_CLIENT_ID) + 1).to_string();
+        let record = StringRecord::from(vec!["deposit", &too_large, "1", "1"]);
+        assert!(matches!(
+            AssetTransaction::from_record(&record, &headers, &eth),
+            Err(AssetError::Record(RecordError::Overflow { .. }))
+        ));
+
+        let mut book = AssetBook::new(eth);
+        assert!(book.process_transaction(&deposit).is_ok());
+        let mut output = Vec::new();
+        write_asset_accounts(&mut output, &book).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             2,12.345678901234567890,0.000000000000000000,12.345678901234567890,false\n"
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Read;
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::policy::MAX_DECIMAL_PLACES;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CurrencyError {
+    #[error("Invalid currency configuration at line {line}: {message}")]
+    InvalidConfig { line: u64, message: String },
+
+    #[error("Unknown currency '{code}', add it with its minor units")]
+    Unknown { code: String },
+}
+
+pub type CurrencyResult<T> = Result<T, CurrencyError>;
+
+// How amounts with more decimal places than the minor units are rounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    // Halves away from zero.
+    #[default]
+    HalfUp,
+    // Halves to the even neighbour, the banker's rounding.
+    HalfEven,
+    // Towards zero.
+    Down,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-up" => Ok(Self::HalfUp),
+            "half-even" => Ok(Self::HalfEven),
+            "down" => Ok(Self::Down),
+            _ => Err(format!(
+                "unknown rounding '{s}', expected half-up, half-even or down"
+            )),
+        }
+    }
+}
+
+// The currency of the books. Amounts of transactions may have at most its
+// minor units as decimal places, computed amounts like fees are rounded to
+// them, and reports write them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Currency {
+    pub code: String,
+    pub minor_units: u32,
+    pub rounding: Rounding,
+}
+
+// Books without a configured currency keep 4 decimal places.
+impl Default for Currency {
+    fn default() -> Self {
+        Self::new("", MAX_DECIMAL_PLACES as u32)
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code)
+    }
+}
+
+impl Currency {
+    pub fn new(code: &str, minor_units: u32) -> Self {
+        Self {
+            code: code.to_ascii_uppercase(),
+            minor_units,
+            rounding: Rounding::default(),
+        }
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn round(&self, amount: f64) -> f64 {
+        let scale = 10f64.powi(self.minor_units as i32);
+        let scaled = amount * scale;
+        let rounded = match self.rounding {
+            Rounding::HalfUp => scaled.round(),
+            Rounding::HalfEven => scaled.round_ties_even(),
+            Rounding::Down => scaled.trunc(),
+        };
+        rounded / scale
+    }
+}
+
+// The minor units of ISO 4217 currencies other than the usual 2.
+const ISO_MINOR_UNITS: &[(&str, u32)] = &[
+    ("BIF", 0),
+    ("CLP", 0),
+    ("DJF", 0),
+    ("GNF", 0),
+    ("ISK", 0),
+    ("JPY", 0),
+    ("KMF", 0),
+    ("KRW", 0),
+    ("PYG", 0),
+    ("RWF", 0),
+    ("UGX", 0),
+    ("VND", 0),
+    ("VUV", 0),
+    ("XAF", 0),
+    ("XOF", 0),
+    ("XPF", 0),
+    ("BHD", 3),
+    ("IQD", 3),
+    ("JOD", 3),
+    ("KWD", 3),
+    ("LYD", 3),
+    ("OMR", 3),
+    ("TND", 3),
+    ("CLF", 4),
+    ("UYW", 4),
+];
+
+const ISO_TWO_MINOR_UNITS: &[&str] = &[
+    "AED", "ARS", "AUD", "BRL", "CAD", "CHF", "CNY", "COP", "CZK", "DKK", "EGP", "EUR", "GBP",
+    "HKD", "HUF", "IDR", "ILS", "INR", "KES", "MAD", "MXN", "MYR", "NGN", "NOK", "NZD", "PEN",
+    "PHP", "PKR", "PLN", "QAR", "RON", "RUB", "SAR", "SEK", "SGD", "THB", "TRY", "TWD", "UAH",
+    "USD", "ZAR",
+];
+
+#[cfg(feature = "csv")]
+#[derive(Deserialize)]
+struct CurrencyRecord {
+    code: String,
+    minor_units: u32,
+    rounding: Option<Rounding>,
+}
+
+// The known currencies by code, the ISO 4217 ones unless configured
+// otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Currencies {
+    currencies: HashMap<String, Currency>,
+}
+
+impl Default for Currencies {
+    fn default() -> Self {
+        let iso = ISO_MINOR_UNITS
+            .iter()
+            .copied()
+            .chain(ISO_TWO_MINOR_UNITS.iter().map(|code| (*code, 2)));
+        Self {
+            currencies: iso
+                .map(|(code, minor_units)| (code.to_string(), Currency::new(code, minor_units)))
+                .collect(),
+        }
+    }
+}
+
+impl Currencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Adds the currency or replaces the one with its code.
+    pub fn insert(&mut self, currency: Currency) {
+        self.currencies.insert(currency.code.clone(), currency);
+    }
+
+    pub fn get(&self, code: &str) -> CurrencyResult<&Currency> {
+        self.currencies
+            .get(&code.to_ascii_uppercase())
+            .ok_or_else(|| CurrencyError::Unknown {
+                code: code.to_string(),
+            })
+    }
+
+    // CSV with the columns code, minor_units and an optional rounding.
+    #[cfg(feature = "csv")]
+    pub fn read<R: Read>(&mut self, reader: R) -> CurrencyResult<()> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        // Records start on the line after the headers.
+        for (line, result) in (2..).zip(reader.deserialize()) {
+            let record: CurrencyRecord = result.map_err(|err| CurrencyError::InvalidConfig {
+                line: err.position().map(|position| position.line()).unwrap_or(0),
+                message: err.to_string(),
+            })?;
+            if record.minor_units > MAX_DECIMAL_PLACES as u32 {
+                return Err(CurrencyError::InvalidConfig {
+                    line,
+                    message: format!(
+                        "{} has {} minor units, at most {MAX_DECIMAL_PLACES} are supported",
+                        record.code, record.minor_units
+                    ),
+                });
+            }
+            let currency = Currency::new(&record.code, record.minor_units)
+                .with_rounding(record.rounding.unwrap_or_default());
+            self.insert(currency);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_to_the_minor_units() {
+        let jpy = Currency::new("JPY", 0);
+        assert_eq!(jpy.round(1234.5), 1235.0);
+        assert_eq!(jpy.round(-1234.5), -1235.0);
+        let even = jpy.clone().with_rounding(Rounding::HalfEven);
+        assert_eq!((even.round(1234.5), even.round(1235.5)), (1234.0, 1236.0));
+        let down = Currency::new("BHD", 3).with_rounding(Rounding::Down);
+        assert_eq!(down.round(1.2349), 1.234);
+        assert_eq!(Currency::default().round(0.00005), 0.0001);
+    }
+
+    #[test]
+    fn knows_the_iso_minor_units() {
+        let currencies = Currencies::new();
+        assert_eq!(currencies.get("JPY").unwrap().minor_units, 0);
+        assert_eq!(currencies.get("bhd").unwrap().minor_units, 3);
+        assert_eq!(currencies.get("EUR").unwrap().minor_units, 2);
+        assert_eq!(
+            currencies.get("XYZ"),
+            Err(CurrencyError::Unknown {
+                code: "XYZ".to_string()
+            })
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn reads_and_overrides_currencies() {
+        let mut currencies = Currencies::new();
+        let csv = "code,minor_units,rounding\nxyz,1,down\nJPY,0,half-even\n";
+        assert!(currencies.read(csv.as_bytes()).is_ok());
+        assert_eq!(
+            currencies.get("XYZ").unwrap(),
+            &Currency::new("XYZ", 1).with_rounding(Rounding::Down)
+        );
+        assert_eq!(currencies.get("JPY").unwrap().rounding, Rounding::HalfEven);
+
+        let invalid = "code,minor_units\nBTC,8\n";
+        assert!(matches!(
+            currencies.read(invalid.as_bytes()),
+            Err(CurrencyError::InvalidConfig { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Write;
+
+#[cfg(feature = "csv")]
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "csv")]
+use crate::amount::ReportAmount;
+#[cfg(feature = "csv")]
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{ClientId, Timestamp, TransactionId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseStatus {
+    Open,
+    Resolved,
+    ChargedBack,
+}
+
+impl CaseStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CaseStatus::Open => "open",
+            CaseStatus::Resolved => "resolved",
+            CaseStatus::ChargedBack => "charged_back",
+        }
+    }
+}
+
+impl fmt::Display for CaseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// One dispute of a transaction from the dispute until it is resolved or
+// charged back. The reason is the description of the dispute, evidence are
+// the references of the rows of the case.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisputeCase {
+    pub tx_id: TransactionId,
+    pub client_id: ClientId,
+    pub amount: f64,
+    pub status: CaseStatus,
+    pub opened_at: Option<Timestamp>,
+    pub closed_at: Option<Timestamp>,
+    pub reason: Option<String>,
+    pub evidence: Vec<String>,
+}
+
+impl DisputeCase {
+    pub fn new(tx_id: TransactionId, client_id: ClientId, amount: f64) -> Self {
+        Self {
+            tx_id,
+            client_id,
+            amount,
+            status: CaseStatus::Open,
+            opened_at: None,
+            closed_at: None,
+            reason: None,
+            evidence: Vec::new(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.status == CaseStatus::Open
+    }
+
+    // Seconds the case is or was open, if both ends are known.
+    pub fn age(&self, now: Option<Timestamp>) -> Option<u64> {
+        let end = match self.status {
+            CaseStatus::Open => now,
+            _ => self.closed_at,
+        };
+        Some(end?.saturating_sub(self.opened_at?))
+    }
+}
+
+// The cases of a transaction at one point, to restore them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CasesMark {
+    len: usize,
+    open: Option<(usize, DisputeCase)>,
+}
+
+// Every dispute case in the order the disputes were opened. A transaction
+// that is disputed again after a resolve gets a new case.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DisputeCases {
+    cases: Vec<DisputeCase>,
+    // Index of the open case of a transaction.
+    open: HashMap<TransactionId, usize>,
+}
+
+impl DisputeCases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DisputeCase> {
+        self.cases.iter()
+    }
+
+    pub fn open_cases(&self) -> impl Iterator<Item = &DisputeCase> {
+        self.cases.iter().filter(|case| case.is_open())
+    }
+
+    pub fn len(&self) -> usize {
+        self.cases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cases.is_empty()
+    }
+
+    pub fn open_count(&self) -> usize {
+        self.open.len()
+    }
+
+    // The open case of the transaction.
+    pub fn open_case(&self, tx_id: TransactionId) -> Option<&DisputeCase> {
+        self.open.get(&tx_id).map(|index| &self.cases[*index])
+    }
+
+    // All cases of the transaction, oldest first.
+    pub fn history(&self, tx_id: TransactionId) -> impl Iterator<Item = &DisputeCase> {
+        self.cases.iter().filter(move |case| case.tx_id == tx_id)
+    }
+
+    pub(crate) fn open(&mut self, case: DisputeCase) {
+        self.open.insert(case.tx_id, self.cases.len());
+        self.cases.push(case);
+    }
+
+    // False if the transaction has no open case.
+    pub(crate) fn attach_evidence(&mut self, tx_id: TransactionId, evidence: String) -> bool {
+        match self.open.get(&tx_id) {
+            Some(index) => {
+                self.cases[*index].evidence.push(evidence);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn mark(&self, tx_id: TransactionId) -> CasesMark {
+        CasesMark {
+            len: self.cases.len(),
+            open: self
+                .open
+                .get(&tx_id)
+                .map(|index| (*index, self.cases[*index].clone())),
+        }
+    }
+
+    // Undoes the cases opened since the mark and the changes to the case of
+    // the transaction.
+    pub(crate) fn restore(&mut self, tx_id: TransactionId, mark: CasesMark) {
+        self.cases.truncate(mark.len);
+        self.open.remove(&tx_id);
+        if let Some((index, case)) = mark.open {
+            self.cases[index] = case;
+            self.open.insert(tx_id, index);
+        }
+    }
+
+    // Drops the cases of the client, which has none open.
+    pub(crate) fn erase_client(&mut self, client_id: ClientId) {
+        self.cases.retain(|case| case.client_id != client_id);
+        self.reindex();
+    }
+
+    // Drops the closed cases among the first `len`, the open ones stay.
+    // Returns the number of dropped cases.
+    pub(crate) fn compact(&mut self, len: usize) -> usize {
+        let before = self.cases.len();
+        let mut index = 0;
+        self.cases.retain(|case| {
+            index += 1;
+            index > len || case.is_open()
+        });
+        self.reindex();
+        before - self.cases.len()
+    }
+
+    fn reindex(&mut self) {
+        self.open = self
+            .cases
+            .iter()
+            .enumerate()
+            .filter(|(_, case)| case.is_open())
+            .map(|(index, case)| (case.tx_id, index))
+            .collect();
+    }
+
+    pub(crate) fn close(
+        &mut self,
+        tx_id: TransactionId,
+        status: CaseStatus,
+        closed_at: Option<Timestamp>,
+    ) {
+        if let Some(index) = self.open.remove(&tx_id) {
+            let case = &mut self.cases[index];
+            case.status = status;
+            case.closed_at = closed_at;
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+#[derive(Serialize)]
+struct CaseRecord<'a> {
+    tx: TransactionId,
+    client: String,
+    amount: ReportAmount,
+    status: CaseStatus,
+    opened_at: Option<Timestamp>,
+    closed_at: Option<Timestamp>,
+    age: Option<u64>,
+    reason: Option<&'a str>,
+    evidence: String,
+}
+
+// The age of open cases is measured up to `now`, evidence references are
+// separated by `;`.
+#[cfg(feature = "csv")]
+pub fn write_dispute_cases<'a, W: Write>(
+    writer: W,
+    cases: impl Iterator<Item = &'a DisputeCase>,
+    now: Option<Timestamp>,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    for case in cases {
+        writer.serialize(CaseRecord {
+            tx: case.tx_id,
+            client: pseudonymizer.client(case.client_id),
+            amount: ReportAmount::new(case.amount, places),
+            status: case.status,
+            opened_at: case.opened_at,
+            closed_at: case.closed_at,
+            age: case.age(now),
+            reason: case.reason.as_deref(),
+            evidence: case.evidence.join(";"),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(tx_id: TransactionId, opened_at: Timestamp) -> DisputeCase {
+        DisputeCase {
+            opened_at: Some(opened_at),
+            ..DisputeCase::new(tx_id, 1, 2.0)
+        }
+    }
+
+    #[test]
+    fn cases_are_tracked_until_closed() {
+        let mut cases = DisputeCases::new();
+        cases.open(case(1, 100));
+        cases.open(case(2, 150));
+        assert!(cases.attach_evidence(1, "receipt.pdf".to_string()));
+        cases.close(1, CaseStatus::Resolved, Some(300));
+        assert!(!cases.attach_evidence(1, "late.pdf".to_string()));
+        cases.open(case(1, 400));
+
+        let open: Vec<_> = cases.open_cases().map(|case| case.tx_id).collect();
+        assert_eq!(open, vec![2, 1]);
+        assert_eq!(cases.open_case(2).unwrap().age(Some(1000)), Some(850));
+
+        let history: Vec<_> = cases.history(1).collect();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].status, CaseStatus::Resolved);
+        assert_eq!(history[0].age(Some(1000)), Some(200));
+        assert_eq!(history[0].evidence, vec!["receipt.pdf".to_string()]);
+        assert!(history[1].is_open());
+    }
+
+    #[test]
+    fn restores_the_cases_of_a_mark() {
+        let mut cases = DisputeCases::new();
+        cases.open(case(1, 100));
+        let mark = cases.mark(1);
+        cases.attach_evidence(1, "receipt.pdf".to_string());
+        cases.close(1, CaseStatus::Resolved, Some(300));
+        cases.open(case(1, 400));
+
+        cases.restore(1, mark);
+        let history: Vec<_> = cases.history(1).collect();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].is_open());
+        assert!(history[0].evidence.is_empty());
+        assert_eq!(cases.open_case(1).unwrap().opened_at, Some(100));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn writes_cases_with_their_age() {
+        let mut closed = case(1, 100);
+        closed.status = CaseStatus::ChargedBack;
+        closed.closed_at = Some(200);
+        closed.reason = Some("Card fraud".to_string());
+        closed.evidence = vec!["CASE-7".to_string(), "chat.txt".to_string()];
+        let cases = [closed, DisputeCase::new(2, 3, 1.5)];
+
+        let mut output = Vec::new();
+        write_dispute_cases(
+            &mut output,
+            cases.iter(),
+            Some(500),
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,client,amount,status,opened_at,closed_at,age,reason,evidence\n\
+             1,1,2.0000,charged_back,100,200,100,Card fraud,CASE-7;chat.txt\n\
+             2,3,1.5000,open,,,,,\n"
+        );
+    }
+}
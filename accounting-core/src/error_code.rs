@@ -0,0 +1,103 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+
+// Stable codes of the errors, for systems that branch on them instead of
+// parsing messages. Codes are never reused or renumbered: E10xx are account
+// rejections, E11xx transaction rejections, E12xx unbalanced books, E2xxx
+// invalid input, E3xxx invalid configuration and E4xxx persisted state that
+// can't be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    InsufficientFunds = 1001,
+    AccountLocked = 1002,
+    BalanceOverflow = 1003,
+
+    Unauthorized = 1101,
+    Undisputed = 1102,
+    AlreadyDisputed = 1103,
+    TransactionNotFound = 1104,
+    InvalidAmount = 1105,
+    InvalidPrecision = 1106,
+    Duplicate = 1107,
+    LimitExceeded = 1108,
+    NotPermitted = 1109,
+    AccountExists = 1110,
+    EscrowNotFound = 1111,
+    DuplicateEscrow = 1112,
+    MissingCounterparty = 1113,
+    Backdated = 1114,
+    MissingReason = 1115,
+    AlreadyProcessed = 1116,
+    RollbackUnavailable = 1117,
+    AlreadyApplied = 1118,
+    RuleRejected = 1119,
+    ScriptRejected = 1120,
+    ScriptFailed = 1121,
+    NotSettled = 1122,
+    AdminActionRefused = 1123,
+    RateLimited = 1124,
+    InvalidApiKey = 1125,
+    Forbidden = 1126,
+    ReservedId = 1127,
+
+    InvariantViolation = 1201,
+
+    InvalidCsv = 2001,
+    InvalidRow = 2002,
+    InvalidHeader = 2003,
+    Io = 2004,
+
+    InvalidGeneratorConfig = 3001,
+    InvalidTiers = 3002,
+    InvalidRoles = 3003,
+    InvalidSchedule = 3004,
+    MissingSigningKey = 3005,
+    MissingPseudonymKey = 3006,
+    InvalidKey = 3007,
+    InvalidPolicy = 3008,
+    InvalidScript = 3009,
+    InvalidCurrency = 3010,
+    UnsupportedOption = 3011,
+
+    InvalidCheckpoint = 4001,
+    InvalidAuditLog = 4002,
+    AuditLogTampered = 4003,
+    SignatureMismatch = 4004,
+    InvalidReplay = 4005,
+}
+
+impl ErrorCode {
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+}
+
+// E and the number, e.g. E1001.
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{}", self.number())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_as_e_and_the_number() {
+        assert_eq!(ErrorCode::InsufficientFunds.to_string(), "E1001");
+        assert_eq!(ErrorCode::SignatureMismatch.number(), 4004);
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::InvalidRow).unwrap(),
+            r#""E2002""#
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::account_manager::AccountManagerResult;
+use crate::store::ShardedStore;
+use crate::types::{client_id_u64, Action, ClientId, Transaction, TransactionId};
+
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+// The outcome of a transaction, by its position in the input from 1 on.
+#[derive(Debug, PartialEq)]
+pub struct Executed {
+    pub sequence: u64,
+    pub client_id: ClientId,
+    pub id: TransactionId,
+    pub result: AccountManagerResult<()>,
+}
+
+// What transactions of different clients may share. A transaction waits for
+// the previous one with the same transaction id or idempotency key, so
+// dispute chains and duplicates are decided in input order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Resource {
+    Transaction(TransactionId),
+    IdempotencyKey(String),
+}
+
+// The lane and sequence of the last transaction that used every resource.
+// Entries of completed transactions are dropped, nothing waits for them.
+#[derive(Default)]
+struct Claims {
+    last: HashMap<Resource, (usize, u64)>,
+}
+
+impl Claims {
+    // The transaction of another lane `sequence` has to wait for.
+    fn claim(&mut self, resource: Resource, lane: usize, sequence: u64) -> Option<(usize, u64)> {
+        self.last
+            .insert(resource, (lane, sequence))
+            .filter(|(other, _)| *other != lane)
+    }
+
+    fn prune(&mut self, completed: &[u64]) {
+        self.last
+            .retain(|_, (lane, sequence)| *sequence > completed[*lane]);
+    }
+
+    fn clear(&mut self) {
+        self.last.clear();
+    }
+}
+
+struct Job {
+    sequence: u64,
+    tx: Transaction,
+    // Lanes and the sequence each must have completed first.
+    waits: Vec<(usize, u64)>,
+}
+
+// Escrows move funds to a counterparty, period closes touch every client and
+// erasures release the transaction ids of a client, they run alone once all
+// transactions before them are done.
+fn exclusive(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::HoldInEscrow
+            | Action::ReleaseEscrow
+            | Action::RefundEscrow
+            | Action::ClosePeriod
+            | Action::EraseClient
+    )
+}
+
+// The last sequence every lane completed. Lanes complete in input order.
+struct Progress {
+    completed: Mutex<Vec<u64>>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn new(lanes: usize) -> Self {
+        Self {
+            completed: Mutex::new(vec![0; lanes]),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn complete(&self, lane: usize, sequence: u64) {
+        self.completed.lock().expect("progress poisoned")[lane] = sequence;
+        self.changed.notify_all();
+    }
+
+    fn wait(&self, waits: &[(usize, u64)]) {
+        let mut completed = self.completed.lock().expect("progress poisoned");
+        while waits
+            .iter()
+            .any(|(lane, sequence)| completed[*lane] < *sequence)
+        {
+            completed = self.changed.wait(completed).expect("progress poisoned");
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u64> {
+        self.completed.lock().expect("progress poisoned").clone()
+    }
+}
+
+// Applies an ordered stream of transactions to a ShardedStore with a pool of
+// workers. Every client belongs to one lane, a queue with one worker, so the
+// transactions of a client are applied in input order while other lanes run
+// in parallel. The results are those of applying the stream one transaction
+// after another, as long as the policy has no cache ttl, whose clock counts
+// the transactions of all clients.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParallelExecutor {
+    workers: usize,
+    queue_depth: usize,
+}
+
+impl ParallelExecutor {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+        }
+    }
+
+    // Transactions queued per lane before the input waits for the workers.
+    pub fn with_queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth.max(1);
+        self
+    }
+
+    fn lane(&self, client_id: ClientId) -> usize {
+        (client_id_u64(client_id) % self.workers as u64) as usize
+    }
+
+    // Hands the outcomes to `report` in input order and returns the number of
+    // transactions. Stops at the first error of the input or of `report`;
+    // queued transactions after it are skipped.
+    pub fn run<E>(
+        &self,
+        store: &ShardedStore,
+        txs: impl IntoIterator<Item = Result<Transaction, E>>,
+        mut report: impl FnMut(Executed) -> Result<(), E>,
+    ) -> Result<u64, E> {
+        let progress = Progress::new(self.workers);
+        let stop = AtomicBool::new(false);
+        let (executed_sender, executed) = channel();
+        thread::scope(|scope| {
+            let lanes: Vec<SyncSender<Job>> = (0..self.workers)
+                .map(|lane| {
+                    let (sender, jobs) = sync_channel(self.queue_depth);
+                    let executed = executed_sender.clone();
+                    let (progress, stop) = (&progress, &stop);
+                    scope.spawn(move || work(lane, jobs, store, progress, stop, executed));
+                    sender
+                })
+                .collect();
+            let mut ordered = Ordered::new();
+            let result = self.dispatch(store, txs, &lanes, &progress, &executed, |done| {
+                ordered.report(done, &mut report)
+            });
+            stop.store(result.is_err(), Ordering::Relaxed);
+            drop(lanes);
+            drop(executed_sender);
+            let sequence = result?;
+            for done in executed {
+                ordered.report(done, &mut report)?;
+            }
+            Ok(sequence)
+        })
+    }
+
+    fn dispatch<E>(
+        &self,
+        store: &ShardedStore,
+        txs: impl IntoIterator<Item = Result<Transaction, E>>,
+        lanes: &[SyncSender<Job>],
+        progress: &Progress,
+        executed: &Receiver<Executed>,
+        mut report: impl FnMut(Executed) -> Result<(), E>,
+    ) -> Result<u64, E> {
+        let mut claims = Claims::default();
+        let mut dispatched = vec![0; lanes.len()];
+        let mut sequence = 0;
+        for tx in txs {
+            let tx = tx?;
+            sequence += 1;
+            if exclusive(&tx.action) {
+                progress.wait(&dispatched.iter().copied().enumerate().collect::<Vec<_>>());
+                claims.clear();
+                // Everything before it is done, so are their outcomes.
+                for done in executed.try_iter() {
+                    report(done)?;
+                }
+                report(Executed {
+                    sequence,
+                    client_id: tx.client_id,
+                    id: tx.id,
+                    result: store.process_transaction(tx),
+                })?;
+                continue;
+            }
+
+            let lane = self.lane(tx.client_id);
+            let resources = std::iter::once(Resource::Transaction(tx.id))
+                .chain(tx.idempotency_key.clone().map(Resource::IdempotencyKey));
+            let waits = resources
+                .filter_map(|resource| claims.claim(resource, lane, sequence))
+                .collect();
+            dispatched[lane] = sequence;
+            let job = Job {
+                sequence,
+                tx,
+                waits,
+            };
+            if lanes[lane].send(job).is_err() {
+                break;
+            }
+            for done in executed.try_iter() {
+                report(done)?;
+            }
+            // Once per wave of queued transactions.
+            if sequence % self.queue_depth as u64 == 0 {
+                claims.prune(&progress.snapshot());
+            }
+        }
+        Ok(sequence)
+    }
+}
+
+fn work(
+    lane: usize,
+    jobs: Receiver<Job>,
+    store: &ShardedStore,
+    progress: &Progress,
+    stop: &AtomicBool,
+    executed: Sender<Executed>,
+) {
+    for job in jobs {
+        if !stop.load(Ordering::Relaxed) {
+            progress.wait(&job.waits);
+            let (client_id, id) = (job.tx.client_id, job.tx.id);
+            let result = store.process_transaction(job.tx);
+            let _ = executed.send(Executed {
+                sequence: job.sequence,
+                client_id,
+                id,
+                result,
+            });
+        }
+        // Skipped jobs complete too, so no other lane waits for them forever.
+        progress.complete(lane, job.sequence);
+    }
+}
+
+// Brings the outcomes of the lanes back into input order.
+struct Ordered {
+    next: u64,
+    pending: BTreeMap<u64, Executed>,
+}
+
+impl Ordered {
+    fn new() -> Self {
+        Self {
+            next: 1,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn report<E>(
+        &mut self,
+        done: Executed,
+        report: &mut impl FnMut(Executed) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.pending.insert(done.sequence, done);
+        while let Some(done) = self.pending.remove(&self.next) {
+            self.next += 1;
+            report(done)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+    use crate::account::Account;
+    use crate::account_manager::{process_transaction, AccountManager};
+
+    fn tx(
+        action: Action,
+        client_id: ClientId,
+        id: TransactionId,
+        amount: Option<f64>,
+    ) -> Transaction {
+        Transaction::new(action, client_id, id, amount)
+    }
+
+    // Clients share transaction ids and idempotency keys now and then, and
+    // dispute each other's transactions.
+    fn workload() -> Vec<Transaction> {
+        let mut txs = Vec::new();
+        for id in 1..=3000u32 {
+            let client_id = (id * 7 % 23) as ClientId;
+            let shared = id % 10 + 1;
+            let mut next = match id % 9 {
+                0 => tx(Action::Withdrawal, client_id, id, Some(f64::from(id % 50))),
+                1 => tx(Action::Dispute, client_id, id.saturating_sub(5), None),
+                2 => tx(Action::Dispute, client_id, shared, None),
+                3 => tx(Action::Resolve, client_id, id.saturating_sub(11), None),
+                4 => tx(Action::Chargeback, client_id, id.saturating_sub(13), None),
+                5 => tx(Action::Deposit, client_id, shared, Some(2.5)),
+                _ => tx(
+                    Action::Deposit,
+                    client_id,
+                    id,
+                    Some(f64::from(id % 40) + 0.25),
+                ),
+            };
+            if id % 17 == 0 {
+                next.idempotency_key = Some(format!("key-{}", id % 5));
+            }
+            txs.push(next);
+        }
+        txs.push(tx(Action::ClosePeriod, 1, 0, None));
+        txs.push(tx(Action::Deposit, 3, 4000, Some(1.0)));
+        txs
+    }
+
+    #[test]
+    fn results_are_those_of_a_sequential_run() {
+        let txs = workload();
+        let mut account_manager = AccountManager::new();
+        let sequential: Vec<_> = txs
+            .iter()
+            .map(|tx| process_transaction(&mut account_manager, tx.clone()))
+            .collect();
+        let balances = |mut accounts: Vec<(ClientId, Account)>| {
+            accounts.sort_by_key(|(client_id, _)| *client_id);
+            accounts
+                .iter()
+                .map(|(client_id, account)| {
+                    let (available, held) = (account.available(), account.held());
+                    (*client_id, available, held, account.locked())
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = balances(account_manager.accounts());
+        let rejected = |kind| {
+            sequential
+                .iter()
+                .any(|result| result.as_ref().is_err_and(|err| err.kind() == kind))
+        };
+        assert!(rejected("unauthorized") && rejected("duplicate"));
+
+        for workers in [1, 4] {
+            let store = ShardedStore::new(8);
+            let executor = ParallelExecutor::new(workers).with_queue_depth(16);
+            let mut results = Vec::new();
+            let count = executor
+                .run(&store, txs.iter().cloned().map(Ok), |done| {
+                    assert_eq!(done.sequence, results.len() as u64 + 1);
+                    results.push(done.result);
+                    Ok::<_, Infallible>(())
+                })
+                .unwrap();
+            assert_eq!(count, txs.len() as u64);
+            assert_eq!(results, sequential);
+            assert_eq!(balances(store.accounts()), expected);
+        }
+    }
+
+    #[test]
+    fn claims_of_completed_transactions_are_dropped() {
+        let mut claims = Claims::default();
+        assert_eq!(claims.claim(Resource::Transaction(1), 0, 1), None);
+        assert_eq!(claims.claim(Resource::Transaction(2), 1, 2), None);
+        assert_eq!(claims.claim(Resource::Transaction(1), 1, 3), Some((0, 1)));
+        assert_eq!(claims.claim(Resource::Transaction(1), 1, 4), None);
+
+        claims.prune(&[1, 2]);
+        assert_eq!(claims.last.len(), 1);
+        claims.prune(&[1, 4]);
+        assert!(claims.last.is_empty());
+        claims.claim(Resource::IdempotencyKey("a".to_string()), 0, 5);
+        claims.clear();
+        assert!(claims.last.is_empty());
+    }
+
+    #[test]
+    fn stops_at_the_first_error() {
+        let store = ShardedStore::new(4);
+        let txs = (1..=100).map(|id| match id {
+            50 => Err("invalid row"),
+            id => Ok(tx(Action::Deposit, (id % 5) as ClientId, id, Some(1.0))),
+        });
+        let mut reported = 0;
+        let result = ParallelExecutor::new(3).run(&store, txs, |_| {
+            reported += 1;
+            Ok(())
+        });
+        assert_eq!(result, Err("invalid row"));
+        assert!(reported < 50);
+
+        let result = ParallelExecutor::new(3).run(
+            &store,
+            (101..=200).map(|id| Ok(tx(Action::Deposit, 1, id, Some(1.0)))),
+            |done| match done.sequence {
+                10 => Err("sink failed"),
+                _ => Ok(()),
+            },
+        );
+        assert_eq!(result, Err("sink failed"));
+    }
+}
@@ -0,0 +1,171 @@
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+const MAX_AMOUNT_MINOR_UNITS: u32 = 1_000_000;
+const MINOR_UNITS_PER_UNIT: f64 = 10_000.0;
+const MAX_SEQUENCE_CLIENTS: ClientId = 8;
+const MAX_SEQUENCE_LEN: usize = 256;
+
+fn arbitrary_amount(u: &mut Unstructured<'_>) -> Result<f64> {
+    Ok(f64::from(u.int_in_range(1..=MAX_AMOUNT_MINOR_UNITS)?) / MINOR_UNITS_PER_UNIT)
+}
+
+impl<'a> Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let action = Action::arbitrary(u)?;
+        let amount = match action {
+            Action::Deposit | Action::Withdrawal | Action::HoldInEscrow | Action::Adjustment => {
+                Some(arbitrary_amount(u)?)
+            }
+            Action::Dispute
+            | Action::Resolve
+            | Action::Chargeback
+            | Action::OpenAccount
+            | Action::ReleaseEscrow
+            | Action::RefundEscrow
+            | Action::ClosePeriod
+            | Action::EraseClient
+            | Action::Freeze
+            | Action::Unfreeze
+            | Action::Balance => None,
+        };
+        let counterparty = match action {
+            Action::HoldInEscrow => Some(u.arbitrary()?),
+            _ => None,
+        };
+        let description = match action {
+            Action::Adjustment | Action::EraseClient => Some(u.arbitrary()?),
+            _ => None,
+        };
+        Ok(Transaction {
+            counterparty,
+            description,
+            ..Transaction::new(action, u.arbitrary()?, u.arbitrary()?, amount)
+        })
+    }
+}
+
+// A sequence of transactions over a handful of clients in which disputes only
+// reference earlier deposits of the same client and resolves/chargebacks only
+// reference open disputes. Independently generated `Transaction`s almost never
+// hit an existing tx id, so this is what property tests of the dispute logic want.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionSequence(pub Vec<Transaction>);
+
+impl<'a> Arbitrary<'a> for TransactionSequence {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let clients = u.int_in_range(1..=MAX_SEQUENCE_CLIENTS)?;
+        let len = u.int_in_range(0..=MAX_SEQUENCE_LEN)?;
+
+        let mut txs = Vec::with_capacity(len);
+        let mut deposits: Vec<(TransactionId, ClientId)> = Vec::new();
+        let mut open_disputes: Vec<(TransactionId, ClientId)> = Vec::new();
+        let mut next_id: TransactionId = 1;
+
+        for _ in 0..len {
+            if u.is_empty() {
+                break;
+            }
+            let tx = match u.int_in_range(0..=4u8)? {
+                0 if !deposits.is_empty() => {
+                    let (id, client_id) = deposits.swap_remove(u.choose_index(deposits.len())?);
+                    open_disputes.push((id, client_id));
+                    Transaction::new(Action::Dispute, client_id, id, None)
+                }
+                1 if !open_disputes.is_empty() => {
+                    let index = u.choose_index(open_disputes.len())?;
+                    let (id, client_id) = open_disputes.swap_remove(index);
+                    if u.arbitrary()? {
+                        deposits.push((id, client_id));
+                        Transaction::new(Action::Resolve, client_id, id, None)
+                    } else {
+                        Transaction::new(Action::Chargeback, client_id, id, None)
+                    }
+                }
+                2 => {
+                    let client_id = u.int_in_range(1..=clients)?;
+                    let amount = arbitrary_amount(u)?;
+                    next_id += 1;
+                    Transaction::new(Action::Withdrawal, client_id, next_id, Some(amount))
+                }
+                _ => {
+                    let client_id = u.int_in_range(1..=clients)?;
+                    let amount = arbitrary_amount(u)?;
+                    next_id += 1;
+                    deposits.push((next_id, client_id));
+                    Transaction::new(Action::Deposit, client_id, next_id, Some(amount))
+                }
+            };
+            txs.push(tx);
+        }
+
+        Ok(Self(txs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{RngCore, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::account_manager::{process_transaction, AccountManager};
+
+    const TOLERANCE: f64 = 1e-9;
+
+    fn sequences() -> impl Iterator<Item = TransactionSequence> {
+        (0..200).map(|seed| {
+            let mut bytes = vec![0u8; 4096];
+            ChaCha8Rng::seed_from_u64(seed).fill_bytes(&mut bytes);
+            TransactionSequence::arbitrary(&mut Unstructured::new(&bytes)).unwrap()
+        })
+    }
+
+    #[test]
+    fn sequences_only_reference_known_transactions() {
+        for TransactionSequence(txs) in sequences() {
+            let mut owners: HashMap<TransactionId, ClientId> = HashMap::new();
+            for tx in txs {
+                match tx.action {
+                    Action::Deposit => {
+                        owners.insert(tx.id, tx.client_id);
+                    }
+                    Action::Withdrawal
+                    | Action::OpenAccount
+                    | Action::HoldInEscrow
+                    | Action::ReleaseEscrow
+                    | Action::RefundEscrow
+                    | Action::ClosePeriod
+                    | Action::Adjustment
+                    | Action::EraseClient
+                    | Action::Freeze
+                    | Action::Unfreeze
+                    | Action::Balance => {}
+                    Action::Dispute | Action::Resolve | Action::Chargeback => {
+                        assert_eq!(owners.get(&tx.id), Some(&tx.client_id));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn engine_keeps_balances_consistent() {
+        for TransactionSequence(txs) in sequences() {
+            let mut account_manager = AccountManager::new();
+            for tx in txs {
+                let _ = process_transaction(&mut account_manager, tx);
+            }
+
+            for (_, account) in account_manager.accounts() {
+                assert!(account.available() > -TOLERANCE);
+                assert!(account.disputed() > -TOLERANCE);
+                let sum = account.available() + account.held();
+                assert!((account.total() - sum).abs() < TOLERANCE);
+            }
+        }
+    }
+}
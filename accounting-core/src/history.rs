@@ -0,0 +1,332 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "csv")]
+use std::io::Write;
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+#[cfg(feature = "csv")]
+use crate::amount::ReportAmount;
+#[cfg(feature = "csv")]
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{Action, ClientId, Timestamp, Transaction, TransactionId};
+
+// The balance of an account after an applied transaction. The sequence counts
+// the applied transactions of all clients, so points of different accounts can
+// be ordered.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalancePoint {
+    pub sequence: u64,
+    pub timestamp: Option<Timestamp>,
+    pub tx_id: TransactionId,
+    // None in histories of checkpoints from before they were recorded.
+    #[serde(default)]
+    pub action: Option<Action>,
+    #[serde(default)]
+    pub amount: Option<f64>,
+    pub available: f64,
+    pub held: f64,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub receivable: f64,
+    // Transactions of the client applied so far.
+    #[serde(default)]
+    pub transactions: u64,
+}
+
+impl BalancePoint {
+    pub fn total(&self) -> f64 {
+        self.available + self.held
+    }
+}
+
+// A point of the history: after the applied transaction with a sequence, or
+// after the last applied transaction with a timestamp not later than the one
+// given. Parsed from `seq:N` or `ts:T`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    Sequence(u64),
+    Timestamp(Timestamp),
+}
+
+impl FromStr for AsOf {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid point '{s}', expected seq:N or ts:T");
+        match s.split_once(':').ok_or_else(invalid)? {
+            ("seq", sequence) => sequence.parse().map(Self::Sequence),
+            ("ts", timestamp) => timestamp.parse().map(Self::Timestamp),
+            _ => return Err(invalid()),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+// Time series of the balances of every account, one point per applied
+// transaction that touched it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredHistory")]
+pub struct BalanceHistory {
+    sequence: u64,
+    clients: BTreeMap<ClientId, Vec<BalancePoint>>,
+    // Points of all clients, counted again when a checkpoint is loaded.
+    #[serde(skip_serializing)]
+    len: usize,
+}
+
+#[derive(Deserialize)]
+struct StoredHistory {
+    sequence: u64,
+    clients: BTreeMap<ClientId, Vec<BalancePoint>>,
+}
+
+impl From<StoredHistory> for BalanceHistory {
+    fn from(stored: StoredHistory) -> Self {
+        Self {
+            sequence: stored.sequence,
+            len: stored.clients.values().map(Vec::len).sum(),
+            clients: stored.clients,
+        }
+    }
+}
+
+impl BalanceHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Sequence of the last applied transaction.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn points(&self, client_id: ClientId) -> &[BalancePoint] {
+        self.clients.get(&client_id).map_or(&[], Vec::as_slice)
+    }
+
+    // The number of points of all clients.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The balance of the account at the point, none if no transaction touched
+    // it up to then. Points without a timestamp only count for a timestamp if
+    // a later point of the account has one not after it.
+    pub fn as_of(&self, client_id: ClientId, point: AsOf) -> Option<&BalancePoint> {
+        let points = self.points(client_id);
+        match point {
+            AsOf::Sequence(sequence) => points.iter().take_while(|p| p.sequence <= sequence).last(),
+            AsOf::Timestamp(timestamp) => points
+                .iter()
+                .rfind(|p| p.timestamp.is_some_and(|t| t <= timestamp)),
+        }
+    }
+
+    // Points ordered by client and sequence.
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &BalancePoint)> {
+        self.clients
+            .iter()
+            .flat_map(|(client_id, points)| points.iter().map(|point| (*client_id, point)))
+    }
+
+    pub(crate) fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    // Drops the points recorded after `sequence` for the clients.
+    pub(crate) fn rewind(&mut self, sequence: u64, clients: impl Iterator<Item = ClientId>) {
+        for client_id in clients {
+            let Some(points) = self.clients.get_mut(&client_id) else {
+                continue;
+            };
+            while points.last().is_some_and(|point| point.sequence > sequence) {
+                points.pop();
+                self.len -= 1;
+            }
+            if points.is_empty() {
+                self.clients.remove(&client_id);
+            }
+        }
+        self.sequence = sequence;
+    }
+
+    // Drops the points up to `sequence` but the last one of every client, so
+    // the balances as of later points stay. Returns the number of dropped
+    // points.
+    pub(crate) fn compact(&mut self, sequence: u64) -> usize {
+        let mut dropped = 0;
+        for points in self.clients.values_mut() {
+            let end = points.partition_point(|point| point.sequence <= sequence);
+            let drained = end.saturating_sub(1);
+            points.drain(..drained);
+            dropped += drained;
+        }
+        self.len -= dropped;
+        dropped
+    }
+
+    pub(crate) fn erase_client(&mut self, client_id: ClientId) {
+        if let Some(points) = self.clients.remove(&client_id) {
+            self.len -= points.len();
+        }
+    }
+
+    pub(crate) fn record(&mut self, client_id: ClientId, account: &Account, tx: &Transaction) {
+        self.len += 1;
+        self.clients
+            .entry(client_id)
+            .or_default()
+            .push(BalancePoint {
+                sequence: self.sequence,
+                timestamp: tx.timestamp,
+                tx_id: tx.id,
+                action: Some(tx.action.clone()),
+                amount: tx.amount,
+                available: account.available(),
+                held: account.held(),
+                locked: account.locked(),
+                receivable: account.receivable(),
+                transactions: account.transactions(),
+            });
+    }
+}
+
+#[cfg(feature = "csv")]
+#[derive(Serialize)]
+struct BalancePointRecord {
+    client: String,
+    sequence: u64,
+    timestamp: Option<Timestamp>,
+    tx: TransactionId,
+    available: ReportAmount,
+    held: ReportAmount,
+    total: ReportAmount,
+}
+
+// The points of all clients, or only of `client_id`.
+#[cfg(feature = "csv")]
+pub fn write_history<W: Write>(
+    writer: W,
+    history: &BalanceHistory,
+    client_id: Option<ClientId>,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    let points = history
+        .iter()
+        .filter(|(client, _)| client_id.is_none_or(|client_id| client_id == *client));
+    for (client, point) in points {
+        writer.serialize(BalancePointRecord {
+            client: pseudonymizer.client(client),
+            sequence: point.sequence,
+            timestamp: point.timestamp,
+            tx: point.tx_id,
+            available: ReportAmount::new(point.available, places),
+            held: ReportAmount::new(point.held, places),
+            total: ReportAmount::new(point.total(), places),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> BalanceHistory {
+        let at = |action, client_id, id, amount, timestamp| Transaction {
+            timestamp,
+            ..Transaction::new(action, client_id, id, amount)
+        };
+        let mut history = BalanceHistory::new();
+        let mut account = Account::new();
+        account.deposit(2.0).unwrap();
+        history.next_sequence();
+        history.record(
+            2,
+            &account,
+            &at(Action::Deposit, 2, 1, Some(2.0), Some(100)),
+        );
+        account.dispute(0.5).unwrap();
+        history.next_sequence();
+        history.record(2, &account, &at(Action::Dispute, 2, 1, None, Some(200)));
+        history.next_sequence();
+        history.record(
+            1,
+            &Account::new(),
+            &at(Action::Deposit, 1, 2, Some(0.0), None),
+        );
+        history
+    }
+
+    #[test]
+    fn records_a_point_per_applied_transaction() {
+        let history = history();
+        assert_eq!(history.sequence(), 3);
+        let points = history.points(2);
+        assert_eq!(points.len(), 2);
+        assert_eq!((points[1].available, points[1].held), (1.5, 0.5));
+        assert_eq!(points[1].total(), 2.0);
+        assert_eq!(points[1].action, Some(Action::Dispute));
+        assert_eq!(points[0].amount, Some(2.0));
+        assert!(history.points(3).is_empty());
+    }
+
+    #[test]
+    fn counts_the_points_of_all_clients() {
+        let mut history = history();
+        assert_eq!(history.len(), 3);
+        let json = serde_json::to_string(&history).unwrap();
+        let restored: BalanceHistory = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, history);
+
+        history.rewind(2, [1].into_iter());
+        assert_eq!(history.len(), 2);
+        history.compact(2);
+        assert_eq!(history.len(), 1);
+        history.erase_client(2);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn finds_the_balance_as_of_a_sequence_or_timestamp() {
+        let history = history();
+        let held = |point: Option<&BalancePoint>| point.map(|point| point.held);
+        assert_eq!(held(history.as_of(2, AsOf::Sequence(1))), Some(0.0));
+        assert_eq!(held(history.as_of(2, AsOf::Sequence(5))), Some(0.5));
+        assert_eq!(held(history.as_of(2, AsOf::Timestamp(199))), Some(0.0));
+        assert_eq!(held(history.as_of(2, AsOf::Timestamp(99))), None);
+        assert_eq!(held(history.as_of(1, AsOf::Sequence(2))), None);
+        assert_eq!(held(history.as_of(1, AsOf::Timestamp(500))), None);
+
+        assert_eq!("seq:10".parse(), Ok(AsOf::Sequence(10)));
+        assert_eq!("ts:1700000000".parse(), Ok(AsOf::Timestamp(1_700_000_000)));
+        assert!("10".parse::<AsOf>().is_err());
+        assert!("seq:x".parse::<AsOf>().is_err());
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn writes_the_points_of_one_client() {
+        let mut output = Vec::new();
+        write_history(&mut output, &history(), Some(2), 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,sequence,timestamp,tx,available,held,total\n\
+             2,1,100,1,2.0000,0.0000,2.0000\n\
+             2,2,200,1,1.5000,0.5000,2.0000\n"
+        );
+    }
+}
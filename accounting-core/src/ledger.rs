@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Write;
+
+#[cfg(feature = "csv")]
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+// Cash is an asset, customer balances are liabilities of the business. A
+// chargeback is first booked as a loss and then recovered from the held funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum LedgerAccount {
+    Cash,
+    CustomerAvailable(ClientId),
+    CustomerHeld(ClientId),
+    CustomerSuspense(ClientId),
+    CustomerEscrow(ClientId),
+    // Fees the client owes, an asset of the business.
+    CustomerReceivable(ClientId),
+    ChargebackLoss,
+    FeeIncome,
+    // Counterpart of manual adjustments of customer balances.
+    Adjustments,
+    // The accounts of erased clients, all settled, so it nets to zero.
+    ErasedCustomer,
+}
+
+impl LedgerAccount {
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self {
+            LedgerAccount::CustomerAvailable(client_id)
+            | LedgerAccount::CustomerHeld(client_id)
+            | LedgerAccount::CustomerSuspense(client_id)
+            | LedgerAccount::CustomerEscrow(client_id)
+            | LedgerAccount::CustomerReceivable(client_id) => Some(*client_id),
+            LedgerAccount::Cash
+            | LedgerAccount::ChargebackLoss
+            | LedgerAccount::FeeIncome
+            | LedgerAccount::Adjustments
+            | LedgerAccount::ErasedCustomer => None,
+        }
+    }
+
+    pub fn label(&self, pseudonymizer: &Pseudonymizer) -> String {
+        match self {
+            LedgerAccount::Cash => "cash".to_string(),
+            LedgerAccount::CustomerAvailable(client_id) => {
+                format!("customer:{}:available", pseudonymizer.client(*client_id))
+            }
+            LedgerAccount::CustomerHeld(client_id) => {
+                format!("customer:{}:held", pseudonymizer.client(*client_id))
+            }
+            LedgerAccount::CustomerSuspense(client_id) => {
+                format!("customer:{}:suspense", pseudonymizer.client(*client_id))
+            }
+            LedgerAccount::CustomerEscrow(client_id) => {
+                format!("customer:{}:escrow", pseudonymizer.client(*client_id))
+            }
+            LedgerAccount::CustomerReceivable(client_id) => {
+                format!("customer:{}:receivable", pseudonymizer.client(*client_id))
+            }
+            LedgerAccount::ChargebackLoss => "chargeback_loss".to_string(),
+            LedgerAccount::FeeIncome => "fee_income".to_string(),
+            LedgerAccount::Adjustments => "adjustments".to_string(),
+            LedgerAccount::ErasedCustomer => "customer:erased".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.label(&Pseudonymizer::new()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub sequence: u64,
+    pub tx_id: TransactionId,
+    pub action: Action,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: f64,
+    // Metadata of the transaction that posted the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+}
+
+#[cfg(feature = "csv")]
+#[derive(Serialize)]
+struct JournalRecord<'a> {
+    sequence: u64,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    action: &'a Action,
+    debit: String,
+    credit: String,
+    amount: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    entries: Vec<JournalEntry>,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn post(
+        &mut self,
+        tx_id: TransactionId,
+        action: Action,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        amount: f64,
+    ) {
+        self.entries.push(JournalEntry {
+            sequence: self.sequence() + 1,
+            tx_id,
+            action,
+            debit,
+            credit,
+            amount,
+            description: None,
+            reference: None,
+            category: None,
+        });
+    }
+
+    // Attaches transaction metadata to the entries posted since `start`.
+    pub fn annotate(&mut self, start: usize, tx: &Transaction) {
+        for entry in self.entries.iter_mut().skip(start) {
+            entry.description.clone_from(&tx.description);
+            entry.reference.clone_from(&tx.reference);
+            entry.category.clone_from(&tx.category);
+        }
+    }
+
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    // The sequence of the last posted entry.
+    pub fn sequence(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| entry.sequence)
+    }
+
+    // Replaces the entries up to `sequence` by one entry per account that
+    // carries its balance against cash, with sequence 0, so the balances stay
+    // the same. Returns the number of dropped entries, carried ones of earlier
+    // compactions aren't counted.
+    pub(crate) fn compact(&mut self, sequence: u64) -> usize {
+        let end = self
+            .entries
+            .partition_point(|entry| entry.sequence <= sequence);
+        let compacted = &self.entries[..end];
+        let dropped = compacted.iter().filter(|entry| entry.sequence > 0).count();
+        let mut carried: Vec<_> = balances(compacted)
+            .into_iter()
+            .filter(|(account, balance)| *account != LedgerAccount::Cash && *balance != 0.0)
+            .map(|(account, balance)| {
+                let (debit, credit) = match balance > 0.0 {
+                    true => (account, LedgerAccount::Cash),
+                    false => (LedgerAccount::Cash, account),
+                };
+                JournalEntry {
+                    sequence: 0,
+                    tx_id: 0,
+                    action: Action::ClosePeriod,
+                    debit,
+                    credit,
+                    amount: balance.abs(),
+                    description: None,
+                    reference: None,
+                    category: None,
+                }
+            })
+            .collect();
+        carried.sort_by_key(|entry| (entry.debit.to_string(), entry.credit.to_string()));
+        self.entries.splice(..end, carried);
+        dropped
+    }
+
+    // Drops the entries posted since `len`.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.entries.truncate(len);
+    }
+
+    // Replaces the accounts of the client by the erased customer and drops
+    // the metadata of the entries that touched them. The amounts stay, so the
+    // books still balance.
+    pub(crate) fn erase_client(&mut self, client_id: ClientId) {
+        let erase = |account: &mut LedgerAccount| {
+            let erased = account.client_id() == Some(client_id);
+            if erased {
+                *account = LedgerAccount::ErasedCustomer;
+            }
+            erased
+        };
+        for entry in &mut self.entries {
+            let (debit, credit) = (erase(&mut entry.debit), erase(&mut entry.credit));
+            if debit || credit {
+                entry.description = None;
+                entry.reference = None;
+                entry.category = None;
+            }
+        }
+    }
+
+    // Debits minus credits, i.e. positive for assets and losses, negative for
+    // liabilities.
+    pub fn balances(&self) -> HashMap<LedgerAccount, f64> {
+        balances(&self.entries)
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> f64 {
+        self.balances().get(&account).copied().unwrap_or_default()
+    }
+
+    #[cfg(feature = "csv")]
+    pub fn write_csv<W: Write>(&self, writer: W, pseudonymizer: &Pseudonymizer) -> csv::Result<()> {
+        let mut writer = Writer::from_writer(writer);
+        for entry in &self.entries {
+            writer.serialize(JournalRecord {
+                sequence: entry.sequence,
+                tx: entry.tx_id,
+                action: &entry.action,
+                debit: entry.debit.label(pseudonymizer),
+                credit: entry.credit.label(pseudonymizer),
+                amount: entry.amount,
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn balances(entries: &[JournalEntry]) -> HashMap<LedgerAccount, f64> {
+    let mut balances = HashMap::new();
+    for entry in entries {
+        *balances.entry(entry.debit).or_default() += entry.amount;
+        *balances.entry(entry.credit).or_default() -= entry.amount;
+    }
+    balances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balances_of_posted_entries() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            1,
+            Action::Deposit,
+            LedgerAccount::Cash,
+            LedgerAccount::CustomerAvailable(1),
+            2.0,
+        );
+        ledger.post(
+            2,
+            Action::Withdrawal,
+            LedgerAccount::CustomerAvailable(1),
+            LedgerAccount::Cash,
+            0.5,
+        );
+
+        assert_eq!(ledger.balance(LedgerAccount::Cash), 1.5);
+        assert_eq!(ledger.balance(LedgerAccount::CustomerAvailable(1)), -1.5);
+        assert_eq!(ledger.balance(LedgerAccount::ChargebackLoss), 0.0);
+        assert_eq!(ledger.balances().values().sum::<f64>(), 0.0);
+    }
+
+    #[test]
+    fn compaction_carries_the_balances_forward() {
+        let mut ledger = Ledger::new();
+        let deposit = |client_id| {
+            (
+                LedgerAccount::Cash,
+                LedgerAccount::CustomerAvailable(client_id),
+            )
+        };
+        let postings = [
+            (Action::Deposit, deposit(1), 2.0),
+            (Action::Deposit, deposit(2), 1.0),
+            (
+                Action::Withdrawal,
+                (LedgerAccount::CustomerAvailable(2), LedgerAccount::Cash),
+                1.0,
+            ),
+            (Action::Deposit, deposit(2), 3.0),
+        ];
+        for (tx_id, (action, (debit, credit), amount)) in (1..).zip(postings) {
+            ledger.post(tx_id, action, debit, credit, amount);
+        }
+        let before = ledger.balances();
+
+        assert_eq!(ledger.compact(3), 3);
+        assert_eq!(ledger.entries().len(), 2);
+        assert_eq!(ledger.entries()[0].sequence, 0);
+        assert_eq!(
+            ledger.entries()[0].credit,
+            LedgerAccount::CustomerAvailable(1)
+        );
+        assert_eq!(ledger.entries()[1].sequence, 4);
+        assert_eq!(
+            ledger.balance(LedgerAccount::Cash),
+            before[&LedgerAccount::Cash]
+        );
+        assert_eq!(ledger.balance(LedgerAccount::CustomerAvailable(2)), -3.0);
+
+        let (debit, credit) = deposit(3);
+        ledger.post(5, Action::Deposit, debit, credit, 1.0);
+        assert_eq!(ledger.sequence(), 5);
+        assert_eq!(ledger.compact(5), 2);
+        assert_eq!(ledger.entries().len(), 3);
+        assert_eq!(ledger.balance(LedgerAccount::Cash), 6.0);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn writes_journal_as_csv() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            7,
+            Action::Dispute,
+            LedgerAccount::CustomerAvailable(3),
+            LedgerAccount::CustomerHeld(3),
+            1.25,
+        );
+
+        let mut output = Vec::new();
+        ledger
+            .write_csv(&mut output, &Pseudonymizer::new())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "sequence,tx,type,debit,credit,amount\n\
+             1,7,dispute,customer:3:available,customer:3:held,1.25\n"
+        );
+    }
+}
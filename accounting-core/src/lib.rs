@@ -0,0 +1,29 @@
+pub mod account;
+pub mod account_manager;
+pub mod amount;
+pub mod asset;
+pub mod currency;
+pub mod dispute;
+pub mod error_code;
+pub mod executor;
+#[cfg(feature = "arbitrary")]
+pub mod fuzzing;
+pub mod history;
+pub mod ledger;
+pub mod period;
+pub mod policy;
+pub mod pseudonym;
+pub mod rate_limit;
+pub mod role;
+pub mod rules;
+pub mod scheduler;
+#[cfg(feature = "rhai")]
+pub mod script;
+pub mod shared;
+pub mod stats;
+pub mod store;
+pub mod tenant;
+pub mod tier;
+pub mod tx_cache;
+pub mod tx_filter;
+pub mod types;
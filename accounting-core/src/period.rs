@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+#[cfg(feature = "csv")]
+use std::io::Write;
+
+#[cfg(feature = "csv")]
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+#[cfg(feature = "csv")]
+use crate::amount::ReportAmount;
+#[cfg(feature = "csv")]
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{ClientId, Timestamp, TransactionId};
+
+// The statement totals of one client for a closed period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientPeriod {
+    pub client_id: ClientId,
+    pub opening: f64,
+    pub closing: f64,
+    pub transactions: u64,
+}
+
+impl ClientPeriod {
+    pub fn change(&self) -> f64 {
+        self.closing - self.opening
+    }
+}
+
+// A period is frozen when it is closed, later transactions count in the next
+// period. Periods are numbered from 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClosedPeriod {
+    pub number: u32,
+    pub label: Option<String>,
+    pub closed_at: Option<Timestamp>,
+    // Ordered by client.
+    pub clients: Vec<ClientPeriod>,
+    // Sequence of the last journal entry of the period, 0 without a ledger.
+    #[serde(default)]
+    pub ledger_sequence: u64,
+}
+
+// A backdated transaction applied after its period was closed. The change is
+// the one of the client's total balance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeriodAdjustment {
+    pub period: u32,
+    pub client_id: ClientId,
+    pub tx_id: TransactionId,
+    pub change: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Opening {
+    total: f64,
+    transactions: u64,
+}
+
+// The closed periods and the opening balances of the current one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Periods {
+    opening: HashMap<ClientId, Opening>,
+    closed: Vec<ClosedPeriod>,
+    #[serde(default)]
+    adjustments: Vec<PeriodAdjustment>,
+}
+
+impl Periods {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn current(&self) -> u32 {
+        self.closed.len() as u32 + 1
+    }
+
+    pub fn closed(&self) -> &[ClosedPeriod] {
+        &self.closed
+    }
+
+    pub fn adjustments(&self) -> &[PeriodAdjustment] {
+        &self.adjustments
+    }
+
+    // The closed period a timestamp falls into. A period closed at `t` holds
+    // the timestamps before `t`, periods closed without a timestamp none.
+    pub fn period_of(&self, timestamp: Timestamp) -> Option<u32> {
+        self.closed
+            .iter()
+            .find(|period| {
+                period
+                    .closed_at
+                    .is_some_and(|closed_at| timestamp < closed_at)
+            })
+            .map(|period| period.number)
+    }
+
+    pub(crate) fn adjust(&mut self, adjustment: PeriodAdjustment) {
+        self.adjustments.push(adjustment);
+    }
+
+    pub(crate) fn truncate_adjustments(&mut self, len: usize) {
+        self.adjustments.truncate(len);
+    }
+
+    // Drops the first `len` adjustments.
+    pub(crate) fn compact_adjustments(&mut self, len: usize) {
+        self.adjustments.drain(..len.min(self.adjustments.len()));
+    }
+
+    // Drops the statement totals and adjustments of the client.
+    pub(crate) fn erase_client(&mut self, client_id: ClientId) {
+        self.opening.remove(&client_id);
+        for period in &mut self.closed {
+            period
+                .clients
+                .retain(|client| client.client_id != client_id);
+        }
+        self.adjustments
+            .retain(|adjustment| adjustment.client_id != client_id);
+    }
+
+    // Archives the totals of every account in the current period and starts
+    // the next one with their balances. Accounts opened during the period
+    // open at zero.
+    pub(crate) fn close<'a>(
+        &mut self,
+        accounts: impl Iterator<Item = (&'a ClientId, &'a Account)>,
+        label: Option<String>,
+        closed_at: Option<Timestamp>,
+        ledger_sequence: u64,
+    ) {
+        let mut clients: Vec<_> = accounts
+            .map(|(client_id, account)| {
+                let opening = self.opening.get(client_id).copied().unwrap_or_default();
+                ClientPeriod {
+                    client_id: *client_id,
+                    opening: opening.total,
+                    closing: account.total(),
+                    transactions: account.transactions() - opening.transactions,
+                }
+            })
+            .collect();
+        clients.sort_by_key(|client| client.client_id);
+
+        self.opening = clients
+            .iter()
+            .map(|client| {
+                let opening = Opening {
+                    total: client.closing,
+                    transactions: self
+                        .opening
+                        .get(&client.client_id)
+                        .map_or(0, |opening| opening.transactions)
+                        + client.transactions,
+                };
+                (client.client_id, opening)
+            })
+            .collect();
+        self.closed.push(ClosedPeriod {
+            number: self.current(),
+            label,
+            closed_at,
+            clients,
+            ledger_sequence,
+        });
+    }
+}
+
+#[cfg(feature = "csv")]
+#[derive(Serialize)]
+struct PeriodRecord<'a> {
+    period: u32,
+    label: Option<&'a str>,
+    client: String,
+    opening: ReportAmount,
+    closing: ReportAmount,
+    change: ReportAmount,
+    transactions: u64,
+}
+
+// One row per client and closed period, followed by the totals of the period
+// with the client `all`.
+#[cfg(feature = "csv")]
+pub fn write_periods<W: Write>(
+    writer: W,
+    periods: &[ClosedPeriod],
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    for period in periods {
+        let record = |client: String, opening: f64, closing: f64, transactions: u64| PeriodRecord {
+            period: period.number,
+            label: period.label.as_deref(),
+            client,
+            opening: ReportAmount::new(opening, places),
+            closing: ReportAmount::new(closing, places),
+            change: ReportAmount::new(closing - opening, places),
+            transactions,
+        };
+        for client in &period.clients {
+            writer.serialize(record(
+                pseudonymizer.client(client.client_id),
+                client.opening,
+                client.closing,
+                client.transactions,
+            ))?;
+        }
+        writer.serialize(record(
+            "all".to_string(),
+            period.clients.iter().map(|client| client.opening).sum(),
+            period.clients.iter().map(|client| client.closing).sum(),
+            period
+                .clients
+                .iter()
+                .map(|client| client.transactions)
+                .sum(),
+        ))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closing_a_period_opens_the_next_one_with_its_balances() {
+        let mut periods = Periods::new();
+        let mut account = Account::new();
+        account.deposit(5.0).unwrap();
+        account.count_transaction();
+        let accounts = HashMap::from([(1, account.clone())]);
+        periods.close(accounts.iter(), Some("2024-01".to_string()), Some(100), 0);
+
+        account.withdraw(2.0).unwrap();
+        account.count_transaction();
+        let accounts = HashMap::from([(1, account), (2, Account::new())]);
+        periods.close(accounts.iter(), None, None, 0);
+
+        assert_eq!(periods.current(), 3);
+        assert_eq!(periods.period_of(99), Some(1));
+        assert_eq!(periods.period_of(100), None);
+        let closed = periods.closed();
+        assert_eq!(closed[0].label.as_deref(), Some("2024-01"));
+        assert_eq!(closed[0].clients[0].change(), 5.0);
+        assert_eq!(
+            closed[1].clients,
+            vec![
+                ClientPeriod {
+                    client_id: 1,
+                    opening: 5.0,
+                    closing: 3.0,
+                    transactions: 1,
+                },
+                ClientPeriod {
+                    client_id: 2,
+                    opening: 0.0,
+                    closing: 0.0,
+                    transactions: 0,
+                },
+            ]
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn writes_the_clients_and_totals_of_every_period() {
+        let periods = [ClosedPeriod {
+            number: 1,
+            label: Some("2024-01".to_string()),
+            closed_at: None,
+            clients: vec![
+                ClientPeriod {
+                    client_id: 1,
+                    opening: 0.0,
+                    closing: 5.0,
+                    transactions: 2,
+                },
+                ClientPeriod {
+                    client_id: 2,
+                    opening: 1.0,
+                    closing: 0.5,
+                    transactions: 1,
+                },
+            ],
+            ledger_sequence: 0,
+        }];
+        let mut output = Vec::new();
+        write_periods(&mut output, &periods, 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "period,label,client,opening,closing,change,transactions\n\
+             1,2024-01,1,0.0000,5.0000,5.0000,2\n\
+             1,2024-01,2,1.0000,0.5000,-0.5000,1\n\
+             1,2024-01,all,1.0000,5.5000,4.5000,3\n"
+        );
+    }
+}
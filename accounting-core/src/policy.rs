@@ -0,0 +1,233 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::currency::Currency;
+use crate::role::Roles;
+use crate::rules::Rules;
+#[cfg(feature = "rhai")]
+use crate::script::TransactionScript;
+use crate::tier::Tiers;
+
+pub const MAX_DECIMAL_PLACES: i32 = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ZeroAmountPolicy {
+    #[default]
+    Reject,
+    Accept,
+}
+
+impl FromStr for ZeroAmountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "accept" => Ok(Self::Accept),
+            _ => Err(format!(
+                "unknown zero amount policy '{s}', expected reject or accept"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PrecisionPolicy {
+    #[default]
+    Reject,
+    Round,
+}
+
+impl FromStr for PrecisionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "round" => Ok(Self::Round),
+            _ => Err(format!(
+                "unknown precision policy '{s}', expected reject or round"
+            )),
+        }
+    }
+}
+
+// What happens to deposits into accounts locked by a chargeback.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LockedDepositPolicy {
+    // Credited to the available funds like any deposit.
+    #[default]
+    Accept,
+    Reject,
+    // Received but kept out of the available funds.
+    Suspense,
+}
+
+impl FromStr for LockedDepositPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(Self::Accept),
+            "reject" => Ok(Self::Reject),
+            "suspense" => Ok(Self::Suspense),
+            _ => Err(format!(
+                "unknown locked deposit policy '{s}', expected accept, reject or suspense"
+            )),
+        }
+    }
+}
+
+// Whether withdrawals can be disputed and what a chargeback of a disputed
+// withdrawal does. Disputes hold the withdrawn amount, resolves pay it out
+// again.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum WithdrawalDisputePolicy {
+    #[default]
+    Reject,
+    // Chargebacks credit the amount back to the available funds.
+    Reverse,
+    // Like Reverse, but the account is locked like after a deposit chargeback.
+    ReverseAndLock,
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "reverse" => Ok(Self::Reverse),
+            "reverse-and-lock" => Ok(Self::ReverseAndLock),
+            _ => Err(format!(
+                "unknown withdrawal dispute policy '{s}', expected reject, reverse or reverse-and-lock"
+            )),
+        }
+    }
+}
+
+// What the input parsers do with columns they don't know.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UnknownColumnPolicy {
+    #[default]
+    Ignore,
+    // Kept as metadata of the transaction.
+    Capture,
+    // The header is invalid, and so are rows with more fields than it.
+    Reject,
+}
+
+impl FromStr for UnknownColumnPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(Self::Ignore),
+            "capture" => Ok(Self::Capture),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!(
+                "unknown column policy '{s}', expected ignore, capture or reject"
+            )),
+        }
+    }
+}
+
+// What happens to transactions with a timestamp in an already closed period.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum BackdatedPolicy {
+    // Applied to the current period like any transaction.
+    #[default]
+    Apply,
+    Reject,
+    // Applied to the current period and recorded as an adjustment of the
+    // closed one.
+    Adjust,
+}
+
+impl FromStr for BackdatedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apply" => Ok(Self::Apply),
+            "reject" => Ok(Self::Reject),
+            "adjust" => Ok(Self::Adjust),
+            _ => Err(format!(
+                "unknown backdated policy '{s}', expected apply, reject or adjust"
+            )),
+        }
+    }
+}
+
+// How long applied deposits and withdrawals stay disputable. Expired entries
+// of the tx cache are dropped unless they are disputed, which bounds memory at
+// the cost of rejecting disputes of older transactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CacheTtl {
+    #[default]
+    Forever,
+    // Until this many later transactions were applied to the books.
+    Transactions(u64),
+    // Until the timestamps of later transactions are this many seconds newer.
+    Seconds(u64),
+}
+
+impl FromStr for CacheTtl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cache ttl '{s}', expected forever, txs:N or secs:N");
+        match s.split_once(':') {
+            None if s == "forever" => Ok(Self::Forever),
+            Some(("txs", count)) => count.parse().map(Self::Transactions),
+            Some(("secs", seconds)) => seconds.parse().map(Self::Seconds),
+            _ => return Err(invalid()),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+// How many closed periods keep the details of their transactions: the
+// disputable transactions, journal entries, history points, closed dispute
+// cases and adjustments. Closing a period compacts the older ones, only their
+// statement totals and the balances stay.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Retention {
+    #[default]
+    Forever,
+    Periods(u32),
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid retention '{s}', expected forever or periods:N");
+        match s.split_once(':') {
+            None if s == "forever" => Ok(Self::Forever),
+            Some(("periods", count)) => count.parse().map(Self::Periods).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Policy {
+    pub zero_amount: ZeroAmountPolicy,
+    pub precision: PrecisionPolicy,
+    pub locked_deposit: LockedDepositPolicy,
+    pub withdrawal_dispute: WithdrawalDisputePolicy,
+    pub backdated: BackdatedPolicy,
+    pub cache_ttl: CacheTtl,
+    pub retention: Retention,
+    // Whether freeze and unfreeze transactions of the input are applied.
+    pub allow_admin_actions: bool,
+    pub currency: Currency,
+    // Shared by the books of all tenants.
+    pub tiers: Arc<Tiers>,
+    pub roles: Arc<Roles>,
+    pub rules: Arc<Rules>,
+    // Screens the transactions of the input, see Tenants::screen.
+    #[cfg(feature = "rhai")]
+    pub script: Option<Arc<TransactionScript>>,
+}
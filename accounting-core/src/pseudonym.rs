@@ -0,0 +1,135 @@
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Serialize, Serializer};
+use sha2::Sha256;
+
+use crate::account_manager::AccountManagerError;
+use crate::types::ClientId;
+
+// Renders client ids in outputs. The default passes ids through unchanged, a
+// keyed one replaces them with a truncated HMAC-SHA256, which is stable for a
+// key but can't be reversed or recomputed without it.
+#[derive(Clone, Default)]
+pub struct Pseudonymizer {
+    key: Option<Vec<u8>>,
+}
+
+impl Pseudonymizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_key(key: &[u8]) -> Self {
+        Self {
+            key: Some(key.to_vec()),
+        }
+    }
+
+    // Whether ids are replaced, otherwise outputs may keep them as numbers.
+    pub fn is_keyed(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn client(&self, client_id: ClientId) -> String {
+        let Some(key) = &self.key else {
+            return client_id.to_string();
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(&client_id.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        hex::encode(&digest[..8])
+    }
+
+    pub fn error(&self, err: &AccountManagerError) -> String {
+        err.describe_with(|client_id| self.client(client_id))
+    }
+
+    // The serialized error with pseudonyms in the message and the client
+    // fields.
+    pub fn error_payload<'a>(&'a self, err: &'a AccountManagerError) -> ErrorPayload<'a> {
+        ErrorPayload {
+            pseudonymizer: self,
+            err,
+        }
+    }
+}
+
+pub struct ErrorPayload<'a> {
+    pseudonymizer: &'a Pseudonymizer,
+    err: &'a AccountManagerError,
+}
+
+impl Serialize for ErrorPayload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pseudonymizer = self.pseudonymizer;
+        match pseudonymizer.key {
+            Some(_) => {
+                self.err
+                    .serialize_with(serializer, &pseudonymizer.error(self.err), |client_id| {
+                        pseudonymizer.client(client_id)
+                    })
+            }
+            None => self.err.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_key_ids_are_unchanged() {
+        assert_eq!(Pseudonymizer::new().client(42), "42");
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_per_key() {
+        let pseudonymizer = Pseudonymizer::with_key(b"secret");
+        let pseudonym = pseudonymizer.client(1);
+        assert_eq!(pseudonym.len(), 16);
+        assert_eq!(pseudonym, pseudonymizer.client(1));
+        assert_ne!(pseudonym, pseudonymizer.client(2));
+        assert_ne!(pseudonym, Pseudonymizer::with_key(b"other").client(1));
+    }
+
+    #[test]
+    fn error_messages_use_pseudonyms() {
+        let pseudonymizer = Pseudonymizer::with_key(b"secret");
+        let err = AccountManagerError::Unauthorized {
+            client_id: 1,
+            owner_id: 2,
+        };
+        assert_eq!(
+            pseudonymizer.error(&err),
+            format!(
+                "Unauthorized. {} can't modify transactions of {}.",
+                pseudonymizer.client(1),
+                pseudonymizer.client(2)
+            )
+        );
+        assert_eq!(
+            Pseudonymizer::new().error(&err),
+            "Unauthorized. 1 can't modify transactions of 2."
+        );
+    }
+
+    #[test]
+    fn error_payloads_use_pseudonyms() {
+        let err = AccountManagerError::AccountExists { client_id: 1 };
+        let payload = |pseudonymizer: &Pseudonymizer| {
+            serde_json::to_string(&pseudonymizer.error_payload(&err)).unwrap()
+        };
+        assert_eq!(
+            payload(&Pseudonymizer::new()),
+            r#"{"code":"E1110","kind":"account_exists","message":"Account of client 1 already exists.","client":1}"#
+        );
+        let pseudonymizer = Pseudonymizer::with_key(b"secret");
+        let pseudonym = pseudonymizer.client(1);
+        assert_eq!(
+            payload(&pseudonymizer),
+            format!(
+                r#"{{"code":"E1110","kind":"account_exists","message":"Account of client {pseudonym} already exists.","client":"{pseudonym}"}}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::error_code::ErrorCode;
+use crate::types::{ClientId, TenantId};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RateLimitError {
+    #[error("{}", exceeded_message(.client_id, .retry_after))]
+    Exceeded {
+        client_id: ClientId,
+        retry_after: Duration,
+    },
+
+    #[error("Invalid rate limit: {0}")]
+    InvalidConfig(String),
+}
+
+fn exceeded_message(client: impl Display, retry_after: &Duration) -> String {
+    format!("Too many requests. Client {client} is rate limited, retry after {retry_after:?}.")
+}
+
+impl RateLimitError {
+    // The message with the client id rendered by `label`, e.g. as a pseudonym.
+    pub fn describe_with(&self, label: impl Fn(ClientId) -> String) -> String {
+        match self {
+            RateLimitError::Exceeded {
+                client_id,
+                retry_after,
+            } => exceeded_message(label(*client_id), retry_after),
+            RateLimitError::InvalidConfig(_) => self.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RateLimitError::Exceeded { .. } => "rate_limited",
+            RateLimitError::InvalidConfig(_) => "invalid_config",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            RateLimitError::Exceeded { .. } => ErrorCode::RateLimited,
+            RateLimitError::InvalidConfig(_) => ErrorCode::InvalidPolicy,
+        }
+    }
+
+    // HTTP status a server should answer with.
+    pub fn status(&self) -> u16 {
+        match self {
+            RateLimitError::Exceeded { .. } => 429,
+            RateLimitError::InvalidConfig(_) => 500,
+        }
+    }
+}
+
+pub type RateLimitResult<T> = Result<T, RateLimitError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+// "RATE" or "RATE/BURST" transactions per second, the burst defaults to the
+// rate rounded up.
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit '{s}', expected e.g. 100 or 100/500");
+        let (rate, burst) = match s.split_once('/') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let per_second: f64 = rate.trim().parse().map_err(|_| invalid())?;
+        let burst = match burst {
+            Some(burst) => burst.trim().parse().map_err(|_| invalid())?,
+            None => per_second.ceil() as u32,
+        };
+        Ok(Self { per_second, burst })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per client of a tenant: a client may send `burst` transactions
+// at once and then `per_second` on average. Buckets of other clients, and of
+// the same client id in other tenants, are unaffected.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<(TenantId, ClientId), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimitResult<Self> {
+        if !(limit.per_second.is_finite() && limit.per_second > 0.0) {
+            return Err(RateLimitError::InvalidConfig(format!(
+                "rate must be a positive number, got {}",
+                limit.per_second
+            )));
+        }
+        if limit.burst == 0 {
+            return Err(RateLimitError::InvalidConfig(
+                "burst must be at least 1".to_string(),
+            ));
+        }
+        Ok(Self {
+            limit,
+            buckets: HashMap::new(),
+        })
+    }
+
+    pub fn check(
+        &mut self,
+        tenant: &str,
+        client_id: ClientId,
+        now: Instant,
+    ) -> RateLimitResult<()> {
+        let limit = self.limit;
+        let key = (tenant.to_string(), client_id);
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let missing = 1.0 - bucket.tokens;
+            return Err(RateLimitError::Exceeded {
+                client_id,
+                retry_after: Duration::from_secs_f64(missing / limit.per_second),
+            });
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            per_second: 2.0,
+            burst: 2,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn allows_bursts_and_then_the_rate() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+        assert!(limiter.check("", 1, start).is_ok());
+        assert!(limiter.check("", 1, start).is_ok());
+        assert_eq!(
+            limiter.check("", 1, start),
+            Err(RateLimitError::Exceeded {
+                client_id: 1,
+                retry_after: Duration::from_millis(500)
+            })
+        );
+
+        assert!(limiter
+            .check("", 1, start + Duration::from_millis(500))
+            .is_ok());
+        assert!(limiter
+            .check("", 1, start + Duration::from_millis(500))
+            .is_err());
+    }
+
+    #[test]
+    fn clients_have_separate_buckets() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+        assert!(limiter.check("", 1, start).is_ok());
+        assert!(limiter.check("", 1, start).is_ok());
+        assert!(limiter.check("", 1, start).is_err());
+        assert!(limiter.check("", 2, start).is_ok());
+    }
+
+    #[test]
+    fn tenants_have_separate_buckets() {
+        let mut limiter = limiter();
+        let start = Instant::now();
+        assert!(limiter.check("shop", 1, start).is_ok());
+        assert!(limiter.check("shop", 1, start).is_ok());
+        assert!(limiter.check("shop", 1, start).is_err());
+        assert!(limiter.check("bank", 1, start).is_ok());
+        assert!(limiter.check("", 1, start).is_ok());
+    }
+
+    #[test]
+    fn parses_rate_and_burst() {
+        assert_eq!(
+            "100/500".parse::<RateLimit>(),
+            Ok(RateLimit {
+                per_second: 100.0,
+                burst: 500
+            })
+        );
+        assert_eq!("0.5".parse::<RateLimit>().unwrap().burst, 1);
+        assert!("fast".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_limits() {
+        let zero_rate = RateLimit {
+            per_second: 0.0,
+            burst: 1,
+        };
+        assert!(RateLimiter::new(zero_rate).is_err());
+        let zero_burst = RateLimit {
+            per_second: 1.0,
+            burst: 0,
+        };
+        assert!(RateLimiter::new(zero_burst).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Read;
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::{ReaderBuilder, Trim};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::currency::Currency;
+use crate::types::ClientId;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RoleError {
+    #[error("Invalid role configuration at line {line}: {message}")]
+    InvalidConfig { line: u64, message: String },
+}
+
+pub type RoleResult<T> = Result<T, RoleError>;
+
+// Merchants receive payments from customers. Their deposits pay the merchant
+// fee, chargebacks of them are debited without locking the account, and they
+// can't dispute their own payouts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Customer,
+    Merchant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Customer => "customer",
+            Role::Merchant => "merchant",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "customer" => Ok(Role::Customer),
+            "merchant" => Ok(Role::Merchant),
+            _ => Err(format!("unknown role '{s}', expected customer or merchant")),
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+#[derive(Deserialize)]
+struct ClientRoleRecord {
+    client: ClientId,
+    role: Role,
+}
+
+// Configured roles of the clients and the fee of merchant deposits. Clients
+// without a configured role are customers unless they opened their account
+// with another one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Roles {
+    clients: HashMap<ClientId, Role>,
+    // Share of every merchant deposit, e.g. 0.029.
+    merchant_fee: f64,
+    // Penalty charged on top of every chargeback of the role.
+    chargeback_fees: HashMap<Role, f64>,
+}
+
+impl Roles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_merchant_fee(mut self, merchant_fee: f64) -> Self {
+        self.merchant_fee = merchant_fee;
+        self
+    }
+
+    pub fn with_chargeback_fee(mut self, role: Role, fee: f64) -> Self {
+        self.chargeback_fees.insert(role, fee);
+        self
+    }
+
+    pub fn chargeback_fee(&self, role: Role) -> f64 {
+        self.chargeback_fees.get(&role).copied().unwrap_or_default()
+    }
+
+    pub fn assign(&mut self, client_id: ClientId, role: Role) {
+        self.clients.insert(client_id, role);
+    }
+
+    // CSV with the columns client and role.
+    #[cfg(feature = "csv")]
+    pub fn read_clients<R: Read>(&mut self, reader: R) -> RoleResult<()> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        for result in reader.deserialize() {
+            let record: ClientRoleRecord = result.map_err(|err| RoleError::InvalidConfig {
+                line: err.position().map(|position| position.line()).unwrap_or(0),
+                message: err.to_string(),
+            })?;
+            self.assign(record.client, record.role);
+        }
+        Ok(())
+    }
+
+    pub fn role(&self, client_id: ClientId) -> Role {
+        self.clients.get(&client_id).copied().unwrap_or_default()
+    }
+
+    // The fee of a deposit of `amount` into an account of `role`, rounded to
+    // the minor units of the currency.
+    pub fn fee(&self, role: Role, amount: f64, currency: &Currency) -> f64 {
+        match role {
+            Role::Customer => 0.0,
+            Role::Merchant => currency.round(amount * self.merchant_fee),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn reads_client_roles() {
+        let mut roles = Roles::new();
+        roles
+            .read_clients("client,role\n2,merchant\n3,customer\n".as_bytes())
+            .unwrap();
+        assert_eq!(roles.role(1), Role::Customer);
+        assert_eq!(roles.role(2), Role::Merchant);
+        assert_eq!(roles.role(3), Role::Customer);
+
+        let err = roles
+            .read_clients("client,role\n1,bank\n".as_bytes())
+            .unwrap_err();
+        assert!(matches!(err, RoleError::InvalidConfig { line: 2, .. }));
+    }
+
+    #[test]
+    fn only_merchants_pay_fees() {
+        let roles = Roles::new().with_merchant_fee(0.029);
+        let currency = Currency::default();
+        assert_eq!(roles.fee(Role::Customer, 100.0, &currency), 0.0);
+        assert_eq!(roles.fee(Role::Merchant, 100.0, &currency), 2.9);
+        assert_eq!(roles.fee(Role::Merchant, 0.33, &currency), 0.0096);
+        assert_eq!(
+            roles.fee(Role::Merchant, 1050.0, &Currency::new("JPY", 0)),
+            30.0
+        );
+    }
+}
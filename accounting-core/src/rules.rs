@@ -0,0 +1,234 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::role::Role;
+use crate::tier::Tier;
+use crate::types::{Action, Transaction};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum RuleError {
+    #[error("Invalid rule '{name}': {message}")]
+    Invalid { name: String, message: String },
+}
+
+pub type RuleResult<T> = Result<T, RuleError>;
+
+// What a matching rule does with the transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Consequence {
+    // Refused before it is applied.
+    Reject,
+    // Applied, then the account is locked.
+    Freeze,
+}
+
+// A declared policy rule. It matches transactions that meet all of its
+// conditions, e.g. withdrawals above 10000 of basic clients:
+//
+//     name = "large basic withdrawals"
+//     type = "withdrawal"
+//     tier = "basic"
+//     amount-above = 10000
+//     then = "reject"
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub action: Option<Action>,
+    pub tier: Option<Tier>,
+    pub role: Option<Role>,
+    // Transactions without an amount never match.
+    pub amount_above: Option<f64>,
+    // Disputes opened against the account, including the transaction if it
+    // is a dispute.
+    pub disputes_at_least: Option<u64>,
+    pub then: Consequence,
+}
+
+// What a transaction is matched against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subject<'a> {
+    pub tx: &'a Transaction,
+    pub tier: Tier,
+    pub role: Role,
+    pub disputes: u64,
+}
+
+impl Rule {
+    pub fn matches(&self, subject: &Subject) -> bool {
+        self.action
+            .as_ref()
+            .is_none_or(|action| *action == subject.tx.action)
+            && self.tier.is_none_or(|tier| tier == subject.tier)
+            && self.role.is_none_or(|role| role == subject.role)
+            && self
+                .amount_above
+                .is_none_or(|max| subject.tx.amount.is_some_and(|amount| amount > max))
+            && self
+                .disputes_at_least
+                .is_none_or(|min| subject.disputes >= min)
+    }
+
+    fn validate(&self) -> RuleResult<()> {
+        let invalid = |message: &str| RuleError::Invalid {
+            name: self.name.clone(),
+            message: message.to_string(),
+        };
+        if self.name.is_empty() {
+            return Err(invalid("the name is empty"));
+        }
+        if self
+            .amount_above
+            .is_some_and(|amount| !amount.is_finite() || amount < 0.0)
+        {
+            return Err(invalid("amount-above must be a positive amount"));
+        }
+        let conditions = [
+            self.action.is_some(),
+            self.tier.is_some(),
+            self.role.is_some(),
+            self.amount_above.is_some(),
+            self.disputes_at_least.is_some(),
+        ];
+        if !conditions.contains(&true) {
+            return Err(invalid("no conditions, it would match every transaction"));
+        }
+        Ok(())
+    }
+}
+
+// The declared rules of the Policy, evaluated in order for every transaction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> RuleResult<Self> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    // The first matching rule of each consequence.
+    pub fn evaluate(&self, subject: &Subject) -> (Option<&Rule>, Option<&Rule>) {
+        let first = |consequence: Consequence| {
+            self.rules
+                .iter()
+                .find(|rule| rule.then == consequence && rule.matches(subject))
+        };
+        (first(Consequence::Reject), first(Consequence::Freeze))
+    }
+}
+
+impl<'de> Deserialize<'de> for Rules {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let rules = Vec::deserialize(deserializer)?;
+        Rules::new(rules).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(then: Consequence) -> Rule {
+        Rule {
+            name: "rule".to_string(),
+            action: None,
+            tier: None,
+            role: None,
+            amount_above: None,
+            disputes_at_least: None,
+            then,
+        }
+    }
+
+    #[test]
+    fn rules_match_when_all_conditions_hold() {
+        let large_withdrawals = Rule {
+            action: Some(Action::Withdrawal),
+            tier: Some(Tier::Basic),
+            amount_above: Some(10000.0),
+            ..rule(Consequence::Reject)
+        };
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 1, Some(10000.5));
+        let subject = Subject {
+            tx: &withdrawal,
+            tier: Tier::Basic,
+            role: Role::Customer,
+            disputes: 0,
+        };
+        assert!(large_withdrawals.matches(&subject));
+        assert!(!large_withdrawals.matches(&Subject {
+            tier: Tier::Premium,
+            ..subject
+        }));
+
+        let small = Transaction::new(Action::Withdrawal, 1, 2, Some(10000.0));
+        assert!(!large_withdrawals.matches(&Subject {
+            tx: &small,
+            ..subject
+        }));
+        let dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        let disputes = Rule {
+            amount_above: Some(0.0),
+            ..rule(Consequence::Reject)
+        };
+        assert!(!disputes.matches(&Subject {
+            tx: &dispute,
+            ..subject
+        }));
+    }
+
+    #[test]
+    fn evaluate_finds_the_first_rule_of_each_consequence() {
+        let freeze = Rule {
+            name: "dispute abuse".to_string(),
+            action: Some(Action::Dispute),
+            disputes_at_least: Some(3),
+            ..rule(Consequence::Freeze)
+        };
+        let reject = Rule {
+            name: "too many disputes".to_string(),
+            disputes_at_least: Some(5),
+            ..rule(Consequence::Reject)
+        };
+        let rules = Rules::new(vec![freeze.clone(), reject.clone()]).unwrap();
+        let dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        let subject = |disputes| Subject {
+            tx: &dispute,
+            tier: Tier::Basic,
+            role: Role::Customer,
+            disputes,
+        };
+        assert_eq!(rules.evaluate(&subject(2)), (None, None));
+        assert_eq!(rules.evaluate(&subject(3)), (None, Some(&freeze)));
+        assert_eq!(rules.evaluate(&subject(5)), (Some(&reject), Some(&freeze)));
+    }
+
+    #[test]
+    fn rules_without_conditions_or_with_invalid_amounts_are_refused() {
+        assert!(matches!(
+            Rules::new(vec![rule(Consequence::Reject)]),
+            Err(RuleError::Invalid { .. })
+        ));
+        let negative = Rule {
+            amount_above: Some(-1.0),
+            ..rule(Consequence::Reject)
+        };
+        assert!(Rules::new(vec![negative]).is_err());
+        assert!(Rules::new(Vec::new()).unwrap().is_empty());
+    }
+}
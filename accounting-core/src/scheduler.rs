@@ -0,0 +1,337 @@
+use std::collections::VecDeque;
+#[cfg(feature = "csv")]
+use std::io::Read;
+
+#[cfg(feature = "csv")]
+use csv::{ReaderBuilder, Trim};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::amount::deserialize_amount;
+use crate::types::{Action, ClientId, TenantId, Timestamp, Transaction, TransactionId};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SchedulerError {
+    #[error("Invalid recurring rule at line {line}: {message}")]
+    InvalidRule { line: u64, message: String },
+}
+
+pub type SchedulerResult<T> = Result<T, SchedulerError>;
+
+// Durations like `3600`, `90m`, `12h` or `30d`, in seconds.
+fn parse_interval(value: &str) -> Result<u64, String> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        Some((index, 'd')) => (&value[..index], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .filter(|interval| *interval > 0)
+        .ok_or_else(|| format!("invalid interval `{value}`"))
+}
+
+fn deserialize_interval<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_interval(&value).map_err(serde::de::Error::custom)
+}
+
+// Occurrences of recurring rules get the ids from here on, in the order they
+// become due, so they never collide with the ids of the input.
+pub const RECURRING_IDS: TransactionId = 1 << 31;
+
+// A transaction that repeats every `interval` seconds from `start` on, e.g. a
+// monthly sweep. `tx` names the rule, occurrence n has the reference
+// `recurring:{tx}:{n}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RecurringRule {
+    #[serde(rename = "type")]
+    pub action: Action,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    pub tx: TransactionId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<f64>,
+    pub start: Timestamp,
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: u64,
+    // Unlimited if empty.
+    #[serde(default)]
+    pub count: Option<u64>,
+    #[serde(default)]
+    pub tenant: Option<TenantId>,
+}
+
+impl RecurringRule {
+    // The first occurrence after `after`.
+    fn first_after(&self, after: Option<Timestamp>) -> u64 {
+        match after {
+            Some(after) if after >= self.start => (after - self.start) / self.interval + 1,
+            _ => 0,
+        }
+    }
+
+    // When occurrence n is due, none if there is no such occurrence.
+    fn due_at(&self, n: u64) -> Option<Timestamp> {
+        if self.count.is_some_and(|count| n >= count) {
+            return None;
+        }
+        n.checked_mul(self.interval)
+            .and_then(|offset| self.start.checked_add(offset))
+    }
+
+    fn occurrence(&self, n: u64, id: TransactionId, at: Timestamp) -> Transaction {
+        Transaction {
+            tenant: self.tenant.clone(),
+            timestamp: Some(at),
+            reference: Some(format!("recurring:{}:{n}", self.tx)),
+            ..Transaction::new(self.action.clone(), self.client_id, id, self.amount)
+        }
+    }
+}
+
+// Holds scheduled transactions until the clock reaches their execution time
+// and generates the occurrences of recurring rules one at a time as they
+// become due. The clock is the latest timestamp of the input. Pending
+// transactions, the clock and the next occurrence id are part of checkpoints,
+// the rules are configuration and not.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scheduler {
+    now: Option<Timestamp>,
+    // Ordered by execution time, ties in the order they were scheduled.
+    pending: VecDeque<(Timestamp, Transaction)>,
+    #[serde(default = "first_recurring_id")]
+    next_id: TransactionId,
+    #[serde(skip)]
+    rules: Vec<RecurringRule>,
+    // The next occurrence of every rule.
+    #[serde(skip)]
+    next: Vec<u64>,
+}
+
+fn first_recurring_id() -> TransactionId {
+    RECURRING_IDS
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            now: None,
+            pending: VecDeque::new(),
+            next_id: RECURRING_IDS,
+            rules: Vec::new(),
+            next: Vec::new(),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> Option<Timestamp> {
+        self.now
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Occurrences before the clock were applied in earlier runs.
+    pub fn set_rules(&mut self, rules: Vec<RecurringRule>) {
+        self.next = rules
+            .iter()
+            .map(|rule| rule.first_after(self.now))
+            .collect();
+        self.rules = rules;
+    }
+
+    // Ids of the input in the range of the occurrences would collide with
+    // them.
+    pub fn reserves(&self, id: TransactionId) -> bool {
+        !self.rules.is_empty() && id >= RECURRING_IDS
+    }
+
+    // CSV with the columns type, client, tx, amount, start, interval and the
+    // optional count and tenant.
+    #[cfg(feature = "csv")]
+    pub fn read_rules<R: Read>(reader: R) -> SchedulerResult<Vec<RecurringRule>> {
+        ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .deserialize()
+            .map(|result| {
+                result.map_err(|err| SchedulerError::InvalidRule {
+                    line: err.position().map(|position| position.line()).unwrap_or(0),
+                    message: err.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    // True if the transaction has to wait for the clock.
+    pub fn is_due(&self, at: Timestamp) -> bool {
+        self.now.is_some_and(|now| at <= now)
+    }
+
+    pub fn schedule(&mut self, at: Timestamp, tx: Transaction) {
+        let index = self.pending.partition_point(|(pending, _)| *pending <= at);
+        self.pending.insert(index, (at, tx));
+    }
+
+    // Moves the clock forward to `now`, after which next_due returns the
+    // transactions that became due. The clock never goes back, rows with older
+    // timestamps don't release anything.
+    pub fn advance(&mut self, now: Timestamp) {
+        if self.now.is_none_or(|before| now > before) {
+            self.now = Some(now);
+        }
+    }
+
+    // The next transaction that is due, in the order of their execution time,
+    // scheduled ones before occurrences of the same time and occurrences in
+    // the order of their rules.
+    pub fn next_due(&mut self) -> Option<Transaction> {
+        let now = self.now?;
+        let occurrence = (0..self.rules.len())
+            .filter_map(|index| Some((self.rules[index].due_at(self.next[index])?, index)))
+            .filter(|(at, _)| *at <= now)
+            .min();
+        match (self.pending.front(), occurrence) {
+            (Some((pending, _)), occurrence)
+                if *pending <= now && occurrence.is_none_or(|(at, _)| *pending <= at) =>
+            {
+                self.pending.pop_front().map(|(_, tx)| tx)
+            }
+            (_, Some((at, index))) => {
+                let id = self.next_id;
+                // Rules stop once the id range is used up.
+                let Some(next_id) = id.checked_add(1) else {
+                    self.rules.clear();
+                    self.next.clear();
+                    return None;
+                };
+                self.next_id = next_id;
+                let n = self.next[index];
+                self.next[index] += 1;
+                Some(self.rules[index].occurrence(n, id, at))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(id: TransactionId) -> Transaction {
+        Transaction::new(Action::Deposit, 1, id, Some(1.0))
+    }
+
+    fn due(scheduler: &mut Scheduler, now: Timestamp) -> Vec<Transaction> {
+        scheduler.advance(now);
+        std::iter::from_fn(|| scheduler.next_due()).collect()
+    }
+
+    #[test]
+    fn scheduled_transactions_are_released_when_due() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(200, deposit(2));
+        scheduler.schedule(100, deposit(1));
+        scheduler.schedule(200, deposit(3));
+        assert!(!scheduler.is_due(100));
+
+        assert_eq!(due(&mut scheduler, 99), vec![]);
+        assert!(scheduler.is_due(99));
+        assert_eq!(
+            due(&mut scheduler, 200),
+            vec![deposit(1), deposit(2), deposit(3)]
+        );
+        assert_eq!(due(&mut scheduler, 150), vec![]);
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn recurring_rules_materialize_every_occurrence_once() {
+        let rules = "type,client,tx,amount,start,interval,count\n\
+                     withdrawal,1,1000,5.0,100,1d,3\n\
+                     deposit,2,2000,1.0,100,1,\n";
+        let mut scheduler = Scheduler::new();
+        scheduler.set_rules(Scheduler::read_rules(rules.as_bytes()).unwrap());
+        assert!(scheduler.reserves(RECURRING_IDS));
+        assert!(!scheduler.reserves(RECURRING_IDS - 1));
+        let day = 24 * 60 * 60;
+
+        let occurrences = |transactions: Vec<Transaction>| -> Vec<_> {
+            transactions
+                .into_iter()
+                .map(|tx| (tx.id - RECURRING_IDS, tx.reference.unwrap()))
+                .collect()
+        };
+        assert_eq!(
+            occurrences(due(&mut scheduler, 101)),
+            [
+                (0, "recurring:1000:0".to_string()),
+                (1, "recurring:2000:0".to_string()),
+                (2, "recurring:2000:1".to_string())
+            ]
+        );
+        assert!(due(&mut scheduler, 101).is_empty());
+
+        // Occurrences are generated as they're taken, not all at once.
+        scheduler.advance(100 + 10 * day);
+        assert_eq!(scheduler.next_due().unwrap().id, RECURRING_IDS + 3);
+        assert_eq!(scheduler.next_due().unwrap().id, RECURRING_IDS + 4);
+        let withdrawals = std::iter::from_fn(|| scheduler.next_due())
+            .filter(|tx| tx.action == Action::Withdrawal)
+            .map(|tx| tx.reference.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(withdrawals, ["recurring:1000:1", "recurring:1000:2"]);
+    }
+
+    #[test]
+    fn scheduled_transactions_come_before_occurrences_of_their_time() {
+        let rule = RecurringRule {
+            action: Action::Deposit,
+            client_id: 1,
+            tx: 7,
+            amount: Some(1.0),
+            start: 100,
+            interval: 100,
+            count: Some(2),
+            tenant: None,
+        };
+        let mut scheduler = Scheduler::new();
+        scheduler.advance(50);
+        scheduler.set_rules(vec![rule.clone()]);
+        scheduler.schedule(200, deposit(1));
+        let ids: Vec<_> = due(&mut scheduler, 300).iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, [RECURRING_IDS, 1, RECURRING_IDS + 1]);
+
+        // A restored scheduler continues after the occurrences before its
+        // clock.
+        let mut scheduler: Scheduler =
+            serde_json::from_str(&serde_json::to_string(&scheduler).unwrap()).unwrap();
+        scheduler.set_rules(vec![RecurringRule {
+            count: None,
+            ..rule
+        }]);
+        let ids: Vec<_> = due(&mut scheduler, 400).iter().map(|tx| tx.id).collect();
+        assert_eq!(ids, [RECURRING_IDS + 2]);
+    }
+
+    #[test]
+    fn invalid_intervals_are_rejected() {
+        assert_eq!(parse_interval("90m"), Ok(5400));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("monthly").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::account_manager::{AccountManagerError, AccountManagerResult};
+use crate::types::{client_id_u64, Transaction};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ScriptError {
+    #[error("Invalid script: {message}")]
+    Invalid { message: String },
+
+    #[error("Invalid script: it doesn't define fn {HOOK}(tx, account)")]
+    MissingHook,
+}
+
+pub type ScriptResult<T> = Result<T, ScriptError>;
+
+const HOOK: &str = "screen";
+
+// Bounds the work of one call, so a runaway loop fails the transaction
+// instead of stalling the engine.
+const MAX_OPERATIONS: u64 = 100_000;
+
+// A rhai script with a hook that sees every transaction and the account of its
+// client before the transaction is applied:
+//
+//     fn screen(tx, account) {
+//         if tx.type == "withdrawal" && tx.amount > account.available * 0.9 {
+//             return "withdraws nearly all funds";
+//         }
+//         if tx.amount != () && tx.amount > 5000.0 {
+//             return #{ review: "large" };
+//         }
+//     }
+//
+// Returning nothing accepts the transaction, a string rejects it with that
+// reason and a map accepts it with the entries added to its metadata.
+pub struct TransactionScript {
+    engine: Engine,
+    ast: AST,
+    source: String,
+}
+
+impl TransactionScript {
+    pub fn compile(source: &str) -> ScriptResult<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine.compile(source).map_err(|err| ScriptError::Invalid {
+            message: err.to_string(),
+        })?;
+        if !ast
+            .iter_functions()
+            .any(|function| function.name == HOOK && function.params.len() == 2)
+        {
+            return Err(ScriptError::MissingHook);
+        }
+        Ok(Self {
+            engine,
+            ast,
+            source: source.to_string(),
+        })
+    }
+
+    // Runs the hook; the account is None for clients without one yet.
+    pub fn screen(
+        &self,
+        tx: &mut Transaction,
+        account: Option<&Account>,
+    ) -> AccountManagerResult<()> {
+        let failed = |error: String| AccountManagerError::ScriptFailed { id: tx.id, error };
+        let account = account.map_or(Dynamic::UNIT, |account| account_map(account).into());
+        // Only the hook runs, top-level statements of the script don't.
+        let options = CallFnOptions::new().eval_ast(false);
+        let verdict = self
+            .engine
+            .call_fn_with_options::<Dynamic>(
+                options,
+                &mut Scope::new(),
+                &self.ast,
+                HOOK,
+                (Dynamic::from(transaction_map(tx)), account),
+            )
+            .map_err(|err| failed(err.to_string()))?;
+        if verdict.is_unit() {
+            return Ok(());
+        }
+        if verdict.is_string() {
+            return Err(AccountManagerError::ScriptRejected {
+                id: tx.id,
+                reason: verdict.to_string(),
+            });
+        }
+        let type_name = verdict.type_name();
+        let Some(annotations) = verdict.try_cast::<Map>() else {
+            return Err(failed(format!(
+                "{HOOK} returned {type_name}, expected nothing, a reason or a map"
+            )));
+        };
+        for (key, value) in annotations {
+            tx.metadata.insert(key.to_string(), value.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for TransactionScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransactionScript")
+            .field("source", &self.source)
+            .finish_non_exhaustive()
+    }
+}
+
+// Scripts are the same if their source is.
+impl PartialEq for TransactionScript {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+fn optional<T: Into<Dynamic>>(value: Option<T>) -> Dynamic {
+    value.map_or(Dynamic::UNIT, Into::into)
+}
+
+fn transaction_map(tx: &Transaction) -> Map {
+    let metadata: Map = tx
+        .metadata
+        .iter()
+        .map(|(key, value)| (key.into(), value.clone().into()))
+        .collect();
+    Map::from_iter([
+        ("type".into(), tx.action.as_str().into()),
+        (
+            "client".into(),
+            i64::try_from(client_id_u64(tx.client_id))
+                .unwrap_or(i64::MAX)
+                .into(),
+        ),
+        ("tx".into(), i64::from(tx.id).into()),
+        ("amount".into(), optional(tx.amount)),
+        ("tenant".into(), optional(tx.tenant.clone())),
+        ("description".into(), optional(tx.description.clone())),
+        ("reference".into(), optional(tx.reference.clone())),
+        ("category".into(), optional(tx.category.clone())),
+        (
+            "timestamp".into(),
+            optional(tx.timestamp.and_then(|at| i64::try_from(at).ok())),
+        ),
+        ("metadata".into(), metadata.into()),
+    ])
+}
+
+fn account_map(account: &Account) -> Map {
+    Map::from_iter([
+        ("available".into(), account.available().into()),
+        ("held".into(), account.held().into()),
+        ("total".into(), account.total().into()),
+        ("locked".into(), account.locked().into()),
+        (
+            "transactions".into(),
+            i64::try_from(account.transactions())
+                .unwrap_or(i64::MAX)
+                .into(),
+        ),
+        (
+            "disputes".into(),
+            i64::try_from(account.disputes()).unwrap_or(i64::MAX).into(),
+        ),
+        (
+            "role".into(),
+            optional(account.role().map(|role| role.as_str())),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Action;
+
+    const SCRIPT: &str = r#"
+        fn screen(tx, account) {
+            if tx.type == "withdrawal" && tx.amount > account.available * 0.9 {
+                return "withdraws nearly all funds";
+            }
+            if tx.amount != () && tx.amount > 5000.0 {
+                return #{ review: "large" };
+            }
+        }
+    "#;
+
+    #[test]
+    fn hook_accepts_rejects_and_annotates() {
+        let script = TransactionScript::compile(SCRIPT).unwrap();
+        let mut account = Account::new();
+        assert!(account.deposit(10000.0).is_ok());
+
+        let mut deposit = Transaction::new(Action::Deposit, 1, 1, Some(100.0));
+        assert!(script.screen(&mut deposit, None).is_ok());
+        assert!(deposit.metadata.is_empty());
+        let mut dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        assert!(script.screen(&mut dispute, Some(&account)).is_ok());
+
+        let mut withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(9500.0));
+        assert_eq!(
+            script.screen(&mut withdrawal, Some(&account)),
+            Err(AccountManagerError::ScriptRejected {
+                id: 2,
+                reason: "withdraws nearly all funds".to_string()
+            })
+        );
+
+        let mut withdrawal = Transaction::new(Action::Withdrawal, 1, 3, Some(6000.0));
+        assert!(script.screen(&mut withdrawal, Some(&account)).is_ok());
+        assert_eq!(withdrawal.metadata["review"], "large");
+    }
+
+    #[test]
+    fn script_errors_fail_the_transaction() {
+        let script = TransactionScript::compile(
+            "fn screen(tx, account) { if tx.client == 1 { loop {} } account.available }",
+        )
+        .unwrap();
+        let mut looping = Transaction::new(Action::Deposit, 1, 1, Some(1.0));
+        assert_eq!(
+            script.screen(&mut looping, None).unwrap_err().kind(),
+            "script_failed"
+        );
+        let mut unknown = Transaction::new(Action::Deposit, 2, 2, Some(1.0));
+        assert!(matches!(
+            script.screen(&mut unknown, Some(&Account::new())),
+            Err(AccountManagerError::ScriptFailed { id: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn scripts_without_the_hook_are_refused() {
+        assert_eq!(
+            TransactionScript::compile("fn check(tx) {}").unwrap_err(),
+            ScriptError::MissingHook
+        );
+        assert!(matches!(
+            TransactionScript::compile("fn screen(tx, account) {"),
+            Err(ScriptError::Invalid { .. })
+        ));
+    }
+}
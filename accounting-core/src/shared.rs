@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::account_manager::{process_transaction, AccountManager, AccountManagerError};
+use crate::error_code::ErrorCode;
+use crate::policy::Policy;
+use crate::tx_cache::TxCache;
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+pub const DEFAULT_ATTEMPTS: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum SharedError {
+    #[error("{0}")]
+    Rejected(#[from] AccountManagerError),
+
+    #[error("Shared books don't support {action} transactions")]
+    Unsupported { action: &'static str },
+
+    #[error("Client {client_id} was changed concurrently {attempts} times, giving up")]
+    Conflict {
+        client_id: ClientId,
+        attempts: usize,
+    },
+
+    #[error("Client {client_id} has a corrupt record: {source}")]
+    Corrupt {
+        client_id: ClientId,
+        source: serde_json::Error,
+    },
+
+    #[error("Shared state backend failed: {0}")]
+    Backend(String),
+}
+
+impl SharedError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SharedError::Rejected(err) => err.kind(),
+            SharedError::Unsupported { .. } => "unsupported",
+            SharedError::Conflict { .. } => "conflict",
+            SharedError::Corrupt { .. } => "corrupt",
+            SharedError::Backend(_) => "backend",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SharedError::Rejected(err) => err.code(),
+            SharedError::Unsupported { .. } => ErrorCode::NotPermitted,
+            SharedError::Conflict { .. }
+            | SharedError::Corrupt { .. }
+            | SharedError::Backend(_) => ErrorCode::Io,
+        }
+    }
+}
+
+pub type SharedResult<T> = Result<T, SharedError>;
+
+// Storage of the client records of a SharedBook. Every write of a record
+// gives it a new version, so writers can tell if it changed since they read it.
+pub trait SharedBackend {
+    // The version and record of the client, none if it was never written.
+    fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>>;
+
+    // Writes the record if the client is still at the `expected` version,
+    // false if another writer came first.
+    fn compare_and_swap(
+        &self,
+        client_id: ClientId,
+        expected: Option<u64>,
+        record: &[u8],
+    ) -> SharedResult<bool>;
+
+    // The client whose deposit or withdrawal last used the tx id.
+    fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>>;
+
+    fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()>;
+}
+
+impl<B: SharedBackend + ?Sized> SharedBackend for Arc<B> {
+    fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>> {
+        (**self).load(client_id)
+    }
+
+    fn compare_and_swap(
+        &self,
+        client_id: ClientId,
+        expected: Option<u64>,
+        record: &[u8],
+    ) -> SharedResult<bool> {
+        (**self).compare_and_swap(client_id, expected, record)
+    }
+
+    fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>> {
+        (**self).owner(tx_id)
+    }
+
+    fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()> {
+        (**self).set_owner(tx_id, client_id)
+    }
+}
+
+impl<B: SharedBackend + ?Sized> SharedBackend for Box<B> {
+    fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>> {
+        (**self).load(client_id)
+    }
+
+    fn compare_and_swap(
+        &self,
+        client_id: ClientId,
+        expected: Option<u64>,
+        record: &[u8],
+    ) -> SharedResult<bool> {
+        (**self).compare_and_swap(client_id, expected, record)
+    }
+
+    fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>> {
+        (**self).owner(tx_id)
+    }
+
+    fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()> {
+        (**self).set_owner(tx_id, client_id)
+    }
+}
+
+// A backend within one process, for tests and a single instance.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    records: Mutex<HashMap<ClientId, (u64, Vec<u8>)>>,
+    owners: Mutex<HashMap<TransactionId, ClientId>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SharedBackend for MemoryBackend {
+    fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>> {
+        let records = self.records.lock().expect("records poisoned");
+        Ok(records.get(&client_id).cloned())
+    }
+
+    fn compare_and_swap(
+        &self,
+        client_id: ClientId,
+        expected: Option<u64>,
+        record: &[u8],
+    ) -> SharedResult<bool> {
+        let mut records = self.records.lock().expect("records poisoned");
+        let version = records.get(&client_id).map(|(version, _)| *version);
+        if version != expected {
+            return Ok(false);
+        }
+        records.insert(client_id, (version.map_or(1, |v| v + 1), record.to_vec()));
+        Ok(true)
+    }
+
+    fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>> {
+        let owners = self.owners.lock().expect("owners poisoned");
+        Ok(owners.get(&tx_id).copied())
+    }
+
+    fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()> {
+        let mut owners = self.owners.lock().expect("owners poisoned");
+        owners.insert(tx_id, client_id);
+        Ok(())
+    }
+}
+
+// Every client is a Redis hash `{prefix}{client_id}` with the fields
+// `version` and `record`. Writes WATCH the hash, so the transaction of a
+// writer that lost the race is aborted. The owners of the tx ids are the hash
+// `{prefix}txs`.
+#[cfg(feature = "redis")]
+pub struct RedisBackend {
+    connection: Mutex<redis::Connection>,
+    prefix: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBackend {
+    pub fn connect(url: &str, prefix: &str) -> SharedResult<Self> {
+        let connection = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .map_err(backend_error)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn key(&self, client_id: ClientId) -> String {
+        format!("{}{client_id}", self.prefix)
+    }
+
+    fn owners_key(&self) -> String {
+        format!("{}txs", self.prefix)
+    }
+}
+
+#[cfg(feature = "redis")]
+fn backend_error(err: redis::RedisError) -> SharedError {
+    SharedError::Backend(err.to_string())
+}
+
+#[cfg(feature = "redis")]
+impl SharedBackend for RedisBackend {
+    fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>> {
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        let (version, record): (Option<u64>, Option<Vec<u8>>) = redis::cmd("HMGET")
+            .arg(self.key(client_id))
+            .arg("version")
+            .arg("record")
+            .query(&mut *connection)
+            .map_err(backend_error)?;
+        Ok(version.zip(record))
+    }
+
+    fn compare_and_swap(
+        &self,
+        client_id: ClientId,
+        expected: Option<u64>,
+        record: &[u8],
+    ) -> SharedResult<bool> {
+        let key = self.key(client_id);
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        let connection = &mut *connection;
+        redis::cmd("WATCH")
+            .arg(&key)
+            .exec(connection)
+            .map_err(backend_error)?;
+        let version: Option<u64> = redis::cmd("HGET")
+            .arg(&key)
+            .arg("version")
+            .query(connection)
+            .map_err(backend_error)?;
+        if version != expected {
+            redis::cmd("UNWATCH")
+                .exec(connection)
+                .map_err(backend_error)?;
+            return Ok(false);
+        }
+        // An aborted transaction answers nil.
+        let written: Option<()> = redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("version")
+            .arg(version.map_or(1, |v| v + 1))
+            .arg("record")
+            .arg(record)
+            .ignore()
+            .query(connection)
+            .map_err(backend_error)?;
+        Ok(written.is_some())
+    }
+
+    fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>> {
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        redis::cmd("HGET")
+            .arg(self.owners_key())
+            .arg(tx_id)
+            .query(&mut *connection)
+            .map_err(backend_error)
+    }
+
+    fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()> {
+        let mut connection = self.connection.lock().expect("connection poisoned");
+        redis::cmd("HSET")
+            .arg(self.owners_key())
+            .arg(tx_id)
+            .arg(client_id)
+            .exec(&mut *connection)
+            .map_err(backend_error)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ClientRecord {
+    account: Option<Account>,
+    tx_cache: TxCache,
+}
+
+// Transactions that only change the account of their client.
+fn shared(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::Deposit
+            | Action::Withdrawal
+            | Action::Dispute
+            | Action::Resolve
+            | Action::Chargeback
+            | Action::OpenAccount
+    )
+}
+
+// Accounts and the tx cache kept in a backend shared by several instances,
+// so stateless instances can serve one book. A transaction reads the record
+// of its client, applies to it like an AccountManager and writes it back only
+// if nobody changed it in between, otherwise it starts over. Transactions of
+// one client are so applied one after another, whichever instance gets them.
+//
+// Tx ids are shared by all clients like in an AccountManager: the backend
+// keeps the client of every deposit and withdrawal, so disputing the
+// transaction of another client is unauthorized. Idempotency keys, ledgers,
+// cases and the other books of an AccountManager aren't shared.
+pub struct SharedBook<B> {
+    backend: B,
+    policy: Policy,
+    attempts: usize,
+}
+
+impl<B: SharedBackend> SharedBook<B> {
+    pub fn new(backend: B) -> Self {
+        Self::with_policy(backend, Policy::default())
+    }
+
+    pub fn with_policy(backend: B, policy: Policy) -> Self {
+        Self {
+            backend,
+            policy,
+            attempts: DEFAULT_ATTEMPTS,
+        }
+    }
+
+    // How often a transaction is tried when its client changes concurrently.
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    pub fn account(&self, client_id: ClientId) -> SharedResult<Option<Account>> {
+        Ok(self.load(client_id)?.1.account)
+    }
+
+    // Applies to the transactions after it.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    pub fn process_transaction(&self, tx: Transaction) -> SharedResult<()> {
+        if !shared(&tx.action) {
+            return Err(SharedError::Unsupported {
+                action: tx.action.as_str(),
+            });
+        }
+        let (client_id, tx_id) = (tx.client_id, tx.id);
+        let disputes = matches!(
+            tx.action,
+            Action::Dispute | Action::Resolve | Action::Chargeback
+        );
+        if disputes {
+            match self.backend.owner(tx_id)? {
+                Some(owner_id) if owner_id != client_id => {
+                    return Err(AccountManagerError::Unauthorized {
+                        client_id,
+                        owner_id,
+                    }
+                    .into());
+                }
+                _ => {}
+            }
+        }
+        let creates = matches!(tx.action, Action::Deposit | Action::Withdrawal);
+        for _ in 0..self.attempts {
+            let (version, record) = self.load(client_id)?;
+            let mut book = AccountManager::client_book(
+                self.policy.clone(),
+                client_id,
+                record.account,
+                record.tx_cache,
+            );
+            process_transaction(&mut book, tx.clone())?;
+            let (account, tx_cache) = book.into_client_book(client_id);
+            let record = serde_json::to_vec(&ClientRecord { account, tx_cache })
+                .map_err(|source| SharedError::Corrupt { client_id, source })?;
+            if self.backend.compare_and_swap(client_id, version, &record)? {
+                if creates {
+                    self.backend.set_owner(tx_id, client_id)?;
+                }
+                return Ok(());
+            }
+        }
+        Err(SharedError::Conflict {
+            client_id,
+            attempts: self.attempts,
+        })
+    }
+
+    fn load(&self, client_id: ClientId) -> SharedResult<(Option<u64>, ClientRecord)> {
+        match self.backend.load(client_id)? {
+            Some((version, record)) => serde_json::from_slice(&record)
+                .map(|record| (Some(version), record))
+                .map_err(|source| SharedError::Corrupt { client_id, source }),
+            None => Ok((None, ClientRecord::default())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    fn deposit(client_id: ClientId, tx_id: u32, amount: f64) -> Transaction {
+        Transaction::new(Action::Deposit, client_id, tx_id, Some(amount))
+    }
+
+    #[test]
+    fn applies_transactions_to_the_shared_records() {
+        let book = SharedBook::new(MemoryBackend::new());
+        book.process_transaction(deposit(1, 1, 5.0)).unwrap();
+        book.process_transaction(Transaction::new(Action::Dispute, 1, 1, None))
+            .unwrap();
+
+        let account = book.account(1).unwrap().unwrap();
+        assert_eq!((account.available(), account.held()), (0.0, 5.0));
+        assert!(book.account(2).unwrap().is_none());
+        let err = book
+            .process_transaction(Transaction::new(Action::Dispute, 2, 1, None))
+            .unwrap_err();
+        assert_eq!(err.kind(), "unauthorized");
+        let err = book
+            .process_transaction(Transaction::new(Action::Dispute, 1, 9, None))
+            .unwrap_err();
+        assert_eq!(err.kind(), "transaction_not_found");
+        let err = book
+            .process_transaction(Transaction::new(Action::HoldInEscrow, 1, 2, Some(1.0)))
+            .unwrap_err();
+        assert_eq!(err.kind(), "unsupported");
+    }
+
+    // Another instance writes the client between every load and write.
+    struct Racing {
+        inner: MemoryBackend,
+        races: AtomicUsize,
+    }
+
+    impl SharedBackend for Racing {
+        fn load(&self, client_id: ClientId) -> SharedResult<Option<(u64, Vec<u8>)>> {
+            self.inner.load(client_id)
+        }
+
+        fn compare_and_swap(
+            &self,
+            client_id: ClientId,
+            expected: Option<u64>,
+            record: &[u8],
+        ) -> SharedResult<bool> {
+            let races = self
+                .races
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |races| {
+                    races.checked_sub(1)
+                });
+            if races.is_ok() {
+                // Rewrites the record as it is, which only changes its version.
+                let (version, record) = match self.inner.load(client_id)? {
+                    Some((version, record)) => (Some(version), record),
+                    None => (None, serde_json::to_vec(&ClientRecord::default()).unwrap()),
+                };
+                self.inner.compare_and_swap(client_id, version, &record)?;
+            }
+            self.inner.compare_and_swap(client_id, expected, record)
+        }
+
+        fn owner(&self, tx_id: TransactionId) -> SharedResult<Option<ClientId>> {
+            self.inner.owner(tx_id)
+        }
+
+        fn set_owner(&self, tx_id: TransactionId, client_id: ClientId) -> SharedResult<()> {
+            self.inner.set_owner(tx_id, client_id)
+        }
+    }
+
+    #[test]
+    fn retries_and_gives_up_on_concurrent_changes() {
+        let racing = |races| Racing {
+            inner: MemoryBackend::new(),
+            races: AtomicUsize::new(races),
+        };
+        let book = SharedBook::new(racing(2)).with_attempts(3);
+        book.process_transaction(deposit(1, 1, 1.0)).unwrap();
+        assert_eq!(book.account(1).unwrap().unwrap().available(), 1.0);
+
+        let book = SharedBook::new(racing(3)).with_attempts(3);
+        let err = book.process_transaction(deposit(1, 1, 1.0)).unwrap_err();
+        assert!(matches!(
+            err,
+            SharedError::Conflict {
+                client_id: 1,
+                attempts: 3
+            }
+        ));
+    }
+
+    #[test]
+    fn concurrent_instances_lose_no_deposits() {
+        let book = Arc::new(SharedBook::new(MemoryBackend::new()).with_attempts(usize::MAX));
+        let instances: Vec<_> = (0..4)
+            .map(|instance| {
+                let book = Arc::clone(&book);
+                thread::spawn(move || {
+                    for n in 0..50 {
+                        let tx_id = instance * 50 + n;
+                        book.process_transaction(deposit(1, tx_id, 1.0)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for instance in instances {
+            instance.join().unwrap();
+        }
+        assert_eq!(book.account(1).unwrap().unwrap().available(), 200.0);
+    }
+}
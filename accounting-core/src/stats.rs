@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter::Sum;
+use std::mem::size_of;
+use std::ops::AddAssign;
+
+use crate::account_manager::AccountManagerResult;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProcessingStats {
+    processed: u64,
+    rejected: BTreeMap<&'static str, u64>,
+}
+
+impl ProcessingStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record<T>(&mut self, result: &AccountManagerResult<T>) {
+        self.processed += 1;
+        if let Err(err) = result {
+            *self.rejected.entry(err.kind()).or_default() += 1;
+        }
+    }
+
+    // A row that was rejected before it became a transaction.
+    pub fn skip(&mut self, kind: &'static str) {
+        self.processed += 1;
+        *self.rejected.entry(kind).or_default() += 1;
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+
+    pub fn rejected_by_kind(&self) -> &BTreeMap<&'static str, u64> {
+        &self.rejected
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "processed {} transactions, rejected {}",
+            self.processed,
+            self.rejected()
+        )?;
+        if !self.rejected.is_empty() {
+            let counts: Vec<String> = self
+                .rejected
+                .iter()
+                .map(|(kind, count)| format!("{kind}: {count}"))
+                .collect();
+            write!(f, " ({})", counts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Estimated bytes of a hash table with `capacity` slots of T, one control
+// byte per slot.
+pub(crate) fn table_bytes<T>(capacity: usize) -> usize {
+    capacity * (size_of::<T>() + 1)
+}
+
+// The size of the books of an AccountManager, for monitoring long runs. The
+// memory is an estimate of its maps and logs, not what the allocator holds.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ManagerStats {
+    pub accounts: usize,
+    pub cached_transactions: usize,
+    pub open_disputes: usize,
+    pub memory_bytes: usize,
+}
+
+impl AddAssign for ManagerStats {
+    fn add_assign(&mut self, other: Self) {
+        self.accounts += other.accounts;
+        self.cached_transactions += other.cached_transactions;
+        self.open_disputes += other.open_disputes;
+        self.memory_bytes += other.memory_bytes;
+    }
+}
+
+impl Sum for ManagerStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut sum, stats| {
+            sum += stats;
+            sum
+        })
+    }
+}
+
+impl fmt::Display for ManagerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} accounts, {} cached transactions, {} open disputes, ~{:.1} MiB",
+            self.accounts,
+            self.cached_transactions,
+            self.open_disputes,
+            self.memory_bytes as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::AccountError;
+    use crate::account_manager::AccountManagerError;
+
+    #[test]
+    fn counts_rejections_by_kind() {
+        let mut stats = ProcessingStats::new();
+        stats.record::<()>(&Ok(()));
+        stats.record::<()>(&Err(AccountManagerError::TransactionNotFound { id: 1 }));
+        stats.record::<()>(&Err(AccountManagerError::Account(AccountError::Locked)));
+        stats.record::<()>(&Err(AccountManagerError::Account(AccountError::Locked)));
+
+        assert_eq!(stats.processed(), 4);
+        assert_eq!(stats.rejected(), 3);
+        assert_eq!(stats.rejected_by_kind().get("locked"), Some(&2));
+        assert_eq!(
+            stats.to_string(),
+            "processed 4 transactions, rejected 3 (locked: 2, transaction_not_found: 1)"
+        );
+    }
+
+    #[test]
+    fn skipped_rows_count_as_rejected() {
+        let mut stats = ProcessingStats::new();
+        stats.record::<()>(&Ok(()));
+        stats.skip("unknown_action");
+
+        assert_eq!(
+            stats.to_string(),
+            "processed 2 transactions, rejected 1 (unknown_action: 1)"
+        );
+    }
+
+    #[test]
+    fn summary_without_rejections() {
+        let mut stats = ProcessingStats::new();
+        stats.record::<()>(&Ok(()));
+
+        assert_eq!(stats.to_string(), "processed 1 transactions, rejected 0");
+    }
+
+    #[test]
+    fn sums_manager_stats() {
+        let stats = ManagerStats {
+            accounts: 2,
+            cached_transactions: 10,
+            open_disputes: 1,
+            memory_bytes: 1024 * 1024,
+        };
+        let total: ManagerStats = [stats, stats].into_iter().sum();
+        assert_eq!(
+            total.to_string(),
+            "4 accounts, 20 cached transactions, 2 open disputes, ~2.0 MiB"
+        );
+    }
+}
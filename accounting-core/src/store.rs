@@ -0,0 +1,528 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, RwLock, RwLockWriteGuard};
+
+use crate::account::Account;
+use crate::account_manager::{
+    apply_transaction_into, validate_transaction, AccountManager, AccountManagerError,
+    AccountManagerResult,
+};
+use crate::history::BalancePoint;
+use crate::period::ClosedPeriod;
+use crate::policy::Policy;
+use crate::types::{Action, ClientId, Timestamp, Transaction, TransactionId};
+
+pub const DEFAULT_SHARDS: usize = 64;
+
+// Internally synchronized accounts for request handlers that apply
+// transactions concurrently. Clients are spread over shards, each an
+// AccountManager behind its own lock, so transactions of clients in different
+// shards don't wait for each other.
+//
+// Transaction ids and idempotency keys are shared by all clients. Their
+// indexes are sharded as well, so the results are the same as with a single
+// AccountManager. Locks are always taken in the order client shards (by
+// index), idempotency keys, transaction owners, which rules out deadlocks.
+// Releasing an escrow to a client of another shard locks both shards.
+pub struct ShardedStore {
+    shards: Vec<RwLock<AccountManager>>,
+    idempotency_keys: Vec<Mutex<HashSet<String>>>,
+    owners: Vec<RwLock<HashMap<TransactionId, ClientId>>>,
+    policy: Policy,
+}
+
+impl ShardedStore {
+    pub fn new(shards: usize) -> Self {
+        Self::with_policy(shards, Policy::default())
+    }
+
+    pub fn with_policy(shards: usize, policy: Policy) -> Self {
+        let shards = shards.max(1);
+        Self {
+            shards: (0..shards)
+                .map(|_| RwLock::new(AccountManager::with_policy(policy.clone())))
+                .collect(),
+            idempotency_keys: (0..shards).map(|_| Mutex::default()).collect(),
+            owners: (0..shards).map(|_| RwLock::default()).collect(),
+            policy,
+        }
+    }
+
+    pub fn with_history(self) -> Self {
+        for shard in &self.shards {
+            shard.write().expect("shard poisoned").enable_history();
+        }
+        self
+    }
+
+    // The history of the client as recorded by its shard. Sequences count
+    // the transactions of that shard.
+    pub fn history(&self, client_id: ClientId) -> Vec<BalancePoint> {
+        let account_manager = self.shard(client_id).read().expect("shard poisoned");
+        account_manager.history(client_id).cloned().collect()
+    }
+
+    fn shard_index(&self, client_id: ClientId) -> usize {
+        client_id as usize % self.shards.len()
+    }
+
+    fn shard(&self, client_id: ClientId) -> &RwLock<AccountManager> {
+        &self.shards[self.shard_index(client_id)]
+    }
+
+    // The shard of the client and, if the transaction releases an escrow to a
+    // client of another shard, the shard of the counterparty.
+    fn lock_shards(
+        &self,
+        tx: &Transaction,
+    ) -> (
+        RwLockWriteGuard<'_, AccountManager>,
+        Option<RwLockWriteGuard<'_, AccountManager>>,
+    ) {
+        let write = |index: usize| self.shards[index].write().expect("shard poisoned");
+        let client = self.shard_index(tx.client_id);
+        loop {
+            let counterparty = match tx.action {
+                Action::ReleaseEscrow => self
+                    .shard(tx.client_id)
+                    .read()
+                    .expect("shard poisoned")
+                    .escrow(tx.id)
+                    .map(|escrow| self.shard_index(escrow.counterparty))
+                    .filter(|index| *index != client),
+                _ => None,
+            };
+            let Some(counterparty) = counterparty else {
+                return (write(client), None);
+            };
+            let (account_manager, receiver) = match client < counterparty {
+                true => {
+                    let account_manager = write(client);
+                    (account_manager, write(counterparty))
+                }
+                false => {
+                    let receiver = write(counterparty);
+                    (write(client), receiver)
+                }
+            };
+            // The escrow may have been settled and held again in between.
+            let unchanged = account_manager
+                .escrow(tx.id)
+                .is_some_and(|escrow| self.shard_index(escrow.counterparty) == counterparty);
+            if unchanged {
+                return (account_manager, Some(receiver));
+            }
+        }
+    }
+
+    fn idempotency_keys(&self, key: &str) -> &Mutex<HashSet<String>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.idempotency_keys[hasher.finish() as usize % self.idempotency_keys.len()]
+    }
+
+    fn owners(&self, tx_id: TransactionId) -> &RwLock<HashMap<TransactionId, ClientId>> {
+        &self.owners[tx_id as usize % self.owners.len()]
+    }
+
+    pub fn process_transaction(&self, mut tx: Transaction) -> AccountManagerResult<()> {
+        validate_transaction(&self.policy, &mut tx)?;
+        if tx.action == Action::ClosePeriod {
+            self.close_period(tx.reference, tx.timestamp);
+            return Ok(());
+        }
+
+        let (mut account_manager, receiver) = self.lock_shards(&tx);
+        let key = tx.idempotency_key.take();
+        let mut keys = key
+            .as_deref()
+            .map(|key| self.idempotency_keys(key).lock().expect("shard poisoned"));
+        if let (Some(key), Some(keys)) = (&key, &keys) {
+            if keys.contains(key) {
+                return Err(AccountManagerError::Duplicate { key: key.clone() });
+            }
+        }
+
+        let (tx_id, client_id, action) = (tx.id, tx.client_id, tx.action.clone());
+        let owners = self.owners(tx_id);
+        if matches!(
+            action,
+            Action::Dispute | Action::Resolve | Action::Chargeback
+        ) {
+            let owner = owners.read().expect("shard poisoned").get(&tx_id).copied();
+            if let Some(owner_id) = owner.filter(|owner_id| *owner_id != client_id) {
+                return Err(AccountManagerError::Unauthorized {
+                    client_id,
+                    owner_id,
+                });
+            }
+        }
+
+        // Releases across shards go through the same checks and records as
+        // any transaction, only the counterparty is credited in its shard.
+        let mut receiver = receiver;
+        apply_transaction_into(&mut account_manager, receiver.as_deref_mut(), tx)?;
+
+        // Deposits and withdrawals take over the transaction id, chargebacks
+        // release it.
+        if matches!(
+            action,
+            Action::Deposit | Action::Withdrawal | Action::Chargeback
+        ) {
+            let mut owners = owners.write().expect("shard poisoned");
+            match account_manager.transaction_owner(tx_id) {
+                Some(owner_id) => {
+                    owners.insert(tx_id, owner_id);
+                }
+                None if owners.get(&tx_id) == Some(&client_id) => {
+                    owners.remove(&tx_id);
+                }
+                None => {}
+            }
+        }
+        // Erasures release every transaction id of the client.
+        if action == Action::EraseClient {
+            for owners in &self.owners {
+                owners
+                    .write()
+                    .expect("shard poisoned")
+                    .retain(|_, owner_id| *owner_id != client_id);
+            }
+        }
+        if let (Some(key), Some(keys)) = (key, keys.as_mut()) {
+            keys.insert(key);
+        }
+        Ok(())
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<Account> {
+        let account_manager = self.shard(client_id).read().expect("shard poisoned");
+        account_manager.account(client_id).cloned()
+    }
+
+    // Shards are read one after another, so concurrent writers only have to
+    // wait for the shard currently being copied.
+    pub fn accounts(&self) -> Vec<(ClientId, Account)> {
+        let mut accounts: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| shard.read().expect("shard poisoned").accounts())
+            .collect();
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        accounts
+    }
+
+    // Closes the period in every shard at once, taking the locks in index
+    // order, so no transaction lands in different periods of two shards.
+    // Transaction ids the close compacted are released.
+    pub fn close_period(&self, label: Option<String>, closed_at: Option<Timestamp>) {
+        let mut shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.write().expect("shard poisoned"))
+            .collect();
+        let mut compacted = 0;
+        for account_manager in &mut shards {
+            compacted += account_manager
+                .close_period(label.clone(), closed_at)
+                .transactions;
+        }
+        if compacted == 0 {
+            return;
+        }
+        for owners in &self.owners {
+            owners
+                .write()
+                .expect("shard poisoned")
+                .retain(|tx_id, owner_id| {
+                    let account_manager = &shards[self.shard_index(*owner_id)];
+                    account_manager.transaction_owner(*tx_id) == Some(*owner_id)
+                });
+        }
+    }
+
+    pub fn closed_periods(&self) -> Vec<ClosedPeriod> {
+        let mut periods: Vec<ClosedPeriod> = Vec::new();
+        for shard in &self.shards {
+            let account_manager = shard.read().expect("shard poisoned");
+            for (index, period) in account_manager.periods().closed().iter().enumerate() {
+                match periods.get_mut(index) {
+                    Some(merged) => merged.clients.extend(period.clients.iter().cloned()),
+                    None => periods.push(period.clone()),
+                }
+            }
+        }
+        for period in &mut periods {
+            period.clients.sort_by_key(|client| client.client_id);
+        }
+        periods
+    }
+
+    pub fn verify_invariants(&self) -> AccountManagerResult<()> {
+        for shard in &self.shards {
+            shard.read().expect("shard poisoned").verify_invariants()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ShardedStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::account_manager::process_transaction;
+    use crate::policy::WithdrawalDisputePolicy;
+    use crate::rules::{Consequence, Rule, Rules};
+
+    fn deposit(client_id: ClientId, id: TransactionId, amount: f64) -> Transaction {
+        Transaction::new(Action::Deposit, client_id, id, Some(amount))
+    }
+
+    #[test]
+    fn clients_are_updated_concurrently() {
+        let store = Arc::new(ShardedStore::new(4));
+        let handles: Vec<_> = (0..8)
+            .map(|client_id: ClientId| {
+                let store = Arc::clone(&store);
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let id = client_id as TransactionId * 1000 + i;
+                        store
+                            .process_transaction(deposit(client_id, id, 1.0))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let accounts = store.accounts();
+        assert_eq!(accounts.len(), 8);
+        assert!(accounts.iter().all(|(_, account)| account.total() == 100.0));
+        assert!(store.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn transactions_of_clients_in_other_shards_are_protected() {
+        let store = ShardedStore::new(2);
+        assert!(store.process_transaction(deposit(1, 7, 2.0)).is_ok());
+        let dispute = Transaction::new(Action::Dispute, 2, 7, None);
+        assert_eq!(
+            store.process_transaction(dispute),
+            Err(AccountManagerError::Unauthorized {
+                client_id: 2,
+                owner_id: 1
+            })
+        );
+
+        let dispute = Transaction::new(Action::Dispute, 1, 7, None);
+        assert!(store.process_transaction(dispute).is_ok());
+        let chargeback = Transaction::new(Action::Chargeback, 1, 7, None);
+        assert!(store.process_transaction(chargeback).is_ok());
+        let dispute = Transaction::new(Action::Dispute, 2, 7, None);
+        assert_eq!(
+            store.process_transaction(dispute),
+            Err(AccountManagerError::TransactionNotFound { id: 7 })
+        );
+        assert!(store.account(1).unwrap().locked());
+    }
+
+    #[test]
+    fn disputable_withdrawals_are_owned_by_their_client() {
+        let store = ShardedStore::with_policy(
+            2,
+            Policy {
+                withdrawal_dispute: WithdrawalDisputePolicy::Reverse,
+                ..Policy::default()
+            },
+        );
+        assert!(store.process_transaction(deposit(1, 1, 2.0)).is_ok());
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(1.0));
+        assert!(store.process_transaction(withdrawal).is_ok());
+        let dispute = Transaction::new(Action::Dispute, 2, 2, None);
+        assert_eq!(
+            store.process_transaction(dispute),
+            Err(AccountManagerError::Unauthorized {
+                client_id: 2,
+                owner_id: 1
+            })
+        );
+        let dispute = Transaction::new(Action::Dispute, 1, 2, None);
+        assert!(store.process_transaction(dispute).is_ok());
+        assert_eq!(store.account(1).unwrap().total(), 2.0);
+    }
+
+    #[test]
+    fn idempotency_keys_are_shared_by_all_shards() {
+        let store = ShardedStore::new(2);
+        let first = Transaction {
+            idempotency_key: Some("abc".to_string()),
+            ..deposit(1, 1, 1.0)
+        };
+        let retry = Transaction {
+            idempotency_key: Some("abc".to_string()),
+            ..deposit(2, 2, 1.0)
+        };
+        assert!(store.process_transaction(first).is_ok());
+        assert_eq!(
+            store.process_transaction(retry),
+            Err(AccountManagerError::Duplicate {
+                key: "abc".to_string()
+            })
+        );
+        assert!(store.account(2).is_none());
+    }
+
+    #[test]
+    fn escrows_are_released_across_shards() {
+        let store = ShardedStore::new(4);
+        assert!(store.process_transaction(deposit(1, 1, 5.0)).is_ok());
+        let escrow = Transaction {
+            counterparty: Some(2),
+            ..Transaction::new(Action::HoldInEscrow, 1, 2, Some(3.0))
+        };
+        assert!(store.process_transaction(escrow).is_ok());
+        let release = Transaction::new(Action::ReleaseEscrow, 1, 2, None);
+        assert!(store.process_transaction(release.clone()).is_ok());
+
+        assert_eq!(store.account(1).unwrap().total(), 2.0);
+        assert_eq!(store.account(2).unwrap().available(), 3.0);
+        assert!(store.verify_invariants().is_ok());
+        assert_eq!(
+            store.process_transaction(release),
+            Err(AccountManagerError::EscrowNotFound { id: 2 })
+        );
+    }
+
+    #[test]
+    fn releases_across_shards_match_a_single_account_manager() {
+        let rule = |name: &str, action, disputes_at_least, amount_above, then| Rule {
+            name: name.to_string(),
+            action: Some(action),
+            tier: None,
+            role: None,
+            amount_above,
+            disputes_at_least,
+            then,
+        };
+        let policy = Policy {
+            rules: Arc::new(
+                Rules::new(vec![
+                    rule(
+                        "disputed payers",
+                        Action::ReleaseEscrow,
+                        Some(1),
+                        None,
+                        Consequence::Reject,
+                    ),
+                    rule(
+                        "large withdrawals",
+                        Action::Withdrawal,
+                        None,
+                        Some(5.0),
+                        Consequence::Freeze,
+                    ),
+                ])
+                .unwrap(),
+            ),
+            ..Policy::default()
+        };
+        let escrow = |client_id, id, amount, counterparty| Transaction {
+            counterparty: Some(counterparty),
+            ..Transaction::new(Action::HoldInEscrow, client_id, id, Some(amount))
+        };
+        let release = |client_id, id| Transaction::new(Action::ReleaseEscrow, client_id, id, None);
+        let txs = [
+            deposit(1, 1, 10.0),
+            deposit(2, 2, 5.0),
+            escrow(1, 3, 3.0, 2),
+            release(1, 3),
+            deposit(3, 4, 4.0),
+            Transaction::new(Action::Dispute, 3, 4, None),
+            deposit(3, 6, 2.0),
+            escrow(3, 5, 1.0, 1),
+            release(3, 5),
+            Transaction::new(Action::Withdrawal, 2, 7, Some(6.0)),
+            escrow(2, 8, 1.0, 1),
+            Transaction::new(Action::Freeze, 1, 9, None),
+        ];
+
+        let store = ShardedStore::with_policy(4, policy.clone()).with_history();
+        let mut account_manager = AccountManager::with_policy(policy);
+        account_manager.enable_history();
+        for tx in txs {
+            assert_eq!(
+                store.process_transaction(tx.clone()),
+                process_transaction(&mut account_manager, tx)
+            );
+        }
+        let balances = |mut accounts: Vec<(ClientId, Account)>| {
+            accounts.sort_by_key(|(client_id, _)| *client_id);
+            accounts
+                .into_iter()
+                .map(|(client_id, account)| {
+                    let balance = (account.available(), account.held(), account.escrow());
+                    (client_id, balance, account.locked(), account.transactions())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            balances(store.accounts()),
+            balances(account_manager.accounts())
+        );
+        assert!(store.account(2).unwrap().locked());
+        assert_eq!(store.account(3).unwrap().escrow(), 1.0);
+        // Sequences count per shard, everything else is the same.
+        let points = |points: Vec<BalancePoint>| {
+            points
+                .into_iter()
+                .map(|point| BalancePoint {
+                    sequence: 0,
+                    ..point
+                })
+                .collect::<Vec<_>>()
+        };
+        for client_id in 1..=3 {
+            assert_eq!(
+                points(store.history(client_id)),
+                points(account_manager.history(client_id).cloned().collect())
+            );
+        }
+        assert_eq!(store.history(2).len(), 3);
+        assert!(store.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn periods_are_closed_in_every_shard() {
+        let store = ShardedStore::new(4);
+        store.process_transaction(deposit(1, 1, 2.0)).unwrap();
+        store.process_transaction(deposit(2, 2, 3.0)).unwrap();
+        let close = Transaction {
+            reference: Some("2024-01".to_string()),
+            ..Transaction::new(Action::ClosePeriod, 0, 0, None)
+        };
+        store.process_transaction(close).unwrap();
+        store.process_transaction(deposit(2, 3, 1.0)).unwrap();
+
+        let periods = store.closed_periods();
+        assert_eq!(periods.len(), 1);
+        assert_eq!(periods[0].label.as_deref(), Some("2024-01"));
+        let closing: Vec<_> = periods[0]
+            .clients
+            .iter()
+            .map(|client| (client.client_id, client.closing))
+            .collect();
+        assert_eq!(closing, vec![(1, 2.0), (2, 3.0)]);
+    }
+}
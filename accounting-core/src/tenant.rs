@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::account_manager::{
+    commit_offset, process_transaction, process_transaction_at, AccountManager,
+    AccountManagerError, AccountManagerResult,
+};
+use crate::policy::{BackdatedPolicy, Policy};
+use crate::scheduler::{RecurringRule, Scheduler};
+use crate::stats::ManagerStats;
+use crate::types::{ClientId, TenantId, Timestamp, Transaction};
+
+// Every tenant has its own AccountManager, so client ids, transaction ids,
+// idempotency keys and ledgers of one tenant never affect another. Rows
+// without a tenant go to the default tenant "".
+#[derive(Default, Serialize, Deserialize)]
+pub struct Tenants {
+    tenants: BTreeMap<TenantId, AccountManager>,
+    ledger: bool,
+    #[serde(default)]
+    history: bool,
+    // Shared by all tenants, the clock is the one of the input.
+    #[serde(default)]
+    scheduler: Scheduler,
+    #[serde(skip)]
+    policy: Policy,
+    #[serde(skip)]
+    capacity: (usize, usize),
+}
+
+impl Tenants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_policy(policy: Policy) -> Self {
+        Self {
+            policy,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        for account_manager in self.tenants.values_mut() {
+            account_manager.set_policy(policy.clone());
+        }
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn enable_ledger(&mut self) {
+        for account_manager in self.tenants.values_mut() {
+            account_manager.enable_ledger();
+        }
+        self.ledger = true;
+    }
+
+    pub fn enable_history(&mut self) {
+        for account_manager in self.tenants.values_mut() {
+            account_manager.enable_history();
+        }
+        self.history = true;
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.history
+    }
+
+    // Capacity hints for the books of all tenants together, see
+    // AccountManager::reserve. They are split evenly across the tenants so
+    // far, and a later tenant gets the share it would have had with them.
+    pub fn reserve(&mut self, accounts: usize, transactions: usize) {
+        self.capacity = (accounts, transactions);
+        let (accounts, transactions) = self.share(self.tenants.len().max(1));
+        for account_manager in self.tenants.values_mut() {
+            account_manager.reserve(accounts, transactions);
+        }
+    }
+
+    fn share(&self, tenants: usize) -> (usize, usize) {
+        (self.capacity.0 / tenants, self.capacity.1 / tenants)
+    }
+
+    pub fn get(&self, tenant: &str) -> Option<&AccountManager> {
+        self.tenants.get(tenant)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&TenantId, &AccountManager)> {
+        self.tenants.iter()
+    }
+
+    // The stats of the books of all tenants.
+    pub fn stats(&self) -> ManagerStats {
+        self.tenants.values().map(AccountManager::stats).sum()
+    }
+
+    // True as soon as any row named a tenant, outputs then carry a tenant column.
+    pub fn is_multi_tenant(&self) -> bool {
+        self.tenants.keys().any(|tenant| !tenant.is_empty())
+    }
+
+    // Accounts ordered by tenant.
+    pub fn accounts(&self) -> Vec<(TenantId, ClientId, Account)> {
+        self.iter_accounts()
+            .map(|(tenant, client_id, account)| (tenant.clone(), *client_id, account.clone()))
+            .collect()
+    }
+
+    pub fn iter_accounts(&self) -> impl Iterator<Item = (&TenantId, &ClientId, &Account)> + Clone {
+        self.tenants.iter().flat_map(|(tenant, account_manager)| {
+            account_manager
+                .iter_accounts()
+                .map(move |(client_id, account)| (tenant, client_id, account))
+        })
+    }
+
+    // The closed period a backdated transaction adjusts, if the policy records
+    // adjustments.
+    pub fn adjusted_period(&self, tx: &Transaction) -> Option<u32> {
+        if self.policy.backdated != BackdatedPolicy::Adjust {
+            return None;
+        }
+        self.tenants
+            .get(tx.tenant.as_deref().unwrap_or_default())
+            .and_then(|account_manager| account_manager.backdated_period(tx))
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn set_recurring_rules(&mut self, rules: Vec<RecurringRule>) {
+        self.scheduler.set_rules(rules);
+    }
+
+    // Moves the clock to the timestamp of the current record. The
+    // transactions that became due are taken one at a time with next_due and
+    // applied with process_scheduled.
+    pub fn advance_clock(&mut self, now: Timestamp) {
+        self.scheduler.advance(now);
+    }
+
+    pub fn next_due(&mut self) -> Option<Transaction> {
+        self.scheduler.next_due()
+    }
+
+    // Records with an execution time after the clock only consume their
+    // offset, they are applied once the clock reaches it. Ids of recurring
+    // transactions are refused.
+    pub fn process_transaction_at(
+        &mut self,
+        offset: u64,
+        tx: Transaction,
+    ) -> AccountManagerResult<()> {
+        if self.scheduler.reserves(tx.id) {
+            return Err(AccountManagerError::ReservedId { id: tx.id });
+        }
+        match tx.execute_at.filter(|at| !self.scheduler.is_due(*at)) {
+            Some(at) => {
+                commit_offset(self.account_manager(&tx.tenant), offset)?;
+                self.scheduler.schedule(at, tx);
+                Ok(())
+            }
+            None => process_transaction_at(self.account_manager(&tx.tenant), offset, tx),
+        }
+    }
+
+    // Runs the script of the policy on a transaction of the input before it
+    // is processed. It may reject the transaction or add to its metadata.
+    // Without the rhai feature there is no script and this accepts everything.
+    #[cfg_attr(not(feature = "rhai"), allow(unused_variables))]
+    pub fn screen(&self, tx: &mut Transaction) -> AccountManagerResult<()> {
+        #[cfg(feature = "rhai")]
+        if let Some(script) = &self.policy.script {
+            let account = self
+                .get(tx.tenant.as_deref().unwrap_or_default())
+                .and_then(|account_manager| account_manager.account(tx.client_id));
+            return script.screen(tx, account);
+        }
+        Ok(())
+    }
+
+    pub fn process_scheduled(&mut self, tx: Transaction) -> AccountManagerResult<()> {
+        process_transaction(self.account_manager(&tx.tenant), tx)
+    }
+
+    fn account_manager(&mut self, tenant: &Option<TenantId>) -> &mut AccountManager {
+        let tenant = tenant.clone().unwrap_or_default();
+        let (accounts, transactions) = self.share(self.tenants.len() + 1);
+        self.tenants.entry(tenant).or_insert_with(|| {
+            let mut account_manager = AccountManager::with_policy(self.policy.clone());
+            account_manager.reserve(accounts, transactions);
+            if self.ledger {
+                account_manager.enable_ledger();
+            }
+            if self.history {
+                account_manager.enable_history();
+            }
+            account_manager
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::RECURRING_IDS;
+    use crate::types::Action;
+
+    fn tx(tenant: Option<&str>, action: Action, id: u32, amount: Option<f64>) -> Transaction {
+        Transaction {
+            tenant: tenant.map(str::to_string),
+            ..Transaction::new(action, 1, id, amount)
+        }
+    }
+
+    #[test]
+    fn colliding_ids_stay_isolated() {
+        let mut tenants = Tenants::new();
+        let deposit = tx(Some("acme"), Action::Deposit, 1, Some(2.0));
+        assert!(tenants.process_transaction_at(10, deposit).is_ok());
+        let deposit = tx(Some("globex"), Action::Deposit, 1, Some(5.0));
+        assert!(tenants.process_transaction_at(20, deposit).is_ok());
+        let dispute = tx(Some("acme"), Action::Dispute, 1, None);
+        assert!(tenants.process_transaction_at(30, dispute).is_ok());
+
+        let accounts = tenants.accounts();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].0, "acme");
+        assert_eq!(accounts[0].2.disputed(), 2.0);
+        assert_eq!(accounts[1].0, "globex");
+        assert_eq!(accounts[1].2.available(), 5.0);
+        assert!(tenants.is_multi_tenant());
+    }
+
+    #[test]
+    fn capacity_hints_are_split_across_tenants() {
+        let mut tenants = Tenants::new();
+        let deposit = tx(Some("acme"), Action::Deposit, 1, Some(2.0));
+        assert!(tenants.process_transaction_at(10, deposit).is_ok());
+        tenants.reserve(0, 1 << 16);
+        let deposit = tx(Some("globex"), Action::Deposit, 1, Some(5.0));
+        assert!(tenants.process_transaction_at(20, deposit).is_ok());
+
+        let memory = |tenant| tenants.get(tenant).unwrap().stats().memory_bytes;
+        // One tenant at the time of the hint, the other one got half of it.
+        assert!(memory("acme") > memory("globex"));
+    }
+
+    #[test]
+    fn transactions_of_another_tenant_are_not_found() {
+        let mut tenants = Tenants::new();
+        let deposit = tx(Some("acme"), Action::Deposit, 1, Some(2.0));
+        assert!(tenants.process_transaction_at(10, deposit).is_ok());
+        let dispute = tx(None, Action::Dispute, 1, None);
+        assert!(tenants.process_transaction_at(20, dispute).is_err());
+    }
+
+    #[test]
+    fn rows_without_tenant_use_the_default_tenant() {
+        let mut tenants = Tenants::new();
+        tenants.enable_ledger();
+        let deposit = tx(None, Action::Deposit, 1, Some(2.0));
+        assert!(tenants.process_transaction_at(10, deposit).is_ok());
+
+        assert!(!tenants.is_multi_tenant());
+        assert!(tenants.get("").unwrap().ledger().is_some());
+    }
+
+    #[test]
+    fn scheduled_transactions_wait_for_the_clock() {
+        let mut tenants = Tenants::new();
+        let deposit = Transaction {
+            execute_at: Some(100),
+            ..tx(Some("acme"), Action::Deposit, 1, Some(2.0))
+        };
+        assert!(tenants.process_transaction_at(10, deposit.clone()).is_ok());
+        assert!(tenants.accounts().is_empty());
+        assert_eq!(tenants.scheduler().pending(), 1);
+        assert!(tenants.process_transaction_at(10, deposit).is_err());
+
+        tenants.advance_clock(99);
+        assert!(tenants.next_due().is_none());
+        tenants.advance_clock(100);
+        let due = tenants.next_due().unwrap();
+        assert!(tenants.next_due().is_none());
+        assert!(tenants.process_scheduled(due).is_ok());
+        assert_eq!(tenants.accounts()[0].2.available(), 2.0);
+
+        let overdue = Transaction {
+            execute_at: Some(50),
+            ..tx(Some("acme"), Action::Deposit, 2, Some(1.0))
+        };
+        assert!(tenants.process_transaction_at(20, overdue).is_ok());
+        assert_eq!(tenants.accounts()[0].2.available(), 3.0);
+    }
+
+    #[test]
+    fn ids_of_recurring_transactions_are_reserved() {
+        let mut tenants = Tenants::new();
+        let deposit = tx(None, Action::Deposit, RECURRING_IDS, Some(1.0));
+        assert!(tenants.process_transaction_at(10, deposit.clone()).is_ok());
+
+        let mut tenants = Tenants::new();
+        tenants.set_recurring_rules(vec![RecurringRule {
+            action: Action::Deposit,
+            client_id: 1,
+            tx: 1,
+            amount: Some(1.0),
+            start: 0,
+            interval: 60,
+            count: None,
+            tenant: None,
+        }]);
+        assert_eq!(
+            tenants.process_transaction_at(10, deposit),
+            Err(AccountManagerError::ReservedId { id: RECURRING_IDS })
+        );
+        tenants.advance_clock(60);
+        while let Some(due) = tenants.next_due() {
+            assert!(tenants.process_scheduled(due).is_ok());
+        }
+        assert_eq!(tenants.accounts()[0].2.available(), 2.0);
+    }
+}
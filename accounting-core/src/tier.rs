@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt;
+#[cfg(feature = "csv")]
+use std::io::Read;
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::{ReaderBuilder, Trim};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::types::ClientId;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TierError {
+    #[error("Invalid tier configuration at line {line}: {message}")]
+    InvalidConfig { line: u64, message: String },
+}
+
+pub type TierResult<T> = Result<T, TierError>;
+
+#[cfg(feature = "csv")]
+fn invalid_config(err: csv::Error) -> TierError {
+    TierError::InvalidConfig {
+        line: err.position().map(|position| position.line()).unwrap_or(0),
+        message: err.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    #[default]
+    Basic,
+    Verified,
+    Premium,
+}
+
+impl Tier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Tier::Basic => "basic",
+            Tier::Verified => "verified",
+            Tier::Premium => "premium",
+        }
+    }
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "basic" => Ok(Tier::Basic),
+            "verified" => Ok(Tier::Verified),
+            "premium" => Ok(Tier::Premium),
+            _ => Err(format!(
+                "unknown tier '{s}', expected basic, verified or premium"
+            )),
+        }
+    }
+}
+
+// Limits of a tier, empty limits don't apply.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TierLimits {
+    pub max_balance: Option<f64>,
+    pub max_withdrawal: Option<f64>,
+    // Locks the account as soon as one of its transactions is disputed.
+    pub freeze_on_dispute: bool,
+    // Penalty charged on top of every chargeback, instead of the one of the
+    // client's role.
+    pub chargeback_fee: Option<f64>,
+}
+
+#[cfg(feature = "csv")]
+#[derive(Deserialize)]
+struct TierLimitsRecord {
+    tier: Tier,
+    max_balance: Option<f64>,
+    max_withdrawal: Option<f64>,
+    #[serde(default)]
+    freeze_on_dispute: bool,
+    #[serde(default)]
+    chargeback_fee: Option<f64>,
+}
+
+#[cfg(feature = "csv")]
+#[derive(Deserialize)]
+struct ClientTierRecord {
+    client: ClientId,
+    tier: Tier,
+}
+
+// The tier of every client and the limits of every tier. Clients without an
+// assigned tier are basic, tiers without limits are unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tiers {
+    limits: HashMap<Tier, TierLimits>,
+    clients: HashMap<ClientId, Tier>,
+}
+
+impl Tiers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_limits(&mut self, tier: Tier, limits: TierLimits) {
+        self.limits.insert(tier, limits);
+    }
+
+    pub fn assign(&mut self, client_id: ClientId, tier: Tier) {
+        self.clients.insert(client_id, tier);
+    }
+
+    // CSV with the columns tier, max_balance, max_withdrawal and the optional
+    // freeze_on_dispute and chargeback_fee.
+    #[cfg(feature = "csv")]
+    pub fn read_limits<R: Read>(&mut self, reader: R) -> TierResult<()> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        for result in reader.deserialize() {
+            let record: TierLimitsRecord = result.map_err(invalid_config)?;
+            let limits = TierLimits {
+                max_balance: record.max_balance,
+                max_withdrawal: record.max_withdrawal,
+                freeze_on_dispute: record.freeze_on_dispute,
+                chargeback_fee: record.chargeback_fee,
+            };
+            self.set_limits(record.tier, limits);
+        }
+        Ok(())
+    }
+
+    // CSV with the columns client and tier.
+    #[cfg(feature = "csv")]
+    pub fn read_clients<R: Read>(&mut self, reader: R) -> TierResult<()> {
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        for result in reader.deserialize() {
+            let record: ClientTierRecord = result.map_err(invalid_config)?;
+            self.assign(record.client, record.tier);
+        }
+        Ok(())
+    }
+
+    pub fn tier(&self, client_id: ClientId) -> Tier {
+        self.clients.get(&client_id).copied().unwrap_or_default()
+    }
+
+    pub fn limits(&self, client_id: ClientId) -> Option<&TierLimits> {
+        self.limits.get(&self.tier(client_id))
+    }
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_limits_and_client_tiers() {
+        let mut tiers = Tiers::new();
+        let limits = "tier,max_balance,max_withdrawal,freeze_on_dispute,chargeback_fee\n\
+                      basic,1000,100,true,\n\
+                      premium,,,false,15\n";
+        tiers.read_limits(limits.as_bytes()).unwrap();
+        tiers
+            .read_clients("client,tier\n2,premium\n3,verified\n".as_bytes())
+            .unwrap();
+
+        assert_eq!(tiers.tier(1), Tier::Basic);
+        assert_eq!(
+            tiers.limits(1),
+            Some(&TierLimits {
+                max_balance: Some(1000.0),
+                max_withdrawal: Some(100.0),
+                freeze_on_dispute: true,
+                chargeback_fee: None,
+            })
+        );
+        assert_eq!(tiers.tier(2), Tier::Premium);
+        assert_eq!(tiers.limits(2).unwrap().chargeback_fee, Some(15.0));
+        assert_eq!(tiers.limits(3), None);
+    }
+
+    #[test]
+    fn unknown_tiers_are_invalid() {
+        let err = Tiers::new()
+            .read_clients("client,tier\n1,gold\n".as_bytes())
+            .unwrap_err();
+        assert!(matches!(err, TierError::InvalidConfig { line: 2, .. }));
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{BTreeSet, HashMap, TryReserveError};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::stats::table_bytes;
+use crate::types::{client_id_u64, Action, ClientId, TransactionId};
+
+// Which way the disputed funds moved. Disputing a deposit holds funds from the
+// available balance, disputing a withdrawal holds the paid out funds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) enum DisputeDirection {
+    #[default]
+    Incoming,
+    Outgoing,
+}
+
+// A disputable transaction. AssetBook keeps its amounts in minor units.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TxCacheEntry<A = f64> {
+    pub client_id: ClientId,
+    pub amount: A,
+    pub disputed: bool,
+    #[serde(default)]
+    pub direction: DisputeDirection,
+}
+
+// A deposit or withdrawal of a client that can still be disputed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DisputableTransaction {
+    pub id: TransactionId,
+    pub action: Action,
+    // Deposits without their fee.
+    pub amount: f64,
+    pub disputed: bool,
+}
+
+impl<A> TxCacheEntry<A> {
+    pub fn new(client_id: ClientId, amount: A, direction: DisputeDirection) -> Self {
+        Self {
+            client_id,
+            amount,
+            disputed: false,
+            direction,
+        }
+    }
+}
+
+// Amounts have at most 4 decimal places.
+const MINOR_UNITS: f64 = 10_000.0;
+const AMOUNT_BITS: u32 = 46;
+const DISPUTED: u64 = 1 << AMOUNT_BITS;
+const OUTGOING: u64 = 1 << (AMOUNT_BITS + 1);
+const CLIENT_SHIFT: u32 = AMOUNT_BITS + 2;
+
+// An entry in 8 bytes aligned to 4, so that a slot of the map takes 12 bytes
+// instead of 24: the client id in the top 16 bits, then the direction and
+// dispute bits and the amount in minor units, up to about 7 billion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Packed([u32; 2]);
+
+impl Packed {
+    // None if the amount has no exact form in minor units.
+    fn pack(entry: &TxCacheEntry) -> Option<Self> {
+        let minor = (entry.amount * MINOR_UNITS).round();
+        if !(0.0..(1u64 << AMOUNT_BITS) as f64).contains(&minor)
+            || minor / MINOR_UNITS != entry.amount
+        {
+            return None;
+        }
+        // Wider client ids don't fit in the top bits.
+        let client = client_id_u64(entry.client_id);
+        if client >> (u64::BITS - CLIENT_SHIFT) != 0 {
+            return None;
+        }
+        let mut bits = minor as u64 | client << CLIENT_SHIFT;
+        if entry.disputed {
+            bits |= DISPUTED;
+        }
+        if entry.direction == DisputeDirection::Outgoing {
+            bits |= OUTGOING;
+        }
+        Some(Self([(bits >> 32) as u32, bits as u32]))
+    }
+
+    fn unpack(self) -> TxCacheEntry {
+        let bits = u64::from(self.0[0]) << 32 | u64::from(self.0[1]);
+        TxCacheEntry {
+            client_id: (bits >> CLIENT_SHIFT) as ClientId,
+            amount: (bits & (DISPUTED - 1)) as f64 / MINOR_UNITS,
+            disputed: bits & DISPUTED != 0,
+            direction: match bits & OUTGOING {
+                0 => DisputeDirection::Incoming,
+                _ => DisputeDirection::Outgoing,
+            },
+        }
+    }
+}
+
+// The disputable transactions. Entries are packed, the few whose amount has no
+// exact packed form are kept as they are, and the ids of every client are
+// indexed. Serialized as a map of the entries, like a plain HashMap.
+// With the client-id-u32 or client-id-u64 features, entries of clients above
+// 65535 don't fit the 16 client bits either and take the 24 bytes of a wide
+// slot, so a book of mostly such clients needs about twice the memory.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TxCache {
+    packed: HashMap<TransactionId, Packed>,
+    wide: HashMap<TransactionId, TxCacheEntry>,
+    by_client: HashMap<ClientId, BTreeSet<TransactionId>>,
+}
+
+impl TxCache {
+    pub fn get(&self, tx_id: TransactionId) -> Option<TxCacheEntry> {
+        match self.packed.get(&tx_id) {
+            Some(packed) => Some(packed.unpack()),
+            None => self.wide.get(&tx_id).cloned(),
+        }
+    }
+
+    pub fn contains(&self, tx_id: TransactionId) -> bool {
+        self.packed.contains_key(&tx_id) || self.wide.contains_key(&tx_id)
+    }
+
+    pub fn insert(&mut self, tx_id: TransactionId, entry: TxCacheEntry) {
+        let client_id = entry.client_id;
+        let previous = match Packed::pack(&entry) {
+            Some(packed) => {
+                let wide = self.wide.remove(&tx_id);
+                self.packed
+                    .insert(tx_id, packed)
+                    .map(Packed::unpack)
+                    .or(wide)
+            }
+            None => {
+                let packed = self.packed.remove(&tx_id).map(Packed::unpack);
+                self.wide.insert(tx_id, entry).or(packed)
+            }
+        };
+        match previous {
+            Some(previous) if previous.client_id == client_id => {}
+            previous => {
+                if let Some(previous) = previous {
+                    self.unindex(previous.client_id, tx_id);
+                }
+                self.by_client.entry(client_id).or_default().insert(tx_id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, tx_id: TransactionId) -> Option<TxCacheEntry> {
+        let entry = match self.packed.remove(&tx_id) {
+            Some(packed) => Some(packed.unpack()),
+            None => self.wide.remove(&tx_id),
+        }?;
+        self.unindex(entry.client_id, tx_id);
+        Some(entry)
+    }
+
+    fn unindex(&mut self, client_id: ClientId, tx_id: TransactionId) {
+        if let Some(tx_ids) = self.by_client.get_mut(&client_id) {
+            tx_ids.remove(&tx_id);
+            if tx_ids.is_empty() {
+                self.by_client.remove(&client_id);
+            }
+        }
+    }
+
+    // The entries of a client ordered by tx id, without a scan of the cache.
+    pub fn client_entries(
+        &self,
+        client_id: ClientId,
+    ) -> impl Iterator<Item = (TransactionId, TxCacheEntry)> + '_ {
+        self.by_client
+            .get(&client_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|tx_id| Some((*tx_id, self.get(*tx_id)?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.packed.len() + self.wide.len()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.packed.reserve(additional);
+    }
+
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.packed.try_reserve(additional)
+    }
+
+    // The index is counted as its ids, the tree nodes add about half again.
+    pub fn memory_bytes(&self) -> usize {
+        table_bytes::<(TransactionId, Packed)>(self.packed.capacity())
+            + table_bytes::<(TransactionId, TxCacheEntry)>(self.wide.capacity())
+            + table_bytes::<(ClientId, BTreeSet<TransactionId>)>(self.by_client.capacity())
+            + self.len() * std::mem::size_of::<TransactionId>()
+    }
+
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.packed.capacity()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionId, TxCacheEntry)> + '_ {
+        let packed = self
+            .packed
+            .iter()
+            .map(|(tx_id, packed)| (*tx_id, packed.unpack()));
+        let wide = self
+            .wide
+            .iter()
+            .map(|(tx_id, entry)| (*tx_id, entry.clone()));
+        packed.chain(wide)
+    }
+}
+
+impl Serialize for TxCache {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TxCache {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = HashMap::<TransactionId, TxCacheEntry>::deserialize(deserializer)?;
+        let mut cache = TxCache::default();
+        cache.reserve(entries.len());
+        for (tx_id, entry) in entries {
+            cache.insert(tx_id, entry);
+        }
+        Ok(cache)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use super::*;
+    #[cfg(any(feature = "client-id-u32", feature = "client-id-u64"))]
+    use crate::types::client_id_from_u64;
+
+    #[test]
+    fn packs_entries_into_half_the_slot_size() {
+        assert_eq!(size_of::<(TransactionId, Packed)>(), 12);
+        assert!(size_of::<(TransactionId, TxCacheEntry)>() >= 24);
+
+        let entry = TxCacheEntry {
+            disputed: true,
+            ..TxCacheEntry::new(65535, 1234.5678, DisputeDirection::Outgoing)
+        };
+        assert_eq!(Packed::pack(&entry).unwrap().unpack(), entry);
+        let entry = TxCacheEntry::new(1, 0.0001, DisputeDirection::Incoming);
+        assert_eq!(Packed::pack(&entry).unwrap().unpack(), entry);
+    }
+
+    #[cfg(any(feature = "client-id-u32", feature = "client-id-u64"))]
+    #[test]
+    fn keeps_wider_client_ids_as_they_are() {
+        let client_id = client_id_from_u64(1 << 16).unwrap();
+        let mut cache = TxCache::default();
+        let entry = TxCacheEntry::new(client_id, 1.0, DisputeDirection::Incoming);
+        assert!(Packed::pack(&entry).is_none());
+        cache.insert(1, entry.clone());
+        assert_eq!(cache.get(1), Some(entry));
+    }
+
+    #[test]
+    fn keeps_amounts_without_a_packed_form_as_they_are() {
+        let mut cache = TxCache::default();
+        let odd = [0.00001, 1e15, -1.0];
+        for (tx_id, amount) in (1..).zip(odd) {
+            let entry = TxCacheEntry::new(2, amount, DisputeDirection::Incoming);
+            assert!(Packed::pack(&entry).is_none());
+            cache.insert(tx_id, entry);
+        }
+        cache.insert(4, TxCacheEntry::new(2, 3.5, DisputeDirection::Incoming));
+        assert_eq!((cache.packed.len(), cache.wide.len()), (1, 3));
+
+        // Updates move an entry between the maps.
+        cache.insert(1, TxCacheEntry::new(2, 1.0, DisputeDirection::Incoming));
+        assert_eq!((cache.packed.len(), cache.wide.len()), (2, 2));
+        assert_eq!(cache.get(2).unwrap().amount, 1e15);
+        assert_eq!(cache.remove(4).unwrap().amount, 3.5);
+        assert!(!cache.contains(4));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn indexes_the_entries_of_every_client() {
+        let mut cache = TxCache::default();
+        for (tx_id, client_id) in [(5, 1), (2, 1), (3, 2), (9, 1)] {
+            let entry = TxCacheEntry::new(client_id, 1.0, DisputeDirection::Incoming);
+            cache.insert(tx_id, entry);
+        }
+        // An odd amount moves the entry to the wide map, it stays indexed.
+        cache.insert(2, TxCacheEntry::new(1, 0.00001, DisputeDirection::Incoming));
+        cache.remove(5);
+        cache.insert(3, TxCacheEntry::new(1, 2.0, DisputeDirection::Outgoing));
+        let tx_ids = |cache: &TxCache, client_id| {
+            cache
+                .client_entries(client_id)
+                .map(|(tx_id, _)| tx_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tx_ids(&cache, 1), [2, 3, 9]);
+        assert!(tx_ids(&cache, 2).is_empty() && !cache.by_client.contains_key(&2));
+
+        let json = serde_json::to_string(&cache).unwrap();
+        let restored: TxCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.by_client, cache.by_client);
+    }
+
+    #[test]
+    fn serializes_like_a_map_of_entries() {
+        let mut cache = TxCache::default();
+        cache.insert(7, TxCacheEntry::new(3, 2.5, DisputeDirection::Outgoing));
+        let json = serde_json::to_string(&cache).unwrap();
+        assert_eq!(
+            json,
+            r#"{"7":{"client_id":3,"amount":2.5,"disputed":false,"direction":"Outgoing"}}"#
+        );
+        let restored: TxCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.get(7), cache.get(7));
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::TransactionId;
+
+// Bits per inserted id and probes for a false positive rate of about 1%.
+const BITS_PER_ID: usize = 10;
+const PROBES: u32 = 7;
+
+// A Bloom filter over tx ids in front of a tx cache that is slow to look up,
+// e.g. one on disk. Ids it doesn't contain were never inserted, so disputes,
+// resolves and chargebacks of unknown tx ids are refused without a lookup;
+// about 1% of the unknown ids still need one. Ids can't be removed, a filter
+// of a cache with many removals is rebuilt from the remaining ids.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxIdFilter {
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl TxIdFilter {
+    // Sized for the expected number of ids. More ids fill the filter and
+    // raise the false positive rate, but never cause false negatives.
+    pub fn with_capacity(ids: usize) -> Self {
+        let words = (ids.max(1) * BITS_PER_ID).div_ceil(u64::BITS as usize);
+        Self {
+            bits: vec![0; words],
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, tx_id: TransactionId) {
+        for bit in self.probes(tx_id) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    // False only if the id was never inserted.
+    pub fn may_contain(&self, tx_id: TransactionId) -> bool {
+        self.probes(tx_id)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // Inserted ids, counting repeated ones every time.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+
+    // Double hashing: two halves of a mixed 64 bit hash give all probes.
+    fn probes(&self, tx_id: TransactionId) -> impl Iterator<Item = usize> {
+        let hash = mix(u64::from(tx_id));
+        let (first, step) = (hash >> 32, (hash & 0xffff_ffff) | 1);
+        let bits = (self.bits.len() * 64) as u64;
+        (0..u64::from(PROBES)).map(move |probe| (first.wrapping_add(probe * step) % bits) as usize)
+    }
+}
+
+// The finalizer of splitmix64, consecutive ids land on unrelated bits.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_ids_are_always_contained() {
+        let mut filter = TxIdFilter::with_capacity(10_000);
+        assert!(filter.is_empty() && !filter.may_contain(1));
+        for tx_id in (0..10_000).map(|n| n * 3) {
+            filter.insert(tx_id);
+        }
+        assert_eq!(filter.len(), 10_000);
+        assert!((0..10_000).all(|n| filter.may_contain(n * 3)));
+        assert_eq!(filter.memory_bytes(), 12_504);
+
+        // Overfilled filters answer more often wrongly, but never miss an id.
+        let mut small = TxIdFilter::with_capacity(10);
+        for tx_id in 0..1000 {
+            small.insert(tx_id);
+        }
+        assert!((0..1000).all(|tx_id| small.may_contain(tx_id)));
+    }
+
+    #[test]
+    fn few_unknown_ids_pass_the_filter() {
+        let mut filter = TxIdFilter::with_capacity(10_000);
+        for tx_id in 0..10_000 {
+            filter.insert(tx_id);
+        }
+        let passed = (10_000..110_000)
+            .filter(|tx_id| filter.may_contain(*tx_id))
+            .count();
+        assert!(passed < 2_000, "{passed} of 100000 unknown ids passed");
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "csv")]
+use std::num::{IntErrorKind, ParseIntError};
+use std::str::FromStr;
+
+#[cfg(feature = "csv")]
+use csv::StringRecord;
+#[cfg(feature = "csv")]
+use thiserror::Error;
+
+use crate::amount::deserialize_amount;
+#[cfg(feature = "csv")]
+use crate::amount::{AmountFormatError, AmountParseError, ExactAmount};
+use crate::role::Role;
+
+// Client ids are u16 unless the client-id-u32 or client-id-u64 feature
+// widens them; ids above the maximum are rejected when parsed.
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
+pub type ClientId = u16;
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub type ClientId = u32;
+#[cfg(feature = "client-id-u64")]
+pub type ClientId = u64;
+
+// Client ids of any width as u64; the cast is a no-op with client-id-u64.
+#[allow(clippy::unnecessary_cast)]
+pub const fn client_id_u64(client_id: ClientId) -> u64 {
+    client_id as u64
+}
+
+// None if the value doesn't fit in the width of client ids.
+#[allow(clippy::useless_conversion)]
+pub fn client_id_from_u64(value: u64) -> Option<ClientId> {
+    value.try_into().ok()
+}
+
+pub const MAX_CLIENT_ID: u64 = client_id_u64(ClientId::MAX);
+pub type TransactionId = u32;
+pub type TenantId = String;
+// Seconds since the unix epoch.
+pub type Timestamp = u64;
+
+// The columns of a transactions CSV, in the order of a record without headers.
+pub const TRANSACTION_COLUMNS: [&str; 13] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "idempotency_key",
+    "tenant",
+    "description",
+    "reference",
+    "category",
+    "timestamp",
+    "execute_at",
+    "role",
+    "counterparty",
+];
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    OpenAccount,
+    HoldInEscrow,
+    ReleaseEscrow,
+    RefundEscrow,
+    ClosePeriod,
+    Adjustment,
+    EraseClient,
+    Freeze,
+    Unfreeze,
+    Balance,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Deposit => "deposit",
+            Action::Withdrawal => "withdrawal",
+            Action::Dispute => "dispute",
+            Action::Resolve => "resolve",
+            Action::Chargeback => "chargeback",
+            Action::OpenAccount => "open_account",
+            Action::HoldInEscrow => "hold_in_escrow",
+            Action::ReleaseEscrow => "release_escrow",
+            Action::RefundEscrow => "refund_escrow",
+            Action::ClosePeriod => "close_period",
+            Action::Adjustment => "adjustment",
+            Action::EraseClient => "erase_client",
+            Action::Freeze => "freeze",
+            Action::Unfreeze => "unfreeze",
+            Action::Balance => "balance",
+        }
+    }
+
+    // Actions that change the books outside of the client's own transactions,
+    // refused unless admin actions are allowed.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Action::Freeze | Action::Unfreeze | Action::Adjustment | Action::EraseClient
+        )
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(Action::Deposit),
+            "withdrawal" => Ok(Action::Withdrawal),
+            "dispute" => Ok(Action::Dispute),
+            "resolve" => Ok(Action::Resolve),
+            "chargeback" => Ok(Action::Chargeback),
+            "open_account" => Ok(Action::OpenAccount),
+            "hold_in_escrow" => Ok(Action::HoldInEscrow),
+            "release_escrow" => Ok(Action::ReleaseEscrow),
+            "refund_escrow" => Ok(Action::RefundEscrow),
+            "close_period" => Ok(Action::ClosePeriod),
+            "adjustment" => Ok(Action::Adjustment),
+            "erase_client" => Ok(Action::EraseClient),
+            "freeze" => Ok(Action::Freeze),
+            "unfreeze" => Ok(Action::Unfreeze),
+            "balance" => Ok(Action::Balance),
+            _ => Err(format!("unknown transaction type '{s}'")),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, PartialEq)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub action: Action,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx")]
+    pub id: TransactionId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    pub amount: Option<f64>,
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    // Rows without a tenant belong to the default tenant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    // Free-form metadata, not interpreted by the engine but kept in the
+    // rejects, the audit log and statements.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // Groups the volume of the transaction in category reports.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    // When the transaction happened. Advances the scheduler clock.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    // Held by the scheduler until the clock reaches it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execute_at: Option<Timestamp>,
+    // Role of an account opened by `open_account`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    // Client an escrow of `hold_in_escrow` is released to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<ClientId>,
+    // Values of unknown columns, filled by the RecordParser with
+    // `UnknownColumnPolicy::Capture`.
+    #[serde(skip)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Transaction {
+    pub fn new(
+        action: Action,
+        client_id: ClientId,
+        id: TransactionId,
+        amount: Option<f64>,
+    ) -> Self {
+        Self {
+            action,
+            client_id,
+            id,
+            amount,
+            idempotency_key: None,
+            tenant: None,
+            description: None,
+            reference: None,
+            category: None,
+            timestamp: None,
+            execute_at: None,
+            role: None,
+            counterparty: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+// Why a CSV record isn't a transaction, with the field that failed.
+#[cfg(feature = "csv")]
+#[derive(Error, Debug, PartialEq)]
+pub enum RecordError {
+    #[error("missing field `{field}`")]
+    MissingField { field: &'static str },
+
+    #[error("type `{value}` is not a transaction type")]
+    UnknownAction { value: String },
+
+    #[error("{field} `{value}` is not a whole number")]
+    InvalidNumber { field: &'static str, value: String },
+
+    #[error("{field} `{value}` overflows, the maximum is {max}")]
+    Overflow {
+        field: &'static str,
+        value: String,
+        max: u64,
+    },
+
+    #[error("{0}")]
+    Amount(#[from] AmountParseError),
+
+    #[error("{0}")]
+    AmountFormat(#[from] AmountFormatError),
+
+    #[error("role `{value}` is neither customer nor merchant")]
+    UnknownRole { value: String },
+
+    #[error("{field} is not valid UTF-8")]
+    InvalidUtf8 { field: String },
+
+    #[error("found {found} fields, but the header has {expected} columns")]
+    ExtraFields { expected: usize, found: usize },
+}
+
+#[cfg(feature = "csv")]
+pub type RecordResult<T> = Result<T, RecordError>;
+
+#[cfg(feature = "csv")]
+impl RecordError {
+    // The column of the failed field, None if the record as a whole is wrong.
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            RecordError::MissingField { field }
+            | RecordError::InvalidNumber { field, .. }
+            | RecordError::Overflow { field, .. } => Some(field),
+            RecordError::UnknownAction { .. } => Some("type"),
+            RecordError::Amount(_) | RecordError::AmountFormat(_) => Some("amount"),
+            RecordError::UnknownRole { .. } => Some("role"),
+            RecordError::InvalidUtf8 { field } => Some(field),
+            RecordError::ExtraFields { .. } => None,
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+fn parse_integer<T: FromStr<Err = ParseIntError>>(
+    field: &'static str,
+    value: &str,
+    max: u64,
+) -> RecordResult<T> {
+    value
+        .parse()
+        .map_err(|err: ParseIntError| match err.kind() {
+            IntErrorKind::PosOverflow => RecordError::Overflow {
+                field,
+                value: value.to_string(),
+                max,
+            },
+            _ => RecordError::InvalidNumber {
+                field,
+                value: value.to_string(),
+            },
+        })
+}
+
+#[cfg(feature = "csv")]
+impl Transaction {
+    // Converts a record with the given headers, unknown columns are ignored.
+    pub fn from_record(record: &StringRecord, headers: &StringRecord) -> RecordResult<Self> {
+        Self::from_fields(|name| {
+            headers
+                .iter()
+                .position(|header| header == name)
+                .and_then(|index| record.get(index))
+        })
+    }
+
+    // Empty optional fields are None, like in the serde deserialization.
+    pub(crate) fn from_fields<'a>(field: impl Fn(&str) -> Option<&'a str>) -> RecordResult<Self> {
+        let required = |name: &'static str| {
+            field(name)
+                .filter(|value| !value.is_empty())
+                .ok_or(RecordError::MissingField { field: name })
+        };
+        let optional = |name: &str| field(name).filter(|value| !value.is_empty());
+        let string = |name: &str| optional(name).map(str::to_string);
+        let timestamp = |name: &'static str| {
+            optional(name)
+                .map(|value| parse_integer(name, value, Timestamp::MAX))
+                .transpose()
+        };
+
+        let action = required("type")?;
+        let action = action.parse().map_err(|_| RecordError::UnknownAction {
+            value: action.to_string(),
+        })?;
+        let client_id = parse_integer("client", required("client")?, MAX_CLIENT_ID)?;
+        let id = parse_integer("tx", required("tx")?, TransactionId::MAX.into())?;
+        let amount = optional("amount")
+            .map(|value| ExactAmount::parse(value).map(ExactAmount::to_f64))
+            .transpose()?;
+        Ok(Transaction {
+            idempotency_key: string("idempotency_key"),
+            tenant: string("tenant"),
+            description: string("description"),
+            reference: string("reference"),
+            category: string("category"),
+            timestamp: timestamp("timestamp")?,
+            execute_at: timestamp("execute_at")?,
+            role: optional("role")
+                .map(|value| {
+                    value.parse().map_err(|_| RecordError::UnknownRole {
+                        value: value.to_string(),
+                    })
+                })
+                .transpose()?,
+            counterparty: optional("counterparty")
+                .map(|value| parse_integer("counterparty", value, MAX_CLIENT_ID))
+                .transpose()?,
+            ..Transaction::new(action, client_id, id, amount)
+        })
+    }
+}
+
+// A record without headers, its fields in the order of TRANSACTION_COLUMNS.
+#[cfg(feature = "csv")]
+impl TryFrom<&StringRecord> for Transaction {
+    type Error = RecordError;
+
+    fn try_from(record: &StringRecord) -> RecordResult<Self> {
+        Self::from_fields(|name| {
+            TRANSACTION_COLUMNS
+                .iter()
+                .position(|column| *column == name)
+                .and_then(|index| record.get(index))
+        })
+    }
+}
+
+#[cfg(feature = "csv")]
+impl TryFrom<StringRecord> for Transaction {
+    type Error = RecordError;
+
+    fn try_from(record: StringRecord) -> RecordResult<Self> {
+        Self::try_from(&record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    fn parse(input: &str) -> Vec<csv::Result<Transaction>> {
+        ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input.as_bytes())
+            .deserialize()
+            .collect()
+    }
+
+    #[test]
+    fn parses_transactions_with_and_without_amount() {
+        let txs =
+            parse("type, client, tx, amount\ndeposit, 1, 1, 1.5\ndispute, 1, 1,\nresolve, 1, 1\n");
+        let txs: Vec<_> = txs.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            txs,
+            vec![
+                Transaction::new(Action::Deposit, 1, 1, Some(1.5)),
+                Transaction::new(Action::Dispute, 1, 1, None),
+                Transaction::new(Action::Resolve, 1, 1, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_optional_idempotency_key() {
+        let txs =
+            parse("type,client,tx,amount,idempotency_key\ndeposit,1,1,1.0,abc\ndeposit,1,2,1.0,\n");
+        let txs: Vec<_> = txs.into_iter().map(Result::unwrap).collect();
+        assert_eq!(txs[0].idempotency_key.as_deref(), Some("abc"));
+        assert_eq!(txs[1].idempotency_key, None);
+    }
+
+    #[test]
+    fn parses_optional_tenant() {
+        let txs = parse("type,client,tx,amount,tenant\ndeposit,1,1,1.0,acme\ndeposit,1,2,1.0,\n");
+        let txs: Vec<_> = txs.into_iter().map(Result::unwrap).collect();
+        assert_eq!(txs[0].tenant.as_deref(), Some("acme"));
+        assert_eq!(txs[1].tenant, None);
+    }
+
+    #[test]
+    fn parses_optional_metadata() {
+        let txs = parse(
+            "type,client,tx,amount,description,reference\n\
+             deposit,1,1,1.0,\"Salary, May\",00123\n\
+             deposit,1,2,1.0,,\n",
+        );
+        let txs: Vec<_> = txs.into_iter().map(Result::unwrap).collect();
+        assert_eq!(txs[0].description.as_deref(), Some("Salary, May"));
+        assert_eq!(txs[0].reference.as_deref(), Some("00123"));
+        assert_eq!(txs[1].description, None);
+        assert_eq!(txs[1].reference, None);
+    }
+
+    #[test]
+    fn non_finite_amounts_are_rejected() {
+        let txs = parse("type,client,tx,amount\ndeposit,1,1,NaN\ndeposit,1,2,inf\ndeposit,1,3,-infinity\ndeposit,1,4,1.0\n");
+        assert_eq!(txs.len(), 4);
+        for result in &txs[..3] {
+            let err = result.as_ref().unwrap_err();
+            assert!(err.to_string().contains("amount must be a finite number"));
+        }
+        assert!(txs[3].is_ok());
+    }
+
+    #[test]
+    fn amounts_are_parsed_exactly() {
+        let txs = parse("type,client,tx,amount\ndeposit,1,1,0.30000000000000004\ndeposit,1,2,1e3\ndeposit,1,3,ten\n");
+        let err = txs[0].as_ref().unwrap_err().to_string();
+        assert!(err.contains("has more than 15 digits"));
+        assert_eq!(txs[1].as_ref().unwrap().amount, Some(1000.0));
+        assert!(txs[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("amount `ten` is not a decimal number"));
+
+        let json = r#"{"type":"deposit","client":1,"tx":1,"amount":1.5}"#;
+        let tx: Transaction = serde_json::from_str(json).unwrap();
+        assert_eq!(tx.amount, Some(1.5));
+        let json = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.25"}"#;
+        let tx: Transaction = serde_json::from_str(json).unwrap();
+        assert_eq!(tx.amount, Some(2.25));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn converts_records_in_column_order() {
+        let record = StringRecord::from(vec!["deposit", "1", "7", "1.5", "", "acme"]);
+        assert_eq!(
+            Transaction::try_from(&record),
+            Ok(Transaction {
+                tenant: Some("acme".to_string()),
+                ..Transaction::new(Action::Deposit, 1, 7, Some(1.5))
+            })
+        );
+        let record = StringRecord::from(vec!["dispute", "1", "7"]);
+        assert_eq!(
+            Transaction::try_from(record),
+            Ok(Transaction::new(Action::Dispute, 1, 7, None))
+        );
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn conversion_errors_name_the_field() {
+        let convert =
+            |fields: Vec<&str>| Transaction::try_from(StringRecord::from(fields)).unwrap_err();
+        let err = convert(vec!["refund", "1", "1", "1.0"]);
+        assert_eq!(err.to_string(), "type `refund` is not a transaction type");
+        assert_eq!(err.field(), Some("type"));
+        let too_large = (u128::from(MAX_CLIENT_ID) + 1).to_string();
+        assert_eq!(
+            convert(vec!["deposit", &too_large, "1", "1.0"]),
+            RecordError::Overflow {
+                field: "client",
+                value: too_large.clone(),
+                max: MAX_CLIENT_ID
+            }
+        );
+        let json = format!(r#"{{"type":"deposit","client":{too_large},"tx":1}}"#);
+        assert!(serde_json::from_str::<Transaction>(&json).is_err());
+        assert_eq!(
+            convert(vec!["deposit", "1", "-1", "1.0"]).to_string(),
+            "tx `-1` is not a whole number"
+        );
+        assert_eq!(
+            convert(vec!["deposit", "1", "1", "1.2.3"]),
+            RecordError::Amount(AmountParseError::Malformed("1.2.3".to_string()))
+        );
+        assert_eq!(
+            convert(vec!["deposit", "1", "1", "inf"]).field(),
+            Some("amount")
+        );
+        assert_eq!(
+            convert(vec!["deposit", "1"]),
+            RecordError::MissingField { field: "tx" }
+        );
+        let headers = StringRecord::from(vec!["tx", "client", "type", "role"]);
+        let record = StringRecord::from(vec!["1", "2", "open_account", "admin"]);
+        assert_eq!(
+            Transaction::from_record(&record, &headers)
+                .unwrap_err()
+                .field(),
+            Some("role")
+        );
+    }
+}
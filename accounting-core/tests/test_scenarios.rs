@@ -1,7 +1,7 @@
 #![allow(clippy::bool_assert_comparison)]
 
-use accounting_demo::account_manager::{process_transaction, AccountManager};
-use accounting_demo::types::{Action, ClientId, Transaction, TransactionId};
+use accounting_core::account_manager::{process_transaction, AccountManager};
+use accounting_core::types::{Action, ClientId, Transaction, TransactionId};
 
 const CLIENT_ID1: ClientId = 1;
 const CLIENT_ID2: ClientId = 2;
@@ -12,12 +12,7 @@ fn new_transaction(
     id: TransactionId,
     amount: Option<f64>,
 ) -> Transaction {
-    Transaction {
-        action,
-        client_id,
-        id,
-        amount,
-    }
+    Transaction::new(action, client_id, id, amount)
 }
 
 fn get_scenario1_transactions() -> Vec<Transaction> {
@@ -0,0 +1,21 @@
+// Generates the C header of the ffi module into OUT_DIR, see cbindgen.toml.
+// It isn't checked in, so it can't fall behind ffi.rs.
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+        let out_dir = std::env::var("OUT_DIR").expect("set by cargo");
+        let config = cbindgen::Config::from_file(format!("{crate_dir}/cbindgen.toml"))
+            .expect("valid cbindgen.toml");
+        // Only the ffi module, not the constants and types of the rest of the
+        // crate.
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{crate_dir}/src/ffi.rs"))
+            .generate()
+            .expect("ffi module can be translated to C")
+            .write_to_file(format!("{out_dir}/accounting_demo.h"));
+    }
+}
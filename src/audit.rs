@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use csv::Position;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::account_manager::AccountManagerError;
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{Action, Transaction, TransactionId};
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid audit entry at line {line}: {source}")]
+    Serialization {
+        line: u64,
+        source: serde_json::Error,
+    },
+
+    #[error("Audit log tampered at line {line}: {reason}")]
+    Tampered { line: u64, reason: String },
+}
+
+pub type AuditResult<T> = Result<T, AuditError>;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// The balances of the client at a `balance` row.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BalanceSnapshot {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl BalanceSnapshot {
+    pub fn new(account: &Account) -> Self {
+        Self {
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub line: u64,
+    pub record: u64,
+    #[serde(rename = "type")]
+    pub action: Action,
+    // The client id, or its pseudonym.
+    pub client: String,
+    pub tx: TransactionId,
+    pub amount: Option<f64>,
+    // Left out if empty, so entries written before they existed still verify.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    // "applied", "adjusted" or the kind of the rejection.
+    pub outcome: String,
+    // Closed period a backdated transaction was recorded as adjustment of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adjusted_period: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<BalanceSnapshot>,
+    pub error: Option<String>,
+    pub prev_hash: String,
+}
+
+impl AuditEntry {
+    // The hash covers the serialized entry including the previous hash, so
+    // editing, removing or reordering any entry breaks every later link.
+    // Verifying re-serializes the parsed entry, which gives the same bytes
+    // because serde_json parses floats with float_roundtrip.
+    fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("audit entries always serialize");
+        hex::encode(Sha256::digest(json))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuditLine {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    hash: String,
+}
+
+pub struct AuditLog<W: Write> {
+    writer: W,
+    sequence: u64,
+    last_hash: String,
+    pseudonymizer: Pseudonymizer,
+}
+
+impl<W: Write> AuditLog<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            sequence: 0,
+            last_hash: GENESIS_HASH.to_string(),
+            pseudonymizer: Pseudonymizer::new(),
+        }
+    }
+
+    pub fn with_pseudonymizer(mut self, pseudonymizer: Pseudonymizer) -> Self {
+        self.pseudonymizer = pseudonymizer;
+        self
+    }
+
+    pub fn append(
+        &mut self,
+        position: &Position,
+        tx: &Transaction,
+        result: &Result<(), AccountManagerError>,
+    ) -> AuditResult<()> {
+        self.append_entry(position, tx, result, None, None)
+    }
+
+    // An applied backdated transaction that adjusted the closed `period`.
+    pub fn append_adjusted(
+        &mut self,
+        position: &Position,
+        tx: &Transaction,
+        period: u32,
+    ) -> AuditResult<()> {
+        self.append_entry(position, tx, &Ok(()), Some(period), None)
+    }
+
+    // An applied `balance` row with the balances of the client at that point.
+    pub fn append_balance(
+        &mut self,
+        position: &Position,
+        tx: &Transaction,
+        balance: BalanceSnapshot,
+    ) -> AuditResult<()> {
+        self.append_entry(position, tx, &Ok(()), None, Some(balance))
+    }
+
+    fn append_entry(
+        &mut self,
+        position: &Position,
+        tx: &Transaction,
+        result: &Result<(), AccountManagerError>,
+        adjusted_period: Option<u32>,
+        balance: Option<BalanceSnapshot>,
+    ) -> AuditResult<()> {
+        let entry = AuditEntry {
+            sequence: self.sequence + 1,
+            line: position.line(),
+            record: position.record(),
+            action: tx.action.clone(),
+            client: self.pseudonymizer.client(tx.client_id),
+            tx: tx.id,
+            amount: tx.amount,
+            description: tx.description.clone(),
+            reference: tx.reference.clone(),
+            category: tx.category.clone(),
+            metadata: tx.metadata.clone(),
+            outcome: match (result, adjusted_period) {
+                (Ok(()), None) => "applied".to_string(),
+                (Ok(()), Some(_)) => "adjusted".to_string(),
+                (Err(err), _) => err.kind().to_string(),
+            },
+            adjusted_period,
+            balance,
+            error: result
+                .as_ref()
+                .err()
+                .map(|err| self.pseudonymizer.error(err)),
+            prev_hash: self.last_hash.clone(),
+        };
+        let hash = entry.hash();
+        let line = AuditLine { entry, hash };
+        serde_json::to_writer(&mut self.writer, &line).map_err(|source| {
+            AuditError::Serialization {
+                line: line.entry.sequence,
+                source,
+            }
+        })?;
+        self.writer.write_all(b"\n")?;
+
+        self.sequence = line.entry.sequence;
+        self.last_hash = line.hash;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // The hash of the last entry. Kept outside the log, it detects entries
+    // removed from its end, which the chain alone can't.
+    pub fn head(&self) -> &str {
+        &self.last_hash
+    }
+}
+
+impl AuditLog<BufWriter<File>> {
+    // The log is append-only: an existing file is verified and continued.
+    pub fn open(path: &Path) -> AuditResult<Self> {
+        let (sequence, last_hash) = match File::open(path) {
+            Ok(file) => verify_chain(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (0, GENESIS_HASH.to_string()),
+            Err(err) => return Err(err.into()),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            sequence,
+            last_hash,
+            pseudonymizer: Pseudonymizer::new(),
+        })
+    }
+}
+
+fn verify_chain<R: BufRead>(reader: R) -> AuditResult<(u64, String)> {
+    let mut sequence = 0;
+    let mut last_hash = GENESIS_HASH.to_string();
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index as u64 + 1;
+        let tampered = |reason: String| AuditError::Tampered {
+            line: line_number,
+            reason,
+        };
+
+        let line: AuditLine =
+            serde_json::from_str(&line?).map_err(|source| AuditError::Serialization {
+                line: line_number,
+                source,
+            })?;
+        if line.entry.sequence != sequence + 1 {
+            return Err(tampered(format!(
+                "expected sequence {}, got {}",
+                sequence + 1,
+                line.entry.sequence
+            )));
+        }
+        if line.entry.prev_hash != last_hash {
+            return Err(tampered(
+                "previous hash doesn't match the previous entry".to_string(),
+            ));
+        }
+        if line.entry.hash() != line.hash {
+            return Err(tampered("hash doesn't match the entry".to_string()));
+        }
+        sequence = line.entry.sequence;
+        last_hash = line.hash;
+    }
+    Ok((sequence, last_hash))
+}
+
+// Returns the number of verified entries.
+pub fn verify_audit<R: BufRead>(reader: R) -> AuditResult<u64> {
+    verify_chain(reader).map(|(sequence, _)| sequence)
+}
+
+// Like verify_audit, but the log must also end with the entry of the `head`
+// hash printed by the run that wrote it, so a truncated log is detected too.
+pub fn verify_audit_head<R: BufRead>(reader: R, head: &str) -> AuditResult<u64> {
+    let (sequence, last_hash) = verify_chain(reader)?;
+    match last_hash.eq_ignore_ascii_case(head.trim()) {
+        true => Ok(sequence),
+        false => Err(AuditError::Tampered {
+            line: sequence,
+            reason: "the log doesn't end with the expected head, entries were removed from its end"
+                .to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> Vec<u8> {
+        let mut audit = AuditLog::new(Vec::new());
+        let mut position = Position::new();
+        position.set_line(2).set_record(1);
+        let deposit = Transaction {
+            description: Some("Salary".to_string()),
+            ..Transaction::new(Action::Deposit, 1, 1, Some(1.5))
+        };
+        assert!(audit.append(&position, &deposit, &Ok(())).is_ok());
+
+        position.set_line(3).set_record(2);
+        let dispute = Transaction::new(Action::Dispute, 1, 2, None);
+        let result = Err(AccountManagerError::TransactionNotFound { id: 2 });
+        assert!(audit.append(&position, &dispute, &result).is_ok());
+
+        position.set_line(4).set_record(3);
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 3, Some(0.5));
+        assert!(audit.append_adjusted(&position, &withdrawal, 1).is_ok());
+
+        position.set_line(5).set_record(4);
+        let balance = BalanceSnapshot {
+            available: 1.0,
+            held: 0.0,
+            total: 1.0,
+            locked: false,
+        };
+        let inquiry = Transaction::new(Action::Balance, 1, 4, None);
+        assert!(audit.append_balance(&position, &inquiry, balance).is_ok());
+        audit.writer
+    }
+
+    #[test]
+    fn verifies_an_untouched_log() {
+        let log = log();
+        assert_eq!(verify_audit(log.as_slice()).unwrap(), 4);
+
+        let lines = String::from_utf8(log).unwrap();
+        assert!(lines.contains("\"outcome\":\"applied\""));
+        assert!(lines.contains("\"outcome\":\"transaction_not_found\""));
+        assert!(lines.contains("\"outcome\":\"adjusted\",\"adjusted_period\":1"));
+        assert!(lines.contains("\"description\":\"Salary\""));
+        assert!(!lines.contains("\"reference\""));
+        assert!(lines.contains(
+            "\"balance\":{\"available\":1.0,\"held\":0.0,\"total\":1.0,\"locked\":false}"
+        ));
+        assert_eq!(lines.matches("\"balance\":").count(), 1);
+    }
+
+    #[test]
+    fn detects_an_edited_entry() {
+        let log = String::from_utf8(log()).unwrap().replace("1.5", "15.0");
+        assert!(matches!(
+            verify_audit(log.as_bytes()),
+            Err(AuditError::Tampered { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn verifies_amounts_that_need_all_digits() {
+        let mut audit = AuditLog::new(Vec::new());
+        let mut position = Position::new();
+        for (id, amount) in [1.9194999999999998, 0.1 + 0.2, 1e-7 / 3.0]
+            .into_iter()
+            .enumerate()
+        {
+            position.set_line(id as u64 + 2).set_record(id as u64 + 1);
+            let tx = Transaction::new(Action::Deposit, 1, id as TransactionId, Some(amount));
+            assert!(audit.append(&position, &tx, &Ok(())).is_ok());
+        }
+        assert_eq!(verify_audit(audit.writer.as_slice()).unwrap(), 3);
+    }
+
+    #[test]
+    fn detects_a_truncated_log_with_its_head() {
+        let mut audit = AuditLog::new(Vec::new());
+        let position = Position::new();
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.0));
+        assert!(audit.append(&position, &deposit, &Ok(())).is_ok());
+        let withdrawal = Transaction::new(Action::Withdrawal, 1, 2, Some(1.0));
+        assert!(audit.append(&position, &withdrawal, &Ok(())).is_ok());
+        let head = audit.head().to_string();
+        let log = String::from_utf8(audit.writer).unwrap();
+        assert_eq!(verify_audit_head(log.as_bytes(), &head).unwrap(), 2);
+
+        let first = log.lines().next().unwrap();
+        assert_eq!(verify_audit(first.as_bytes()).unwrap(), 1);
+        assert!(matches!(
+            verify_audit_head(first.as_bytes(), &head),
+            Err(AuditError::Tampered { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn detects_a_removed_entry() {
+        let log = String::from_utf8(log()).unwrap();
+        let second = log.lines().nth(1).unwrap();
+        assert!(matches!(
+            verify_audit(second.as_bytes()),
+            Err(AuditError::Tampered { line: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::error_code::ErrorCode;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Missing API key")]
+    MissingKey,
+
+    #[error("Invalid API key")]
+    InvalidKey,
+
+    #[error("Forbidden. Key {name} may not {operation}.")]
+    Forbidden {
+        name: String,
+        operation: &'static str,
+    },
+
+    #[error("Invalid API key configuration at line {line}: {message}")]
+    InvalidConfig { line: u64, message: String },
+}
+
+impl AuthError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthError::MissingKey => "missing_key",
+            AuthError::InvalidKey => "invalid_key",
+            AuthError::Forbidden { .. } => "forbidden",
+            AuthError::InvalidConfig { .. } => "invalid_config",
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => ErrorCode::InvalidApiKey,
+            AuthError::Forbidden { .. } => ErrorCode::Forbidden,
+            AuthError::InvalidConfig { .. } => ErrorCode::InvalidKey,
+        }
+    }
+
+    // HTTP status a server should answer with.
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => 401,
+            AuthError::Forbidden { .. } => 403,
+            AuthError::InvalidConfig { .. } => 500,
+        }
+    }
+}
+
+pub type AuthResult<T> = Result<T, AuthError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Submit,
+    Read,
+    Admin,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Submit => "submit",
+            Operation::Read => "read",
+            Operation::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Integrators that only push transactions.
+    Submit,
+    // Dashboards and support tooling.
+    Read,
+    // Everything, including operations like unlocking accounts.
+    Admin,
+}
+
+impl Role {
+    pub fn allows(&self, operation: Operation) -> bool {
+        matches!(
+            (self, operation),
+            (Role::Admin, _) | (Role::Submit, Operation::Submit) | (Role::Read, Operation::Read)
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiKeyRecord {
+    name: String,
+    key: String,
+    role: Role,
+}
+
+#[derive(Debug, Clone)]
+struct ApiKey {
+    name: String,
+    role: Role,
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+// Keys are only kept as SHA-256 digests, so a memory dump doesn't reveal them
+// and lookups don't compare secrets byte by byte.
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys {
+    keys: HashMap<[u8; 32], ApiKey>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, name: &str, key: &str, role: Role) {
+        self.keys.insert(
+            digest(key),
+            ApiKey {
+                name: name.to_string(),
+                role,
+            },
+        );
+    }
+
+    // CSV with the columns name, key and role.
+    pub fn read<R: Read>(reader: R) -> AuthResult<Self> {
+        let mut keys = Self::new();
+        let mut reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        for result in reader.deserialize() {
+            let record: ApiKeyRecord = result.map_err(|err| AuthError::InvalidConfig {
+                line: err.position().map(|position| position.line()).unwrap_or(0),
+                message: err.to_string(),
+            })?;
+            keys.insert(&record.name, &record.key, record.role);
+        }
+        Ok(keys)
+    }
+
+    // Returns the name of a known key, whatever its role.
+    pub fn authenticate(&self, key: Option<&str>) -> AuthResult<&str> {
+        let key = key.ok_or(AuthError::MissingKey)?;
+        let api_key = self.keys.get(&digest(key)).ok_or(AuthError::InvalidKey)?;
+        Ok(&api_key.name)
+    }
+
+    // Returns the name of the key on success.
+    pub fn authorize(&self, key: Option<&str>, operation: Operation) -> AuthResult<&str> {
+        let key = key.ok_or(AuthError::MissingKey)?;
+        let api_key = self.keys.get(&digest(key)).ok_or(AuthError::InvalidKey)?;
+        if !api_key.role.allows(operation) {
+            return Err(AuthError::Forbidden {
+                name: api_key.name.clone(),
+                operation: operation.as_str(),
+            });
+        }
+        Ok(&api_key.name)
+    }
+}
+
+// Extracts the token of an `Authorization: Bearer <token>` header value.
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    match scheme.eq_ignore_ascii_case("bearer") {
+        true => Some(token.trim()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> ApiKeys {
+        ApiKeys::read(
+            "name,key,role\n\
+             partner,s3cret,submit\n\
+             support,l00k,read\n\
+             ops,r00t,admin\n"
+                .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn roles_limit_operations() {
+        let keys = keys();
+        assert_eq!(
+            keys.authorize(Some("s3cret"), Operation::Submit),
+            Ok("partner")
+        );
+        assert_eq!(
+            keys.authorize(Some("s3cret"), Operation::Read),
+            Err(AuthError::Forbidden {
+                name: "partner".to_string(),
+                operation: "read"
+            })
+        );
+        assert!(keys.authorize(Some("l00k"), Operation::Read).is_ok());
+        assert!(keys.authorize(Some("l00k"), Operation::Admin).is_err());
+        assert!(keys.authorize(Some("r00t"), Operation::Admin).is_ok());
+        assert!(keys.authorize(Some("r00t"), Operation::Submit).is_ok());
+    }
+
+    #[test]
+    fn unknown_and_missing_keys_are_unauthorized() {
+        let keys = keys();
+        let invalid = keys.authorize(Some("guess"), Operation::Read).unwrap_err();
+        assert_eq!(invalid, AuthError::InvalidKey);
+        assert_eq!(invalid.status(), 401);
+        assert_eq!(
+            keys.authorize(None, Operation::Read),
+            Err(AuthError::MissingKey)
+        );
+    }
+
+    #[test]
+    fn invalid_roles_are_rejected() {
+        let err = ApiKeys::read("name,key,role\nx,y,root\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, AuthError::InvalidConfig { line: 2, .. }));
+    }
+
+    #[test]
+    fn parses_bearer_tokens() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+    }
+}
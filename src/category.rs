@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::amount::ReportAmount;
+use crate::ledger::{JournalEntry, Ledger, LedgerAccount};
+use crate::pseudonym::Pseudonymizer;
+use crate::types::ClientId;
+
+// The money moved in and out of the accounts of a client, or of all clients if
+// `client_id` is none, by the transactions of one category.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryVolume {
+    pub client_id: Option<ClientId>,
+    pub category: String,
+    pub entries: u64,
+    pub inflows: f64,
+    pub outflows: f64,
+}
+
+impl CategoryVolume {
+    pub fn net(&self) -> f64 {
+        self.inflows - self.outflows
+    }
+}
+
+// Entries without a category of their transaction are grouped by their type,
+// and fees by themselves, so deposits, payouts and fees are told apart without
+// a category column.
+fn category(entry: &JournalEntry) -> &str {
+    if let Some(category) = &entry.category {
+        return category;
+    }
+    match (entry.debit, entry.credit) {
+        (LedgerAccount::FeeIncome, _) | (_, LedgerAccount::FeeIncome) => "fee",
+        _ => entry.action.as_str(),
+    }
+}
+
+// The volume per client and category, ordered by client and category,
+// followed by the totals of every category over all clients. Moves between
+// the accounts of one client, like disputes, don't move money and are left out.
+// A transfer between two clients, like a released escrow, is an outflow of
+// one and an inflow of the other, and one entry of the totals.
+pub fn category_volumes(ledger: &Ledger) -> Vec<CategoryVolume> {
+    let mut clients: BTreeMap<(ClientId, &str), CategoryVolume> = BTreeMap::new();
+    let mut totals: BTreeMap<&str, CategoryVolume> = BTreeMap::new();
+    for entry in ledger.entries() {
+        let (debited, credited) = (entry.debit.client_id(), entry.credit.client_id());
+        if debited == credited {
+            continue;
+        }
+        let category = category(entry);
+        let total = totals.entry(category).or_insert_with(|| CategoryVolume {
+            category: category.to_string(),
+            ..CategoryVolume::default()
+        });
+        total.entries += 1;
+        for (client_id, inflow) in [(debited, false), (credited, true)] {
+            let Some(client_id) = client_id else {
+                continue;
+            };
+            let volume = clients
+                .entry((client_id, category))
+                .or_insert_with(|| CategoryVolume {
+                    client_id: Some(client_id),
+                    category: category.to_string(),
+                    ..CategoryVolume::default()
+                });
+            volume.entries += 1;
+            for volume in [volume, &mut *total] {
+                match inflow {
+                    true => volume.inflows += entry.amount,
+                    false => volume.outflows += entry.amount,
+                }
+            }
+        }
+    }
+    clients.into_values().chain(totals.into_values()).collect()
+}
+
+#[derive(Serialize)]
+struct CategoryRecord<'a> {
+    client: String,
+    category: &'a str,
+    entries: u64,
+    inflows: ReportAmount,
+    outflows: ReportAmount,
+    net: ReportAmount,
+}
+
+// The totals over all clients have the client `all`.
+pub fn write_category_report<W: Write>(
+    writer: W,
+    volumes: &[CategoryVolume],
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    for volume in volumes {
+        writer.serialize(CategoryRecord {
+            client: match volume.client_id {
+                Some(client_id) => pseudonymizer.client(client_id),
+                None => "all".to_string(),
+            },
+            category: &volume.category,
+            entries: volume.entries,
+            inflows: ReportAmount::new(volume.inflows, places),
+            outflows: ReportAmount::new(volume.outflows, places),
+            net: ReportAmount::new(volume.net(), places),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Action, Transaction};
+
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::new();
+        let entries = [
+            (
+                Action::Deposit,
+                LedgerAccount::Cash,
+                LedgerAccount::CustomerAvailable(2),
+                5.0,
+                Some("payroll"),
+            ),
+            (
+                Action::Deposit,
+                LedgerAccount::CustomerAvailable(2),
+                LedgerAccount::FeeIncome,
+                0.5,
+                None,
+            ),
+            (
+                Action::Deposit,
+                LedgerAccount::Cash,
+                LedgerAccount::CustomerAvailable(1),
+                3.0,
+                None,
+            ),
+            (
+                Action::Withdrawal,
+                LedgerAccount::CustomerAvailable(1),
+                LedgerAccount::Cash,
+                1.0,
+                None,
+            ),
+            (
+                Action::Dispute,
+                LedgerAccount::CustomerAvailable(1),
+                LedgerAccount::CustomerHeld(1),
+                3.0,
+                None,
+            ),
+        ];
+        for (tx_id, (action, debit, credit, amount, category)) in (1..).zip(entries) {
+            let start = ledger.entries().len();
+            ledger.post(tx_id, action.clone(), debit, credit, amount);
+            ledger.annotate(
+                start,
+                &Transaction {
+                    category: category.map(str::to_string),
+                    ..Transaction::new(action, 1, tx_id, Some(amount))
+                },
+            );
+        }
+        ledger
+    }
+
+    #[test]
+    fn aggregates_the_volume_per_client_and_category() {
+        let volumes: Vec<_> = category_volumes(&ledger())
+            .into_iter()
+            .map(|volume| {
+                (
+                    volume.client_id,
+                    volume.category.clone(),
+                    volume.entries,
+                    volume.net(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            volumes,
+            vec![
+                (Some(1), "deposit".to_string(), 1, 3.0),
+                (Some(1), "withdrawal".to_string(), 1, -1.0),
+                (Some(2), "fee".to_string(), 1, -0.5),
+                (Some(2), "payroll".to_string(), 1, 5.0),
+                (None, "deposit".to_string(), 1, 3.0),
+                (None, "fee".to_string(), 1, -0.5),
+                (None, "payroll".to_string(), 1, 5.0),
+                (None, "withdrawal".to_string(), 1, -1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_between_clients_are_one_entry_of_the_totals() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            1,
+            Action::HoldInEscrow,
+            LedgerAccount::CustomerAvailable(1),
+            LedgerAccount::CustomerEscrow(1),
+            2.0,
+        );
+        ledger.post(
+            1,
+            Action::ReleaseEscrow,
+            LedgerAccount::CustomerEscrow(1),
+            LedgerAccount::CustomerAvailable(2),
+            2.0,
+        );
+        let volumes = category_volumes(&ledger);
+        assert_eq!(volumes.len(), 3);
+        assert_eq!(volumes[0].outflows, 2.0);
+        assert_eq!(volumes[1].inflows, 2.0);
+        assert_eq!(
+            volumes[2],
+            CategoryVolume {
+                client_id: None,
+                category: "release_escrow".to_string(),
+                entries: 1,
+                inflows: 2.0,
+                outflows: 2.0,
+            }
+        );
+    }
+
+    #[test]
+    fn writes_the_totals_as_client_all() {
+        let mut output = Vec::new();
+        write_category_report(
+            &mut output,
+            &category_volumes(&ledger())[4..5],
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,category,entries,inflows,outflows,net\n\
+             all,deposit,1,3.0000,0.0000,3.0000\n"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::encryption::{self, EncryptionError, EncryptionKey};
+use crate::tenant::Tenants;
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid checkpoint: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Checkpoint belongs to {expected}, not {actual}")]
+    SourceMismatch { expected: String, actual: String },
+
+    #[error("Checkpoint: {0}")]
+    Encryption(#[from] EncryptionError),
+}
+
+pub type CheckpointResult<T> = Result<T, CheckpointError>;
+
+// Position of the next unread record in the source, mirroring `csv::Position`
+// so that a reader can seek there directly on resume.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceOffset {
+    pub byte: u64,
+    pub line: u64,
+    pub record: u64,
+}
+
+// Lengths of the output files when the checkpoint was saved. Rows after the
+// checkpoint are written again on resume, so the files are cut back to them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutputOffsets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejects: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<u64>,
+}
+
+impl OutputOffsets {
+    // The current length of an output, after it was flushed.
+    pub fn length(path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    // Cuts an output back to its length at the checkpoint. Outputs the
+    // checkpoint has no length of are left as they are.
+    pub fn truncate(path: &Path, length: Option<u64>) -> io::Result<()> {
+        match length {
+            Some(length) if path.exists() && Self::length(path)? > length => {
+                OpenOptions::new().write(true).open(path)?.set_len(length)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    source: &'a str,
+    next: SourceOffset,
+    outputs: OutputOffsets,
+    tenants: &'a Tenants,
+}
+
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    pub source: String,
+    pub next: SourceOffset,
+    #[serde(default)]
+    pub outputs: OutputOffsets,
+    pub tenants: Tenants,
+}
+
+impl Checkpoint {
+    pub fn load(
+        path: &Path,
+        source: &str,
+        key: Option<&EncryptionKey>,
+    ) -> CheckpointResult<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let checkpoint: Checkpoint = serde_json::from_slice(&encryption::open(key, data)?)?;
+        if checkpoint.source != source {
+            return Err(CheckpointError::SourceMismatch {
+                expected: checkpoint.source,
+                actual: source.to_string(),
+            });
+        }
+        Ok(Some(checkpoint))
+    }
+
+    // Writes to a temporary file first and renames it, so a crash while saving
+    // never leaves a truncated checkpoint behind.
+    pub fn save(
+        path: &Path,
+        source: &str,
+        next: SourceOffset,
+        tenants: &Tenants,
+        key: Option<&EncryptionKey>,
+    ) -> CheckpointResult<()> {
+        Self::save_with_outputs(path, source, next, OutputOffsets::default(), tenants, key)
+    }
+
+    pub fn save_with_outputs(
+        path: &Path,
+        source: &str,
+        next: SourceOffset,
+        outputs: OutputOffsets,
+        tenants: &Tenants,
+        key: Option<&EncryptionKey>,
+    ) -> CheckpointResult<()> {
+        let json = serde_json::to_vec(&CheckpointRef {
+            source,
+            next,
+            outputs,
+            tenants,
+        })?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encryption::seal(key, json))?;
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::types::{Action, Transaction};
+
+    fn checkpoint_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!(
+            "accounting-demo-{}-{name}.json",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn restores_saved_state() {
+        let path = checkpoint_path("restore");
+        let mut tenants = Tenants::new();
+        let deposit = Transaction::new(Action::Deposit, 1, 1, Some(1.5));
+        assert!(tenants.process_transaction_at(22, deposit).is_ok());
+        let next = SourceOffset {
+            byte: 40,
+            line: 3,
+            record: 2,
+        };
+        Checkpoint::save(&path, "input.csv", next, &tenants, None).unwrap();
+
+        let checkpoint = Checkpoint::load(&path, "input.csv", None).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(checkpoint.next, next);
+        let account_manager = checkpoint.tenants.get("").unwrap();
+        assert_eq!(account_manager.committed_offset(), Some(22));
+        let accounts = account_manager.accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].1.available(), 1.5);
+
+        let mut tenants = checkpoint.tenants;
+        let dispute = Transaction::new(Action::Dispute, 1, 1, None);
+        assert!(tenants.process_transaction_at(40, dispute).is_ok());
+        assert_eq!(tenants.accounts()[0].2.disputed(), 1.5);
+    }
+
+    #[test]
+    fn outputs_are_cut_back_to_the_checkpoint() {
+        let path = checkpoint_path("outputs");
+        let output = checkpoint_path("rejects");
+        fs::write(&output, "type,client\n").unwrap();
+        let outputs = OutputOffsets {
+            rejects: Some(OutputOffsets::length(&output).unwrap()),
+            audit: None,
+        };
+        Checkpoint::save_with_outputs(
+            &path,
+            "input.csv",
+            SourceOffset::default(),
+            outputs,
+            &Tenants::new(),
+            None,
+        )
+        .unwrap();
+        let checkpoint = Checkpoint::load(&path, "input.csv", None).unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.outputs, outputs);
+
+        fs::write(&output, "type,client\ndeposit,1\n").unwrap();
+        OutputOffsets::truncate(&output, checkpoint.outputs.rejects).unwrap();
+        OutputOffsets::truncate(&output, checkpoint.outputs.audit).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "type,client\n");
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn missing_checkpoint_is_not_an_error() {
+        let path = checkpoint_path("missing");
+        assert!(Checkpoint::load(&path, "input.csv", None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn checkpoint_of_other_source_is_rejected() {
+        let path = checkpoint_path("mismatch");
+        Checkpoint::save(
+            &path,
+            "a.csv",
+            SourceOffset::default(),
+            &Tenants::new(),
+            None,
+        )
+        .unwrap();
+
+        let err = Checkpoint::load(&path, "b.csv", None).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(err, CheckpointError::SourceMismatch { .. }));
+    }
+
+    #[test]
+    fn encrypted_checkpoint_needs_the_key() {
+        let path = checkpoint_path("encrypted");
+        let key = EncryptionKey::from_hex(&"42".repeat(32)).unwrap();
+        let mut tenants = Tenants::new();
+        let deposit = Transaction::new(Action::Deposit, 7, 1, Some(2.0));
+        assert!(tenants.process_transaction_at(22, deposit).is_ok());
+        Checkpoint::save(
+            &path,
+            "input.csv",
+            SourceOffset::default(),
+            &tenants,
+            Some(&key),
+        )
+        .unwrap();
+
+        let raw = fs::read(&path).unwrap();
+        let without_key = Checkpoint::load(&path, "input.csv", None).err().unwrap();
+        let checkpoint = Checkpoint::load(&path, "input.csv", Some(&key)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(!String::from_utf8_lossy(&raw).contains("input.csv"));
+        assert!(matches!(
+            without_key,
+            CheckpointError::Encryption(EncryptionError::Encrypted)
+        ));
+        assert_eq!(checkpoint.unwrap().tenants.accounts()[0].1, 7);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::amount::ReportAmount;
+use crate::reconcile::AMOUNT_TOLERANCE;
+use crate::report::AccountRecord;
+use crate::types::{ClientId, TenantId};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DiffFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for DiffFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown diff format '{s}', expected csv or json")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    New,
+    Removed,
+    Changed,
+}
+
+// The change of an account from the old to the new report. The balances of a
+// missing account count as zero.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub change: ChangeKind,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked_before: Option<bool>,
+    pub locked_after: Option<bool>,
+}
+
+impl AccountDelta {
+    fn new(old: Option<&AccountRecord>, new: Option<&AccountRecord>) -> Option<Self> {
+        let record = new.or(old)?;
+        let delta =
+            |field: fn(&AccountRecord) -> f64| new.map_or(0.0, field) - old.map_or(0.0, field);
+        let change = match (old, new) {
+            (None, _) => ChangeKind::New,
+            (_, None) => ChangeKind::Removed,
+            _ => ChangeKind::Changed,
+        };
+        let delta = Self {
+            tenant: record.tenant.clone(),
+            client: record.client,
+            change,
+            available: delta(|record| record.available),
+            held: delta(|record| record.held),
+            total: delta(|record| record.total),
+            locked_before: old.map(|record| record.locked),
+            locked_after: new.map(|record| record.locked),
+        };
+        (change != ChangeKind::Changed || !delta.is_unchanged()).then_some(delta)
+    }
+
+    fn is_unchanged(&self) -> bool {
+        [self.available, self.held, self.total]
+            .iter()
+            .all(|amount| amount.abs() <= AMOUNT_TOLERANCE)
+            && self.locked_before == self.locked_after
+    }
+}
+
+fn key(record: &AccountRecord) -> (Option<&str>, ClientId) {
+    (record.tenant.as_deref(), record.client)
+}
+
+// The new, removed and changed accounts ordered by tenant and client id.
+// Accounts whose balances differ by less than the printed precision are
+// unchanged.
+pub fn diff(old: &[AccountRecord], new: &[AccountRecord]) -> Vec<AccountDelta> {
+    let old: BTreeMap<_, _> = old.iter().map(|record| (key(record), record)).collect();
+    let new: BTreeMap<_, _> = new.iter().map(|record| (key(record), record)).collect();
+
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter_map(|key| AccountDelta::new(old.get(key).copied(), new.get(key).copied()))
+        .collect()
+}
+
+#[derive(Serialize)]
+struct DeltaRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    client: ClientId,
+    change: ChangeKind,
+    available: ReportAmount,
+    held: ReportAmount,
+    total: ReportAmount,
+    locked_before: Option<bool>,
+    locked_after: Option<bool>,
+}
+
+// CSV rows with the tenant column if any account has a tenant, or a JSON
+// array of the deltas.
+pub fn write_deltas<W: Write>(
+    mut writer: W,
+    deltas: &[AccountDelta],
+    format: DiffFormat,
+    places: u32,
+) -> csv::Result<()> {
+    if format == DiffFormat::Json {
+        serde_json::to_writer(&mut writer, deltas).map_err(io::Error::from)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+    let with_tenant = deltas.iter().any(|delta| delta.tenant.is_some());
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    if with_tenant {
+        writer.write_field("tenant")?;
+    }
+    writer.write_record([
+        "client",
+        "change",
+        "available",
+        "held",
+        "total",
+        "locked_before",
+        "locked_after",
+    ])?;
+    for delta in deltas {
+        writer.serialize(DeltaRow {
+            tenant: with_tenant.then(|| delta.tenant.as_deref().unwrap_or_default()),
+            client: delta.client,
+            change: delta.change,
+            available: ReportAmount::new(delta.available, places),
+            held: ReportAmount::new(delta.held, places),
+            total: ReportAmount::new(delta.total, places),
+            locked_before: delta.locked_before,
+            locked_after: delta.locked_after,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::role::Role;
+    use crate::tier::Tier;
+
+    fn record(client: ClientId, available: f64, held: f64, locked: bool) -> AccountRecord {
+        AccountRecord {
+            tenant: None,
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            tx_count: 0,
+            tier: Tier::default(),
+            role: Role::default(),
+            receivable: 0.0,
+        }
+    }
+
+    #[test]
+    fn reports_new_removed_and_changed_accounts() {
+        let old = vec![
+            record(1, 1.5, 0.0, false),
+            record(2, 1.0, 0.0, false),
+            record(3, 2.0, 0.0, false),
+        ];
+        let new = vec![
+            record(4, 1.0, 0.0, false),
+            record(3, 2.00001, 0.0, false),
+            record(1, 1.0, 0.5, true),
+        ];
+
+        let deltas = diff(&old, &new);
+        let changes: Vec<_> = deltas
+            .iter()
+            .map(|delta| (delta.client, delta.change, delta.available))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (1, ChangeKind::Changed, -0.5),
+                (2, ChangeKind::Removed, -1.0),
+                (4, ChangeKind::New, 1.0),
+            ]
+        );
+
+        let mut output = Vec::new();
+        write_deltas(&mut output, &deltas, DiffFormat::Csv, 4).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,change,available,held,total,locked_before,locked_after\n\
+             1,changed,-0.5000,0.5000,0.0000,false,true\n\
+             2,removed,-1.0000,0.0000,-1.0000,false,\n\
+             4,new,1.0000,0.0000,1.0000,,false\n"
+        );
+    }
+
+    #[test]
+    fn writes_the_deltas_as_json() {
+        let old = vec![AccountRecord {
+            tenant: Some("acme".to_string()),
+            ..record(1, 1.0, 0.0, false)
+        }];
+        let mut output = Vec::new();
+        write_deltas(&mut output, &diff(&old, &[]), DiffFormat::Json, 4).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "[{\"tenant\":\"acme\",\"client\":1,\"change\":\"removed\",\"available\":-1.0,\
+             \"held\":0.0,\"total\":-1.0,\"locked_before\":false,\"locked_after\":null}]\n"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("File is not encrypted")]
+    NotEncrypted,
+
+    #[error("File is encrypted, an encryption key is required")]
+    Encrypted,
+
+    #[error("Decryption failed, wrong key or modified file")]
+    Decryption,
+}
+
+pub type EncryptionResult<T> = Result<T, EncryptionError>;
+
+// Encrypted files are MAGIC, a random 96 bit nonce and the AES-256-GCM
+// ciphertext with its authentication tag.
+const MAGIC: &[u8] = b"ACDEMO-AES256GCM-1\n";
+const NONCE_LENGTH: usize = 12;
+
+pub struct EncryptionKey {
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    pub fn from_hex(text: &str) -> EncryptionResult<Self> {
+        let bytes =
+            hex::decode(text.trim()).map_err(|err| EncryptionError::InvalidKey(err.to_string()))?;
+        if bytes.len() != 32 {
+            return Err(EncryptionError::InvalidKey(format!(
+                "expected 32 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        Ok(Self {
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)),
+        })
+    }
+
+    pub fn load(path: &Path) -> EncryptionResult<Self> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption into a Vec doesn't fail");
+
+        let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LENGTH + ciphertext.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        data
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> EncryptionResult<Vec<u8>> {
+        let data = data
+            .strip_prefix(MAGIC)
+            .ok_or(EncryptionError::NotEncrypted)?;
+        if data.len() < NONCE_LENGTH {
+            return Err(EncryptionError::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decryption)
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Encrypts with the key if there is one and passes the data through otherwise.
+pub fn seal(key: Option<&EncryptionKey>, plaintext: Vec<u8>) -> Vec<u8> {
+    match key {
+        Some(key) => key.encrypt(&plaintext),
+        None => plaintext,
+    }
+}
+
+// The inverse of `seal`. A key never accepts plaintext, so an encrypted file
+// can't be swapped for an unauthenticated one.
+pub fn open(key: Option<&EncryptionKey>, data: Vec<u8>) -> EncryptionResult<Vec<u8>> {
+    match key {
+        Some(key) => key.decrypt(&data),
+        None if is_encrypted(&data) => Err(EncryptionError::Encrypted),
+        None => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn round_trips_and_detects_modification() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let mut data = seal(Some(&key), b"{\"balance\":1.5}".to_vec());
+        assert!(is_encrypted(&data));
+        assert_eq!(
+            open(Some(&key), data.clone()).unwrap(),
+            b"{\"balance\":1.5}"
+        );
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            open(Some(&key), data),
+            Err(EncryptionError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn key_and_file_must_agree() {
+        let key = EncryptionKey::from_hex(KEY).unwrap();
+        let encrypted = seal(Some(&key), b"{}".to_vec());
+        assert!(matches!(
+            open(None, encrypted),
+            Err(EncryptionError::Encrypted)
+        ));
+        assert!(matches!(
+            open(Some(&key), b"{}".to_vec()),
+            Err(EncryptionError::NotEncrypted)
+        ));
+        assert_eq!(open(None, b"{}".to_vec()).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        assert!(matches!(
+            EncryptionKey::from_hex("0001"),
+            Err(EncryptionError::InvalidKey(_))
+        ));
+    }
+}
@@ -0,0 +1,584 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::amount::format_amount;
+use crate::history::BalancePoint;
+use crate::ledger::{Ledger, LedgerAccount};
+use crate::pseudonym::Pseudonymizer;
+use crate::types::{Action, ClientId, Timestamp, TransactionId};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalFormat {
+    Beancount,
+    LedgerCli,
+}
+
+impl FromStr for JournalFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "beancount" => Ok(Self::Beancount),
+            "ledger" => Ok(Self::LedgerCli),
+            _ => Err(format!(
+                "unknown journal format '{s}', expected beancount or ledger"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountNames {
+    pub cash: String,
+    pub customers: String,
+    pub chargeback_loss: String,
+    pub fees: String,
+    pub adjustments: String,
+    pub currency: String,
+    // There are no transaction timestamps, so all postings share one date.
+    pub date: String,
+}
+
+impl Default for AccountNames {
+    fn default() -> Self {
+        Self {
+            cash: "Assets:Bank".to_string(),
+            customers: "Liabilities:Customers".to_string(),
+            chargeback_loss: "Expenses:ChargebackLoss".to_string(),
+            fees: "Income:Fees".to_string(),
+            adjustments: "Equity:Adjustments".to_string(),
+            currency: "USD".to_string(),
+            date: "1970-01-01".to_string(),
+        }
+    }
+}
+
+impl AccountNames {
+    pub fn name(&self, account: LedgerAccount, pseudonymizer: &Pseudonymizer) -> String {
+        match account {
+            LedgerAccount::Cash => self.cash.clone(),
+            LedgerAccount::CustomerAvailable(client_id) => format!(
+                "{}:Client{}:Available",
+                self.customers,
+                pseudonymizer.client(client_id)
+            ),
+            LedgerAccount::CustomerHeld(client_id) => format!(
+                "{}:Client{}:Held",
+                self.customers,
+                pseudonymizer.client(client_id)
+            ),
+            LedgerAccount::CustomerSuspense(client_id) => format!(
+                "{}:Client{}:Suspense",
+                self.customers,
+                pseudonymizer.client(client_id)
+            ),
+            LedgerAccount::CustomerEscrow(client_id) => format!(
+                "{}:Client{}:Escrow",
+                self.customers,
+                pseudonymizer.client(client_id)
+            ),
+            LedgerAccount::CustomerReceivable(client_id) => format!(
+                "{}:Client{}:Receivable",
+                self.customers,
+                pseudonymizer.client(client_id)
+            ),
+            LedgerAccount::ChargebackLoss => self.chargeback_loss.clone(),
+            LedgerAccount::FeeIncome => self.fees.clone(),
+            LedgerAccount::Adjustments => self.adjustments.clone(),
+            LedgerAccount::ErasedCustomer => format!("{}:Erased", self.customers),
+        }
+    }
+}
+
+fn write_open_directives<W: Write>(
+    writer: &mut W,
+    ledger: &Ledger,
+    names: &AccountNames,
+    pseudonymizer: &Pseudonymizer,
+) -> io::Result<()> {
+    // In the order of their first entry.
+    let mut seen = BTreeSet::new();
+    let opened = ledger
+        .entries()
+        .iter()
+        .flat_map(|entry| [entry.debit, entry.credit])
+        .filter(|account| seen.insert(*account));
+    for account in opened {
+        writeln!(
+            writer,
+            "{} open {} {}",
+            names.date,
+            names.name(account, pseudonymizer),
+            names.currency
+        )?;
+    }
+    writeln!(writer)
+}
+
+pub fn write_journal<W: Write>(
+    mut writer: W,
+    ledger: &Ledger,
+    format: JournalFormat,
+    names: &AccountNames,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> io::Result<()> {
+    let (date, indent) = match format {
+        JournalFormat::Beancount => {
+            write_open_directives(&mut writer, ledger, names, pseudonymizer)?;
+            (names.date.clone(), "  ")
+        }
+        JournalFormat::LedgerCli => (names.date.replace('-', "/"), "    "),
+    };
+
+    for entry in ledger.entries() {
+        let action = entry.action.as_str();
+        match format {
+            JournalFormat::Beancount => {
+                writeln!(writer, "{date} * \"{action} tx {}\"", entry.tx_id)?
+            }
+            JournalFormat::LedgerCli => writeln!(writer, "{date} {action} tx {}", entry.tx_id)?,
+        }
+        writeln!(
+            writer,
+            "{indent}{}  {} {}",
+            names.name(entry.debit, pseudonymizer),
+            format_amount(entry.amount, places),
+            names.currency
+        )?;
+        writeln!(
+            writer,
+            "{indent}{}  {} {}",
+            names.name(entry.credit, pseudonymizer),
+            format_amount(-entry.amount, places),
+            names.currency
+        )?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+// QIF dates are MM/DD/YYYY.
+fn qif_date(date: &str) -> String {
+    match date.split('-').collect::<Vec<_>>()[..] {
+        [year, month, day] => format!("{month}/{day}/{year}"),
+        _ => date.to_string(),
+    }
+}
+
+// QIF fields end at the line break, so line breaks of free text become
+// spaces.
+fn qif_text(text: &str) -> Cow<'_, str> {
+    match text.contains(['\r', '\n']) {
+        true => Cow::Owned(text.replace("\r\n", " ").replace(['\r', '\n'], " ")),
+        false => Cow::Borrowed(text),
+    }
+}
+
+// One QIF bank transaction per applied operation of the client. Amounts are
+// changes of the client's total balance, so disputes and resolves show up with
+// a zero amount and the moved funds in the memo. The payee is the description
+// of the transaction if it has one.
+pub fn write_qif_statement<W: Write>(
+    mut writer: W,
+    ledger: &Ledger,
+    client_id: ClientId,
+    date: &str,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> io::Result<()> {
+    let date = qif_date(date);
+    writeln!(writer, "!Type:Bank")?;
+    for entry in ledger.entries() {
+        let credited = entry.credit.client_id() == Some(client_id);
+        let debited = entry.debit.client_id() == Some(client_id);
+        if !credited && !debited {
+            continue;
+        }
+        let change = match (credited, debited) {
+            (true, false) => entry.amount,
+            (false, true) => -entry.amount,
+            _ => 0.0,
+        };
+        writeln!(writer, "D{date}")?;
+        writeln!(writer, "T{}", format_amount(change, places))?;
+        writeln!(writer, "N{}", entry.tx_id)?;
+        writeln!(
+            writer,
+            "P{}",
+            qif_text(
+                entry
+                    .description
+                    .as_deref()
+                    .unwrap_or(entry.action.as_str())
+            )
+        )?;
+        write!(
+            writer,
+            "M{} {} from {} to {}",
+            entry.action.as_str(),
+            format_amount(entry.amount, places),
+            entry.debit.label(pseudonymizer),
+            entry.credit.label(pseudonymizer)
+        )?;
+        match &entry.reference {
+            Some(reference) => writeln!(writer, ", reference {}", qif_text(reference))?,
+            None => writeln!(writer)?,
+        }
+        writeln!(writer, "^")?;
+    }
+    writer.flush()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ClientLogFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for ClientLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format '{s}', expected csv or json")),
+        }
+    }
+}
+
+// Where the dispute of a deposit or withdrawal ended up.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    Undisputed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+// An applied transaction of a client with the balances after it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientLogEntry {
+    pub sequence: u64,
+    pub timestamp: Option<Timestamp>,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub action: Option<Action>,
+    pub amount: Option<f64>,
+    // The state of the deposit or withdrawal the row refers to at the end of
+    // the log, none for other transactions.
+    pub dispute_state: Option<DisputeState>,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+// The log of the balance history points of one client.
+pub fn client_log(points: &[BalancePoint]) -> Vec<ClientLogEntry> {
+    let mut states = HashMap::new();
+    for point in points {
+        let state = match point.action {
+            Some(Action::Deposit | Action::Withdrawal) => DisputeState::Undisputed,
+            Some(Action::Dispute) => DisputeState::Disputed,
+            Some(Action::Resolve) => DisputeState::Resolved,
+            Some(Action::Chargeback) => DisputeState::ChargedBack,
+            _ => continue,
+        };
+        states.insert(point.tx_id, state);
+    }
+    points
+        .iter()
+        .map(|point| ClientLogEntry {
+            sequence: point.sequence,
+            timestamp: point.timestamp,
+            tx: point.tx_id,
+            action: point.action.clone(),
+            amount: point.amount,
+            dispute_state: match point.action {
+                Some(
+                    Action::Deposit
+                    | Action::Withdrawal
+                    | Action::Dispute
+                    | Action::Resolve
+                    | Action::Chargeback,
+                ) => states.get(&point.tx_id).copied(),
+                _ => None,
+            },
+            available: point.available,
+            held: point.held,
+            total: point.total(),
+            locked: point.locked,
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct ClientLogRow<'a> {
+    client: &'a str,
+    sequence: u64,
+    timestamp: Option<Timestamp>,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    action: Option<&'a str>,
+    amount: Option<String>,
+    dispute_state: Option<DisputeState>,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+#[derive(Serialize)]
+struct ClientLog<'a> {
+    client: &'a str,
+    transactions: &'a [ClientLogEntry],
+}
+
+// CSV rows with the amounts formatted like the report, or one JSON object
+// with the client and its transactions.
+pub fn write_client_log<W: Write>(
+    mut writer: W,
+    client_id: ClientId,
+    entries: &[ClientLogEntry],
+    format: ClientLogFormat,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let client = pseudonymizer.client(client_id);
+    if format == ClientLogFormat::Json {
+        let log = ClientLog {
+            client: &client,
+            transactions: entries,
+        };
+        serde_json::to_writer(&mut writer, &log).map_err(io::Error::from)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+    let mut writer = Writer::from_writer(writer);
+    for entry in entries {
+        writer.serialize(ClientLogRow {
+            client: &client,
+            sequence: entry.sequence,
+            timestamp: entry.timestamp,
+            tx: entry.tx,
+            action: entry.action.as_ref().map(Action::as_str),
+            amount: entry.amount.map(|amount| format_amount(amount, places)),
+            dispute_state: entry.dispute_state,
+            available: format_amount(entry.available, places),
+            held: format_amount(entry.held, places),
+            total: format_amount(entry.total, places),
+            locked: entry.locked,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::{process_transaction, AccountManager};
+    use crate::types::Transaction;
+
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            1,
+            Action::Deposit,
+            LedgerAccount::Cash,
+            LedgerAccount::CustomerAvailable(1),
+            1.5,
+        );
+        ledger.post(
+            1,
+            Action::Dispute,
+            LedgerAccount::CustomerAvailable(1),
+            LedgerAccount::CustomerHeld(1),
+            1.5,
+        );
+        ledger
+    }
+
+    #[test]
+    fn exports_beancount() {
+        let mut output = Vec::new();
+        write_journal(
+            &mut output,
+            &ledger(),
+            JournalFormat::Beancount,
+            &AccountNames::default(),
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1970-01-01 open Assets:Bank USD\n\
+             1970-01-01 open Liabilities:Customers:Client1:Available USD\n\
+             1970-01-01 open Liabilities:Customers:Client1:Held USD\n\
+             \n\
+             1970-01-01 * \"deposit tx 1\"\n  \
+             Assets:Bank  1.5000 USD\n  \
+             Liabilities:Customers:Client1:Available  -1.5000 USD\n\
+             \n\
+             1970-01-01 * \"dispute tx 1\"\n  \
+             Liabilities:Customers:Client1:Available  1.5000 USD\n  \
+             Liabilities:Customers:Client1:Held  -1.5000 USD\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn exports_ledger_cli_with_custom_names() {
+        let names = AccountNames {
+            cash: "Assets:Checking".to_string(),
+            customers: "Liabilities:Clients".to_string(),
+            currency: "EUR".to_string(),
+            date: "2024-01-31".to_string(),
+            ..AccountNames::default()
+        };
+        let mut output = Vec::new();
+        write_journal(
+            &mut output,
+            &ledger(),
+            JournalFormat::LedgerCli,
+            &names,
+            2,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(
+            "2024/01/31 deposit tx 1\n    \
+             Assets:Checking  1.50 EUR\n    \
+             Liabilities:Clients:Client1:Available  -1.50 EUR\n\n"
+        ));
+    }
+
+    #[test]
+    fn qif_statement_contains_only_the_client_activity() {
+        let mut ledger = ledger();
+        ledger.post(
+            2,
+            Action::Deposit,
+            LedgerAccount::Cash,
+            LedgerAccount::CustomerAvailable(2),
+            3.0,
+        );
+        ledger.annotate(
+            1,
+            &Transaction {
+                description: Some("Card\r\nfraud".to_string()),
+                reference: Some("CASE-7\n^\nD".to_string()),
+                ..Transaction::new(Action::Dispute, 1, 1, None)
+            },
+        );
+        ledger.post(
+            1,
+            Action::Chargeback,
+            LedgerAccount::ChargebackLoss,
+            LedgerAccount::Cash,
+            1.5,
+        );
+        ledger.post(
+            1,
+            Action::Chargeback,
+            LedgerAccount::CustomerHeld(1),
+            LedgerAccount::ChargebackLoss,
+            1.5,
+        );
+
+        let mut output = Vec::new();
+        write_qif_statement(
+            &mut output,
+            &ledger,
+            1,
+            "2024-01-31",
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "!Type:Bank\n\
+             D01/31/2024\nT1.5000\nN1\nPdeposit\n\
+             Mdeposit 1.5000 from cash to customer:1:available\n^\n\
+             D01/31/2024\nT0.0000\nN1\nPCard fraud\n\
+             Mdispute 1.5000 from customer:1:available to customer:1:held, reference CASE-7 ^ D\n^\n\
+             D01/31/2024\nT-1.5000\nN1\nPchargeback\n\
+             Mchargeback 1.5000 from customer:1:held to chargeback_loss\n^\n"
+        );
+    }
+
+    #[test]
+    fn client_log_shows_where_disputes_ended_up() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_history();
+        let txs = [
+            Transaction::new(Action::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(Action::Deposit, 2, 2, Some(9.0)),
+            Transaction::new(Action::Deposit, 1, 3, Some(2.0)),
+            Transaction::new(Action::Dispute, 1, 1, None),
+            Transaction::new(Action::Resolve, 1, 1, None),
+            Transaction::new(Action::Dispute, 1, 3, None),
+            Transaction::new(Action::Chargeback, 1, 3, None),
+        ];
+        for tx in txs {
+            assert!(process_transaction(&mut account_manager, tx).is_ok());
+        }
+        let points: Vec<_> = account_manager.history(1).cloned().collect();
+        let log = client_log(&points);
+        let states: Vec<_> = log
+            .iter()
+            .map(|entry| (entry.tx, entry.dispute_state))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                (1, Some(DisputeState::Resolved)),
+                (3, Some(DisputeState::ChargedBack)),
+                (1, Some(DisputeState::Resolved)),
+                (1, Some(DisputeState::Resolved)),
+                (3, Some(DisputeState::ChargedBack)),
+                (3, Some(DisputeState::ChargedBack)),
+            ]
+        );
+
+        let mut csv = Vec::new();
+        let format = ClientLogFormat::Csv;
+        write_client_log(&mut csv, 1, &log, format, 4, &Pseudonymizer::new()).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("client,sequence,timestamp,tx,type,amount,dispute_state,available,held,total,locked")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("1,1,,1,deposit,5.0000,resolved,5.0000,0.0000,5.0000,false")
+        );
+        assert_eq!(
+            lines.last(),
+            Some("1,7,,3,chargeback,,charged_back,5.0000,0.0000,5.0000,true")
+        );
+
+        let mut json = Vec::new();
+        let format = ClientLogFormat::Json;
+        write_client_log(&mut json, 1, &log[..1], format, 4, &Pseudonymizer::new()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["client"], "1");
+        assert_eq!(json["transactions"][0]["type"], "deposit");
+        assert_eq!(json["transactions"][0]["dispute_state"], "resolved");
+    }
+}
@@ -0,0 +1,966 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Read;
+use std::str::{self, FromStr};
+
+use csv::{ByteRecord, Reader, ReaderBuilder, StringRecord, Trim};
+use thiserror::Error;
+
+use crate::amount::{AmountFormat, AmountParseError, ExactAmount};
+use crate::policy::UnknownColumnPolicy;
+use crate::role::Role;
+use crate::types::{
+    client_id_from_u64, Action, ClientId, RecordError, RecordResult, Transaction,
+    TRANSACTION_COLUMNS,
+};
+
+// Columns the parsers know, all others are handled by UnknownColumnPolicy.
+pub const KNOWN_COLUMNS: [&str; 13] = TRANSACTION_COLUMNS;
+
+// The separators of a transactions CSV.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CsvDialect {
+    // `,` between fields, amounts in the --amount-format.
+    #[default]
+    Standard,
+    // `;` between fields and `,` before the decimals, e.g. 1234,56 or
+    // 1.234,56, like the exports of many European tools.
+    DecimalComma,
+}
+
+impl FromStr for CsvDialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standard" => Ok(Self::Standard),
+            "decimal-comma" => Ok(Self::DecimalComma),
+            _ => Err(format!(
+                "unknown dialect '{s}', expected standard or decimal-comma"
+            )),
+        }
+    }
+}
+
+impl CsvDialect {
+    pub fn delimiter(self) -> u8 {
+        match self {
+            Self::Standard => b',',
+            Self::DecimalComma => b';',
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FastParseError {
+    #[error("missing column `{0}`")]
+    MissingColumn(&'static str),
+
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+
+    #[error("unknown variant `{0}`, expected one of `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`")]
+    InvalidAction(String),
+
+    #[error("invalid {field} `{value}`")]
+    InvalidField { field: &'static str, value: String },
+
+    #[error("{0}")]
+    Amount(#[from] AmountParseError),
+
+    #[error("unknown column `{0}`")]
+    UnknownColumn(String),
+
+    #[error("{}", schema_message(.missing, .unexpected, .duplicate))]
+    SchemaMismatch {
+        missing: Vec<&'static str>,
+        unexpected: Vec<String>,
+        duplicate: Vec<String>,
+    },
+}
+
+pub type FastParseResult<T> = Result<T, FastParseError>;
+
+// The columns a header needs in strict mode.
+pub const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+// Checks the whole header up front for the strict mode: every required column
+// once, and no unknown columns unless they are captured. Lists all problems.
+pub fn check_schema(headers: &ByteRecord, policy: UnknownColumnPolicy) -> FastParseResult<()> {
+    let count = |name: &str| {
+        headers
+            .iter()
+            .filter(|header| *header == name.as_bytes())
+            .count()
+    };
+    let missing: Vec<_> = REQUIRED_COLUMNS
+        .into_iter()
+        .filter(|name| count(name) == 0)
+        .collect();
+    let unexpected: Vec<_> = match policy {
+        UnknownColumnPolicy::Capture => Vec::new(),
+        _ => headers
+            .iter()
+            .filter(|header| {
+                !KNOWN_COLUMNS
+                    .iter()
+                    .any(|known| known.as_bytes() == *header)
+            })
+            .map(|header| String::from_utf8_lossy(header).into_owned())
+            .collect(),
+    };
+    let duplicate: Vec<_> = KNOWN_COLUMNS
+        .into_iter()
+        .filter(|name| count(name) > 1)
+        .map(str::to_string)
+        .collect();
+    match missing.is_empty() && unexpected.is_empty() && duplicate.is_empty() {
+        true => Ok(()),
+        false => Err(FastParseError::SchemaMismatch {
+            missing,
+            unexpected,
+            duplicate,
+        }),
+    }
+}
+
+fn schema_message(missing: &[&str], unexpected: &[String], duplicate: &[String]) -> String {
+    let problems: Vec<String> = [
+        (
+            "missing",
+            missing
+                .iter()
+                .map(|name| format!("`{name}`"))
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "unexpected",
+            unexpected.iter().map(|name| format!("`{name}`")).collect(),
+        ),
+        (
+            "duplicate",
+            duplicate.iter().map(|name| format!("`{name}`")).collect(),
+        ),
+    ]
+    .into_iter()
+    .filter(|(_, names)| !names.is_empty())
+    .map(|(problem, names)| format!("{problem} columns {}", names.join(", ")))
+    .collect();
+    format!(
+        "header doesn't match the schema: {}. Expected {} and optionally {}",
+        problems.join("; "),
+        REQUIRED_COLUMNS.join(", "),
+        KNOWN_COLUMNS[REQUIRED_COLUMNS.len()..].join(", ")
+    )
+}
+
+pub const COMMENT_PREFIX: u8 = b'#';
+
+// Why a row isn't a transaction. Rows of unknown types can be skipped, the
+// other errors make the input invalid.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RowError {
+    #[error("type `{0}` is not a transaction type")]
+    UnknownAction(String),
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<RecordError> for RowError {
+    fn from(err: RecordError) -> Self {
+        match err {
+            RecordError::UnknownAction { value } => RowError::UnknownAction(value),
+            err => RowError::Invalid(err.to_string()),
+        }
+    }
+}
+
+// Names of the transaction types in other systems, e.g. WITHDRAW for a
+// withdrawal. Names are compared ignoring case, the canonical ones always
+// resolve.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ActionAliases {
+    aliases: HashMap<String, Action>,
+}
+
+impl ActionAliases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_alias(mut self, name: &str, action: Action) -> Self {
+        self.aliases.insert(name.to_lowercase(), action);
+        self
+    }
+
+    pub fn resolve(&self, name: &[u8]) -> Option<Action> {
+        if let Ok(action) = parse_action(name) {
+            return Some(action);
+        }
+        let name = str::from_utf8(name).ok()?.to_lowercase();
+        self.aliases
+            .get(&name)
+            .cloned()
+            .or_else(|| name.parse().ok())
+    }
+}
+
+fn invalid(field: &'static str, value: &[u8]) -> FastParseError {
+    FastParseError::InvalidField {
+        field,
+        value: String::from_utf8_lossy(value).into_owned(),
+    }
+}
+
+fn parse_action(value: &[u8]) -> FastParseResult<Action> {
+    match value {
+        b"deposit" => Ok(Action::Deposit),
+        b"withdrawal" => Ok(Action::Withdrawal),
+        b"dispute" => Ok(Action::Dispute),
+        b"resolve" => Ok(Action::Resolve),
+        b"chargeback" => Ok(Action::Chargeback),
+        b"open_account" => Ok(Action::OpenAccount),
+        b"hold_in_escrow" => Ok(Action::HoldInEscrow),
+        b"release_escrow" => Ok(Action::ReleaseEscrow),
+        b"refund_escrow" => Ok(Action::RefundEscrow),
+        b"close_period" => Ok(Action::ClosePeriod),
+        b"adjustment" => Ok(Action::Adjustment),
+        b"erase_client" => Ok(Action::EraseClient),
+        b"freeze" => Ok(Action::Freeze),
+        b"unfreeze" => Ok(Action::Unfreeze),
+        b"balance" => Ok(Action::Balance),
+        _ => Err(FastParseError::InvalidAction(
+            String::from_utf8_lossy(value).into_owned(),
+        )),
+    }
+}
+
+fn parse_client(field: &'static str, value: &[u8]) -> FastParseResult<ClientId> {
+    client_id_from_u64(parse_integer(field, value)?).ok_or_else(|| invalid(field, value))
+}
+
+fn parse_role(value: &[u8]) -> FastParseResult<Role> {
+    match value {
+        b"customer" => Ok(Role::Customer),
+        b"merchant" => Ok(Role::Merchant),
+        _ => Err(invalid("role", value)),
+    }
+}
+
+fn parse_integer(field: &'static str, value: &[u8]) -> FastParseResult<u64> {
+    let digits = value.strip_prefix(b"+").unwrap_or(value);
+    if digits.is_empty() {
+        return Err(invalid(field, value));
+    }
+    digits.iter().try_fold(0u64, |number, byte| {
+        let digit = byte.wrapping_sub(b'0');
+        match digit < 10 {
+            true => number
+                .checked_mul(10)
+                .and_then(|number| number.checked_add(digit as u64))
+                .ok_or_else(|| invalid(field, value)),
+            false => Err(invalid(field, value)),
+        }
+    })
+}
+
+fn parse_amount(value: &[u8]) -> FastParseResult<f64> {
+    let value = str::from_utf8(value).map_err(|_| invalid("amount", value))?;
+    Ok(ExactAmount::parse(value)?.to_f64())
+}
+
+fn parse_string(field: &'static str, value: &[u8]) -> FastParseResult<Option<String>> {
+    match value.is_empty() {
+        true => Ok(None),
+        false => str::from_utf8(value)
+            .map(|value| Some(value.to_string()))
+            .map_err(|_| invalid(field, value)),
+    }
+}
+
+// Parses transactions straight from the bytes of a record, without serde and
+// without validating UTF-8 for fields that are numbers. The columns are looked
+// up once in the header.
+#[derive(Debug, Clone)]
+pub struct FastParser {
+    action: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    idempotency_key: Option<usize>,
+    tenant: Option<usize>,
+    description: Option<usize>,
+    reference: Option<usize>,
+    category: Option<usize>,
+    timestamp: Option<usize>,
+    execute_at: Option<usize>,
+    role: Option<usize>,
+    counterparty: Option<usize>,
+}
+
+impl FastParser {
+    pub fn new(headers: &ByteRecord) -> FastParseResult<Self> {
+        let column = |name: &str| headers.iter().position(|header| header == name.as_bytes());
+        let required = |name: &'static str| column(name).ok_or(FastParseError::MissingColumn(name));
+        Ok(Self {
+            action: required("type")?,
+            client: required("client")?,
+            tx: required("tx")?,
+            amount: column("amount"),
+            idempotency_key: column("idempotency_key"),
+            tenant: column("tenant"),
+            description: column("description"),
+            reference: column("reference"),
+            category: column("category"),
+            timestamp: column("timestamp"),
+            execute_at: column("execute_at"),
+            role: column("role"),
+            counterparty: column("counterparty"),
+        })
+    }
+
+    pub fn parse(&self, record: &ByteRecord) -> FastParseResult<Transaction> {
+        let required = |index: usize, name: &'static str| {
+            record.get(index).ok_or(FastParseError::MissingField(name))
+        };
+        let optional = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .unwrap_or_default()
+        };
+
+        let action = parse_action(required(self.action, "type")?)?;
+        let client = required(self.client, "client")?;
+        let client_id = parse_client("client", client)?;
+        let tx = required(self.tx, "tx")?;
+        let id = parse_integer("tx", tx)?
+            .try_into()
+            .map_err(|_| invalid("tx", tx))?;
+        let amount = match optional(self.amount) {
+            b"" => None,
+            amount => Some(parse_amount(amount)?),
+        };
+        let timestamp = |name: &'static str, index: Option<usize>| match optional(index) {
+            b"" => Ok(None),
+            value => parse_integer(name, value).map(Some),
+        };
+        Ok(Transaction {
+            idempotency_key: parse_string("idempotency_key", optional(self.idempotency_key))?,
+            tenant: parse_string("tenant", optional(self.tenant))?,
+            description: parse_string("description", optional(self.description))?,
+            reference: parse_string("reference", optional(self.reference))?,
+            category: parse_string("category", optional(self.category))?,
+            timestamp: timestamp("timestamp", self.timestamp)?,
+            execute_at: timestamp("execute_at", self.execute_at)?,
+            counterparty: match optional(self.counterparty) {
+                b"" => None,
+                counterparty => Some(parse_client("counterparty", counterparty)?),
+            },
+            role: match optional(self.role) {
+                b"" => None,
+                role => Some(parse_role(role)?),
+            },
+            ..Transaction::new(action, client_id, id, amount)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Decoder {
+    Serde,
+    Fast(FastParser),
+}
+
+// Parses records either with serde or the fast parser, e.g. in parser threads,
+// and applies the UnknownColumnPolicy.
+#[derive(Debug, Clone)]
+pub struct RecordParser {
+    headers: ByteRecord,
+    // The headers for Transaction::from_record.
+    names: StringRecord,
+    decoder: Decoder,
+    unknown_columns: UnknownColumnPolicy,
+    // Index and name of the unknown columns.
+    unknown: Vec<(usize, String)>,
+    amount: Option<usize>,
+    amount_format: AmountFormat,
+    action: Option<usize>,
+    aliases: ActionAliases,
+    delimiter: u8,
+    skip_comments: bool,
+}
+
+impl RecordParser {
+    pub fn new(headers: &ByteRecord, fast: bool) -> FastParseResult<Self> {
+        let unknown = headers
+            .iter()
+            .enumerate()
+            .filter(|(_, header)| {
+                !KNOWN_COLUMNS
+                    .iter()
+                    .any(|known| known.as_bytes() == *header)
+            })
+            .map(|(index, header)| (index, String::from_utf8_lossy(header).into_owned()))
+            .collect();
+        Ok(Self {
+            headers: headers.clone(),
+            names: StringRecord::from_byte_record_lossy(headers.clone()),
+            decoder: match fast {
+                true => Decoder::Fast(FastParser::new(headers)?),
+                false => Decoder::Serde,
+            },
+            unknown_columns: UnknownColumnPolicy::default(),
+            unknown,
+            amount: headers.iter().position(|header| header == b"amount"),
+            amount_format: AmountFormat::default(),
+            action: headers.iter().position(|header| header == b"type"),
+            aliases: ActionAliases::new(),
+            delimiter: CsvDialect::default().delimiter(),
+            skip_comments: false,
+        })
+    }
+
+    pub fn with_amount_format(mut self, format: AmountFormat) -> Self {
+        self.amount_format = format;
+        self
+    }
+
+    pub fn with_action_aliases(mut self, aliases: ActionAliases) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    // The headers must have been read with the delimiter of the dialect.
+    pub fn with_dialect(mut self, dialect: CsvDialect) -> Self {
+        if dialect == CsvDialect::DecimalComma {
+            self.amount_format = AmountFormat::DecimalComma;
+        }
+        self.delimiter = dialect.delimiter();
+        self
+    }
+
+    // Whether the input has the column, e.g. to pass it through to outputs.
+    pub fn has_column(&self, name: &str) -> bool {
+        self.headers.iter().any(|header| header == name.as_bytes())
+    }
+
+    // The field separator of the rows, e.g. for readers of chunks.
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    // Skips lines starting with # and blank lines, e.g. of hand-maintained
+    // test files. Only `read_record` skips them.
+    pub fn with_skip_comments(mut self, skip: bool) -> Self {
+        self.skip_comments = skip;
+        self
+    }
+
+    // The comment prefix for readers of the input, so quotes in comments
+    // don't run into the next lines.
+    pub fn comment(&self) -> Option<u8> {
+        self.skip_comments.then_some(COMMENT_PREFIX)
+    }
+
+    // Reads the next record like Reader::read_byte_record, skipping comments
+    // and records of empty fields, e.g. lines of spaces, if the parser skips
+    // comments. Comments may be indented.
+    pub fn read_record<R: Read>(
+        &self,
+        reader: &mut Reader<R>,
+        record: &mut ByteRecord,
+    ) -> csv::Result<bool> {
+        while reader.read_byte_record(record)? {
+            let comment = record
+                .get(0)
+                .is_some_and(|field| field.first() == Some(&COMMENT_PREFIX));
+            let blank = record.iter().all(<[u8]>::is_empty);
+            if !(self.skip_comments && (comment || blank)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // The record with the canonical type and the amount in the plain format.
+    fn normalize<'a>(&self, record: &'a ByteRecord) -> RecordResult<Cow<'a, ByteRecord>> {
+        let action = self.canonical_action(record)?;
+        let amount = self.plain_amount(record)?;
+        if action.is_none() && amount.is_none() {
+            return Ok(Cow::Borrowed(record));
+        }
+        Ok(Cow::Owned(
+            record
+                .iter()
+                .enumerate()
+                .map(|(i, field)| match (action, &amount) {
+                    (Some(action), _) if Some(i) == self.action => action.as_bytes(),
+                    (_, Some(amount)) if Some(i) == self.amount => amount.as_bytes(),
+                    _ => field,
+                })
+                .collect(),
+        ))
+    }
+
+    // The name of a type written another way, e.g. WITHDRAW, None if the
+    // type is canonical or missing.
+    fn canonical_action(&self, record: &ByteRecord) -> RecordResult<Option<&'static str>> {
+        let Some(value) = self.action.and_then(|index| record.get(index)) else {
+            return Ok(None);
+        };
+        if value.is_empty() || parse_action(value).is_ok() {
+            return Ok(None);
+        }
+        match self.aliases.resolve(value) {
+            Some(action) => Ok(Some(action.as_str())),
+            None => Err(RecordError::UnknownAction {
+                value: String::from_utf8_lossy(value).into_owned(),
+            }),
+        }
+    }
+
+    // None if the amount is missing or already plain.
+    fn plain_amount(&self, record: &ByteRecord) -> RecordResult<Option<String>> {
+        let Some(index) = self
+            .amount
+            .filter(|_| self.amount_format != AmountFormat::Plain)
+        else {
+            return Ok(None);
+        };
+        let Some(value) = record.get(index) else {
+            return Ok(None);
+        };
+        let value = str::from_utf8(value).map_err(|_| RecordError::InvalidUtf8 {
+            field: "amount".to_string(),
+        })?;
+        match self.amount_format.normalize(value)? {
+            Cow::Borrowed(_) => Ok(None),
+            Cow::Owned(amount) => Ok(Some(amount)),
+        }
+    }
+
+    pub fn with_unknown_columns(mut self, policy: UnknownColumnPolicy) -> FastParseResult<Self> {
+        if let (UnknownColumnPolicy::Reject, Some((_, name))) = (policy, self.unknown.first()) {
+            return Err(FastParseError::UnknownColumn(name.clone()));
+        }
+        self.unknown_columns = policy;
+        Ok(self)
+    }
+
+    fn check_length(&self, record: &ByteRecord) -> RecordResult<()> {
+        match self.unknown_columns == UnknownColumnPolicy::Reject
+            && record.len() > self.headers.len()
+        {
+            true => Err(RecordError::ExtraFields {
+                expected: self.headers.len(),
+                found: record.len(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    fn capture(&self, record: &ByteRecord, tx: &mut Transaction) {
+        if self.unknown_columns == UnknownColumnPolicy::Capture {
+            for (index, name) in &self.unknown {
+                let value = record.get(*index).unwrap_or_default();
+                if !value.is_empty() {
+                    let value = String::from_utf8_lossy(value).into_owned();
+                    tx.metadata.insert(name.clone(), value);
+                }
+            }
+        }
+    }
+
+    pub fn parse(&self, record: &ByteRecord) -> Result<Transaction, RowError> {
+        self.check_length(record)?;
+        let record = self.normalize(record)?;
+        let record = record.as_ref();
+        let mut tx: Transaction = match &self.decoder {
+            Decoder::Serde => {
+                // Numbers reach serde as f64, their digits are checked here.
+                if let Some(amount) = self.amount.and_then(|index| record.get(index)) {
+                    if !amount.is_empty() {
+                        parse_amount(amount).map_err(|err| RowError::Invalid(err.to_string()))?;
+                    }
+                }
+                record
+                    .deserialize(Some(&self.headers))
+                    .map_err(|err| match err.kind() {
+                        csv::ErrorKind::Deserialize { err, .. } => {
+                            RowError::Invalid(err.to_string())
+                        }
+                        _ => RowError::Invalid(err.to_string()),
+                    })?
+            }
+            Decoder::Fast(parser) => parser
+                .parse(record)
+                .map_err(|err| RowError::Invalid(err.to_string()))?,
+        };
+        self.capture(record, &mut tx);
+        Ok(tx)
+    }
+
+    // Parses like `parse`, but with Transaction::from_record, whose errors
+    // name the field that failed. Slower, e.g. for the validate subcommand.
+    pub fn parse_record(&self, record: &ByteRecord) -> RecordResult<Transaction> {
+        self.check_length(record)?;
+        let record = self.normalize(record)?;
+        let record = record.as_ref();
+        // Unknown columns and surplus fields don't have to be UTF-8, values
+        // are captured lossily like in `parse`.
+        let fields = match StringRecord::from_byte_record(record.clone()) {
+            Ok(fields) => fields,
+            Err(err) => {
+                let fields = err.into_byte_record();
+                let invalid = fields.iter().enumerate().find(|(index, value)| {
+                    str::from_utf8(value).is_err()
+                        && *index < self.headers.len()
+                        && !self.unknown.iter().any(|(unknown, _)| unknown == index)
+                });
+                if let Some((index, _)) = invalid {
+                    return Err(RecordError::InvalidUtf8 {
+                        field: self.names.get(index).unwrap_or_default().to_string(),
+                    });
+                }
+                StringRecord::from_byte_record_lossy(fields)
+            }
+        };
+        let mut tx = Transaction::from_record(&fields, &self.names)?;
+        self.capture(record, &mut tx);
+        Ok(tx)
+    }
+
+    // Parses one CSV row without a header, e.g. a message or a line of a
+    // stream, with the headers of the parser.
+    pub fn parse_row(&self, row: &[u8]) -> Result<Transaction, String> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(row);
+        let mut record = ByteRecord::new();
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => self.parse(&record).map_err(|err| err.to_string()),
+            Ok(false) => Err("empty row".to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    // Parses a JSON object with the headers of the parser as keys like a row
+    // of them, through the same aliases and amount formats. Numbers are taken
+    // in their shortest form, strings keep amounts exact.
+    pub fn parse_json_row(&self, row: &str) -> Result<Transaction, String> {
+        let mut object: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(row).map_err(|err| err.to_string())?;
+        let mut record = ByteRecord::new();
+        for header in self.names.iter() {
+            let field = match object.remove(header) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(value)) => value,
+                Some(serde_json::Value::Number(value)) => value.to_string(),
+                Some(serde_json::Value::Bool(value)) => value.to_string(),
+                Some(_) => return Err(format!("`{header}` must be a string or a number")),
+            };
+            record.push_field(field.trim().as_bytes());
+        }
+        if let Some(key) = object.keys().next() {
+            return Err(format!("unknown key `{key}`, expected one of the columns"));
+        }
+        self.parse(&record).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MAX_CLIENT_ID;
+
+    fn parse_both(input: &str) -> Vec<(Option<Transaction>, Option<Transaction>)> {
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input.as_bytes());
+        let headers = reader.byte_headers().unwrap().clone();
+        let parser = FastParser::new(&headers).unwrap();
+        reader
+            .byte_records()
+            .map(|record| {
+                let record = record.unwrap();
+                (
+                    parser.parse(&record).ok(),
+                    record.deserialize(Some(&headers)).ok(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn agrees_with_serde() {
+        let input = "type, client, tx, amount, idempotency_key, tenant, description, reference, category, timestamp, execute_at, role, counterparty\n\
+                     deposit, 1, 1, 1.5, abc, acme, \"Salary, May\", 00123, payroll, 1000, 2000, merchant, 2\n\
+                     withdrawal, 65535, 4294967295, 0.1234\n\
+                     deposit, 2, 3, 123456789.0001\n\
+                     deposit, 2, 4, 0.30000000000000004\n\
+                     deposit, 2, 5, 1e3\n\
+                     deposit, 2, 6, -.5\n\
+                     deposit, 2, 7, +2.\n\
+                     dispute, 1, 1,\n\
+                     resolve, 1, 1\n\
+                     deposit, 65536, 1, 1.0\n\
+                     deposit, 1, -1, 1.0\n\
+                     deposit, 1, 1, NaN\n\
+                     deposit, 1, 1, 1.2.3\n\
+                     deposit, 1, 1, .\n\
+                     refund, 1, 1, 1.0\n\
+                     deposit, 1\n";
+        let results = parse_both(input);
+        assert_eq!(results.len(), 16);
+        for (line, (fast, serde)) in results.into_iter().enumerate() {
+            assert_eq!(fast, serde, "record {}", line + 1);
+        }
+    }
+
+    #[test]
+    fn reports_the_invalid_field() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let parser = FastParser::new(&headers).unwrap();
+        let parse = |row: Vec<&str>| parser.parse(&ByteRecord::from(row)).unwrap_err();
+        assert_eq!(
+            parse(vec!["refund", "1", "1", "1.0"]),
+            FastParseError::InvalidAction("refund".to_string())
+        );
+        assert_eq!(
+            parse(vec!["deposit", "x", "1", "1.0"]).to_string(),
+            "invalid client `x`"
+        );
+        assert_eq!(
+            parse(vec!["deposit", "1", "1", "inf"]),
+            FastParseError::Amount(AmountParseError::NonFinite("inf".to_string()))
+        );
+        assert_eq!(
+            parse(vec!["deposit", "1", "1", "1.00000000000000001"]).to_string(),
+            "amount `1.00000000000000001` has more than 15 digits and can't be kept exactly"
+        );
+        assert_eq!(
+            parse(vec!["deposit", "1"]),
+            FastParseError::MissingField("tx")
+        );
+    }
+
+    #[test]
+    fn unknown_columns_are_ignored_captured_or_rejected() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount", "batch", "operator"]);
+        let record = ByteRecord::from(vec!["deposit", "1", "1", "1.0", "B7", "", "surplus"]);
+        for fast in [false, true] {
+            let parser = RecordParser::new(&headers, fast).unwrap();
+            assert!(parser.parse(&record).unwrap().metadata.is_empty());
+
+            let parser = parser
+                .with_unknown_columns(UnknownColumnPolicy::Capture)
+                .unwrap();
+            let tx = parser.parse(&record).unwrap();
+            assert_eq!(
+                tx.metadata.into_iter().collect::<Vec<_>>(),
+                vec![("batch".to_string(), "B7".to_string())]
+            );
+        }
+
+        let parser = RecordParser::new(&headers, true).unwrap();
+        assert_eq!(
+            parser
+                .with_unknown_columns(UnknownColumnPolicy::Reject)
+                .unwrap_err(),
+            FastParseError::UnknownColumn("batch".to_string())
+        );
+        let parser = RecordParser::new(&headers.iter().take(4).collect(), true)
+            .unwrap()
+            .with_unknown_columns(UnknownColumnPolicy::Reject)
+            .unwrap();
+        assert_eq!(
+            parser.parse(&record).unwrap_err().to_string(),
+            "found 7 fields, but the header has 4 columns"
+        );
+    }
+
+    #[test]
+    fn strict_headers_list_all_schema_problems() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount", "batch"]);
+        assert!(check_schema(&headers, UnknownColumnPolicy::Capture).is_ok());
+
+        let headers = ByteRecord::from(vec!["Type", "client", "tx", "tx", "amt", "batch"]);
+        let err = check_schema(&headers, UnknownColumnPolicy::Ignore).unwrap_err();
+        assert_eq!(
+            err,
+            FastParseError::SchemaMismatch {
+                missing: vec!["type", "amount"],
+                unexpected: vec!["Type".to_string(), "amt".to_string(), "batch".to_string()],
+                duplicate: vec!["tx".to_string()],
+            }
+        );
+        assert!(err.to_string().starts_with(
+            "header doesn't match the schema: missing columns `type`, `amount`; \
+             unexpected columns `Type`, `amt`, `batch`; duplicate columns `tx`. \
+             Expected type, client, tx, amount and optionally idempotency_key"
+        ));
+    }
+
+    #[test]
+    fn localized_amounts_are_normalized_before_decoding() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        for fast in [false, true] {
+            let parser = RecordParser::new(&headers, fast)
+                .unwrap()
+                .with_amount_format(AmountFormat::DecimalComma);
+            let tx = parser
+                .parse(&ByteRecord::from(vec!["deposit", "1", "1", "1.234,5"]))
+                .unwrap();
+            assert_eq!(tx.amount, Some(1234.5));
+            let err = parser
+                .parse(&ByteRecord::from(vec!["deposit", "1", "1", "1.234"]))
+                .unwrap_err();
+            assert!(err.to_string().starts_with("ambiguous amount `1.234`"));
+        }
+    }
+
+    #[test]
+    fn decimal_comma_files_separate_fields_with_semicolons() {
+        assert_eq!("decimal-comma".parse(), Ok(CsvDialect::DecimalComma));
+        assert!("excel".parse::<CsvDialect>().is_err());
+
+        let input = "type;client;tx;amount\ndeposit; 1; 1; 1.234,5\nwithdrawal;1;2;0,25\n";
+        for fast in [false, true] {
+            let mut reader = ReaderBuilder::new()
+                .delimiter(CsvDialect::DecimalComma.delimiter())
+                .trim(Trim::All)
+                .from_reader(input.as_bytes());
+            let parser = RecordParser::new(reader.byte_headers().unwrap(), fast)
+                .unwrap()
+                .with_dialect(CsvDialect::DecimalComma);
+            let amounts: Vec<_> = reader
+                .byte_records()
+                .map(|record| parser.parse(&record.unwrap()).unwrap().amount)
+                .collect();
+            assert_eq!(amounts, [Some(1234.5), Some(0.25)]);
+            let tx = parser.parse_row(b"deposit;2;3;7,5").unwrap();
+            assert_eq!(tx, Transaction::new(Action::Deposit, 2, 3, Some(7.5)));
+        }
+    }
+
+    #[test]
+    fn requires_the_transaction_columns() {
+        let headers = ByteRecord::from(vec!["type", "client", "amount"]);
+        assert_eq!(
+            FastParser::new(&headers).unwrap_err(),
+            FastParseError::MissingColumn("tx")
+        );
+    }
+
+    #[test]
+    fn aliases_and_other_cases_resolve_to_the_canonical_types() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let aliases = ActionAliases::new()
+            .with_alias("Withdraw", Action::Withdrawal)
+            .with_alias("charge_back", Action::Chargeback);
+        for fast in [false, true] {
+            let parser = RecordParser::new(&headers, fast)
+                .unwrap()
+                .with_action_aliases(aliases.clone());
+            let parse = |row: Vec<&str>| parser.parse(&ByteRecord::from(row));
+            let tx = parse(vec!["WITHDRAW", "1", "2", "1.5"]).unwrap();
+            assert_eq!(tx, Transaction::new(Action::Withdrawal, 1, 2, Some(1.5)));
+            let tx = parse(vec!["Deposit", "1", "3", "2.0"]).unwrap();
+            assert_eq!(tx.action, Action::Deposit);
+            let tx = parse(vec!["CHARGE_BACK", "1", "3", ""]).unwrap();
+            assert_eq!(tx.action, Action::Chargeback);
+
+            assert_eq!(
+                parse(vec!["send", "1", "4", "1.0"]).unwrap_err(),
+                RowError::UnknownAction("send".to_string())
+            );
+            assert!(matches!(
+                parse(vec!["deposit", "one", "4", "1.0"]),
+                Err(RowError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     # \"refunds\n\
+                     \x20  \n\
+                     \x20 # indented\n\
+                     ,,,\n\
+                     \n\
+                     deposit,1,2,2.0\n";
+        for skip in [true, false] {
+            let parser = RecordParser::new(
+                &ByteRecord::from(vec!["type", "client", "tx", "amount"]),
+                true,
+            )
+            .unwrap()
+            .with_skip_comments(skip);
+            let mut reader = ReaderBuilder::new()
+                .comment(parser.comment())
+                .flexible(true)
+                .trim(Trim::All)
+                .from_reader(input.as_bytes());
+            let mut record = ByteRecord::new();
+            let mut txs = Vec::new();
+            while parser.read_record(&mut reader, &mut record).unwrap() {
+                txs.push(record.get(2).unwrap_or_default().to_vec());
+            }
+            match skip {
+                true => assert_eq!(txs, vec![b"1".to_vec(), b"2".to_vec()]),
+                // All but the empty line reach the parser.
+                false => assert_eq!(txs.len(), 6),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_rows_without_a_header() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let parser = RecordParser::new(&headers, true).unwrap();
+        let tx = parser.parse_row(b"deposit, 1, 7, 2.5\n").unwrap();
+        assert_eq!(tx, Transaction::new(Action::Deposit, 1, 7, Some(2.5)));
+        let tx = parser.parse_row(b"dispute,1,7,").unwrap();
+        assert_eq!(tx, Transaction::new(Action::Dispute, 1, 7, None));
+
+        assert!(parser.parse_row(b"").is_err());
+        assert!(parser.parse_row(b"deposit,one,7,2.5").is_err());
+    }
+
+    #[test]
+    fn parse_record_names_the_failed_field() {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount", "batch"]);
+        let parser = RecordParser::new(&headers, false)
+            .unwrap()
+            .with_amount_format(AmountFormat::DecimalComma)
+            .with_unknown_columns(UnknownColumnPolicy::Capture)
+            .unwrap();
+        let parse = |row: Vec<&[u8]>| parser.parse_record(&ByteRecord::from(row));
+        let tx = parse(vec![b"deposit", b"1", b"1", b"1.234,5", b"B\xff"]).unwrap();
+        assert_eq!(tx.amount, Some(1234.5));
+        assert_eq!(tx.metadata["batch"], "B\u{fffd}");
+
+        let too_large = (u128::from(MAX_CLIENT_ID) + 1).to_string();
+        let err = parse(vec![b"deposit", too_large.as_bytes(), b"1", b"1,0"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("client `{too_large}` overflows, the maximum is {MAX_CLIENT_ID}")
+        );
+        let err = parse(vec![b"deposit", b"1", b"1", b"1.234"]).unwrap_err();
+        assert_eq!(err.field(), Some("amount"));
+        let err = parse(vec![b"deposit", b"1", b"\xff", b"1,0"]).unwrap_err();
+        assert_eq!(
+            err,
+            RecordError::InvalidUtf8 {
+                field: "tx".to_string()
+            }
+        );
+    }
+}
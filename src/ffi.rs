@@ -0,0 +1,197 @@
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+
+use crate::account_manager::{process_transaction, AccountManager};
+use crate::report::accounts_json;
+use crate::types::Transaction;
+
+pub const AM_OK: c_int = 0;
+pub const AM_REJECTED: c_int = 1;
+pub const AM_INVALID_ROW: c_int = 2;
+pub const AM_INVALID_ARGUMENT: c_int = 3;
+
+// Rows are passed without a header line, in the column order of the CSV input.
+const COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "idempotency_key"];
+
+/// Opaque engine handle, only used through pointers from C.
+pub struct AmEngine {
+    account_manager: AccountManager,
+    last_error: Option<CString>,
+}
+
+impl AmEngine {
+    fn process_csv_row(&mut self, row: &str) -> (c_int, Option<String>) {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(row.as_bytes());
+        let mut record = StringRecord::new();
+        let tx: Transaction = match reader.read_record(&mut record) {
+            Ok(true) => match record.deserialize(Some(&StringRecord::from(&COLUMNS[..]))) {
+                Ok(tx) => tx,
+                Err(err) => return (AM_INVALID_ROW, Some(err.to_string())),
+            },
+            Ok(false) => return (AM_INVALID_ROW, Some("empty row".to_string())),
+            Err(err) => return (AM_INVALID_ROW, Some(err.to_string())),
+        };
+        match process_transaction(&mut self.account_manager, tx) {
+            Ok(()) => (AM_OK, None),
+            Err(err) => (AM_REJECTED, Some(err.to_string())),
+        }
+    }
+}
+
+/// Creates an engine, to be released with am_free.
+#[no_mangle]
+pub extern "C" fn am_new() -> *mut AmEngine {
+    Box::into_raw(Box::new(AmEngine {
+        account_manager: AccountManager::new(),
+        last_error: None,
+    }))
+}
+
+/// Applies one row like "deposit,1,1,2.0". Returns AM_OK, AM_REJECTED if the
+/// engine refused the transaction, AM_INVALID_ROW if the row can't be parsed and
+/// AM_INVALID_ARGUMENT for null pointers or invalid UTF-8. The reason of the
+/// last failure is available through am_last_error.
+///
+/// # Safety
+///
+/// `engine` must come from am_new and `row` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn am_process_csv_row(engine: *mut AmEngine, row: *const c_char) -> c_int {
+    let Some(engine) = engine.as_mut() else {
+        return AM_INVALID_ARGUMENT;
+    };
+    let (status, error) = match row.is_null() {
+        true => (AM_INVALID_ARGUMENT, Some("row is null".to_string())),
+        false => match CStr::from_ptr(row).to_str() {
+            Ok(row) => engine.process_csv_row(row),
+            Err(err) => (AM_INVALID_ARGUMENT, Some(err.to_string())),
+        },
+    };
+    engine.last_error = error.map(|error| CString::new(error).unwrap_or_default());
+    status
+}
+
+/// Borrowed from the engine and valid until the next call, null after a success.
+///
+/// # Safety
+///
+/// `engine` must come from am_new.
+#[no_mangle]
+pub unsafe extern "C" fn am_last_error(engine: *const AmEngine) -> *const c_char {
+    match engine
+        .as_ref()
+        .and_then(|engine| engine.last_error.as_ref())
+    {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// The accounts as a JSON array, to be released with am_string_free.
+///
+/// # Safety
+///
+/// `engine` must come from am_new.
+#[no_mangle]
+pub unsafe extern "C" fn am_accounts_json(engine: *const AmEngine) -> *mut c_char {
+    match engine.as_ref() {
+        Some(engine) => CString::new(accounts_json(engine.account_manager.iter_accounts()))
+            .map_or(ptr::null_mut(), CString::into_raw),
+        None => ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `string` must come from am_accounts_json and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn am_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// # Safety
+///
+/// `engine` must come from am_new and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn am_free(engine: *mut AmEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error(engine: *const AmEngine) -> String {
+        unsafe { CStr::from_ptr(am_last_error(engine)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn rows_are_applied_through_the_c_api() {
+        let engine = am_new();
+        unsafe {
+            assert_eq!(
+                am_process_csv_row(engine, c"deposit, 1, 1, 2.0".as_ptr()),
+                AM_OK
+            );
+            assert_eq!(am_process_csv_row(engine, c"dispute,1,1".as_ptr()), AM_OK);
+            assert!(am_last_error(engine).is_null());
+
+            let json = am_accounts_json(engine);
+            assert_eq!(
+                CStr::from_ptr(json).to_str().unwrap(),
+                r#"[{"client":1,"available":0.0,"held":2.0,"total":2.0,"locked":false}]"#
+            );
+            am_string_free(json);
+            am_free(engine);
+        }
+    }
+
+    #[test]
+    fn failures_set_the_last_error() {
+        let engine = am_new();
+        unsafe {
+            let status = am_process_csv_row(engine, c"withdrawal,1,1,2.0".as_ptr());
+            assert_eq!(status, AM_REJECTED);
+            assert!(last_error(engine).contains("Insufficient funds"));
+
+            let status = am_process_csv_row(engine, c"refund,1,1,2.0".as_ptr());
+            assert_eq!(status, AM_INVALID_ROW);
+            assert!(last_error(engine).contains("refund"));
+
+            assert_eq!(am_process_csv_row(engine, ptr::null()), AM_INVALID_ARGUMENT);
+            assert_eq!(
+                am_process_csv_row(ptr::null_mut(), c"deposit,1,1,1.0".as_ptr()),
+                AM_INVALID_ARGUMENT
+            );
+            am_free(engine);
+        }
+    }
+
+    #[test]
+    fn the_generated_header_declares_the_api() {
+        let header = include_str!(concat!(env!("OUT_DIR"), "/accounting_demo.h"));
+        for function in [
+            "am_new",
+            "am_process_csv_row",
+            "am_last_error",
+            "am_accounts_json",
+            "am_string_free",
+            "am_free",
+        ] {
+            assert!(header.contains(&format!("{function}(")), "{function}");
+        }
+        assert!(header.contains("#define AM_REJECTED"));
+    }
+}
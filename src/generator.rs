@@ -0,0 +1,231 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use thiserror::Error;
+
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+const WITHDRAWAL_RATE: f64 = 0.3;
+const CHARGEBACK_RATE: f64 = 0.2;
+const MAX_AMOUNT_MINOR_UNITS: u32 = 1_000_000;
+const MINOR_UNITS_PER_UNIT: f64 = 10_000.0;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum GeneratorError {
+    #[error("At least one client is required")]
+    NoClients,
+
+    #[error("Dispute rate must be between 0.0 and 0.5, got {0}")]
+    InvalidDisputeRate(f64),
+
+    #[error("Can't generate {0} transactions, the maximum is {max}", max = TransactionId::MAX)]
+    TooManyTransactions(u64),
+}
+
+pub type GeneratorResult<T> = Result<T, GeneratorError>;
+
+#[derive(Debug, Clone)]
+pub struct GeneratorConfig {
+    pub clients: ClientId,
+    pub transactions: u64,
+    pub dispute_rate: f64,
+    pub seed: u64,
+}
+
+impl GeneratorConfig {
+    fn validate(&self) -> GeneratorResult<()> {
+        if self.clients == 0 {
+            return Err(GeneratorError::NoClients);
+        }
+        if !(0.0..=0.5).contains(&self.dispute_rate) {
+            return Err(GeneratorError::InvalidDisputeRate(self.dispute_rate));
+        }
+        if self.transactions > u64::from(TransactionId::MAX) {
+            return Err(GeneratorError::TooManyTransactions(self.transactions));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DepositRef {
+    id: TransactionId,
+    client_id: ClientId,
+}
+
+// Produces a reproducible stream of transactions for a given seed. Disputes only
+// reference earlier deposits of the same client and every resolve/chargeback
+// closes a dispute that is currently open, so dispute chains are always valid.
+pub struct Generator {
+    config: GeneratorConfig,
+    rng: ChaCha8Rng,
+    generated: u64,
+    next_id: TransactionId,
+    deposits: Vec<DepositRef>,
+    open_disputes: Vec<DepositRef>,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig) -> GeneratorResult<Self> {
+        config.validate()?;
+        let rng = ChaCha8Rng::seed_from_u64(config.seed);
+        Ok(Self {
+            config,
+            rng,
+            generated: 0,
+            next_id: 1,
+            deposits: Vec::new(),
+            open_disputes: Vec::new(),
+        })
+    }
+
+    fn random_client(&mut self) -> ClientId {
+        self.rng.random_range(1..=self.config.clients)
+    }
+
+    fn random_amount(&mut self) -> f64 {
+        f64::from(self.rng.random_range(1..=MAX_AMOUNT_MINOR_UNITS)) / MINOR_UNITS_PER_UNIT
+    }
+
+    fn next_id(&mut self) -> TransactionId {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn deposit_or_withdrawal(&mut self) -> Transaction {
+        let id = self.next_id();
+        let client_id = self.random_client();
+        let amount = self.random_amount();
+        if self.rng.random_bool(WITHDRAWAL_RATE) {
+            return Transaction::new(Action::Withdrawal, client_id, id, Some(amount));
+        }
+        self.deposits.push(DepositRef { id, client_id });
+        Transaction::new(Action::Deposit, client_id, id, Some(amount))
+    }
+
+    fn open_dispute(&mut self) -> Transaction {
+        let index = self.rng.random_range(0..self.deposits.len());
+        let deposit = self.deposits.swap_remove(index);
+        self.open_disputes.push(deposit);
+        Transaction::new(Action::Dispute, deposit.client_id, deposit.id, None)
+    }
+
+    fn close_dispute(&mut self) -> Transaction {
+        let index = self.rng.random_range(0..self.open_disputes.len());
+        let deposit = self.open_disputes.swap_remove(index);
+        if self.rng.random_bool(CHARGEBACK_RATE) {
+            return Transaction::new(Action::Chargeback, deposit.client_id, deposit.id, None);
+        }
+        self.deposits.push(deposit);
+        Transaction::new(Action::Resolve, deposit.client_id, deposit.id, None)
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.generated == self.config.transactions {
+            return None;
+        }
+        self.generated += 1;
+
+        let roll: f64 = self.rng.random();
+        let tx = if roll < self.config.dispute_rate && !self.open_disputes.is_empty() {
+            self.close_dispute()
+        } else if roll < 2.0 * self.config.dispute_rate && !self.deposits.is_empty() {
+            self.open_dispute()
+        } else {
+            self.deposit_or_withdrawal()
+        };
+        Some(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn config(seed: u64) -> GeneratorConfig {
+        GeneratorConfig {
+            clients: 10,
+            transactions: 5_000,
+            dispute_rate: 0.05,
+            seed,
+        }
+    }
+
+    #[test]
+    fn same_seed_produces_same_transactions() {
+        let first: Vec<_> = Generator::new(config(42)).unwrap().collect();
+        let second: Vec<_> = Generator::new(config(42)).unwrap().collect();
+        assert_eq!(first, second);
+
+        let other: Vec<_> = Generator::new(config(43)).unwrap().collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn generates_requested_number_of_transactions() {
+        let txs: Vec<_> = Generator::new(config(1)).unwrap().collect();
+        assert_eq!(txs.len(), 5_000);
+    }
+
+    #[test]
+    fn dispute_chains_are_valid() {
+        let mut deposits: HashMap<TransactionId, ClientId> = HashMap::new();
+        let mut disputed: HashMap<TransactionId, bool> = HashMap::new();
+
+        for tx in Generator::new(config(7)).unwrap() {
+            match tx.action {
+                Action::Deposit => {
+                    assert!(tx.amount.unwrap() > 0.0);
+                    deposits.insert(tx.id, tx.client_id);
+                }
+                Action::Withdrawal => assert!(tx.amount.unwrap() > 0.0),
+                Action::OpenAccount
+                | Action::HoldInEscrow
+                | Action::ReleaseEscrow
+                | Action::RefundEscrow
+                | Action::ClosePeriod
+                | Action::Adjustment
+                | Action::EraseClient
+                | Action::Freeze
+                | Action::Unfreeze
+                | Action::Balance => panic!("unexpected {}", tx.action.as_str()),
+                Action::Dispute => {
+                    assert_eq!(deposits.get(&tx.id), Some(&tx.client_id));
+                    assert!(!disputed.get(&tx.id).copied().unwrap_or(false));
+                    disputed.insert(tx.id, true);
+                }
+                Action::Resolve | Action::Chargeback => {
+                    assert_eq!(deposits.get(&tx.id), Some(&tx.client_id));
+                    assert_eq!(disputed.insert(tx.id, false), Some(true));
+                    if tx.action == Action::Chargeback {
+                        deposits.remove(&tx.id);
+                    }
+                }
+            }
+        }
+        assert!(disputed.values().any(|open| !open));
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        let mut invalid = config(1);
+        invalid.clients = 0;
+        assert_eq!(
+            Generator::new(invalid).err(),
+            Some(GeneratorError::NoClients)
+        );
+
+        let mut invalid = config(1);
+        invalid.dispute_rate = 0.9;
+        assert_eq!(
+            Generator::new(invalid).err(),
+            Some(GeneratorError::InvalidDisputeRate(0.9))
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread;
+
+use ureq::{Agent, BodyReader};
+
+use crate::retry::{is_transient, RetryPolicy};
+
+pub fn is_http_url(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+// Client errors won't go away on retry, server and connection errors might.
+fn io_error(err: ureq::Error) -> io::Error {
+    let kind = match err {
+        ureq::Error::StatusCode(404 | 410) => io::ErrorKind::NotFound,
+        ureq::Error::StatusCode(401 | 403) => io::ErrorKind::PermissionDenied,
+        ureq::Error::StatusCode(400..=499) => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+// The body of an HTTP(S) resource streamed as it is read. When the connection
+// fails or the server errors, the request is repeated with a Range from the
+// current position as the RetryPolicy allows. Seeking, e.g. to
+// resume from a checkpoint, starts a ranged request as well. Servers that
+// ignore ranges get the skipped bytes read and dropped.
+pub struct HttpReader {
+    agent: Agent,
+    url: String,
+    size: Option<u64>,
+    position: u64,
+    body: Option<BodyReader<'static>>,
+    retry: RetryPolicy,
+}
+
+impl HttpReader {
+    // Requests the resource right away, so a missing one fails early.
+    pub fn open(url: &str) -> io::Result<Self> {
+        Self::with_retry(url, RetryPolicy::default())
+    }
+
+    pub fn with_retry(url: &str, retry: RetryPolicy) -> io::Result<Self> {
+        let mut reader = Self {
+            agent: Agent::new_with_defaults(),
+            url: url.to_string(),
+            size: None,
+            position: 0,
+            body: None,
+            retry,
+        };
+        reader.body = Some(reader.with_retries(Self::request)?);
+        Ok(reader)
+    }
+
+    // The length of the resource, if the server told it.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    fn request(&mut self) -> io::Result<BodyReader<'static>> {
+        let mut request = self.agent.get(&self.url);
+        if self.position > 0 {
+            request = request.header("Range", format!("bytes={}-", self.position));
+        }
+        let response = request.call().map_err(io_error)?;
+        let partial = response.status() == 206;
+        let body = response.into_body();
+        if let Some(len) = body.content_length() {
+            self.size = Some(if partial { self.position + len } else { len });
+        }
+        let mut body = body.into_reader();
+        if self.position > 0 && !partial {
+            io::copy(&mut (&mut body).take(self.position), &mut io::sink())?;
+        }
+        Ok(body)
+    }
+
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let body = match &mut self.body {
+            Some(body) => body,
+            None => {
+                let body = self.request()?;
+                self.body.insert(body)
+            }
+        };
+        let len = body.read(buf)?;
+        // The connection closed before the whole body arrived.
+        let truncated = self.size.is_some_and(|size| self.position < size);
+        if len == 0 && !buf.is_empty() && truncated {
+            self.body = None;
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(len)
+    }
+
+    fn with_retries<T>(&mut self, mut f: impl FnMut(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        let mut delays = self.retry.delays();
+        loop {
+            match (f(self), delays.next()) {
+                (Err(err), Some(delay)) if is_transient(&err) => {
+                    eprintln!(
+                        "reading {} failed at byte {}, retrying in {delay:?}: {err}",
+                        self.url, self.position
+                    );
+                    self.body = None;
+                    thread::sleep(delay);
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.size.is_some_and(|size| self.position >= size) {
+            return Ok(0);
+        }
+        let len = self.with_retries(|reader| reader.read_body(buf))?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => {
+                let size = self.size.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Unsupported, "the size is unknown")
+                })?;
+                size.checked_add_signed(offset)
+            }
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        if position != self.position {
+            self.body = None;
+            self.position = position;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+
+    const CONTENT: &str = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+    // Answers one request per response, sends the Range header of every
+    // request and closes each connection after the response.
+    fn serve(responses: Vec<String>) -> (String, mpsc::Receiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/transactions.csv", listener.local_addr().unwrap());
+        let (ranges, received) = mpsc::channel();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("range: ") {
+                        range = Some(value.to_string());
+                    }
+                }
+                // Tests that don't check the ranges drop the receiver.
+                ranges.send(range).ok();
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn no_backoff() -> RetryPolicy {
+        RetryPolicy {
+            backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!("HTTP/1.1 {status}\r\nconnection: close\r\n{headers}\r\n{body}")
+    }
+
+    #[test]
+    fn resumes_with_a_range_request_after_a_dropped_connection() {
+        let (url, ranges) = serve(vec![
+            response("200 OK", "content-length: 38\r\n", &CONTENT[..10]),
+            response(
+                "206 Partial Content",
+                "content-length: 28\r\ncontent-range: bytes 10-37/38\r\n",
+                &CONTENT[10..],
+            ),
+            response(
+                "206 Partial Content",
+                "content-length: 16\r\ncontent-range: bytes 22-37/38\r\n",
+                &CONTENT[22..],
+            ),
+        ]);
+        let mut reader = HttpReader::with_retry(&url, no_backoff()).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, CONTENT);
+        assert_eq!(reader.size(), Some(38));
+
+        reader.seek(SeekFrom::Start(22)).unwrap();
+        content.clear();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "deposit,1,1,1.0\n");
+
+        let ranges: Vec<_> = ranges.iter().collect();
+        assert_eq!(
+            ranges,
+            vec![
+                None,
+                Some("bytes=10-".to_string()),
+                Some("bytes=22-".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_to_the_position_if_the_server_ignores_ranges() {
+        let full = response("200 OK", "content-length: 38\r\n", CONTENT);
+        let (url, _) = serve(vec![full.clone(), full]);
+        let mut reader = HttpReader::with_retry(&url, no_backoff()).unwrap();
+        reader.seek(SeekFrom::End(-16)).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "deposit,1,1,1.0\n");
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let truncated = response("200 OK", "content-length: 38\r\n", &CONTENT[..10]);
+        let (url, _) = serve(vec![truncated]);
+        let mut reader = HttpReader::with_retry(&url, RetryPolicy::none()).unwrap();
+        let err = reader.read_to_string(&mut String::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn missing_resources_fail_without_retries() {
+        let (url, ranges) = serve(vec![response("404 Not Found", "content-length: 0\r\n", "")]);
+        let err = HttpReader::with_retry(&url, no_backoff()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(ranges.iter().count(), 1);
+    }
+}
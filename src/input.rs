@@ -0,0 +1,229 @@
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+#[cfg(feature = "http")]
+use crate::http_input::{is_http_url, HttpReader};
+#[cfg(feature = "object-store")]
+use crate::object_input::{is_object_url, ObjectReader};
+use crate::retry::RetryPolicy;
+
+// A transaction file, either read with syscalls or parsed straight from a
+// memory mapping, or an object streamed from an object store or an HTTP(S)
+// server. All can seek, which checkpoints use to resume.
+pub enum Input {
+    File(File),
+    Mapped(Cursor<Mmap>),
+    // A UTF-16 file transcoded to UTF-8.
+    Decoded(Cursor<Vec<u8>>),
+    #[cfg(feature = "object-store")]
+    Object(Box<ObjectReader>),
+    #[cfg(feature = "http")]
+    Http(Box<HttpReader>),
+}
+
+impl Input {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Input::File(File::open(path)?))
+    }
+
+    // A file, or with the object-store feature an s3://, gs:// or az:// URL,
+    // or with the http feature an http:// or https:// URL. Reads of URLs are
+    // retried by `retry`.
+    #[cfg_attr(
+        not(any(feature = "http", feature = "object-store")),
+        allow(unused_variables)
+    )]
+    pub fn open_location(location: &str, retry: RetryPolicy) -> io::Result<Self> {
+        #[cfg(feature = "http")]
+        if is_http_url(location) {
+            let reader = HttpReader::with_retry(location, retry)?;
+            return Ok(Input::Http(Box::new(reader)));
+        }
+        #[cfg(feature = "object-store")]
+        if is_object_url(location) {
+            let reader = ObjectReader::with_retry(location, retry)?;
+            return Ok(Input::Object(Box::new(reader)));
+        }
+        Self::open(location)
+    }
+
+    // Pipes and other special files can't be mapped. The file must not be
+    // truncated while it is mapped, the process would get a SIGBUS.
+    pub fn map(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file) }.map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Can't memory-map {}: {err}", path.display()),
+            )
+        })?;
+        Ok(Input::Mapped(Cursor::new(mmap)))
+    }
+
+    // The whole input if it is mapped or transcoded.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Input::Mapped(cursor) => Some(cursor.get_ref()),
+            Input::Decoded(cursor) => Some(cursor.get_ref()),
+            _ => None,
+        }
+    }
+
+    // Transcodes UTF-16 input, e.g. from Windows tools, to UTF-8 in memory.
+    // Positions then refer to the UTF-8 text, so checkpoints of the input
+    // resume like those of any file. UTF-8 input is left as it is, the CSV
+    // reader skips a byte order mark before the header.
+    pub fn decoded(mut self) -> io::Result<Self> {
+        let mut start = Vec::with_capacity(2);
+        (&mut self).take(2).read_to_end(&mut start)?;
+        let encoding = Encoding::detect(&start);
+        if encoding == Encoding::Utf8 {
+            self.seek(SeekFrom::Start(0))?;
+            return Ok(self);
+        }
+        let mut data = start;
+        self.read_to_end(&mut data)?;
+        let text = decode_utf16(&data, encoding)?;
+        Ok(Input::Decoded(Cursor::new(text.into_bytes())))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    // By the byte order mark, or by the zero byte of an ASCII first character
+    // like the `t` of the header: text files don't contain zero bytes.
+    fn detect(start: &[u8]) -> Self {
+        match start {
+            [0xff, 0xfe, ..] | [1..=0xff, 0, ..] => Self::Utf16Le,
+            [0xfe, 0xff, ..] | [0, 1..=0xff, ..] => Self::Utf16Be,
+            _ => Self::Utf8,
+        }
+    }
+}
+
+// The text after the byte order mark, if there is one.
+fn decode_utf16(data: &[u8], encoding: Encoding) -> io::Result<String> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    if !data.len().is_multiple_of(2) {
+        return Err(invalid(format!(
+            "UTF-16 input of {} bytes, expected an even number",
+            data.len()
+        )));
+    }
+    let units = data.chunks_exact(2).map(|unit| match encoding {
+        Encoding::Utf16Be => u16::from_be_bytes([unit[0], unit[1]]),
+        _ => u16::from_le_bytes([unit[0], unit[1]]),
+    });
+    let mut text = String::with_capacity(data.len() / 2);
+    for (index, char) in char::decode_utf16(units).enumerate() {
+        let char = char
+            .map_err(|err| invalid(format!("invalid UTF-16 input at byte {}: {err}", index * 2)))?;
+        if !(index == 0 && char == '\u{feff}') {
+            text.push(char);
+        }
+    }
+    Ok(text)
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Input::File(file) => file.read(buf),
+            Input::Mapped(cursor) => cursor.read(buf),
+            Input::Decoded(cursor) => cursor.read(buf),
+            #[cfg(feature = "object-store")]
+            Input::Object(object) => object.read(buf),
+            #[cfg(feature = "http")]
+            Input::Http(http) => http.read(buf),
+        }
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Input::File(file) => file.seek(pos),
+            Input::Mapped(cursor) => cursor.seek(pos),
+            Input::Decoded(cursor) => cursor.seek(pos),
+            #[cfg(feature = "object-store")]
+            Input::Object(object) => object.seek(pos),
+            #[cfg(feature = "http")]
+            Input::Http(http) => http.seek(pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn mapped_input_reads_and_seeks_like_the_file() {
+        let path = env::temp_dir().join(format!("input-{}.csv", std::process::id()));
+        fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+
+        for mut input in [Input::open(&path).unwrap(), Input::map(&path).unwrap()] {
+            let mut content = String::new();
+            input.read_to_string(&mut content).unwrap();
+            assert!(content.ends_with("deposit,1,1,1.0\n"));
+
+            input.seek(SeekFrom::Start(22)).unwrap();
+            content.clear();
+            input.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "deposit,1,1,1.0\n");
+        }
+        assert_eq!(Input::map(&path).unwrap().as_bytes().unwrap().len(), 38);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn utf16_input_is_transcoded() {
+        let path = env::temp_dir().join(format!("input-utf16-{}.csv", std::process::id()));
+        let text = "type,client,tx,amount\r\ndeposit,1,1,1.0 €\r\n";
+        let little: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let big: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let with_bom = |bom: &[u8], data: &[u8]| [bom, data].concat();
+        for data in [
+            with_bom(&[0xff, 0xfe], &little),
+            with_bom(&[0xfe, 0xff], &big),
+            little.clone(),
+        ] {
+            fs::write(&path, data).unwrap();
+            for input in [Input::open(&path).unwrap(), Input::map(&path).unwrap()] {
+                let mut input = input.decoded().unwrap();
+                assert_eq!(input.as_bytes(), Some(text.as_bytes()));
+                input.seek(SeekFrom::Start(23)).unwrap();
+                let mut content = String::new();
+                input.read_to_string(&mut content).unwrap();
+                assert_eq!(content, "deposit,1,1,1.0 €\r\n");
+            }
+        }
+
+        fs::write(&path, "\u{feff}type,client,tx,amount\n").unwrap();
+        let mut reader = csv::Reader::from_reader(Input::open(&path).unwrap().decoded().unwrap());
+        assert_eq!(&reader.headers().unwrap()[0], "type");
+
+        fs::write(&path, with_bom(&[0xff, 0xfe], &little[..5])).unwrap();
+        let err = Input::open(&path).unwrap().decoded().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::write(&path, [0xff, 0xfe, 0x00, 0xd8, 0x41, 0x00]).unwrap();
+        let err = Input::open(&path).unwrap().decoded().err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid UTF-16 input at byte 2"));
+        fs::remove_file(path).unwrap();
+    }
+}
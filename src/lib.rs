@@ -1,3 +1,50 @@
-pub mod account;
-pub mod account_manager;
-pub mod types;
+// The engine, re-exported so that dependents of the CLI crate and its modules
+// see one crate.
+pub use accounting_core::{
+    account, account_manager, amount, asset, currency, dispute, error_code, executor, history,
+    ledger, period, policy, pseudonym, rate_limit, role, rules, scheduler, shared, stats, store,
+    tenant, tier, tx_cache, tx_filter, types,
+};
+
+#[cfg(feature = "arbitrary")]
+pub use accounting_core::fuzzing;
+#[cfg(feature = "rhai")]
+pub use accounting_core::script;
+
+pub mod audit;
+pub mod auth;
+pub mod category;
+pub mod checkpoint;
+pub mod diff;
+pub mod encryption;
+pub mod export;
+pub mod fast_parse;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod generator;
+#[cfg(feature = "http")]
+pub mod http_input;
+pub mod input;
+#[cfg(feature = "nats")]
+pub mod nats_source;
+#[cfg(feature = "object-store")]
+pub mod object_input;
+pub mod parallel;
+pub mod pipeline;
+pub mod policy_file;
+pub mod reconcile;
+pub mod registry;
+pub mod rejects;
+pub mod replay;
+pub mod report;
+pub mod retry;
+pub mod settlement;
+pub mod signing;
+#[cfg(unix)]
+pub mod socket_server;
+#[cfg(feature = "test_support")]
+pub mod test_support;
+pub mod top;
+pub mod validate;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,68 +1,2771 @@
-use std::{env, fs::File};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use csv::{Error as CsvError, Reader, ReaderBuilder, Trim};
+use clap::{Args, Parser, Subcommand};
+use csv::{
+    ByteRecord, Error as CsvError, Position, Reader, ReaderBuilder, StringRecord, Trim, Writer,
+};
+use ed25519_dalek::SigningKey;
 use thiserror::Error;
 
-use accounting_demo::account::{Account, AccountError};
-use accounting_demo::account_manager::{process_transaction, AccountManager};
-use accounting_demo::types::{ClientId, Transaction};
+use accounting_cli::account::AccountError;
+use accounting_cli::account_manager::{AccountManager, AccountManagerResult};
+use accounting_cli::amount::AmountFormat;
+use accounting_cli::asset::{write_asset_accounts, Asset, AssetBook, AssetTransaction};
+use accounting_cli::audit::{
+    verify_audit, verify_audit_head, AuditError, AuditLog, BalanceSnapshot,
+};
+use accounting_cli::auth::{ApiKeys, AuthError};
+use accounting_cli::category::{category_volumes, write_category_report};
+use accounting_cli::checkpoint::{Checkpoint, CheckpointError, OutputOffsets, SourceOffset};
+use accounting_cli::currency::{Currencies, Currency, CurrencyError};
+use accounting_cli::diff::{diff, write_deltas, DiffFormat};
+use accounting_cli::dispute::write_dispute_cases;
+use accounting_cli::encryption::EncryptionKey;
+use accounting_cli::error_code::ErrorCode;
+use accounting_cli::executor::ParallelExecutor;
+use accounting_cli::export::{
+    client_log, write_client_log, write_journal, write_qif_statement, AccountNames,
+    ClientLogFormat, JournalFormat,
+};
+use accounting_cli::fast_parse::{
+    check_schema, ActionAliases, CsvDialect, FastParseError, RecordParser, RowError, COMMENT_PREFIX,
+};
+use accounting_cli::generator::{Generator, GeneratorConfig, GeneratorError};
+use accounting_cli::history::{write_history, AsOf, BalanceHistory};
+use accounting_cli::input::Input;
+use accounting_cli::ledger::Ledger;
+#[cfg(feature = "nats")]
+use accounting_cli::nats_source::NatsSource;
+use accounting_cli::parallel::parse_parallel;
+use accounting_cli::period::write_periods;
+use accounting_cli::pipeline::{run_pipeline, PipelineDepths};
+use accounting_cli::policy::{
+    BackdatedPolicy, CacheTtl, LockedDepositPolicy, Policy, PrecisionPolicy, Retention,
+    UnknownColumnPolicy, WithdrawalDisputePolicy, ZeroAmountPolicy,
+};
+use accounting_cli::policy_file::{PolicyFile, PolicyFileError, PolicyWatch};
+use accounting_cli::pseudonym::Pseudonymizer;
+use accounting_cli::rate_limit::{RateLimit, RateLimitError, RateLimiter};
+use accounting_cli::reconcile::{reconcile, write_discrepancies};
+use accounting_cli::registry::ProcessedRegistry;
+use accounting_cli::rejects::{RejectsFormat, RejectsWriter};
+use accounting_cli::replay::{Replay, ReplayError, ReplayRecorder};
+use accounting_cli::report::{
+    read_account_records, write_account_records, write_account_table, AccountRecord,
+    CurrencyFormat, ReportColumn, ReportFormat,
+};
+use accounting_cli::retry::{RetryPolicy, DEFAULT_BACKOFF, DEFAULT_MAX_BACKOFF, DEFAULT_RETRIES};
+use accounting_cli::role::{Role, RoleError, Roles};
+use accounting_cli::scheduler::{Scheduler, SchedulerError};
+#[cfg(feature = "rhai")]
+use accounting_cli::script::{ScriptError, TransactionScript};
+use accounting_cli::settlement::{settle, settle_periods, write_settlements};
+#[cfg(feature = "redis")]
+use accounting_cli::shared::RedisBackend;
+use accounting_cli::shared::SharedError;
+use accounting_cli::signing::{
+    parse_signing_key, parse_verifying_key, public_key_hex, sign as sign_report,
+    verify as verify_report, SigningError,
+};
+#[cfg(unix)]
+use accounting_cli::socket_server::{Engine, SocketServer};
+use accounting_cli::stats::ProcessingStats;
+use accounting_cli::store::{ShardedStore, DEFAULT_SHARDS};
+use accounting_cli::tenant::Tenants;
+#[cfg(feature = "test_support")]
+use accounting_cli::test_support::{fixtures, FixtureError};
+use accounting_cli::tier::{TierError, Tiers};
+use accounting_cli::top::{write_top, TopAccounts};
+use accounting_cli::types::{Action, ClientId, Transaction, TransactionId};
+use accounting_cli::validate::{write_findings, Validator};
 
 #[derive(Error, Debug)]
 pub enum ApplicationError {
-    #[error{"0"}]
+    #[error("{0}")]
     Account(#[from] AccountError),
 
-    #[error{"0"}]
+    #[error("{0}")]
     CsvReader(#[from] CsvError),
 
-    #[error("Usage: cargo run -- <TRANSACTIONS_CSV>")]
-    InvalidArgs,
+    #[error("Invalid row at line {line} (record {record}): {message}")]
+    InvalidRow {
+        line: u64,
+        record: u64,
+        message: String,
+    },
+
+    #[error("Invalid header: {0}")]
+    InvalidHeader(#[from] FastParseError),
+
+    #[error("{0}")]
+    Generator(#[from] GeneratorError),
+
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Checkpoint(#[from] CheckpointError),
+
+    #[error("{0}")]
+    Invariant(String),
+
+    #[error("{0}")]
+    Audit(#[from] AuditError),
+
+    #[error("{0}")]
+    Signing(#[from] SigningError),
+
+    #[error("{0}")]
+    Tier(#[from] TierError),
+
+    #[error("{0}")]
+    Role(#[from] RoleError),
+
+    #[error("{0}")]
+    Scheduler(#[from] SchedulerError),
+
+    #[error("{0}")]
+    PolicyFile(#[from] PolicyFileError),
+
+    #[error("{0}")]
+    Currency(#[from] CurrencyError),
+
+    #[error("{0}")]
+    Replay(#[from] ReplayError),
+
+    #[error("{0}")]
+    RateLimit(#[from] RateLimitError),
+
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+
+    #[error("{0}")]
+    Shared(#[from] SharedError),
+
+    #[cfg(feature = "test_support")]
+    #[error("{0}")]
+    Fixture(#[from] FixtureError),
+
+    #[cfg(feature = "rhai")]
+    #[error("{0}")]
+    Script(#[from] ScriptError),
+
+    #[error("{option} is not supported by {command}")]
+    Unsupported {
+        option: &'static str,
+        command: &'static str,
+    },
+
+    #[error("No signing key, use --signing-key or set {SIGNING_KEY_ENV}")]
+    MissingSigningKey,
+
+    #[error("No pseudonym key, use --pseudonym-key or set {PSEUDONYM_KEY_ENV}")]
+    MissingPseudonymKey,
+}
+
+impl ApplicationError {
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            ApplicationError::Generator(_) => ExitCode::from(EXIT_USAGE),
+            ApplicationError::Invariant(_) => ExitCode::from(EXIT_UNBALANCED),
+            ApplicationError::Audit(AuditError::Tampered { .. })
+            | ApplicationError::Signing(SigningError::Mismatch) => ExitCode::from(EXIT_TAMPERED),
+            ApplicationError::MissingSigningKey
+            | ApplicationError::MissingPseudonymKey
+            | ApplicationError::Unsupported { .. } => ExitCode::from(EXIT_USAGE),
+            _ => ExitCode::from(EXIT_FATAL),
+        }
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            ApplicationError::Account(err) => err.code(),
+            ApplicationError::CsvReader(_) => ErrorCode::InvalidCsv,
+            ApplicationError::InvalidRow { .. } => ErrorCode::InvalidRow,
+            ApplicationError::InvalidHeader(_) => ErrorCode::InvalidHeader,
+            ApplicationError::Generator(_) => ErrorCode::InvalidGeneratorConfig,
+            ApplicationError::Io(_) => ErrorCode::Io,
+            ApplicationError::Checkpoint(_) => ErrorCode::InvalidCheckpoint,
+            ApplicationError::Invariant(_) => ErrorCode::InvariantViolation,
+            ApplicationError::Audit(AuditError::Tampered { .. }) => ErrorCode::AuditLogTampered,
+            ApplicationError::Audit(_) => ErrorCode::InvalidAuditLog,
+            ApplicationError::Signing(SigningError::Mismatch) => ErrorCode::SignatureMismatch,
+            ApplicationError::Signing(_) => ErrorCode::InvalidKey,
+            ApplicationError::Tier(_) => ErrorCode::InvalidTiers,
+            ApplicationError::Role(_) => ErrorCode::InvalidRoles,
+            ApplicationError::Scheduler(_) => ErrorCode::InvalidSchedule,
+            ApplicationError::PolicyFile(_) => ErrorCode::InvalidPolicy,
+            ApplicationError::Currency(_) => ErrorCode::InvalidCurrency,
+            ApplicationError::Replay(ReplayError::Io(_)) => ErrorCode::Io,
+            ApplicationError::Replay(_) => ErrorCode::InvalidReplay,
+            ApplicationError::RateLimit(err) => err.code(),
+            ApplicationError::Auth(err) => err.code(),
+            ApplicationError::Shared(err) => err.code(),
+            #[cfg(feature = "test_support")]
+            ApplicationError::Fixture(err) => match err {
+                FixtureError::Io(_) | FixtureError::MissingExpectation { .. } => ErrorCode::Io,
+                FixtureError::Csv(_) => ErrorCode::InvalidCsv,
+                FixtureError::InvalidHeader(_) => ErrorCode::InvalidHeader,
+                FixtureError::InvalidRow { .. } => ErrorCode::InvalidRow,
+            },
+            #[cfg(feature = "rhai")]
+            ApplicationError::Script(_) => ErrorCode::InvalidScript,
+            ApplicationError::MissingSigningKey => ErrorCode::MissingSigningKey,
+            ApplicationError::MissingPseudonymKey => ErrorCode::MissingPseudonymKey,
+            ApplicationError::Unsupported { .. } => ErrorCode::UnsupportedOption,
+        }
+    }
 }
 
 pub type ApplicationResult<T> = Result<T, ApplicationError>;
 
-fn read_csv_path() -> ApplicationResult<String> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err(ApplicationError::InvalidArgs);
+const EXIT_REJECTIONS: u8 = 1;
+const EXIT_DISCREPANCIES: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_FATAL: u8 = 3;
+const EXIT_UNBALANCED: u8 = 4;
+const EXIT_TAMPERED: u8 = 5;
+const EXIT_INTERRUPTED: u8 = 130;
+
+const SIGNING_KEY_ENV: &str = "ACCOUNTING_SIGNING_KEY";
+const PSEUDONYM_KEY_ENV: &str = "ACCOUNTING_PSEUDONYM_KEY";
+
+#[derive(Parser)]
+#[command(
+    about = "Processes a CSV file of transactions and prints the resulting account balances",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+
+    #[command(flatten)]
+    sign: SignArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Args)]
+struct PseudonymArgs {
+    #[arg(
+        long,
+        help = "Replaces client ids in all outputs with keyed HMAC pseudonyms"
+    )]
+    pseudonymize: bool,
+
+    #[arg(
+        long,
+        requires = "pseudonymize",
+        help = "File with the pseudonym key, defaults to $ACCOUNTING_PSEUDONYM_KEY"
+    )]
+    pseudonym_key: Option<PathBuf>,
+}
+
+impl PseudonymArgs {
+    fn pseudonymizer(&self) -> ApplicationResult<Pseudonymizer> {
+        if !self.pseudonymize {
+            return Ok(Pseudonymizer::new());
+        }
+        let key = match &self.pseudonym_key {
+            Some(path) => fs::read_to_string(path)?,
+            None => {
+                env::var(PSEUDONYM_KEY_ENV).map_err(|_| ApplicationError::MissingPseudonymKey)?
+            }
+        };
+        Ok(Pseudonymizer::with_key(key.trim().as_bytes()))
+    }
+}
+
+#[derive(Args)]
+struct ReportArgs {
+    #[arg(
+        long,
+        default_value = "csv",
+        help = "Format of the report: csv, or text with currency formatted amounts"
+    )]
+    report_format: ReportFormat,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma separated report columns in order: tenant, client, available, held, total, locked, tx_count, state, tier, role, receivable"
+    )]
+    columns: Vec<ReportColumn>,
+
+    #[arg(
+        long,
+        default_value = "decimal-point",
+        help = "Grouping of the text report amounts: plain, decimal-point, decimal-comma, apostrophe or space-comma"
+    )]
+    report_amounts: AmountFormat,
+
+    #[arg(
+        long,
+        help = "Currency symbol in front of the text report amounts instead of the --currency code after them"
+    )]
+    currency_symbol: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "POINT",
+        help = "Reports the balances as of an input record, seq:N, or a timestamp, ts:T"
+    )]
+    as_of: Option<AsOf>,
+}
+
+impl ReportArgs {
+    fn currency_format(&self, currency: &str, places: u32) -> CurrencyFormat {
+        CurrencyFormat {
+            currency: currency.to_string(),
+            symbol: self.currency_symbol.clone(),
+            amounts: self.report_amounts,
+            places,
+        }
+    }
+}
+
+#[derive(Args)]
+struct SignArgs {
+    #[arg(
+        long,
+        help = "Signs the report with ed25519 and writes the hex signature to this file"
+    )]
+    signature: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "signature",
+        help = "File with the hex encoded ed25519 secret key, defaults to $ACCOUNTING_SIGNING_KEY"
+    )]
+    signing_key: Option<PathBuf>,
+}
+
+impl SignArgs {
+    fn signing_key(&self) -> ApplicationResult<SigningKey> {
+        let key = match &self.signing_key {
+            Some(path) => fs::read_to_string(path)?,
+            None => env::var(SIGNING_KEY_ENV).map_err(|_| ApplicationError::MissingSigningKey)?,
+        };
+        Ok(parse_signing_key(&key)?)
+    }
+}
+
+#[derive(Args)]
+struct RetryArgs {
+    #[arg(
+        long,
+        default_value_t = DEFAULT_RETRIES,
+        help = "How often failed reads of URLs and streams are retried, resuming where they stopped"
+    )]
+    retries: u32,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = DEFAULT_BACKOFF.as_millis() as u64,
+        help = "Delay before the first retry, doubled for every further retry"
+    )]
+    retry_backoff: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = DEFAULT_MAX_BACKOFF.as_secs(),
+        help = "Longest delay between retries"
+    )]
+    retry_max_backoff: u64,
+}
+
+impl RetryArgs {
+    fn policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            backoff: Duration::from_millis(self.retry_backoff),
+            max_backoff: Duration::from_secs(self.retry_max_backoff),
+        }
+    }
+}
+
+#[derive(Args)]
+struct PolicyArgs {
+    #[arg(
+        long,
+        help = "TOML file with policy settings named like the flags, e.g. cache-ttl = \"txs:1000\", and [[rule]] tables; the settings take precedence over the flags"
+    )]
+    policy_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "reject",
+        help = "Zero amount policy: reject or accept"
+    )]
+    zero_amounts: ZeroAmountPolicy,
+
+    #[arg(
+        long,
+        default_value = "reject",
+        help = "Policy for amounts with more decimal places than the minor units of the --denomination (4 without one): reject or round"
+    )]
+    precision: PrecisionPolicy,
+
+    #[arg(
+        long,
+        default_value = "accept",
+        help = "Policy for deposits into locked accounts: accept, reject or suspense"
+    )]
+    locked_deposits: LockedDepositPolicy,
+
+    #[arg(
+        long,
+        default_value = "reject",
+        help = "Policy for disputes of withdrawals: reject, reverse or reverse-and-lock"
+    )]
+    withdrawal_disputes: WithdrawalDisputePolicy,
+
+    #[arg(
+        long,
+        default_value = "apply",
+        help = "Policy for transactions with a timestamp in a closed period: apply, reject or adjust (recorded as adjustment of the period)"
+    )]
+    backdated: BackdatedPolicy,
+
+    #[arg(
+        long,
+        default_value = "forever",
+        help = "How long deposits and withdrawals stay disputable: forever, txs:N (later applied transactions) or secs:N (by timestamp)"
+    )]
+    cache_ttl: CacheTtl,
+
+    #[arg(
+        long,
+        default_value = "forever",
+        help = "How many closed periods keep the details of their transactions: forever or periods:N. Closing a period compacts the older ones"
+    )]
+    retention: Retention,
+
+    #[arg(
+        long,
+        help = "Apply freeze, unfreeze, adjustment and erase_client rows of the input, which lock, unlock, correct and erase the account of the client. Without it they are rejected as admin_action_refused"
+    )]
+    allow_admin_actions: bool,
+
+    #[arg(
+        long,
+        help = "CSV with the limits of the account tiers: tier, max_balance, max_withdrawal, freeze_on_dispute, chargeback_fee"
+    )]
+    tiers: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "CSV with the tier of the clients: client, tier. Other clients are basic"
+    )]
+    client_tiers: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "CSV with the role of the clients: client, role (customer or merchant)"
+    )]
+    roles: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Currency of the books, e.g. JPY: amounts are checked against, rounded to and reported with its minor units instead of 4 decimal places"
+    )]
+    denomination: Option<String>,
+
+    #[arg(
+        long,
+        help = "CSV adding currencies to the ISO 4217 ones or overriding them: code, minor_units, rounding (half-up, half-even or down)"
+    )]
+    currencies: Option<PathBuf>,
+
+    #[cfg(feature = "rhai")]
+    #[arg(
+        long,
+        help = "rhai script with a fn screen(tx, account) that accepts, rejects or annotates every transaction of the input"
+    )]
+    script: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "0",
+        value_parser = parse_fee_rate,
+        help = "Share of every merchant deposit charged as fee, e.g. 0.029"
+    )]
+    merchant_fee: f64,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_role_fee,
+        help = "Comma separated penalties charged on every chargeback per role, e.g. merchant=15,customer=5. A chargeback_fee of the tier takes precedence"
+    )]
+    chargeback_fee: Vec<(Role, f64)>,
+}
+
+impl PolicyArgs {
+    fn policy(&self) -> ApplicationResult<Policy> {
+        let file = self
+            .policy_file
+            .as_deref()
+            .map(PolicyFile::load)
+            .transpose()?
+            .unwrap_or_default();
+        let mut tiers = Tiers::new();
+        if let Some(path) = file.tiers.as_ref().or(self.tiers.as_ref()) {
+            tiers.read_limits(File::open(path)?)?;
+        }
+        if let Some(path) = file.client_tiers.as_ref().or(self.client_tiers.as_ref()) {
+            tiers.read_clients(File::open(path)?)?;
+        }
+        let merchant_fee = file.merchant_fee.unwrap_or(self.merchant_fee);
+        let mut roles = Roles::new().with_merchant_fee(merchant_fee);
+        let file_fees = file.chargeback_fee.iter().map(|(role, fee)| (*role, *fee));
+        for (role, fee) in self.chargeback_fee.iter().copied().chain(file_fees) {
+            roles = roles.with_chargeback_fee(role, fee);
+        }
+        if let Some(path) = file.roles.as_ref().or(self.roles.as_ref()) {
+            roles.read_clients(File::open(path)?)?;
+        }
+        let mut currencies = Currencies::new();
+        if let Some(path) = file.currencies.as_ref().or(self.currencies.as_ref()) {
+            currencies.read(File::open(path)?)?;
+        }
+        let currency = match file.denomination.as_ref().or(self.denomination.as_ref()) {
+            Some(code) => currencies.get(code)?.clone(),
+            None => Currency::default(),
+        };
+        #[cfg(feature = "rhai")]
+        let script = match file.script.as_ref().or(self.script.as_ref()) {
+            Some(path) => Some(Arc::new(TransactionScript::compile(&fs::read_to_string(
+                path,
+            )?)?)),
+            None => None,
+        };
+        Ok(Policy {
+            zero_amount: file.zero_amounts.unwrap_or(self.zero_amounts),
+            precision: file.precision.unwrap_or(self.precision),
+            locked_deposit: file.locked_deposits.unwrap_or(self.locked_deposits),
+            withdrawal_dispute: file.withdrawal_disputes.unwrap_or(self.withdrawal_disputes),
+            backdated: file.backdated.unwrap_or(self.backdated),
+            cache_ttl: file.cache_ttl.unwrap_or(self.cache_ttl),
+            retention: file.retention.unwrap_or(self.retention),
+            allow_admin_actions: file.allow_admin_actions.unwrap_or(self.allow_admin_actions),
+            currency,
+            tiers: Arc::new(tiers),
+            roles: Arc::new(roles),
+            rules: Arc::new(file.rules),
+            #[cfg(feature = "rhai")]
+            script,
+        })
+    }
+
+    // The policy file and the CSV files of the flags and the policy file.
+    fn watch(&self) -> PolicyWatch {
+        let file = self
+            .policy_file
+            .as_deref()
+            .and_then(|path| PolicyFile::load(path).ok())
+            .unwrap_or_default();
+        let flags = [
+            &self.policy_file,
+            &self.tiers,
+            &self.client_tiers,
+            &self.roles,
+            &self.currencies,
+        ];
+        let paths = flags.into_iter().flatten().map(PathBuf::as_path);
+        #[cfg(feature = "rhai")]
+        let paths = paths.chain(self.script.as_deref());
+        PolicyWatch::new(paths.chain(file.paths()))
+    }
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    #[arg(value_name = "TRANSACTIONS_CSV", required = true)]
+    input: Option<String>,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[arg(
+        long,
+        help = "CSV with recurring transactions: type, client, tx, amount, start, interval, count, tenant"
+    )]
+    recurring: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Writes rejected transactions and the reason to this CSV file"
+    )]
+    rejects: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "csv",
+        help = "Format of --rejects: csv, or json with one object per line and the error fields"
+    )]
+    rejects_format: RejectsFormat,
+
+    #[arg(
+        long,
+        help = "Appends every applied and rejected transaction to this hash-chained audit log"
+    )]
+    audit: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Records the transactions the books received, after parsing, screening and scheduling, to this file for the replay command"
+    )]
+    record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Writes the double-entry journal of all applied transactions to this CSV file"
+    )]
+    journal: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Tenant whose ledger is written by --journal, --export, --settlement, --category-report, --periods, --dispute-cases and statement"
+    )]
+    tenant: String,
+
+    #[arg(
+        long,
+        help = "Exports the journal as plain-text accounting entries to this file"
+    )]
+    export: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value = "beancount",
+        help = "Format of the exported journal: beancount or ledger"
+    )]
+    export_format: JournalFormat,
+
+    #[arg(
+        long,
+        help = "Writes one netted settlement entry per client to this CSV file"
+    )]
+    settlement: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "settlement",
+        help = "Nets every statement period on its own, with a period column"
+    )]
+    settle_per_period: bool,
+
+    #[arg(
+        long,
+        default_value = "Assets:Settlement",
+        help = "Account the settlement entries are booked against"
+    )]
+    settlement_account: String,
+
+    #[arg(
+        long,
+        help = "Writes the volume per client and category, and per category over all clients, to this CSV file"
+    )]
+    category_report: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Writes the top accounts by deposit, withdrawal and disputed volume of this run to this CSV file"
+    )]
+    top_report: Option<PathBuf>,
+
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "Number of accounts per metric in --top-report"
+    )]
+    top: usize,
+
+    #[arg(
+        long,
+        help = "Writes the totals of every client in the periods closed by close_period rows to this CSV file"
+    )]
+    periods: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Writes every dispute case with its status, age, reason and evidence to this CSV file"
+    )]
+    dispute_cases: Option<PathBuf>,
+
+    #[arg(long, default_value = "Assets:Bank", help = "Account name for cash")]
+    cash_account: String,
+
+    #[arg(
+        long,
+        default_value = "Liabilities:Customers",
+        help = "Parent account name of the customer balances"
+    )]
+    customer_account: String,
+
+    #[arg(
+        long,
+        default_value = "Expenses:ChargebackLoss",
+        help = "Account name for chargeback losses"
+    )]
+    chargeback_account: String,
+
+    #[arg(
+        long,
+        default_value = "Income:Fees",
+        help = "Account name for the fees of merchant deposits"
+    )]
+    fee_account: String,
+
+    #[arg(
+        long,
+        default_value = "Equity:Adjustments",
+        help = "Account name for the counterpart of manual adjustments"
+    )]
+    adjustment_account: String,
+
+    #[arg(
+        long,
+        default_value = "USD",
+        help = "Currency of the exported amounts and the text report"
+    )]
+    currency: String,
+
+    #[arg(
+        long,
+        default_value = "1970-01-01",
+        help = "Date of the exported entries (YYYY-MM-DD)"
+    )]
+    export_date: String,
+
+    #[arg(
+        long,
+        help = "Fails the run if the books don't balance after processing"
+    )]
+    check: bool,
+
+    #[arg(
+        long,
+        help = "Simulates the run: writes the stats and --rejects, but no report, checkpoint, audit log or other outputs"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        help = "Resumes from and saves state and the input offset to this file"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_parser = parse_positive_count,
+        requires = "checkpoint",
+        help = "Saves a checkpoint every N records in addition to the end of the run"
+    )]
+    checkpoint_every: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = parse_interval,
+        requires = "checkpoint",
+        help = "Saves a checkpoint when this much time passed since the last one, e.g. 90s, 15m or 1h"
+    )]
+    checkpoint_interval: Option<Duration>,
+
+    #[arg(
+        long,
+        help = "Skips deposits and withdrawals whose tx ids this file registered in earlier runs, and registers the applied ones"
+    )]
+    processed_registry: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "checkpoint",
+        help = "Encrypts the checkpoint with AES-256-GCM using the hex encoded 32 byte key in this file"
+    )]
+    encryption_key: Option<PathBuf>,
+
+    #[command(flatten)]
+    pseudonyms: PseudonymArgs,
+
+    #[arg(
+        long,
+        help = "Parses rows directly from their bytes instead of through serde"
+    )]
+    fast_parse: bool,
+
+    #[arg(
+        long,
+        default_value = "ignore",
+        help = "Policy for input columns other than the transaction fields: ignore, capture (into the audit log) or reject"
+    )]
+    unknown_columns: UnknownColumnPolicy,
+
+    #[arg(
+        long,
+        default_value = "plain",
+        help = "Format of the amounts: plain (1234.56), decimal-point (1,234.56), decimal-comma (1.234,56), apostrophe (1'234.56) or space-comma (1 234,56, also with no-break spaces)"
+    )]
+    amount_format: AmountFormat,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_action_alias,
+        help = "Comma separated names of transaction types in the input, e.g. withdraw=withdrawal,charge_back=chargeback. Types are compared ignoring case, rows of unknown types are rejected one by one"
+    )]
+    action_alias: Vec<(String, Action)>,
+
+    #[arg(
+        long,
+        help = "Skip lines starting with # (also before the header) and lines of only spaces, e.g. of hand-maintained test files"
+    )]
+    skip_comments: bool,
+
+    #[arg(
+        long,
+        help = "Check the header row up front: fail listing the missing, unexpected and duplicate columns instead of parsing rows against a wrong header"
+    )]
+    strict_headers: bool,
+
+    #[arg(
+        long,
+        default_value = "standard",
+        conflicts_with = "amount_format",
+        help = "Separators of the input: standard (, between fields) or decimal-comma (; between fields, amounts like 1.234,56)"
+    )]
+    dialect: CsvDialect,
+
+    #[arg(
+        long,
+        help = "Memory-maps the input file instead of reading it, not for pipes"
+    )]
+    mmap: bool,
+
+    #[arg(
+        long,
+        value_name = "THREADS",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Parses chunks of the memory-mapped input in this many threads, transactions are still applied in file order"
+    )]
+    parse_threads: Option<u16>,
+
+    #[arg(
+        long,
+        value_name = "READ[,PARSE]",
+        conflicts_with = "parse_threads",
+        help = "Reads, parses and applies the rows in three threads with at most this many rows between them, e.g. 1024 or 4096,1024; a read depth of 0 reads and parses in one thread"
+    )]
+    pipeline_depth: Option<PipelineDepths>,
+
+    #[arg(
+        long,
+        value_parser = parse_count,
+        help = "Expected number of clients, preallocates the accounts"
+    )]
+    expected_clients: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = parse_count,
+        help = "Expected number of transactions, preallocates the transaction cache"
+    )]
+    expected_txs: Option<u64>,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+}
+
+impl ProcessArgs {
+    fn input(&self) -> &str {
+        self.input
+            .as_deref()
+            .expect("clap enforces the input argument")
+            .trim()
+    }
+
+    fn account_names(&self) -> AccountNames {
+        AccountNames {
+            cash: self.cash_account.clone(),
+            customers: self.customer_account.clone(),
+            chargeback_loss: self.chargeback_account.clone(),
+            fees: self.fee_account.clone(),
+            adjustments: self.adjustment_account.clone(),
+            currency: self.currency.clone(),
+            date: self.export_date.clone(),
+        }
+    }
+
+    fn pseudonymizer(&self) -> ApplicationResult<Pseudonymizer> {
+        self.pseudonyms.pseudonymizer()
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Writes a reproducible CSV of synthetic transactions")]
+    Generate(GenerateArgs),
+
+    #[command(about = "Compares the processed balances against an expected balances CSV")]
+    Reconcile(Box<ReconcileArgs>),
+
+    #[command(about = "Prints the per-client changes between two account reports")]
+    Diff(DiffArgs),
+
+    #[command(about = "Checks a transactions CSV for problems without processing it")]
+    Validate(ValidateArgs),
+
+    #[command(about = "Applies transactions of an asset with up to 18 decimals exactly")]
+    Asset(AssetArgs),
+
+    #[command(
+        about = "Applies transactions of different clients in parallel, in input order per client"
+    )]
+    Parallel(Box<ParallelArgs>),
+
+    #[command(about = "Checks that a hash-chained audit log hasn't been edited")]
+    VerifyAudit(VerifyAuditArgs),
+
+    #[command(about = "Checks the detached signature of a report")]
+    Verify(VerifyArgs),
+
+    #[command(about = "Writes a QIF statement of one client's applied transactions")]
+    Statement(Box<StatementArgs>),
+
+    #[command(about = "Writes the balances of the accounts after every applied transaction")]
+    History(Box<HistoryArgs>),
+
+    #[command(
+        about = "Writes the applied transactions of one client with their dispute states and the balances after them"
+    )]
+    Export(Box<ExportArgs>),
+
+    #[command(
+        about = "Re-applies the transactions of a --record file and dumps the account at breakpoints"
+    )]
+    Replay(Box<ReplayArgs>),
+
+    #[cfg(feature = "test_support")]
+    #[command(
+        about = "Checks the scenarios of a fixtures directory against their expected reports"
+    )]
+    Fixtures(FixturesArgs),
+
+    #[cfg(feature = "nats")]
+    #[command(
+        about = "Applies transactions from a NATS JetStream consumer and prints the balances"
+    )]
+    Nats(Box<NatsArgs>),
+
+    #[cfg(unix)]
+    #[command(about = "Applies newline-delimited CSV or JSON transactions sent to a Unix socket")]
+    Serve(Box<ServeArgs>),
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    #[arg(value_name = "OLD_REPORT_CSV")]
+    old: PathBuf,
+
+    #[arg(value_name = "NEW_REPORT_CSV")]
+    new: PathBuf,
+
+    #[arg(long, default_value = "csv", help = "Output format: csv or json")]
+    format: DiffFormat,
+}
+
+#[derive(Args)]
+struct ValidateArgs {
+    #[arg(value_name = "TRANSACTIONS_CSV")]
+    input: String,
+
+    #[arg(
+        long,
+        default_value = "ignore",
+        help = "Policy for input columns other than the transaction fields: ignore, capture or reject"
+    )]
+    unknown_columns: UnknownColumnPolicy,
+
+    #[arg(
+        long,
+        default_value = "plain",
+        help = "Format of the amounts: plain (1234.56), decimal-point (1,234.56), decimal-comma (1.234,56), apostrophe (1'234.56) or space-comma (1 234,56, also with no-break spaces)"
+    )]
+    amount_format: AmountFormat,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_action_alias,
+        help = "Comma separated names of transaction types in the input, e.g. withdraw=withdrawal,charge_back=chargeback. Types are compared ignoring case, rows of unknown types are rejected one by one"
+    )]
+    action_alias: Vec<(String, Action)>,
+
+    #[arg(
+        long,
+        help = "Skip lines starting with # (also before the header) and lines of only spaces, e.g. of hand-maintained test files"
+    )]
+    skip_comments: bool,
+
+    #[arg(
+        long,
+        help = "Check the header row up front: fail listing the missing, unexpected and duplicate columns instead of parsing rows against a wrong header"
+    )]
+    strict_headers: bool,
+
+    #[arg(
+        long,
+        default_value = "standard",
+        conflicts_with = "amount_format",
+        help = "Separators of the input: standard (, between fields) or decimal-comma (; between fields, amounts like 1.234,56)"
+    )]
+    dialect: CsvDialect,
+}
+
+#[derive(Args)]
+struct AssetArgs {
+    #[arg(value_name = "TRANSACTIONS_CSV")]
+    input: String,
+
+    #[arg(
+        long,
+        help = "Asset of the amounts: BTC, ETH, SOL, USDC, USDT or CODE:DECIMALS with at most 18 decimals"
+    )]
+    asset: Asset,
+}
+
+#[derive(Args)]
+struct ParallelArgs {
+    #[command(flatten)]
+    process: ProcessArgs,
+
+    #[arg(
+        long,
+        default_value = "4",
+        help = "Applies the transactions in this many threads, each one owning a share of the clients"
+    )]
+    workers: u16,
+}
+
+#[derive(Args)]
+struct VerifyAuditArgs {
+    #[arg(value_name = "AUDIT_LOG")]
+    path: PathBuf,
+
+    #[arg(
+        long,
+        help = "The `audit log head` printed by the last run, so entries removed from the end are detected too"
+    )]
+    head: Option<String>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[arg(long, help = "File with the hex encoded ed25519 public key")]
+    public_key: PathBuf,
+
+    #[arg(long, help = "File with the hex encoded signature")]
+    signature: PathBuf,
+
+    #[arg(value_name = "REPORT_CSV")]
+    report: PathBuf,
+}
+
+#[derive(Args)]
+struct StatementArgs {
+    #[arg(long)]
+    client: ClientId,
+
+    #[arg(long, short, help = "Output file, defaults to stdout")]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Args)]
+struct HistoryArgs {
+    #[arg(long, help = "Only the history of this client")]
+    client: Option<ClientId>,
+
+    #[arg(long, short, help = "Output file, defaults to stdout")]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    #[arg(long)]
+    client: ClientId,
+
+    #[arg(long, default_value = "csv", help = "Output format: csv or json")]
+    format: ClientLogFormat,
+
+    #[arg(long, short, help = "Output file, defaults to stdout")]
+    output: Option<PathBuf>,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Args)]
+struct ReplayArgs {
+    #[arg(value_name = "RECORDING")]
+    recording: PathBuf,
+
+    #[arg(
+        long = "break",
+        value_name = "TX",
+        value_delimiter = ',',
+        help = "Comma separated tx ids; the transaction, its outcome and the account of its client before and after it are dumped to stderr as JSON"
+    )]
+    breakpoints: Vec<TransactionId>,
+
+    #[arg(
+        long,
+        requires = "breakpoints",
+        help = "Stops after the first breakpoint and prints the balances at that point"
+    )]
+    stop: bool,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    pseudonyms: PseudonymArgs,
+
+    #[arg(long, default_value = "USD", help = "Currency of the text report")]
+    currency: String,
+
+    #[command(flatten)]
+    sign: SignArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[cfg(feature = "test_support")]
+#[derive(Args)]
+struct FixturesArgs {
+    #[arg(value_name = "DIR", default_value = "fixtures")]
+    dir: PathBuf,
+
+    #[arg(
+        long,
+        help = "Writes the current reports as the expected ones instead of checking them"
+    )]
+    regenerate: bool,
+}
+
+#[cfg(feature = "nats")]
+#[derive(Args)]
+struct NatsArgs {
+    #[arg(long, default_value = "nats://localhost:4222")]
+    server: String,
+
+    #[arg(long, help = "JetStream stream with the transactions")]
+    stream: String,
+
+    #[arg(
+        long,
+        default_value = "accounting-demo",
+        help = "Durable consumer, created if it doesn't exist"
+    )]
+    consumer: String,
+
+    #[arg(
+        long,
+        default_value = "",
+        help = "Only the messages of this subject, defaults to all of the stream"
+    )]
+    subject: String,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "type,client,tx,amount",
+        help = "Comma separated columns of the CSV row in every message"
+    )]
+    fields: Vec<String>,
+
+    #[arg(long, default_value_t = 256, help = "Messages applied per checkpoint")]
+    batch: usize,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    retry: RetryArgs,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Stops and prints the balances after this many seconds without messages"
+    )]
+    idle_exit: u64,
+
+    #[arg(
+        long,
+        help = "Resumes from and saves state and the last applied stream sequence to this file"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "checkpoint",
+        help = "Encrypts the checkpoint with AES-256-GCM using the hex encoded 32 byte key in this file"
+    )]
+    encryption_key: Option<PathBuf>,
+
+    #[command(flatten)]
+    pseudonyms: PseudonymArgs,
+
+    #[arg(long, default_value = "USD", help = "Currency of the text report")]
+    currency: String,
+
+    #[command(flatten)]
+    sign: SignArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[cfg(unix)]
+#[derive(Args)]
+struct ServeArgs {
+    #[arg(long, help = "Path of the Unix socket, replaced if it exists")]
+    socket: PathBuf,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "type,client,tx,amount",
+        help = "Comma separated columns of the CSV lines, JSON lines use the column names as keys"
+    )]
+    fields: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Resumes from this file and saves the state to it whenever a connection closes"
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "checkpoint",
+        help = "Encrypts the checkpoint with AES-256-GCM using the hex encoded 32 byte key in this file"
+    )]
+    encryption_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Records the balances after every applied transaction, answering `history CLIENT [TENANT]` lines"
+    )]
+    history: bool,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[arg(
+        long,
+        default_value_t = 30,
+        value_name = "SECS",
+        help = "On SIGTERM or SIGINT, waits this long for open connections to finish"
+    )]
+    shutdown_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "RATE[/BURST]",
+        help = "Transactions per second a client may send on average, and at once (defaults to the rate). Others are answered with rate_limited"
+    )]
+    rate_limit: Option<RateLimit>,
+
+    #[arg(
+        long,
+        value_name = "KEYS_CSV",
+        help = "CSV with the name,key,role of the API keys (submit, read or admin). Connections must start with an `auth KEY` line"
+    )]
+    api_keys: Option<PathBuf>,
+
+    #[cfg(feature = "redis")]
+    #[arg(
+        long,
+        value_name = "URL",
+        conflicts_with_all = ["checkpoint", "history"],
+        help = "Keeps the accounts and the tx cache in Redis, e.g. redis://127.0.0.1/, shared by all servers using it"
+    )]
+    backend: Option<String>,
+
+    #[cfg(feature = "redis")]
+    #[arg(long, default_value = "accounting:", help = "Prefix of the Redis keys")]
+    backend_prefix: String,
+
+    #[command(flatten)]
+    pseudonyms: PseudonymArgs,
+
+    #[arg(long, default_value = "USD", help = "Currency of the text report")]
+    currency: String,
+
+    #[command(flatten)]
+    sign: SignArgs,
+
+    #[command(flatten)]
+    report: ReportArgs,
+}
+
+#[derive(Args)]
+struct ReconcileArgs {
+    #[arg(
+        long,
+        help = "CSV with the expected client,available,held,total,locked"
+    )]
+    expected: PathBuf,
+
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    #[arg(long, default_value_t = 100)]
+    clients: ClientId,
+
+    #[arg(long, default_value = "1K", value_parser = parse_count)]
+    transactions: u64,
+
+    #[arg(long, default_value_t = 0.01)]
+    dispute_rate: f64,
+
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    #[arg(long, short, help = "Output file, defaults to stdout")]
+    output: Option<PathBuf>,
+}
+
+fn parse_count(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last() {
+        Some('k' | 'K') => (&value[..value.len() - 1], 1_000),
+        Some('m' | 'M') => (&value[..value.len() - 1], 1_000_000),
+        Some('b' | 'B') => (&value[..value.len() - 1], 1_000_000_000),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid count '{value}', expected e.g. 500, 10K or 10M"))
+}
+
+// A count of at least 1, e.g. of records between checkpoints.
+fn parse_positive_count(value: &str) -> Result<u64, String> {
+    match parse_count(value)? {
+        0 => Err(format!("invalid count '{value}', expected at least 1")),
+        count => Ok(count),
     }
+}
 
-    Ok(args[1].trim().to_string())
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (digits, seconds) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 3600),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .filter(|count| *count > 0)
+        .and_then(|count| count.checked_mul(seconds))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid interval '{value}', expected e.g. 90s, 15m or 1h"))
 }
 
-fn get_csv_reader(path: &str) -> ApplicationResult<Reader<File>> {
+fn parse_fee_rate(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+        .ok_or_else(|| format!("invalid fee rate '{value}', expected a share between 0 and 1"))
+}
+
+fn parse_role_fee(value: &str) -> Result<(Role, f64), String> {
+    let (role, fee) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid chargeback fee '{value}', expected ROLE=AMOUNT"))?;
+    let fee = fee
+        .parse::<f64>()
+        .ok()
+        .filter(|fee| fee.is_finite() && *fee >= 0.0)
+        .ok_or_else(|| format!("invalid chargeback fee '{fee}', expected a positive amount"))?;
+    Ok((role.parse()?, fee))
+}
+
+fn parse_action_alias(value: &str) -> Result<(String, Action), String> {
+    let (name, action) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid action alias '{value}', expected NAME=TYPE"))?;
+    let action = action.parse().map_err(|_| {
+        format!("invalid action alias '{value}', '{action}' is not a transaction type")
+    })?;
+    Ok((name.to_string(), action))
+}
+
+fn action_aliases(aliases: &[(String, Action)]) -> ActionAliases {
+    aliases
+        .iter()
+        .fold(ActionAliases::new(), |aliases, (name, action)| {
+            aliases.with_alias(name, action.clone())
+        })
+}
+
+// The balances of the client at an applied `balance` row, zero if it has no
+// account.
+fn balance_snapshot(
+    tenants: &Tenants,
+    tx: &Transaction,
+    result: &AccountManagerResult<()>,
+) -> Option<BalanceSnapshot> {
+    if tx.action != Action::Balance || result.is_err() {
+        return None;
+    }
+    let account = tenants
+        .get(tx.tenant.as_deref().unwrap_or_default())
+        .and_then(|account_manager| account_manager.account(tx.client_id))
+        .cloned()
+        .unwrap_or_default();
+    Some(BalanceSnapshot::new(&account))
+}
+
+fn invalid_row(position: &Position, message: String) -> ApplicationError {
+    ApplicationError::InvalidRow {
+        line: position.line(),
+        record: position.record(),
+        message,
+    }
+}
+
+fn get_csv_reader(
+    path: &str,
+    mmap: bool,
+    retry: RetryPolicy,
+    dialect: CsvDialect,
+    skip_comments: bool,
+) -> ApplicationResult<Reader<Input>> {
+    let input = match mmap {
+        true => Input::map(path)?,
+        false => Input::open_location(path, retry)?,
+    }
+    .decoded()?;
     Ok(ReaderBuilder::new()
+        .delimiter(dialect.delimiter())
+        .comment(skip_comments.then_some(COMMENT_PREFIX))
         .flexible(true)
         .trim(Trim::All)
-        .from_path(path)?)
-}
-
-fn write_accounts(accounts: Vec<(ClientId, Account)>) {
-    println!("client,available,held,total,locked");
-    accounts.iter().for_each(|(id, account)| {
-        println!(
-            "{},{:.4},{:.4},{:.4},{}",
-            id,
-            account.available(),
-            account.disputed(),
-            account.total(),
-            account.locked()
-        )
+        .from_reader(input))
+}
+
+// Only accounts of named tenants carry a tenant, so single tenant reports keep
+// their columns and the default tenant reads back the same from a report.
+// With a point, the balances of the accounts at that point of the history of
+// their tenant; accounts no transaction touched until then are left out.
+fn account_records(
+    tenants: &Tenants,
+    as_of: Option<AsOf>,
+) -> impl Iterator<Item = AccountRecord> + Clone + '_ {
+    let Policy { tiers, roles, .. } = tenants.policy();
+    tenants
+        .iter_accounts()
+        .filter_map(move |(tenant, id, account)| {
+            let record = AccountRecord {
+                tenant: (!tenant.is_empty()).then(|| tenant.clone()),
+                tier: tiers.tier(*id),
+                role: account.role().unwrap_or_else(|| roles.role(*id)),
+                ..AccountRecord::new(*id, account)
+            };
+            let Some(as_of) = as_of else {
+                return Some(record);
+            };
+            let point = tenants.get(tenant)?.balance_as_of(*id, as_of)?;
+            Some(AccountRecord {
+                available: point.available,
+                held: point.held,
+                total: point.total(),
+                locked: point.locked,
+                tx_count: point.transactions,
+                receivable: point.receivable,
+                ..record
+            })
+        })
+}
+
+fn tenant_ledger<'a>(tenants: &'a Tenants, tenant: &str, empty: &'a Ledger) -> &'a Ledger {
+    tenants
+        .get(tenant)
+        .and_then(AccountManager::ledger)
+        .unwrap_or(empty)
+}
+
+fn write_accounts(
+    records: impl Iterator<Item = AccountRecord> + Clone,
+    report_args: &ReportArgs,
+    currency: &str,
+    places: u32,
+    sign: &SignArgs,
+    pseudonymizer: &Pseudonymizer,
+) -> ApplicationResult<()> {
+    let write = |writer: &mut dyn Write| -> ApplicationResult<()> {
+        match report_args.report_format {
+            ReportFormat::Csv => {
+                write_account_records(writer, records, &report_args.columns, places, pseudonymizer)?
+            }
+            ReportFormat::Text => write_account_table(
+                writer,
+                records,
+                &report_args.columns,
+                &report_args.currency_format(currency, places),
+                pseudonymizer,
+            )?,
+        }
+        Ok(())
+    };
+    let Some(signature_path) = &sign.signature else {
+        return write(&mut io::stdout().lock());
+    };
+
+    // The key is loaded first so that a missing key fails before any output.
+    let key = sign.signing_key()?;
+    let mut report = Vec::new();
+    write(&mut report)?;
+    fs::write(signature_path, sign_report(&key, &report))?;
+    io::stdout().lock().write_all(&report)?;
+    eprintln!("signed report with public key {}", public_key_hex(&key));
+    Ok(())
+}
+
+fn source_offset(position: &Position) -> SourceOffset {
+    SourceOffset {
+        byte: position.byte(),
+        line: position.line(),
+        record: position.record(),
+    }
+}
+
+// The lengths of the flushed rejects and audit log of a run.
+fn output_offsets(args: &ProcessArgs) -> io::Result<OutputOffsets> {
+    let length = |path: Option<&PathBuf>| path.map(|path| OutputOffsets::length(path)).transpose();
+    Ok(OutputOffsets {
+        rejects: length(args.rejects.as_ref())?,
+        audit: length(args.audit.as_ref())?,
+    })
+}
+
+// The CSV has the description and reference columns if the input has them.
+fn open_rejects(
+    path: &PathBuf,
+    format: RejectsFormat,
+    resumed: bool,
+    pseudonymizer: &Pseudonymizer,
+    parser: &RecordParser,
+) -> io::Result<RejectsWriter<File>> {
+    let writer = match (format, resumed && path.exists()) {
+        (RejectsFormat::Csv, true) => {
+            RejectsWriter::without_headers(OpenOptions::new().append(true).open(path)?)
+        }
+        (RejectsFormat::Csv, false) => RejectsWriter::new(File::create(path)?),
+        (RejectsFormat::Json, true) => {
+            RejectsWriter::json(OpenOptions::new().append(true).open(path)?)
+        }
+        (RejectsFormat::Json, false) => RejectsWriter::json(File::create(path)?),
+    };
+    let details = parser.has_column("description") || parser.has_column("reference");
+    Ok(writer
+        .with_pseudonymizer(pseudonymizer.clone())
+        .with_details(details))
+}
+
+// Set on SIGUSR1, the stats of a long run are then printed after the current
+// row.
+fn stats_signal() -> io::Result<Arc<AtomicBool>> {
+    let requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&requested))?;
+    Ok(requested)
+}
+
+// Set on SIGTERM or SIGINT to stop gracefully. A second signal while stopping
+// exits at once.
+fn shutdown_signal() -> io::Result<Arc<AtomicBool>> {
+    let requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&requested))?;
+        signal_hook::flag::register(signal, Arc::clone(&requested))?;
+    }
+    Ok(requested)
+}
+
+// Where an interrupted run stopped; the results cover the records before it.
+struct Interrupted {
+    next: SourceOffset,
+}
+
+// Set on SIGHUP to reload the policy.
+fn reload_signal() -> io::Result<Arc<AtomicBool>> {
+    let requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&requested))?;
+    Ok(requested)
+}
+
+// How often servers check whether the policy files changed.
+const POLICY_POLL: Duration = Duration::from_secs(1);
+
+// Reads the policy again if it was requested or one of its files changed. A
+// policy that fails to load is reported and the current one kept.
+fn reload_policy(
+    args: &PolicyArgs,
+    watch: &mut PolicyWatch,
+    requested: &AtomicBool,
+) -> Option<Policy> {
+    let requested = requested.swap(false, Ordering::Relaxed);
+    if !(watch.changed() || requested) {
+        return None;
+    }
+    *watch = args.watch();
+    match args.policy() {
+        Ok(policy) => {
+            eprintln!("reloaded the policy");
+            Some(policy)
+        }
+        Err(err) => {
+            eprintln!("keeping the current policy, Error {}: {err}", err.code());
+            None
+        }
+    }
+}
+
+// The reader of the input after its headers and the parser of its rows, set
+// up by the input options.
+fn open_input(args: &ProcessArgs) -> ApplicationResult<(Reader<Input>, RecordParser)> {
+    let mut csv_reader = get_csv_reader(
+        args.input(),
+        args.mmap || args.parse_threads.is_some(),
+        args.retry.policy(),
+        args.dialect,
+        args.skip_comments,
+    )?;
+    if args.strict_headers {
+        check_schema(csv_reader.byte_headers()?, args.unknown_columns)?;
+    }
+    let parser = RecordParser::new(csv_reader.byte_headers()?, args.fast_parse)?
+        .with_unknown_columns(args.unknown_columns)?
+        .with_amount_format(args.amount_format)
+        .with_action_aliases(action_aliases(&args.action_alias))
+        .with_skip_comments(args.skip_comments)
+        .with_dialect(args.dialect);
+    Ok((csv_reader, parser))
+}
+
+// The accounts after an input record, for `--as-of seq:N`. Nothing is
+// recorded after it.
+struct RecordSnapshot {
+    record: u64,
+    accounts: Option<Vec<AccountRecord>>,
+}
+
+impl RecordSnapshot {
+    fn new(record: u64) -> Self {
+        Self {
+            record,
+            accounts: None,
+        }
+    }
+
+    // Before the first record after the point, or at the end of the input.
+    fn take(&mut self, tenants: &Tenants, next_record: Option<u64>) {
+        if self.accounts.is_none() && next_record.is_none_or(|next| next > self.record) {
+            self.accounts = Some(account_records(tenants, None).collect());
+        }
+    }
+}
+
+// The checkpoint key of --encryption-key, if given.
+fn encryption_key(path: Option<&Path>) -> ApplicationResult<Option<EncryptionKey>> {
+    let key = path.map(EncryptionKey::load).transpose();
+    Ok(key.map_err(CheckpointError::from)?)
+}
+
+// Stops at the next record on SIGINT or SIGTERM. Everything up to it is still
+// saved and written as usual, and the place it stopped returned.
+fn process(
+    args: &ProcessArgs,
+    ledger: bool,
+    history: bool,
+    stats: &mut ProcessingStats,
+    mut snapshot: Option<&mut RecordSnapshot>,
+) -> ApplicationResult<(Tenants, Option<Interrupted>)> {
+    let csv_path = args.input();
+    let (mut csv_reader, parser) = open_input(args)?;
+
+    let pseudonymizer = args.pseudonymizer()?;
+    let key = encryption_key(args.encryption_key.as_deref())?;
+    let checkpoint = match &args.checkpoint {
+        Some(path) => Checkpoint::load(path, csv_path, key.as_ref())?,
+        None => None,
+    };
+    let resumed = checkpoint.is_some();
+    // Rows after the checkpoint are written to the rejects and the audit log
+    // again, so what the interrupted run wrote of them is cut off.
+    if let Some(checkpoint) = &checkpoint {
+        if let Some(path) = &args.rejects {
+            OutputOffsets::truncate(path, checkpoint.outputs.rejects)?;
+        }
+        if let Some(path) = args.audit.as_ref().filter(|_| !args.dry_run) {
+            OutputOffsets::truncate(path, checkpoint.outputs.audit)?;
+        }
+    }
+    let mut tenants = match checkpoint {
+        Some(checkpoint) => {
+            let mut position = Position::new();
+            position
+                .set_byte(checkpoint.next.byte)
+                .set_line(checkpoint.next.line)
+                .set_record(checkpoint.next.record);
+            csv_reader.seek(position)?;
+
+            let mut tenants = checkpoint.tenants;
+            tenants.set_policy(args.policy.policy()?);
+            tenants
+        }
+        None => Tenants::with_policy(args.policy.policy()?),
+    };
+    if ledger
+        || args.journal.is_some()
+        || args.export.is_some()
+        || args.settlement.is_some()
+        || args.category_report.is_some()
+    {
+        tenants.enable_ledger();
+    }
+    if history {
+        tenants.enable_history();
+    }
+    if let Some(path) = &args.recurring {
+        tenants.set_recurring_rules(Scheduler::read_rules(File::open(path)?)?);
+    }
+    if args.expected_clients.is_some() || args.expected_txs.is_some() {
+        let hint = |count: Option<u64>| count.map_or(0, |count| count as usize);
+        tenants.reserve(hint(args.expected_clients), hint(args.expected_txs));
+    }
+    let mut rejects = args
+        .rejects
+        .as_ref()
+        .map(|path| open_rejects(path, args.rejects_format, resumed, &pseudonymizer, &parser))
+        .transpose()?;
+    // A dry run may resume from a checkpoint, but never saves one.
+    let saved_checkpoint = args.checkpoint.as_ref().filter(|_| !args.dry_run);
+    let mut registry = args
+        .processed_registry
+        .as_deref()
+        .map(ProcessedRegistry::load)
+        .transpose()?;
+    let saved_registry = args.processed_registry.as_ref().filter(|_| !args.dry_run);
+    let mut audit = args
+        .audit
+        .as_deref()
+        .filter(|_| !args.dry_run)
+        .map(|path| AuditLog::open(path).map(|log| log.with_pseudonymizer(pseudonymizer.clone())))
+        .transpose()?;
+
+    let mut recorder = args
+        .record
+        .as_deref()
+        .filter(|_| !args.dry_run)
+        .map(ReplayRecorder::create)
+        .transpose()?;
+
+    let mut top = args.top_report.as_ref().map(|_| TopAccounts::new(args.top));
+    let stats_requested = stats_signal()?;
+    let interrupt_requested = shutdown_signal()?;
+    // Stops the parallel and pipelined readers, which only stop on errors.
+    let interrupt = || match interrupt_requested.load(Ordering::Relaxed) {
+        true => Err(io::Error::from(io::ErrorKind::Interrupted)),
+        false => Ok(()),
+    };
+
+    let mut next = csv_reader.position().clone();
+    let mut checkpointed = Instant::now();
+    let mut handle = |position: &Position, tx: Result<Transaction, RowError>, next: &Position| {
+        // Rows of unknown types are rejected one by one, other invalid rows
+        // end the run.
+        let mut tx = match tx {
+            Ok(tx) => tx,
+            Err(RowError::UnknownAction(value)) => {
+                stats.skip("unknown_action");
+                eprintln!(
+                    "line {} (record {}): type `{value}` is not a transaction type, rejected",
+                    position.line(),
+                    position.record()
+                );
+                return Ok(());
+            }
+            Err(RowError::Invalid(message)) => return Err(invalid_row(position, message)),
+        };
+        if let Some(snapshot) = snapshot.as_deref_mut() {
+            snapshot.take(&tenants, Some(position.record()));
+        }
+        let processed = stats.processed();
+        let mut record = |tx: &Transaction, adjusted: Option<u32>, balance, result| {
+            stats.record(&result);
+            match (audit.as_mut(), adjusted, balance, &result) {
+                (Some(audit), _, Some(balance), Ok(())) => {
+                    audit.append_balance(position, tx, balance)?
+                }
+                (Some(audit), Some(period), _, Ok(())) => {
+                    audit.append_adjusted(position, tx, period)?
+                }
+                (Some(audit), _, _, _) => audit.append(position, tx, &result)?,
+                (None, _, _, _) => {}
+            }
+            if let (Err(err), Some(rejects)) = (result, rejects.as_mut()) {
+                rejects.write(position, tx, &err)?;
+            }
+            ApplicationResult::Ok(())
+        };
+        // Transactions that became due are applied before the record that
+        // moved the clock, and logged at its position.
+        if let Some(now) = tx.timestamp {
+            tenants.advance_clock(now);
+            while let Some(due) = tenants.next_due() {
+                let adjusted = tenants.adjusted_period(&due);
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record(&due)?;
+                }
+                let result = tenants.process_scheduled(due.clone());
+                if let (Some(registry), Ok(())) = (registry.as_mut(), &result) {
+                    registry.record(&due);
+                }
+                if let (Some(top), Ok(())) = (top.as_mut(), &result) {
+                    top.record(&due, &tenants);
+                }
+                let balance = balance_snapshot(&tenants, &due, &result);
+                record(&due, adjusted, balance, result)?;
+            }
+        }
+        let adjusted = tenants.adjusted_period(&tx);
+        let screened = registry
+            .as_ref()
+            .map_or(Ok(()), |registry| registry.check(&tx))
+            .and_then(|()| tenants.screen(&mut tx));
+        // Scheduled transactions are recorded once they are due.
+        let deferred = tx
+            .execute_at
+            .is_some_and(|at| !tenants.scheduler().is_due(at));
+        if let (Ok(()), false, Some(recorder)) = (&screened, deferred, recorder.as_mut()) {
+            recorder.record(&tx)?;
+        }
+        let result =
+            screened.and_then(|()| tenants.process_transaction_at(position.byte(), tx.clone()));
+        // Like the recorder, the registry only holds applied transactions.
+        if let (Some(registry), Ok(()), false) = (registry.as_mut(), &result, deferred) {
+            registry.record(&tx);
+        }
+        if let (Some(top), Ok(())) = (top.as_mut(), &result) {
+            top.record(&tx, &tenants);
+        }
+        // Deferred rows are counted and logged once they are due, unless
+        // they were rejected right away.
+        if !(deferred && result.is_ok()) {
+            let balance = balance_snapshot(&tenants, &tx, &result);
+            record(&tx, adjusted, balance, result)?;
+        }
+        if stats_requested.swap(false, Ordering::Relaxed) {
+            eprintln!("{stats}; {}", tenants.stats());
+        }
+
+        if let Some(path) = saved_checkpoint {
+            let counted = args
+                .checkpoint_every
+                .is_some_and(|every| processed / every != stats.processed() / every);
+            let timed = args
+                .checkpoint_interval
+                .is_some_and(|interval| checkpointed.elapsed() >= interval);
+            if counted || timed {
+                if let Some(rejects) = rejects.as_mut() {
+                    rejects.flush()?;
+                }
+                if let Some(audit) = audit.as_mut() {
+                    audit.flush()?;
+                }
+                Checkpoint::save_with_outputs(
+                    path,
+                    csv_path,
+                    source_offset(next),
+                    output_offsets(args)?,
+                    &tenants,
+                    key.as_ref(),
+                )?;
+                // After the checkpoint, so a crash in between never skips rows
+                // the checkpoint doesn't hold.
+                if let (Some(path), Some(registry)) = (saved_registry, registry.as_ref()) {
+                    registry.save(path)?;
+                }
+                checkpointed = Instant::now();
+            }
+        }
+        ApplicationResult::Ok(())
+    };
+    let result = match (args.parse_threads, args.pipeline_depth) {
+        (Some(threads), _) => {
+            let data = csv_reader
+                .get_ref()
+                .as_bytes()
+                .expect("the input is mapped");
+            let start = next.clone();
+            parse_parallel(data, &start, &parser, threads.into(), |parsed| {
+                handle(&parsed.position, parsed.tx, &parsed.next)?;
+                next = parsed.next;
+                Ok(interrupt()?)
+            })
+        }
+        (None, Some(depths)) => run_pipeline(&mut csv_reader, &parser, depths, |parsed| {
+            handle(&parsed.position, parsed.tx, &parsed.next)?;
+            next = parsed.next;
+            Ok(interrupt()?)
+        }),
+        (None, None) => {
+            let mut record = ByteRecord::new();
+            while parser.read_record(&mut csv_reader, &mut record)? {
+                let position = record.position().cloned().unwrap_or_else(Position::new);
+                handle(&position, parser.parse(&record), csv_reader.position())?;
+                if interrupt().is_err() {
+                    break;
+                }
+            }
+            next = csv_reader.position().clone();
+            Ok(())
+        }
+    };
+    match result {
+        Err(ApplicationError::Io(err)) if err.kind() == io::ErrorKind::Interrupted => {}
+        result => result?,
+    }
+    if let Some(snapshot) = snapshot {
+        snapshot.take(&tenants, None);
+    }
+    let interrupted = interrupt_requested
+        .load(Ordering::Relaxed)
+        .then(|| Interrupted {
+            next: source_offset(&next),
+        });
+    if let Some(rejects) = rejects.as_mut() {
+        rejects.flush()?;
+    }
+    if let Some(audit) = audit.as_mut() {
+        audit.flush()?;
+        eprintln!("audit log head {}", audit.head());
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.flush()?;
+    }
+    if let Some(path) = saved_checkpoint {
+        Checkpoint::save_with_outputs(
+            path,
+            csv_path,
+            source_offset(&next),
+            output_offsets(args)?,
+            &tenants,
+            key.as_ref(),
+        )?;
+    }
+    if let (Some(path), Some(registry)) = (saved_registry, registry.as_ref()) {
+        registry.save(path)?;
+    }
+
+    let pending = tenants.scheduler().pending();
+    if pending > 0 {
+        eprintln!("{pending} scheduled transactions are pending");
+    }
+    let evicted: u64 = tenants
+        .iter()
+        .map(|(_, account_manager)| account_manager.evicted_transactions())
+        .sum();
+    if evicted > 0 {
+        eprintln!("{evicted} cached transactions expired and can't be disputed anymore");
+    }
+    let compacted: u64 = tenants
+        .iter()
+        .map(|(_, account_manager)| account_manager.compacted().transactions)
+        .sum();
+    if compacted > 0 {
+        eprintln!("{compacted} cached transactions of compacted periods can't be disputed anymore");
+    }
+
+    if args.check {
+        for (tenant, account_manager) in tenants.iter() {
+            account_manager.verify_invariants().map_err(|err| {
+                let message = pseudonymizer.error(&err);
+                ApplicationError::Invariant(match tenant.is_empty() {
+                    true => message,
+                    false => format!("Tenant {tenant}: {message}"),
+                })
+            })?;
+        }
+    }
+    if args.dry_run {
+        return Ok((tenants, interrupted));
+    }
+
+    let places = tenants.policy().currency.minor_units;
+    let empty = Ledger::new();
+    let ledger = tenant_ledger(&tenants, &args.tenant, &empty);
+    if let Some(path) = &args.journal {
+        ledger.write_csv(File::create(path)?, &pseudonymizer)?;
+    }
+    if let Some(path) = &args.export {
+        write_journal(
+            BufWriter::new(File::create(path)?),
+            ledger,
+            args.export_format,
+            &args.account_names(),
+            places,
+            &pseudonymizer,
+        )?;
+    }
+    if let Some(path) = &args.settlement {
+        let settlements = match args.settle_per_period {
+            true => {
+                let closed = tenants
+                    .get(&args.tenant)
+                    .map_or(&[][..], |account_manager| {
+                        account_manager.periods().closed()
+                    });
+                settle_periods(ledger, closed)
+            }
+            false => settle(ledger),
+        };
+        write_settlements(
+            File::create(path)?,
+            &settlements,
+            &args.cash_account,
+            &args.settlement_account,
+            places,
+            &pseudonymizer,
+        )?;
+    }
+    if let Some(path) = &args.periods {
+        let closed = tenants
+            .get(&args.tenant)
+            .map_or(&[][..], |account_manager| {
+                account_manager.periods().closed()
+            });
+        write_periods(File::create(path)?, closed, places, &pseudonymizer)?;
+    }
+    if let Some(path) = &args.dispute_cases {
+        let cases = tenants
+            .get(&args.tenant)
+            .map(|account_manager| account_manager.dispute_cases().iter());
+        write_dispute_cases(
+            File::create(path)?,
+            cases.into_iter().flatten(),
+            tenants.scheduler().now(),
+            places,
+            &pseudonymizer,
+        )?;
+    }
+    if let Some(path) = &args.category_report {
+        write_category_report(
+            File::create(path)?,
+            &category_volumes(ledger),
+            places,
+            &pseudonymizer,
+        )?;
+    }
+    if let (Some(path), Some(top)) = (&args.top_report, &top) {
+        write_top(File::create(path)?, top, places, &pseudonymizer)?;
+    }
+
+    Ok((tenants, interrupted))
+}
+
+fn process_with_summary(
+    args: &ProcessArgs,
+    ledger: bool,
+    history: bool,
+    snapshot: Option<&mut RecordSnapshot>,
+) -> ApplicationResult<(Tenants, ExitCode)> {
+    let mut stats = ProcessingStats::new();
+    let result = process(args, ledger, history, &mut stats, snapshot);
+    match args.dry_run {
+        true => eprintln!("dry run, simulated only: {stats}"),
+        false => eprintln!("{stats}"),
+    }
+    let (tenants, interrupted) = result?;
+    let exit_code = match (interrupted, stats.rejected()) {
+        (Some(Interrupted { next }), _) => {
+            eprintln!(
+                "interrupted before line {} (byte {}, record {}), the results are PARTIAL",
+                next.line, next.byte, next.record
+            );
+            ExitCode::from(EXIT_INTERRUPTED)
+        }
+        (None, 0) => ExitCode::SUCCESS,
+        (None, _) => ExitCode::from(EXIT_REJECTIONS),
+    };
+    Ok((tenants, exit_code))
+}
+
+fn run(args: &ProcessArgs, sign: &SignArgs, report: &ReportArgs) -> ApplicationResult<ExitCode> {
+    // Points in time need the history, records only the balances after them.
+    let mut snapshot = match report.as_of {
+        Some(AsOf::Sequence(record)) => Some(RecordSnapshot::new(record)),
+        _ => None,
+    };
+    let history = matches!(report.as_of, Some(AsOf::Timestamp(_)));
+    let (tenants, exit_code) = process_with_summary(args, false, history, snapshot.as_mut())?;
+    if args.dry_run {
+        return Ok(exit_code);
+    }
+    match snapshot.and_then(|snapshot| snapshot.accounts) {
+        Some(accounts) => write_accounts(
+            accounts.into_iter(),
+            report,
+            &args.currency,
+            tenants.policy().currency.minor_units,
+            sign,
+            &args.pseudonymizer()?,
+        )?,
+        None => write_accounts(
+            account_records(&tenants, report.as_of),
+            report,
+            &args.currency,
+            tenants.policy().currency.minor_units,
+            sign,
+            &args.pseudonymizer()?,
+        )?,
+    }
+    Ok(exit_code)
+}
+
+fn run_reconcile(args: &ReconcileArgs) -> ApplicationResult<ExitCode> {
+    let (tenants, _) = process_with_summary(&args.process, false, false, None)?;
+    let expected = read_account_records(File::open(&args.expected)?)?;
+    let actual: Vec<_> = account_records(&tenants, None).collect();
+
+    let discrepancies = reconcile(&expected, &actual, tenants.policy().currency.minor_units);
+    write_discrepancies(
+        io::stdout().lock(),
+        &discrepancies,
+        &args.process.pseudonymizer()?,
+    )?;
+    eprintln!("found {} discrepancies", discrepancies.len());
+    Ok(match discrepancies.len() {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_DISCREPANCIES),
+    })
+}
+
+fn run_diff(args: &DiffArgs) -> ApplicationResult<ExitCode> {
+    let old = read_account_records(File::open(&args.old)?)?;
+    let new = read_account_records(File::open(&args.new)?)?;
+
+    let deltas = diff(&old, &new);
+    // The reports don't say their currency.
+    let places = Currency::default().minor_units;
+    write_deltas(io::stdout().lock(), &deltas, args.format, places)?;
+    eprintln!("found {} changed accounts", deltas.len());
+    Ok(match deltas.len() {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_DISCREPANCIES),
+    })
+}
+
+fn run_validate(args: &ValidateArgs) -> ApplicationResult<ExitCode> {
+    let mut csv_reader = get_csv_reader(
+        &args.input,
+        false,
+        RetryPolicy::default(),
+        args.dialect,
+        args.skip_comments,
+    )?;
+    let mut validator = Validator::new();
+    let headers = csv_reader.byte_headers()?;
+    let parser = match args.strict_headers {
+        true => check_schema(headers, args.unknown_columns),
+        false => Ok(()),
+    }
+    .and_then(|()| RecordParser::new(headers, true))
+    .and_then(|parser| parser.with_unknown_columns(args.unknown_columns));
+    match parser {
+        Ok(parser) => {
+            let parser = parser
+                .with_amount_format(args.amount_format)
+                .with_action_aliases(action_aliases(&args.action_alias))
+                .with_skip_comments(args.skip_comments)
+                .with_dialect(args.dialect);
+            let mut record = ByteRecord::new();
+            while parser.read_record(&mut csv_reader, &mut record)? {
+                let line = record.position().map_or(0, Position::line);
+                match parser.parse_record(&record) {
+                    Ok(tx) => validator.check(line, &tx),
+                    Err(err) => validator.unparsable_row(line, &err),
+                }
+            }
+        }
+        Err(err) => validator.invalid_header(err.to_string()),
+    }
+
+    let findings = validator.findings();
+    write_findings(io::stdout().lock(), findings)?;
+    eprintln!("found {} problems", findings.len());
+    Ok(match findings.len() {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_DISCREPANCIES),
+    })
+}
+
+fn run_asset(args: &AssetArgs) -> ApplicationResult<ExitCode> {
+    let mut csv_reader = get_csv_reader(
+        &args.input,
+        false,
+        RetryPolicy::default(),
+        CsvDialect::default(),
+        false,
+    )?;
+    let headers = csv_reader.headers()?.clone();
+    let mut book = AssetBook::new(args.asset.clone());
+    let (mut applied, mut rejected) = (0u64, 0u64);
+    let mut record = StringRecord::new();
+    while csv_reader.read_record(&mut record)? {
+        let result = AssetTransaction::from_record(&record, &headers, &args.asset)
+            .and_then(|tx| book.process_transaction(&tx));
+        match result {
+            Ok(()) => applied += 1,
+            Err(err) => {
+                let line = record.position().map_or(0, Position::line);
+                eprintln!("line {line}: {} ({err})", err.kind());
+                rejected += 1;
+            }
+        }
+    }
+    eprintln!(
+        "applied {applied}, rejected {rejected} {} transactions",
+        args.asset
+    );
+    write_asset_accounts(io::stdout().lock(), &book)?;
+    Ok(match rejected {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_REJECTIONS),
+    })
+}
+
+// The options of a run that parallel doesn't support: it keeps no state
+// between runs and writes no other outputs than the report and the rejects.
+fn parallel_unsupported(args: &ProcessArgs) -> Option<&'static str> {
+    [
+        (args.checkpoint.is_some(), "--checkpoint"),
+        (args.audit.is_some(), "--audit"),
+        (args.record.is_some(), "--record"),
+        (args.journal.is_some(), "--journal"),
+        (args.export.is_some(), "--export"),
+        (args.settlement.is_some(), "--settlement"),
+        (args.category_report.is_some(), "--category-report"),
+        (args.top_report.is_some(), "--top-report"),
+        (args.periods.is_some(), "--periods"),
+        (args.dispute_cases.is_some(), "--dispute-cases"),
+        (args.recurring.is_some(), "--recurring"),
+        (args.processed_registry.is_some(), "--processed-registry"),
+        (args.parse_threads.is_some(), "--parse-threads"),
+        (args.pipeline_depth.is_some(), "--pipeline-depth"),
+    ]
+    .into_iter()
+    .find_map(|(set, option)| set.then_some(option))
+}
+
+// Applies the rows of the books of --tenant to a sharded store, rows of
+// other tenants are skipped. The outcomes are those of applying the input
+// one transaction after another.
+fn run_parallel(args: &ParallelArgs) -> ApplicationResult<ExitCode> {
+    let process = &args.process;
+    if let Some(option) = parallel_unsupported(process) {
+        return Err(ApplicationError::Unsupported {
+            option,
+            command: "parallel",
+        });
+    }
+    let (mut csv_reader, parser) = open_input(process)?;
+    let pseudonymizer = process.pseudonymizer()?;
+    let mut rejects = process
+        .rejects
+        .as_ref()
+        .map(|path| open_rejects(path, process.rejects_format, false, &pseudonymizer, &parser))
+        .transpose()?;
+    // The rows in flight, their outcomes come back in input order.
+    let in_flight = RefCell::new(VecDeque::new());
+    let keep_rows = rejects.is_some();
+    let (mut skipped, mut other_tenants) = (0u64, 0u64);
+    let mut record = ByteRecord::new();
+    let txs = std::iter::from_fn(|| loop {
+        match parser.read_record(&mut csv_reader, &mut record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err.into())),
+        }
+        let position = record.position().cloned().unwrap_or_else(Position::new);
+        let tx = match parser.parse(&record) {
+            Ok(tx) => tx,
+            Err(RowError::UnknownAction(value)) => {
+                skipped += 1;
+                eprintln!(
+                    "line {} (record {}): type `{value}` is not a transaction type, rejected",
+                    position.line(),
+                    position.record()
+                );
+                continue;
+            }
+            Err(RowError::Invalid(message)) => return Some(Err(invalid_row(&position, message))),
+        };
+        if tx.tenant.as_deref().unwrap_or_default() != process.tenant {
+            other_tenants += 1;
+            continue;
+        }
+        if keep_rows {
+            in_flight.borrow_mut().push_back((position, tx.clone()));
+        }
+        return Some(Ok(tx));
     });
+    let policy = process.policy.policy()?;
+    // Scripts screen the rows of a run before the books apply them.
+    #[cfg(feature = "rhai")]
+    if policy.script.is_some() {
+        return Err(ApplicationError::Unsupported {
+            option: "--script",
+            command: "parallel",
+        });
+    }
+    let places = policy.currency.minor_units;
+    let store = ShardedStore::with_policy(DEFAULT_SHARDS, policy);
+    let mut rejected = 0u64;
+    let count = ParallelExecutor::new(args.workers.into()).run(&store, txs, |done| {
+        let row = keep_rows
+            .then(|| in_flight.borrow_mut().pop_front())
+            .flatten();
+        if let Err(err) = done.result {
+            eprintln!(
+                "tx {} of client {}: {} ({})",
+                done.id,
+                pseudonymizer.client(done.client_id),
+                err.kind(),
+                pseudonymizer.error(&err)
+            );
+            if let (Some(rejects), Some((position, tx))) = (rejects.as_mut(), row) {
+                rejects.write(&position, &tx, &err)?;
+            }
+            rejected += 1;
+        }
+        Ok(())
+    })?;
+    if let Some(rejects) = rejects.as_mut() {
+        rejects.flush()?;
+    }
+    eprintln!(
+        "applied {}, rejected {} transactions in {} workers",
+        count - rejected,
+        rejected + skipped,
+        args.workers
+    );
+    if other_tenants > 0 {
+        eprintln!(
+            "skipped {other_tenants} transactions of other tenants than `{}`",
+            process.tenant
+        );
+    }
+    if process.check {
+        store
+            .verify_invariants()
+            .map_err(|err| ApplicationError::Invariant(err.to_string()))?;
+    }
+
+    if !process.dry_run {
+        let mut accounts = store.accounts();
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        let records: Vec<_> = accounts
+            .iter()
+            .map(|(client_id, account)| AccountRecord::new(*client_id, account))
+            .collect();
+        write_account_records(io::stdout().lock(), records, &[], places, &pseudonymizer)?;
+    }
+    Ok(match rejected + skipped {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_REJECTIONS),
+    })
+}
+
+fn run_statement(args: &StatementArgs) -> ApplicationResult<ExitCode> {
+    let (tenants, exit_code) = process_with_summary(&args.process, true, false, None)?;
+    if args.process.dry_run {
+        return Ok(exit_code);
+    }
+    let empty = Ledger::new();
+    let ledger = tenant_ledger(&tenants, &args.process.tenant, &empty);
+
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_qif_statement(
+        output,
+        ledger,
+        args.client,
+        &args.process.export_date,
+        tenants.policy().currency.minor_units,
+        &args.process.pseudonymizer()?,
+    )?;
+    Ok(exit_code)
 }
 
-fn main() -> ApplicationResult<()> {
-    let csv_path = read_csv_path()?;
-    let mut csv_reader = get_csv_reader(&csv_path)?;
+fn run_history(args: &HistoryArgs) -> ApplicationResult<ExitCode> {
+    let (tenants, exit_code) = process_with_summary(&args.process, false, true, None)?;
+    if args.process.dry_run {
+        return Ok(exit_code);
+    }
+    let empty = BalanceHistory::new();
+    let history = tenants
+        .get(&args.process.tenant)
+        .and_then(AccountManager::balance_history)
+        .unwrap_or(&empty);
 
-    let mut account_manager = AccountManager::new();
-    for result in csv_reader.deserialize() {
-        let tx: Transaction = result?;
-        let _ = process_transaction(&mut account_manager, tx);
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_history(
+        output,
+        history,
+        args.client,
+        tenants.policy().currency.minor_units,
+        &args.process.pseudonymizer()?,
+    )?;
+    Ok(exit_code)
+}
+
+fn run_export(args: &ExportArgs) -> ApplicationResult<ExitCode> {
+    let (tenants, exit_code) = process_with_summary(&args.process, false, true, None)?;
+    if args.process.dry_run {
+        return Ok(exit_code);
     }
+    let points: Vec<_> = tenants
+        .get(&args.process.tenant)
+        .into_iter()
+        .flat_map(|account_manager| account_manager.history(args.client))
+        .cloned()
+        .collect();
 
-    let accounts = account_manager.accounts();
-    write_accounts(accounts);
+    let output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    write_client_log(
+        output,
+        args.client,
+        &client_log(&points),
+        args.format,
+        tenants.policy().currency.minor_units,
+        &args.process.pseudonymizer()?,
+    )?;
+    Ok(exit_code)
+}
+
+// Every batch is applied, then saved to the checkpoint, then acked. The
+// stream sequence is the offset of a transaction, so messages redelivered
+// after a crash between saving and acking are refused as already applied.
+#[cfg(feature = "nats")]
+fn run_nats(args: &NatsArgs) -> ApplicationResult<ExitCode> {
+    let pseudonymizer = args.pseudonyms.pseudonymizer()?;
+    let headers: ByteRecord = args.fields.iter().map(|field| field.trim()).collect();
+    let parser = RecordParser::new(&headers, true)?;
+    let source_name = format!("{}/{}/{}", args.server, args.stream, args.consumer);
+    let key = encryption_key(args.encryption_key.as_deref())?;
+    let checkpoint = match &args.checkpoint {
+        Some(path) => Checkpoint::load(path, &source_name, key.as_ref())?,
+        None => None,
+    };
+    let policy = args.policy.policy()?;
+    let mut tenants = match checkpoint {
+        Some(checkpoint) => checkpoint.tenants,
+        None => Tenants::new(),
+    };
+    tenants.set_policy(policy);
+    let mut source =
+        NatsSource::connect(&args.server, &args.stream, &args.consumer, &args.subject)?
+            .with_retry(args.retry.policy());
+    let mut stats = ProcessingStats::new();
+    let stats_requested = stats_signal()?;
+    let reload_requested = reload_signal()?;
+    let mut policy_watch = args.policy.watch();
+
+    loop {
+        let batch = source.next_batch(args.batch, Duration::from_secs(args.idle_exit))?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        let mut invalid = Vec::new();
+        for (index, message) in batch.iter().enumerate() {
+            match parser.parse_row(&message.payload) {
+                Ok(mut tx) => {
+                    let result = tenants
+                        .screen(&mut tx)
+                        .and_then(|()| tenants.process_transaction_at(message.sequence, tx));
+                    stats.record(&result);
+                }
+                Err(err) => {
+                    eprintln!("Invalid message {}: {err}", message.sequence);
+                    invalid.push(index);
+                }
+            }
+        }
+        if let Some(path) = &args.checkpoint {
+            let next = SourceOffset {
+                record: last.sequence,
+                ..SourceOffset::default()
+            };
+            Checkpoint::save(path, &source_name, next, &tenants, key.as_ref())?;
+        }
+        for (index, message) in batch.iter().enumerate() {
+            match invalid.contains(&index) {
+                true => source.terminate(message)?,
+                false => source.ack(message)?,
+            }
+        }
+        if stats_requested.swap(false, Ordering::Relaxed) {
+            eprintln!("{stats}; {}", tenants.stats());
+        }
+        if let Some(policy) = reload_policy(&args.policy, &mut policy_watch, &reload_requested) {
+            tenants.set_policy(policy);
+        }
+    }
+    eprintln!("{stats}");
+
+    write_accounts(
+        account_records(&tenants, None),
+        &args.report,
+        &args.currency,
+        tenants.policy().currency.minor_units,
+        &args.sign,
+        &pseudonymizer,
+    )?;
+    Ok(match stats.rejected() {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_REJECTIONS),
+    })
+}
+
+#[cfg(unix)]
+fn run_serve(args: &ServeArgs) -> ApplicationResult<ExitCode> {
+    let pseudonymizer = args.pseudonyms.pseudonymizer()?;
+    let headers: ByteRecord = args.fields.iter().map(|field| field.trim()).collect();
+    let parser = RecordParser::new(&headers, true)?;
+    let mut tenants = Tenants::with_policy(args.policy.policy()?);
+    if args.history {
+        tenants.enable_history();
+    }
+    let mut engine = Engine::new(tenants).with_pseudonymizer(pseudonymizer.clone());
+    if let Some(limit) = args.rate_limit {
+        engine = engine.with_rate_limiter(RateLimiter::new(limit)?);
+    }
+    #[cfg(feature = "redis")]
+    if let Some(url) = &args.backend {
+        let backend = RedisBackend::connect(url, &args.backend_prefix)?;
+        engine = engine.with_shared_backend(Box::new(backend));
+    }
+    if let Some(path) = &args.checkpoint {
+        let source = format!("unix:{}", args.socket.display());
+        let key = encryption_key(args.encryption_key.as_deref())?;
+        engine = engine.with_checkpoint(path, &source, key)?;
+    }
+    let shutdown = shutdown_signal()?;
+    let reload_requested = reload_signal()?;
+    let mut server = SocketServer::bind(&args.socket)?
+        .with_shutdown(Arc::clone(&shutdown))
+        .with_drain_timeout(Duration::from_secs(args.shutdown_timeout));
+    if let Some(path) = &args.api_keys {
+        server = server.with_api_keys(ApiKeys::read(File::open(path)?)?);
+    }
+    eprintln!("listening on {}", args.socket.display());
+    let engine = Arc::new(Mutex::new(engine));
+    thread::scope(|scope| {
+        // Swaps the policy between transactions; accounts and the tx cache
+        // are kept.
+        scope.spawn(|| {
+            let mut watch = args.policy.watch();
+            while !shutdown.load(Ordering::Relaxed) {
+                thread::sleep(POLICY_POLL);
+                if let Some(policy) = reload_policy(&args.policy, &mut watch, &reload_requested) {
+                    engine.lock().expect("engine poisoned").set_policy(policy);
+                }
+            }
+        });
+        let served = server.serve(parser, Arc::clone(&engine));
+        shutdown.store(true, Ordering::Relaxed);
+        served
+    })?;
+    drop(server);
+
+    let engine = engine.lock().expect("engine poisoned");
+    eprintln!("{}", engine.stats());
+    if engine.shared() {
+        eprintln!("the balances are kept in the shared backend, no report written");
+        return Ok(ExitCode::SUCCESS);
+    }
+    write_accounts(
+        account_records(engine.tenants(), None),
+        &args.report,
+        &args.currency,
+        engine.tenants().policy().currency.minor_units,
+        &args.sign,
+        &pseudonymizer,
+    )?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_replay(args: &ReplayArgs) -> ApplicationResult<ExitCode> {
+    let pseudonymizer = args.pseudonyms.pseudonymizer()?;
+    let mut tenants = Tenants::with_policy(args.policy.policy()?);
+    let mut replay = Replay::new()
+        .with_breakpoints(args.breakpoints.iter().copied())
+        .with_pseudonymizer(pseudonymizer.clone());
+    if args.stop {
+        replay = replay.stop_at_breakpoint();
+    }
+    let recording = BufReader::new(File::open(&args.recording)?);
+    let replayed = replay.run(recording, &mut tenants, io::stderr().lock())?;
+    eprintln!("replayed {replayed} transactions");
+    write_accounts(
+        account_records(&tenants, None),
+        &args.report,
+        &args.currency,
+        tenants.policy().currency.minor_units,
+        &args.sign,
+        &pseudonymizer,
+    )?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// Exits with 1 if a report differs from the expected one.
+#[cfg(feature = "test_support")]
+fn run_fixtures(args: &FixturesArgs) -> ApplicationResult<ExitCode> {
+    let fixtures = fixtures(&args.dir)?;
+    if args.regenerate {
+        for fixture in &fixtures {
+            fixture.regenerate()?;
+        }
+        eprintln!("regenerated {} expectations", fixtures.len());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut mismatches = 0;
+    for fixture in &fixtures {
+        if let Some(mismatch) = fixture.check()? {
+            eprint!("{mismatch}");
+            mismatches += 1;
+        }
+    }
+    eprintln!("{} fixtures, {mismatches} differ", fixtures.len());
+    Ok(match mismatches {
+        0 => ExitCode::SUCCESS,
+        _ => ExitCode::from(EXIT_DISCREPANCIES),
+    })
+}
+
+fn run_verify_audit(args: &VerifyAuditArgs) -> ApplicationResult<ExitCode> {
+    let reader = BufReader::new(File::open(&args.path)?);
+    let entries = match &args.head {
+        Some(head) => verify_audit_head(reader, head)?,
+        None => verify_audit(reader)?,
+    };
+    eprintln!("verified {entries} audit entries");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_verify(args: &VerifyArgs) -> ApplicationResult<ExitCode> {
+    let public_key = parse_verifying_key(&fs::read_to_string(&args.public_key)?)?;
+    let signature = fs::read_to_string(&args.signature)?;
+    verify_report(&public_key, &fs::read(&args.report)?, &signature)?;
+    eprintln!("signature is valid");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn generate(args: GenerateArgs) -> ApplicationResult<()> {
+    let generator = Generator::new(GeneratorConfig {
+        clients: args.clients,
+        transactions: args.transactions,
+        dispute_rate: args.dispute_rate,
+        seed: args.seed,
+    })?;
+
+    let output: Box<dyn Write> = match args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut csv_writer = Writer::from_writer(output);
+    for tx in generator {
+        csv_writer.serialize(tx)?;
+    }
+    csv_writer.flush()?;
 
     Ok(())
 }
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::Generate(args)) => generate(args).map(|()| ExitCode::SUCCESS),
+        Some(Command::Reconcile(args)) => run_reconcile(&args),
+        Some(Command::Diff(args)) => run_diff(&args),
+        Some(Command::Validate(args)) => run_validate(&args),
+        Some(Command::Asset(args)) => run_asset(&args),
+        Some(Command::Parallel(args)) => run_parallel(&args),
+        Some(Command::VerifyAudit(args)) => run_verify_audit(&args),
+        Some(Command::Statement(args)) => run_statement(&args),
+        Some(Command::History(args)) => run_history(&args),
+        Some(Command::Export(args)) => run_export(&args),
+        Some(Command::Replay(args)) => run_replay(&args),
+        #[cfg(feature = "test_support")]
+        Some(Command::Fixtures(args)) => run_fixtures(&args),
+        Some(Command::Verify(args)) => run_verify(&args),
+        #[cfg(feature = "nats")]
+        Some(Command::Nats(args)) => run_nats(&args),
+        #[cfg(unix)]
+        Some(Command::Serve(args)) => run_serve(&args),
+        None => run(&cli.process, &cli.sign, &cli.report),
+    };
+
+    result.unwrap_or_else(|err| {
+        eprintln!("Error {}: {err}", err.code());
+        err.exit_code()
+    })
+}
@@ -0,0 +1,168 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use async_nats::jetstream::consumer::{pull, AckPolicy, Consumer};
+use async_nats::jetstream::{self, AckKind};
+use futures::StreamExt;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::retry::{is_transient, RetryPolicy};
+
+// A transaction published to the stream. The stream sequence is unique and
+// grows with every message, so it serves as the offset of the transaction.
+pub struct NatsMessage {
+    pub sequence: u64,
+    pub payload: Vec<u8>,
+    message: jetstream::Message,
+}
+
+// Pulls transactions from a durable JetStream consumer with explicit acks.
+// Messages are acked only once their transactions are applied and saved, so
+// a crash in between gets them redelivered rather than lost.
+pub struct NatsSource {
+    runtime: Runtime,
+    server: String,
+    stream: String,
+    name: String,
+    subject: String,
+    consumer: Option<Consumer<pull::Config>>,
+    retry: RetryPolicy,
+}
+
+impl NatsSource {
+    // Connects on the first fetch, so a server that isn't up yet is retried
+    // like a failed fetch. The durable consumer of the stream is created if it
+    // doesn't exist yet. An empty subject consumes every subject of the stream.
+    pub fn connect(server: &str, stream: &str, consumer: &str, subject: &str) -> io::Result<Self> {
+        Ok(Self {
+            runtime: Builder::new_current_thread().enable_all().build()?,
+            server: server.to_string(),
+            stream: stream.to_string(),
+            name: consumer.to_string(),
+            subject: subject.to_string(),
+            consumer: None,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    // Failed connects and fetches are retried as `retry` allows. The durable
+    // consumer knows what was acked, so a retry continues where the last one
+    // stopped.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Up to `max` messages, waiting at most `wait` for the first one. An
+    // empty batch means the stream stayed idle.
+    pub fn next_batch(&mut self, max: usize, wait: Duration) -> io::Result<Vec<NatsMessage>> {
+        let mut delays = self.retry.delays();
+        loop {
+            match (self.fetch(max, wait), delays.next()) {
+                (Err(err), Some(delay)) if is_transient(&err) => {
+                    eprintln!("fetching messages failed, retrying in {delay:?}: {err}");
+                    thread::sleep(delay);
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    fn connect_consumer(&self) -> io::Result<Consumer<pull::Config>> {
+        let config = pull::Config {
+            durable_name: Some(self.name.clone()),
+            filter_subject: self.subject.clone(),
+            ack_policy: AckPolicy::Explicit,
+            ..pull::Config::default()
+        };
+        self.runtime.block_on(async {
+            let client = async_nats::connect(&self.server)
+                .await
+                .map_err(io::Error::other)?;
+            let stream = jetstream::new(client)
+                .get_stream(&self.stream)
+                .await
+                .map_err(io::Error::other)?;
+            stream
+                .get_or_create_consumer(&self.name, config)
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+
+    fn fetch(&mut self, max: usize, wait: Duration) -> io::Result<Vec<NatsMessage>> {
+        if self.consumer.is_none() {
+            self.consumer = Some(self.connect_consumer()?);
+        }
+        let consumer = self.consumer.as_ref().expect("connected above");
+        self.runtime.block_on(async {
+            let mut batch = consumer
+                .batch()
+                .max_messages(max)
+                .expires(wait)
+                .messages()
+                .await
+                .map_err(io::Error::other)?;
+            let mut messages = Vec::new();
+            while let Some(message) = batch.next().await {
+                let message = message.map_err(io::Error::other)?;
+                let sequence = message.info().map_err(io::Error::other)?.stream_sequence;
+                messages.push(NatsMessage {
+                    sequence,
+                    payload: message.payload.to_vec(),
+                    message,
+                });
+            }
+            Ok(messages)
+        })
+    }
+
+    pub fn ack(&self, message: &NatsMessage) -> io::Result<()> {
+        self.runtime
+            .block_on(message.message.ack())
+            .map_err(io::Error::other)
+    }
+
+    // Tells the server to never redeliver the message, for payloads that no
+    // retry makes valid.
+    pub fn terminate(&self, message: &NatsMessage) -> io::Result<()> {
+        self.runtime
+            .block_on(message.message.ack_with(AckKind::Term))
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn connects_as_often_as_the_retry_policy_allows() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!("nats://{}", listener.local_addr().unwrap());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&attempts);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+
+        let retry = RetryPolicy {
+            retries: 2,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        };
+        let mut source = NatsSource::connect(&server, "transactions", "accounting-demo", "")
+            .unwrap()
+            .with_retry(retry);
+        assert!(source.next_batch(1, Duration::from_millis(10)).is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,216 @@
+use std::env;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread;
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use object_store::path::Path;
+use object_store::{GetOptions, GetRange, ObjectStore};
+use tokio::runtime::{Builder, Runtime};
+use url::Url;
+
+use crate::retry::{is_transient, RetryPolicy};
+
+const SCHEMES: [&str; 7] = ["s3", "s3a", "gs", "az", "azure", "abfs", "abfss"];
+
+// True for s3://, gs:// and az:// (and the other Azure schemes) URLs.
+pub fn is_object_url(location: &str) -> bool {
+    Url::parse(location).is_ok_and(|url| SCHEMES.contains(&url.scheme()))
+}
+
+fn io_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        object_store::Error::PermissionDenied { .. }
+        | object_store::Error::Unauthenticated { .. } => {
+            io::Error::new(io::ErrorKind::PermissionDenied, err)
+        }
+        object_store::Error::InvalidPath { .. }
+        | object_store::Error::UnknownConfigurationKey { .. } => {
+            io::Error::new(io::ErrorKind::InvalidInput, err)
+        }
+        _ => io::Error::other(err),
+    }
+}
+
+// An object streamed from an object store. Reads pull the body of one GET
+// request chunk by chunk, so nothing is downloaded up front. Seeking, e.g. to
+// resume from a checkpoint, starts a new request from the new position, and
+// so do failed reads as the RetryPolicy allows.
+pub struct ObjectReader {
+    runtime: Runtime,
+    store: Box<dyn ObjectStore>,
+    path: Path,
+    size: u64,
+    position: u64,
+    body: Option<BoxStream<'static, object_store::Result<Bytes>>>,
+    chunk: Bytes,
+    retry: RetryPolicy,
+}
+
+impl ObjectReader {
+    // Credentials and other options come from the environment, e.g.
+    // AWS_ACCESS_KEY_ID, GOOGLE_SERVICE_ACCOUNT or AZURE_STORAGE_ACCOUNT_KEY.
+    pub fn open(location: &str) -> io::Result<Self> {
+        Self::with_retry(location, RetryPolicy::default())
+    }
+
+    pub fn with_retry(location: &str, retry: RetryPolicy) -> io::Result<Self> {
+        let url =
+            Url::parse(location).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let options = env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+        let (store, path) = object_store::parse_url_opts(&url, options).map_err(io_error)?;
+        Self::with_store(store, path, retry)
+    }
+
+    pub fn with_store(
+        store: Box<dyn ObjectStore>,
+        path: Path,
+        retry: RetryPolicy,
+    ) -> io::Result<Self> {
+        let mut reader = Self {
+            runtime: Builder::new_current_thread().enable_all().build()?,
+            store,
+            path,
+            size: 0,
+            position: 0,
+            body: None,
+            chunk: Bytes::new(),
+            retry,
+        };
+        reader.size = reader.with_retries(|reader| {
+            let head = reader.runtime.block_on(reader.store.head(&reader.path));
+            Ok(head.map_err(io_error)?.size)
+        })?;
+        Ok(reader)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn with_retries<T>(&mut self, mut f: impl FnMut(&mut Self) -> io::Result<T>) -> io::Result<T> {
+        let mut delays = self.retry.delays();
+        loop {
+            match (f(self), delays.next()) {
+                (Err(err), Some(delay)) if is_transient(&err) => {
+                    eprintln!(
+                        "reading {} failed at byte {}, retrying in {delay:?}: {err}",
+                        self.path, self.position
+                    );
+                    self.body = None;
+                    thread::sleep(delay);
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    fn fill_chunk(&mut self) -> io::Result<()> {
+        while self.chunk.is_empty() {
+            if self.position >= self.size {
+                return Ok(());
+            }
+            let body = match &mut self.body {
+                Some(body) => body,
+                None => {
+                    let options = GetOptions {
+                        range: Some(GetRange::Offset(self.position)),
+                        ..GetOptions::default()
+                    };
+                    let result = self
+                        .runtime
+                        .block_on(self.store.get_opts(&self.path, options))
+                        .map_err(io_error)?;
+                    self.body.insert(result.into_stream())
+                }
+            };
+            match self.runtime.block_on(body.next()) {
+                Some(chunk) => self.chunk = chunk.map_err(io_error)?,
+                // The object shrank since it was opened.
+                None => {
+                    self.body = None;
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.chunk.is_empty() {
+            self.with_retries(Self::fill_chunk)?;
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for ObjectReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        if position != self.position {
+            self.body = None;
+            self.chunk = Bytes::new();
+            self.position = position;
+        }
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+    use object_store::PutPayload;
+
+    use super::*;
+
+    fn reader(content: &'static str) -> ObjectReader {
+        let store = InMemory::new();
+        let path = Path::from("dumps/transactions.csv");
+        Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(store.put(&path, PutPayload::from_static(content.as_bytes())))
+            .unwrap();
+        ObjectReader::with_store(Box::new(store), path, RetryPolicy::none()).unwrap()
+    }
+
+    #[test]
+    fn streams_and_seeks_like_a_file() {
+        let mut reader = reader("type,client,tx,amount\ndeposit,1,1,1.0\n");
+        assert_eq!(reader.size(), 38);
+        let mut header = [0; 22];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"type,client,tx,amount\n");
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "deposit,1,1,1.0\n");
+
+        reader.seek(SeekFrom::Start(22)).unwrap();
+        assert_eq!(reader.seek(SeekFrom::Current(8)).unwrap(), 30);
+        rest.clear();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "1,1,1.0\n");
+        assert!(reader.seek(SeekFrom::End(-39)).is_err());
+    }
+
+    #[test]
+    fn recognizes_object_store_urls() {
+        assert!(is_object_url("s3://bucket/dumps/transactions.csv"));
+        assert!(is_object_url("gs://bucket/transactions.csv"));
+        assert!(is_object_url("az://container/transactions.csv"));
+        assert!(!is_object_url("transactions.csv"));
+        assert!(!is_object_url("https://example.com/transactions.csv"));
+    }
+}
@@ -0,0 +1,339 @@
+use std::ops::Range;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use csv::{ByteRecord, Position, ReaderBuilder, Trim};
+
+use crate::fast_parse::{RecordParser, RowError};
+use crate::types::Transaction;
+
+// Small enough that the chunks in flight stay at a few MB per thread.
+pub const CHUNK_SIZE: usize = 1 << 20;
+const CHUNKS_IN_FLIGHT: usize = 1;
+
+#[derive(Debug)]
+pub struct ParsedRecord {
+    pub position: Position,
+    // Where the record after this one starts, e.g. to resume from a checkpoint.
+    pub next: Position,
+    pub tx: Result<Transaction, RowError>,
+}
+
+struct Chunk {
+    records: Vec<ParsedRecord>,
+    lines: u64,
+}
+
+// Byte ranges of about `chunk_size` from `start` on, each ending after the
+// newline of a record. Quoted fields may contain newlines, so the records are
+// scanned like the csv reader does: quotes only open a field at its start, and
+// comments only at the start of a record.
+fn chunk_ranges(
+    data: &[u8],
+    start: usize,
+    chunk_size: usize,
+    delimiter: u8,
+    comment: Option<u8>,
+) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut begin = start.min(data.len());
+    let (mut quoted, mut commented) = (false, false);
+    let (mut field_start, mut record_start) = (true, true);
+    let mut index = begin;
+    while index < data.len() {
+        let byte = data[index];
+        index += 1;
+        if quoted {
+            if byte == b'"' {
+                // A doubled quote is an escaped one.
+                if data.get(index) == Some(&b'"') {
+                    index += 1;
+                } else {
+                    quoted = false;
+                }
+            }
+            continue;
+        }
+        if byte == b'\n' {
+            if index - begin > chunk_size {
+                ranges.push(begin..index);
+                begin = index;
+            }
+            (commented, field_start, record_start) = (false, true, true);
+            continue;
+        }
+        if commented {
+            continue;
+        }
+        if record_start && Some(byte) == comment {
+            commented = true;
+        } else if byte == delimiter {
+            field_start = true;
+        } else {
+            quoted = field_start && byte == b'"';
+            field_start = false;
+        }
+        record_start = false;
+    }
+    if begin < data.len() {
+        ranges.push(begin..data.len());
+    }
+    ranges
+}
+
+// Positions are relative to the chunk: bytes from its start, lines from 1 and
+// records from 0.
+fn parse_chunk(data: &[u8], parser: &RecordParser) -> Chunk {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(parser.delimiter())
+        .comment(parser.comment())
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(data);
+    let mut records = Vec::new();
+    let mut record = ByteRecord::new();
+    loop {
+        let position = reader.position().clone();
+        let tx = match parser.read_record(&mut reader, &mut record) {
+            Ok(false) => break,
+            Ok(true) => parser.parse(&record),
+            Err(err) => Err(RowError::Invalid(err.to_string())),
+        };
+        let position = record.position().cloned().unwrap_or(position);
+        // Rows of unknown types are skipped, the ones after them still parse.
+        let failed = matches!(tx, Err(RowError::Invalid(_)));
+        records.push(ParsedRecord {
+            position,
+            next: reader.position().clone(),
+            tx,
+        });
+        if failed {
+            break;
+        }
+    }
+    Chunk {
+        records,
+        lines: data.iter().filter(|byte| **byte == b'\n').count() as u64,
+    }
+}
+
+fn rebase(position: &mut Position, byte: u64, line: u64, record: u64) {
+    let (local_byte, local_line, local_record) =
+        (position.byte(), position.line(), position.record());
+    position
+        .set_byte(byte + local_byte)
+        .set_line(line + local_line - 1)
+        .set_record(record + local_record);
+}
+
+// Parses `data` from `start` (the position of the first record) in `threads`
+// parser threads and hands the records to `process` in file order. Parsing
+// stops at the first invalid row of a chunk and at the first error of
+// `process`.
+pub fn parse_parallel<E>(
+    data: &[u8],
+    start: &Position,
+    parser: &RecordParser,
+    threads: usize,
+    process: impl FnMut(ParsedRecord) -> Result<(), E>,
+) -> Result<(), E> {
+    parse_chunks(data, start, parser, threads, CHUNK_SIZE, process)
+}
+
+fn parse_chunks<E>(
+    data: &[u8],
+    start: &Position,
+    parser: &RecordParser,
+    threads: usize,
+    chunk_size: usize,
+    mut process: impl FnMut(ParsedRecord) -> Result<(), E>,
+) -> Result<(), E> {
+    let ranges = chunk_ranges(
+        data,
+        start.byte() as usize,
+        chunk_size,
+        parser.delimiter(),
+        parser.comment(),
+    );
+    let threads = threads.clamp(1, ranges.len().max(1));
+
+    thread::scope(|scope| {
+        // Chunk i is parsed by thread i % threads, so reading the channels in
+        // turn yields the chunks in order without buffering.
+        let receivers: Vec<_> = (0..threads)
+            .map(|worker| {
+                let (sender, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+                let ranges = &ranges;
+                scope.spawn(move || {
+                    for range in ranges.iter().skip(worker).step_by(threads) {
+                        if sender
+                            .send(parse_chunk(&data[range.clone()], parser))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+                receiver
+            })
+            .collect();
+
+        let (mut line, mut record) = (start.line(), start.record());
+        for (index, range) in ranges.iter().enumerate() {
+            let chunk = receivers[index % threads]
+                .recv()
+                .expect("parser thread exited early");
+            let count = chunk.records.len() as u64;
+            for mut parsed in chunk.records {
+                rebase(&mut parsed.position, range.start as u64, line, record);
+                rebase(&mut parsed.next, range.start as u64, line, record);
+                process(parsed)?;
+            }
+            line += chunk.lines;
+            record += count;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fast_parse::CsvDialect;
+
+    fn sequential(input: &str) -> (Position, Vec<(u64, u64, u64, Transaction)>) {
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input.as_bytes());
+        let headers = reader.byte_headers().unwrap().clone();
+        let start = reader.position().clone();
+        let records = reader
+            .byte_records()
+            .map(|record| {
+                let record = record.unwrap();
+                let position = record.position().unwrap();
+                let tx = record.deserialize(Some(&headers)).unwrap();
+                (position.byte(), position.line(), position.record(), tx)
+            })
+            .collect();
+        (start, records)
+    }
+
+    #[test]
+    fn chunks_end_after_newlines() {
+        let data = b"aaaa\nbb\ncccccc\nd";
+        let ranges = |start, chunk_size| chunk_ranges(data, start, chunk_size, b',', None);
+        assert_eq!(ranges(0, 3), vec![0..5, 5..15, 15..16]);
+        assert_eq!(ranges(5, 100), vec![5..16]);
+        assert!(ranges(16, 3).is_empty());
+    }
+
+    #[test]
+    fn chunks_dont_end_inside_quoted_fields() {
+        let data = b"a,\"b\n\"\"\nc\"\nd,e\"\nf\n#,\"g\nh\n";
+        let ranges = |comment| chunk_ranges(data, 0, 1, b',', comment);
+        assert_eq!(ranges(None), vec![0..11, 11..16, 16..18, 18..25]);
+        assert_eq!(
+            ranges(Some(b'#')),
+            vec![0..11, 11..16, 16..18, 18..23, 23..25]
+        );
+
+        let input = "deposit,1,1,1.0,\"first\nline\"\ndeposit,1,2,2.0,\"a \"\"quote\"\"\"\n";
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount", "description"]);
+        let parser = RecordParser::new(&headers, false).unwrap();
+        let mut descriptions = Vec::new();
+        let result: Result<(), ()> = parse_chunks(
+            input.as_bytes(),
+            &Position::new(),
+            &parser,
+            2,
+            4,
+            |parsed| {
+                descriptions.push(parsed.tx.unwrap().description);
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(
+            descriptions,
+            [
+                Some("first\nline".to_string()),
+                Some("a \"quote\"".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn records_arrive_in_order_with_file_positions() {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for id in 1..=20_000 {
+            input.push_str(&format!("deposit,{},{id},1.5\n", id % 7));
+            if id % 1000 == 500 {
+                input.push('\n');
+            }
+        }
+        let (start, expected) = sequential(&input);
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+
+        for fast in [false, true] {
+            let parser = RecordParser::new(&headers, fast).unwrap();
+            let mut records = Vec::new();
+            let mut next = start.clone();
+            let result: Result<(), ()> =
+                parse_chunks(input.as_bytes(), &start, &parser, 4, 4096, |parsed| {
+                    let position = parsed.position;
+                    assert_eq!(position.byte(), next.byte());
+                    next = parsed.next;
+                    let tx = parsed.tx.unwrap();
+                    records.push((position.byte(), position.line(), position.record(), tx));
+                    Ok(())
+                });
+            assert!(result.is_ok());
+            assert_eq!(records, expected);
+            assert_eq!(next.byte(), input.len() as u64);
+        }
+    }
+
+    #[test]
+    fn stops_at_invalid_rows_and_process_errors() {
+        let input = "deposit,1,1,1.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n";
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let parser = RecordParser::new(&headers, true).unwrap();
+        let mut start = Position::new();
+        start.set_line(2).set_record(1);
+
+        let result = parse_chunks(input.as_bytes(), &start, &parser, 2, 8, |parsed| {
+            parsed
+                .tx
+                .map(|_| ())
+                .map_err(|message| (parsed.position.line(), parsed.position.record(), message))
+        });
+        let invalid = RowError::Invalid("invalid client `x`".to_string());
+        assert_eq!(result, Err((3, 2, invalid)));
+    }
+
+    #[test]
+    fn chunks_are_read_with_the_delimiter_of_the_parser() {
+        let input = "deposit;1;1;1,5\ndeposit;2;2;2,25\n";
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        let parser = RecordParser::new(&headers, true)
+            .unwrap()
+            .with_dialect(CsvDialect::DecimalComma);
+        let mut amounts = Vec::new();
+        let result: Result<(), ()> = parse_chunks(
+            input.as_bytes(),
+            &Position::new(),
+            &parser,
+            2,
+            8,
+            |parsed| {
+                amounts.push(parsed.tx.unwrap().amount);
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(amounts, [Some(1.5), Some(2.25)]);
+    }
+}
@@ -0,0 +1,252 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
+use std::thread;
+
+use csv::{ByteRecord, Position, Reader};
+
+use crate::fast_parse::RecordParser;
+use crate::parallel::ParsedRecord;
+
+pub const DEFAULT_DEPTH: usize = 1024;
+
+// Records in flight between the stages: read but not parsed, and parsed but
+// not yet processed. Without a read depth the records are read and parsed in
+// one thread, which saves a hand-over per record when parsing is cheap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PipelineDepths {
+    pub read: usize,
+    pub parse: usize,
+}
+
+impl Default for PipelineDepths {
+    fn default() -> Self {
+        Self {
+            read: DEFAULT_DEPTH,
+            parse: DEFAULT_DEPTH,
+        }
+    }
+}
+
+// `READ[,PARSE]`, e.g. `1024` for both, `4096,1024`, or `0,1024` for a
+// thread that reads and parses.
+impl FromStr for PipelineDepths {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let depth = |value: &str| match value.trim().parse() {
+            Ok(0) | Err(_) => Err(format!(
+                "invalid depth '{value}', expected a positive count"
+            )),
+            Ok(depth) => Ok(depth),
+        };
+        match s.split_once(',') {
+            Some((read, parse)) => Ok(Self {
+                read: match read.trim() {
+                    "0" => 0,
+                    read => depth(read)?,
+                },
+                parse: depth(parse)?,
+            }),
+            None => {
+                let depth = depth(s)?;
+                Ok(Self {
+                    read: depth,
+                    parse: depth,
+                })
+            }
+        }
+    }
+}
+
+fn parse_record(parser: &RecordParser, record: &ByteRecord, next: Position) -> ParsedRecord {
+    ParsedRecord {
+        position: record.position().cloned().unwrap_or_else(Position::new),
+        next,
+        tx: parser.parse(record),
+    }
+}
+
+// Reads the records of `reader` in one thread, parses them in another (or in
+// the same one without a read depth) and hands them to `process` in order in
+// the calling thread. The stages are connected by channels of the given
+// depths, so when `process` is slow, e.g. on a slow sink, the other stages
+// wait instead of buffering the input. Stops at the first read error or error
+// of `process`.
+pub fn run_pipeline<R, E>(
+    reader: &mut Reader<R>,
+    parser: &RecordParser,
+    depths: PipelineDepths,
+    mut process: impl FnMut(ParsedRecord) -> Result<(), E>,
+) -> Result<(), E>
+where
+    R: Read + Send,
+    E: From<csv::Error>,
+{
+    let (parsed_sender, parsed) = sync_channel(depths.parse);
+    thread::scope(|scope| {
+        if depths.read == 0 {
+            scope.spawn(move || loop {
+                let mut record = ByteRecord::new();
+                let item = match parser.read_record(reader, &mut record) {
+                    Ok(true) => Ok(parse_record(parser, &record, reader.position().clone())),
+                    Ok(false) => break,
+                    Err(err) => Err(err),
+                };
+                let failed = item.is_err();
+                if parsed_sender.send(item).is_err() || failed {
+                    break;
+                }
+            });
+        } else {
+            let (read_sender, read) =
+                sync_channel::<csv::Result<(ByteRecord, Position)>>(depths.read);
+            scope.spawn(move || loop {
+                let mut record = ByteRecord::new();
+                let result = parser
+                    .read_record(reader, &mut record)
+                    .map(|more| more.then(|| (record, reader.position().clone())));
+                let item = match result {
+                    Ok(Some(item)) => Ok(item),
+                    Ok(None) => break,
+                    Err(err) => Err(err),
+                };
+                let failed = item.is_err();
+                if read_sender.send(item).is_err() || failed {
+                    break;
+                }
+            });
+            scope.spawn(move || {
+                for item in read {
+                    let item = item.map(|(record, next)| parse_record(parser, &record, next));
+                    if parsed_sender.send(item).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // Returning early drops the receiver, which stops the other stages.
+        for item in parsed {
+            process(item?)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    fn reader<R: Read>(input: R) -> (Reader<R>, RecordParser) {
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input);
+        let parser = RecordParser::new(reader.byte_headers().unwrap(), true).unwrap();
+        (reader, parser)
+    }
+
+    #[test]
+    fn parses_one_or_two_depths() {
+        assert_eq!(
+            "64".parse(),
+            Ok(PipelineDepths {
+                read: 64,
+                parse: 64
+            })
+        );
+        assert_eq!(
+            "4096,1024".parse(),
+            Ok(PipelineDepths {
+                read: 4096,
+                parse: 1024
+            })
+        );
+        assert_eq!(
+            "0,256".parse(),
+            Ok(PipelineDepths {
+                read: 0,
+                parse: 256
+            })
+        );
+        assert!("0".parse::<PipelineDepths>().is_err());
+        assert!("64,0".parse::<PipelineDepths>().is_err());
+        assert!("1,2,3".parse::<PipelineDepths>().is_err());
+    }
+
+    #[test]
+    fn hands_over_the_records_in_order_with_positions() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,x\ndeposit,1,3,2.0\n";
+        for read in [1, 0] {
+            let (mut reader, parser) = reader(input.as_bytes());
+            let depths = PipelineDepths { read, parse: 1 };
+            let mut records = Vec::new();
+            run_pipeline(&mut reader, &parser, depths, |parsed| {
+                records.push(parsed);
+                csv::Result::Ok(())
+            })
+            .unwrap();
+
+            let summary: Vec<_> = records
+                .iter()
+                .map(|parsed| {
+                    (
+                        parsed.position.line(),
+                        parsed.next.byte(),
+                        parsed.tx.is_ok(),
+                    )
+                })
+                .collect();
+            assert_eq!(summary, vec![(2, 38, true), (3, 52, false), (4, 68, true)]);
+        }
+    }
+
+    struct Counting<'a> {
+        data: &'a [u8],
+        read: Arc<AtomicUsize>,
+    }
+
+    impl Read for Counting<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.data.read(buf)?;
+            self.read.fetch_add(len, Ordering::Relaxed);
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn a_slow_consumer_holds_back_the_reader() {
+        let mut input = "type,client,tx,amount\n".to_string();
+        for id in 1..=100_000 {
+            input.push_str(&format!("deposit,1,{id},1.0\n"));
+        }
+        let read = Arc::new(AtomicUsize::new(0));
+        for read_depth in [8, 0] {
+            let counting = Counting {
+                data: input.as_bytes(),
+                read: Arc::clone(&read),
+            };
+            read.store(0, Ordering::Relaxed);
+            let (mut reader, parser) = reader(counting);
+            let depths = PipelineDepths {
+                read: read_depth,
+                parse: 8,
+            };
+            let result = run_pipeline(&mut reader, &parser, depths, |_| {
+                thread::sleep(Duration::from_millis(50));
+                Err(csv::Error::from(io::Error::other("sink failed")))
+            });
+
+            assert!(result.is_err());
+            // Two buffers of the CSV reader at most, not the whole input.
+            assert!(read.load(Ordering::Relaxed) < 64 * 1024);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::policy::{
+    BackdatedPolicy, CacheTtl, LockedDepositPolicy, PrecisionPolicy, Retention,
+    WithdrawalDisputePolicy, ZeroAmountPolicy,
+};
+use crate::role::Role;
+use crate::rules::Rules;
+
+#[derive(Error, Debug)]
+pub enum PolicyFileError {
+    #[error("Policy file {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Invalid policy file {path}: {message}")]
+    Invalid { path: PathBuf, message: String },
+}
+
+pub type PolicyFileResult<T> = Result<T, PolicyFileError>;
+
+// The policy settings of the command line in a TOML file, with the names of
+// the flags as keys:
+//
+//     zero-amounts = "accept"
+//     cache-ttl = "txs:100000"
+//     tiers = "tiers.csv"
+//     merchant-fee = 0.029
+//     denomination = "JPY"
+//     chargeback-fee = { merchant = 15, customer = 5 }
+//
+//     [[rule]]
+//     name = "dispute abuse"
+//     type = "dispute"
+//     disputes-at-least = 3
+//     then = "freeze"
+//
+// Settings missing from the file keep the value of the flag. Relative paths
+// are relative to the directory of the file. The rules only exist in the
+// file.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PolicyFile {
+    #[serde(default, deserialize_with = "parsed")]
+    pub zero_amounts: Option<ZeroAmountPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub precision: Option<PrecisionPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub locked_deposits: Option<LockedDepositPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub backdated: Option<BackdatedPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub cache_ttl: Option<CacheTtl>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub retention: Option<Retention>,
+    pub allow_admin_actions: Option<bool>,
+    pub tiers: Option<PathBuf>,
+    pub client_tiers: Option<PathBuf>,
+    pub roles: Option<PathBuf>,
+    pub denomination: Option<String>,
+    pub currencies: Option<PathBuf>,
+    #[cfg(feature = "rhai")]
+    pub script: Option<PathBuf>,
+    pub merchant_fee: Option<f64>,
+    #[serde(default)]
+    pub chargeback_fee: HashMap<Role, f64>,
+    #[serde(default, rename = "rule")]
+    pub rules: Rules,
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl PolicyFile {
+    pub fn load(path: &Path) -> PolicyFileResult<Self> {
+        let text = fs::read_to_string(path).map_err(|source| PolicyFileError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let invalid = |message: String| PolicyFileError::Invalid {
+            path: path.to_path_buf(),
+            message,
+        };
+        let mut file: Self = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        if let Some(fee) = file.merchant_fee.filter(|fee| !(0.0..=1.0).contains(fee)) {
+            return Err(invalid(format!(
+                "invalid merchant-fee {fee}, expected a share between 0 and 1"
+            )));
+        }
+        if let Some(fee) = file
+            .chargeback_fee
+            .values()
+            .find(|fee| !fee.is_finite() || **fee < 0.0)
+        {
+            return Err(invalid(format!(
+                "invalid chargeback-fee {fee}, expected a positive amount"
+            )));
+        }
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let csvs = [
+            &mut file.tiers,
+            &mut file.client_tiers,
+            &mut file.roles,
+            &mut file.currencies,
+        ];
+        for csv in csvs.into_iter().flatten() {
+            *csv = dir.join(&*csv);
+        }
+        #[cfg(feature = "rhai")]
+        if let Some(script) = &mut file.script {
+            *script = dir.join(&*script);
+        }
+        Ok(file)
+    }
+
+    // The CSV files and the script the policy is read from.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        let csvs = [
+            &self.tiers,
+            &self.client_tiers,
+            &self.roles,
+            &self.currencies,
+        ];
+        let paths = csvs.into_iter().flatten().map(PathBuf::as_path);
+        #[cfg(feature = "rhai")]
+        let paths = paths.chain(self.script.as_deref());
+        paths
+    }
+}
+
+// Tells when one of the policy files was modified, created or removed, by
+// polling their modification times.
+pub struct PolicyWatch {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl PolicyWatch {
+    pub fn new<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Self {
+        let files = paths
+            .into_iter()
+            .map(|path| (path.to_path_buf(), modified(path)))
+            .collect();
+        Self { files }
+    }
+
+    // Whether a file changed since the last call, or since `new`.
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last) in &mut self.files {
+            let current = modified(path);
+            if current != *last {
+                *last = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::rules::Consequence;
+    use crate::tier::Tier;
+    use crate::types::Action;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("accounting-demo-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn loads_the_settings_with_paths_relative_to_the_file() {
+        let dir = temp_dir("policy-file");
+        let path = dir.join("policy.toml");
+        fs::write(
+            &path,
+            "zero-amounts = \"accept\"\n\
+             cache-ttl = \"txs:100\"\n\
+             retention = \"periods:12\"\n\
+             allow-admin-actions = true\n\
+             tiers = \"tiers.csv\"\n\
+             merchant-fee = 0.029\n\
+             chargeback-fee = { merchant = 15 }\n",
+        )
+        .unwrap();
+
+        let file = PolicyFile::load(&path).unwrap();
+        assert_eq!(file.zero_amounts, Some(ZeroAmountPolicy::Accept));
+        assert_eq!(file.cache_ttl, Some(CacheTtl::Transactions(100)));
+        assert_eq!(file.retention, Some(Retention::Periods(12)));
+        assert_eq!(file.allow_admin_actions, Some(true));
+        assert_eq!(file.precision, None);
+        assert_eq!(file.tiers, Some(dir.join("tiers.csv")));
+        assert_eq!(file.merchant_fee, Some(0.029));
+        assert_eq!(file.chargeback_fee, HashMap::from([(Role::Merchant, 15.0)]));
+        assert_eq!(
+            file.paths().collect::<Vec<_>>(),
+            vec![dir.join("tiers.csv")]
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn refuses_unknown_keys_and_values() {
+        let dir = temp_dir("policy-invalid");
+        let path = dir.join("policy.toml");
+        for text in [
+            "zero-amount = \"accept\"",
+            "cache-ttl = \"weekly\"",
+            "retention = \"periods:-1\"",
+            "merchant-fee = 2.0",
+            "chargeback-fee = { admin = 1 }",
+            "[[rule]]\nname = \"everything\"\nthen = \"reject\"",
+            "[[rule]]\nname = \"typo\"\ntype = \"withdrawl\"\nthen = \"reject\"",
+        ] {
+            fs::write(&path, text).unwrap();
+            assert!(
+                matches!(
+                    PolicyFile::load(&path),
+                    Err(PolicyFileError::Invalid { .. })
+                ),
+                "{text}"
+            );
+        }
+        assert!(matches!(
+            PolicyFile::load(&dir.join("missing.toml")),
+            Err(PolicyFileError::Io { .. })
+        ));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn loads_the_rules_in_order() {
+        let dir = temp_dir("policy-rules");
+        let path = dir.join("policy.toml");
+        fs::write(
+            &path,
+            "[[rule]]\n\
+             name = \"large basic withdrawals\"\n\
+             type = \"withdrawal\"\n\
+             tier = \"basic\"\n\
+             amount-above = 10000\n\
+             then = \"reject\"\n\
+             [[rule]]\n\
+             name = \"dispute abuse\"\n\
+             type = \"dispute\"\n\
+             disputes-at-least = 3\n\
+             then = \"freeze\"\n",
+        )
+        .unwrap();
+
+        let file = PolicyFile::load(&path).unwrap();
+        let rules: Vec<_> = file.rules.iter().collect();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].tier, Some(Tier::Basic));
+        assert_eq!(rules[0].amount_above, Some(10000.0));
+        assert_eq!(rules[1].action, Some(Action::Dispute));
+        assert_eq!(rules[1].then, Consequence::Freeze);
+        assert!(PolicyFile::default().rules.is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn notices_modified_created_and_removed_files() {
+        let dir = temp_dir("policy-watch");
+        let (tiers, roles) = (dir.join("tiers.csv"), dir.join("roles.csv"));
+        fs::write(&tiers, "a").unwrap();
+        let mut watch = PolicyWatch::new([tiers.as_path(), roles.as_path()]);
+        assert!(!watch.changed());
+
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&tiers)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(watch.changed());
+        assert!(!watch.changed());
+
+        fs::write(&roles, "b").unwrap();
+        assert!(watch.changed());
+        fs::remove_file(&tiers).unwrap();
+        assert!(watch.changed());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::amount::format_amount;
+use crate::pseudonym::Pseudonymizer;
+use crate::report::AccountRecord;
+use crate::types::{ClientId, TenantId};
+
+// Half of the smallest unit printed in reports.
+pub(crate) const AMOUNT_TOLERANCE: f64 = 0.00005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    MissingAccount,
+    UnexpectedAccount,
+    Mismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub kind: DiscrepancyKind,
+    pub field: Option<&'static str>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DiscrepancyRow<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    client: String,
+    kind: DiscrepancyKind,
+    field: Option<&'static str>,
+    expected: &'a Option<String>,
+    actual: &'a Option<String>,
+}
+
+impl Discrepancy {
+    fn account(record: &AccountRecord, kind: DiscrepancyKind) -> Self {
+        Self {
+            tenant: record.tenant.clone(),
+            client: record.client,
+            kind,
+            field: None,
+            expected: None,
+            actual: None,
+        }
+    }
+
+    fn mismatch(
+        record: &AccountRecord,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    ) -> Self {
+        Self {
+            tenant: record.tenant.clone(),
+            client: record.client,
+            kind: DiscrepancyKind::Mismatch,
+            field: Some(field),
+            expected: Some(expected),
+            actual: Some(actual),
+        }
+    }
+}
+
+fn compare_amount(
+    discrepancies: &mut Vec<Discrepancy>,
+    record: &AccountRecord,
+    field: &'static str,
+    expected: f64,
+    actual: f64,
+    places: u32,
+) {
+    if (expected - actual).abs() > AMOUNT_TOLERANCE {
+        discrepancies.push(Discrepancy::mismatch(
+            record,
+            field,
+            format_amount(expected, places),
+            format_amount(actual, places),
+        ));
+    }
+}
+
+fn compare(expected: &AccountRecord, actual: &AccountRecord, places: u32) -> Vec<Discrepancy> {
+    let mut discrepancies = Vec::new();
+    compare_amount(
+        &mut discrepancies,
+        expected,
+        "available",
+        expected.available,
+        actual.available,
+        places,
+    );
+    compare_amount(
+        &mut discrepancies,
+        expected,
+        "held",
+        expected.held,
+        actual.held,
+        places,
+    );
+    compare_amount(
+        &mut discrepancies,
+        expected,
+        "total",
+        expected.total,
+        actual.total,
+        places,
+    );
+    if expected.locked != actual.locked {
+        discrepancies.push(Discrepancy::mismatch(
+            expected,
+            "locked",
+            expected.locked.to_string(),
+            actual.locked.to_string(),
+        ));
+    }
+    discrepancies
+}
+
+fn key(record: &AccountRecord) -> (Option<&str>, ClientId) {
+    (record.tenant.as_deref(), record.client)
+}
+
+// Returns the discrepancies ordered by tenant and client id.
+pub fn reconcile(
+    expected: &[AccountRecord],
+    actual: &[AccountRecord],
+    places: u32,
+) -> Vec<Discrepancy> {
+    let expected: BTreeMap<_, _> = expected
+        .iter()
+        .map(|record| (key(record), record))
+        .collect();
+    let actual: BTreeMap<_, _> = actual.iter().map(|record| (key(record), record)).collect();
+
+    let mut discrepancies = Vec::new();
+    for (key, expected_record) in &expected {
+        match actual.get(key) {
+            Some(actual_record) => {
+                discrepancies.extend(compare(expected_record, actual_record, places))
+            }
+            None => discrepancies.push(Discrepancy::account(
+                expected_record,
+                DiscrepancyKind::MissingAccount,
+            )),
+        }
+    }
+    for (_, actual_record) in actual.iter().filter(|(key, _)| !expected.contains_key(key)) {
+        discrepancies.push(Discrepancy::account(
+            actual_record,
+            DiscrepancyKind::UnexpectedAccount,
+        ));
+    }
+    discrepancies.sort_by(|a, b| (&a.tenant, a.client).cmp(&(&b.tenant, b.client)));
+    discrepancies
+}
+
+pub fn write_discrepancies<W: Write>(
+    writer: W,
+    discrepancies: &[Discrepancy],
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let with_tenant = discrepancies
+        .iter()
+        .any(|discrepancy| discrepancy.tenant.is_some());
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    if with_tenant {
+        writer.write_field("tenant")?;
+    }
+    writer.write_record(["client", "kind", "field", "expected", "actual"])?;
+    for discrepancy in discrepancies {
+        writer.serialize(DiscrepancyRow {
+            tenant: with_tenant.then(|| discrepancy.tenant.as_deref().unwrap_or_default()),
+            client: pseudonymizer.client(discrepancy.client),
+            kind: discrepancy.kind,
+            field: discrepancy.field,
+            expected: &discrepancy.expected,
+            actual: &discrepancy.actual,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::role::Role;
+    use crate::tier::Tier;
+
+    fn record(client: ClientId, available: f64, held: f64, locked: bool) -> AccountRecord {
+        AccountRecord {
+            tenant: None,
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            tx_count: 0,
+            tier: Tier::default(),
+            role: Role::default(),
+            receivable: 0.0,
+        }
+    }
+
+    #[test]
+    fn matching_balances_have_no_discrepancies() {
+        let expected = vec![record(1, 1.5, 0.0, false), record(2, 0.0, 1.0, true)];
+        let actual = vec![record(2, 0.0, 1.0, true), record(1, 1.50001, 0.0, false)];
+        assert!(reconcile(&expected, &actual, 4).is_empty());
+    }
+
+    #[test]
+    fn reports_missing_unexpected_and_mismatched_accounts() {
+        let expected = vec![record(1, 1.5, 0.0, false), record(2, 1.0, 0.0, false)];
+        let actual = vec![record(1, 1.0, 0.5, true), record(3, 1.0, 0.0, false)];
+
+        let discrepancies = reconcile(&expected, &actual, 4);
+        assert_eq!(
+            discrepancies,
+            vec![
+                Discrepancy::mismatch(&expected[0], "available", "1.5000".into(), "1.0000".into()),
+                Discrepancy::mismatch(&expected[0], "held", "0.0000".into(), "0.5000".into()),
+                Discrepancy::mismatch(&expected[0], "locked", "false".into(), "true".into()),
+                Discrepancy::account(&expected[1], DiscrepancyKind::MissingAccount),
+                Discrepancy::account(&actual[1], DiscrepancyKind::UnexpectedAccount),
+            ]
+        );
+
+        let mut output = Vec::new();
+        write_discrepancies(&mut output, &discrepancies[2..], &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,kind,field,expected,actual\n\
+             1,mismatch,locked,false,true\n\
+             2,missing_account,,,\n\
+             3,unexpected_account,,,\n"
+        );
+    }
+
+    #[test]
+    fn accounts_are_matched_per_tenant() {
+        let tenant = |tenant: &str, record: AccountRecord| AccountRecord {
+            tenant: Some(tenant.to_string()),
+            ..record
+        };
+        let expected = vec![
+            tenant("acme", record(1, 1.0, 0.0, false)),
+            tenant("globex", record(1, 2.0, 0.0, false)),
+        ];
+        let actual = vec![
+            tenant("globex", record(1, 2.0, 0.0, false)),
+            tenant("acme", record(1, 1.0, 0.0, false)),
+        ];
+        assert!(reconcile(&expected, &actual, 4).is_empty());
+
+        let discrepancies = reconcile(&expected[..1], &actual[..1], 4);
+        let mut output = Vec::new();
+        write_discrepancies(&mut output, &discrepancies, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tenant,client,kind,field,expected,actual\n\
+             acme,1,missing_account,,,\n\
+             globex,1,unexpected_account,,,\n"
+        );
+    }
+}
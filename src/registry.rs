@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use roaring::RoaringBitmap;
+
+use crate::account_manager::{AccountManagerError, AccountManagerResult};
+use crate::types::{Action, TenantId, Transaction, TransactionId};
+
+fn registered(action: &Action) -> bool {
+    matches!(action, Action::Deposit | Action::Withdrawal)
+}
+
+// Tx ids of the deposits and withdrawals applied in earlier runs, per tenant.
+// It's kept in its own file across runs, so rows of a file, or of overlapping
+// files, that were already applied are skipped even without a checkpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessedRegistry {
+    tenants: BTreeMap<TenantId, RoaringBitmap>,
+}
+
+impl ProcessedRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // An empty registry if the file doesn't exist yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::read_from(io::BufReader::new(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Writes to a temporary file first and renames it, like checkpoints.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = io::BufWriter::new(File::create(&tmp_path)?);
+        self.write_to(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(tmp_path, path)
+    }
+
+    // The number of tenants, then per tenant the length of its name, the name,
+    // the size of its id set and the set in the portable roaring format.
+    // Lengths are little endian.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut tenants = BTreeMap::new();
+        let mut count = [0; 4];
+        reader.read_exact(&mut count)?;
+        for _ in 0..u32::from_le_bytes(count) {
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            // Read up to the length rather than allocated for it, so a corrupt
+            // length can't ask for more memory than the rest of the file.
+            let len = u32::from_le_bytes(len);
+            let mut name = Vec::new();
+            (&mut reader).take(len.into()).read_to_end(&mut name)?;
+            if name.len() != len as usize {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let name = String::from_utf8(name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let mut size = [0; 8];
+            reader.read_exact(&mut size)?;
+            let ids =
+                RoaringBitmap::deserialize_from((&mut reader).take(u64::from_le_bytes(size)))?;
+            tenants.insert(name, ids);
+        }
+        Ok(Self { tenants })
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&(self.tenants.len() as u32).to_le_bytes())?;
+        for (name, ids) in &self.tenants {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_all(&(ids.serialized_size() as u64).to_le_bytes())?;
+            ids.serialize_into(&mut writer)?;
+        }
+        writer.flush()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.tenants.values().map(RoaringBitmap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.values().all(RoaringBitmap::is_empty)
+    }
+
+    pub fn contains(&self, tenant: &str, tx_id: TransactionId) -> bool {
+        self.tenants
+            .get(tenant)
+            .is_some_and(|ids| ids.contains(tx_id))
+    }
+
+    // Refuses deposits and withdrawals applied in an earlier run.
+    pub fn check(&self, tx: &Transaction) -> AccountManagerResult<()> {
+        let tenant = tx.tenant.as_deref().unwrap_or_default();
+        if registered(&tx.action) && self.contains(tenant, tx.id) {
+            return Err(AccountManagerError::AlreadyProcessed { id: tx.id });
+        }
+        Ok(())
+    }
+
+    // Registers an applied transaction.
+    pub fn record(&mut self, tx: &Transaction) {
+        if registered(&tx.action) {
+            let tenant = tx.tenant.clone().unwrap_or_default();
+            self.tenants.entry(tenant).or_default().insert(tx.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_registered_deposits_and_withdrawals() {
+        let mut registry = ProcessedRegistry::new();
+        let deposit = Transaction::new(Action::Deposit, 1, 7, Some(1.0));
+        assert!(registry.check(&deposit).is_ok());
+        registry.record(&deposit);
+        registry.record(&Transaction::new(Action::Dispute, 1, 8, None));
+
+        assert_eq!(
+            registry.check(&deposit),
+            Err(AccountManagerError::AlreadyProcessed { id: 7 })
+        );
+        assert!(registry
+            .check(&Transaction::new(Action::Dispute, 1, 7, None))
+            .is_ok());
+        let other_tenant = Transaction {
+            tenant: Some("acme".to_string()),
+            ..deposit
+        };
+        assert!(registry.check(&other_tenant).is_ok());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn round_trips_through_its_file_format() {
+        let mut registry = ProcessedRegistry::new();
+        for (tenant, id) in [("", 1), ("", 100_000), ("acme", 1)] {
+            registry.record(&Transaction {
+                tenant: Some(tenant.to_string()),
+                ..Transaction::new(Action::Withdrawal, 1, id, Some(1.0))
+            });
+        }
+        let mut data = Vec::new();
+        registry.write_to(&mut data).unwrap();
+        assert_eq!(ProcessedRegistry::read_from(&data[..]).unwrap(), registry);
+        assert!(ProcessedRegistry::read_from(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn corrupt_name_lengths_are_refused_without_allocating_them() {
+        let mut data = 1u32.to_le_bytes().to_vec();
+        data.extend(u32::MAX.to_le_bytes());
+        data.extend(b"acme");
+        let err = ProcessedRegistry::read_from(&data[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
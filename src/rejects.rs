@@ -0,0 +1,272 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use csv::{Position, Writer, WriterBuilder};
+use serde::Serialize;
+
+use crate::account_manager::AccountManagerError;
+use crate::error_code::ErrorCode;
+use crate::pseudonym::{ErrorPayload, Pseudonymizer};
+use crate::types::{Action, Transaction, TransactionId};
+
+#[derive(Serialize)]
+struct RejectRecord<'a> {
+    line: u64,
+    record: u64,
+    #[serde(rename = "type")]
+    action: &'a Action,
+    client: String,
+    tx: TransactionId,
+    amount: Option<f64>,
+    code: ErrorCode,
+    error: String,
+}
+
+// With the description and reference columns of inputs that have them.
+#[derive(Serialize)]
+struct DetailedRejectRecord<'a> {
+    line: u64,
+    record: u64,
+    #[serde(rename = "type")]
+    action: &'a Action,
+    client: String,
+    tx: TransactionId,
+    amount: Option<f64>,
+    description: Option<&'a str>,
+    reference: Option<&'a str>,
+    code: ErrorCode,
+    error: String,
+}
+
+// One JSON object per line, with the error as an object of its code, kind,
+// message and context fields.
+#[derive(Serialize)]
+struct JsonReject<'a> {
+    line: u64,
+    record: u64,
+    #[serde(rename = "type")]
+    action: &'a Action,
+    client: String,
+    tx: TransactionId,
+    amount: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<&'a str>,
+    error: ErrorPayload<'a>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RejectsFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for RejectsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(RejectsFormat::Csv),
+            "json" => Ok(RejectsFormat::Json),
+            _ => Err(format!(
+                "unknown rejects format '{s}', expected csv or json"
+            )),
+        }
+    }
+}
+
+enum Output<W: Write> {
+    Csv(Box<Writer<W>>),
+    Json(W),
+}
+
+pub struct RejectsWriter<W: Write> {
+    output: Output<W>,
+    pseudonymizer: Pseudonymizer,
+    details: bool,
+}
+
+impl<W: Write> RejectsWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_output(Output::Csv(Box::new(Writer::from_writer(writer))))
+    }
+
+    // Appends to the CSV of an earlier run.
+    pub fn without_headers(writer: W) -> Self {
+        let writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+        Self::with_output(Output::Csv(Box::new(writer)))
+    }
+
+    // JSON lines need no headers, so they're appended to as they are.
+    pub fn json(writer: W) -> Self {
+        Self::with_output(Output::Json(writer))
+    }
+
+    fn with_output(output: Output<W>) -> Self {
+        Self {
+            output,
+            pseudonymizer: Pseudonymizer::new(),
+            details: false,
+        }
+    }
+
+    // Adds the description and reference columns to the CSV, e.g. if the
+    // input has them. JSON lines have them whenever the row has them.
+    pub fn with_details(mut self, details: bool) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub fn with_pseudonymizer(mut self, pseudonymizer: Pseudonymizer) -> Self {
+        self.pseudonymizer = pseudonymizer;
+        self
+    }
+
+    pub fn write(
+        &mut self,
+        position: &Position,
+        tx: &Transaction,
+        error: &AccountManagerError,
+    ) -> csv::Result<()> {
+        let writer = match &mut self.output {
+            Output::Csv(writer) if self.details => {
+                return writer.serialize(DetailedRejectRecord {
+                    line: position.line(),
+                    record: position.record(),
+                    action: &tx.action,
+                    client: self.pseudonymizer.client(tx.client_id),
+                    tx: tx.id,
+                    amount: tx.amount,
+                    description: tx.description.as_deref(),
+                    reference: tx.reference.as_deref(),
+                    code: error.code(),
+                    error: self.pseudonymizer.error(error),
+                })
+            }
+            Output::Csv(writer) => {
+                return writer.serialize(RejectRecord {
+                    line: position.line(),
+                    record: position.record(),
+                    action: &tx.action,
+                    client: self.pseudonymizer.client(tx.client_id),
+                    tx: tx.id,
+                    amount: tx.amount,
+                    code: error.code(),
+                    error: self.pseudonymizer.error(error),
+                })
+            }
+            Output::Json(writer) => writer,
+        };
+        let reject = JsonReject {
+            line: position.line(),
+            record: position.record(),
+            action: &tx.action,
+            client: self.pseudonymizer.client(tx.client_id),
+            tx: tx.id,
+            amount: tx.amount,
+            description: tx.description.as_deref(),
+            reference: tx.reference.as_deref(),
+            error: self.pseudonymizer.error_payload(error),
+        };
+        serde_json::to_writer(&mut *writer, &reject).map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Csv(writer) => writer.flush(),
+            Output::Json(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::AccountError;
+
+    #[test]
+    fn writes_rejected_transaction_with_error() {
+        let mut rejects = RejectsWriter::new(Vec::new());
+        let mut position = Position::new();
+        let tx = Transaction {
+            description: Some("refund".to_string()),
+            ..Transaction::new(Action::Deposit, 1, 2, Some(-5.0))
+        };
+        let error = AccountManagerError::InvalidAmount {
+            id: 2,
+            amount: -5.0,
+        };
+        position.set_line(2).set_record(1);
+        rejects.write(&position, &tx, &error).unwrap();
+
+        let Output::Csv(writer) = rejects.output else {
+            unreachable!()
+        };
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "line,record,type,client,tx,amount,code,error\n\
+             2,1,deposit,1,2,-5.0,E1105,Transaction 2 has an invalid amount of -5\n"
+        );
+    }
+
+    #[test]
+    fn writes_the_details_of_inputs_that_have_them() {
+        let mut rejects = RejectsWriter::new(Vec::new()).with_details(true);
+        let mut position = Position::new();
+
+        let tx = Transaction::new(Action::Deposit, 1, 2, Some(-5.0));
+        let error = AccountManagerError::InvalidAmount {
+            id: 2,
+            amount: -5.0,
+        };
+        position.set_line(2).set_record(1);
+        rejects.write(&position, &tx, &error).unwrap();
+
+        let tx = Transaction {
+            reference: Some("00123".to_string()),
+            ..Transaction::new(Action::Dispute, 1, 3, None)
+        };
+        let error = AccountManagerError::TransactionNotFound { id: 3 };
+        position.set_line(5).set_record(3);
+        rejects.write(&position, &tx, &error).unwrap();
+
+        let Output::Csv(writer) = rejects.output else {
+            unreachable!()
+        };
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "line,record,type,client,tx,amount,description,reference,code,error\n\
+             2,1,deposit,1,2,-5.0,,,E1105,Transaction 2 has an invalid amount of -5\n\
+             5,3,dispute,1,3,,,00123,E1104,Transaction 3 not found\n"
+        );
+    }
+
+    #[test]
+    fn writes_json_lines_with_the_error_fields() {
+        let mut output = Vec::new();
+        let mut rejects = RejectsWriter::json(&mut output);
+        let mut position = Position::new();
+        position.set_line(3).set_record(2);
+        let tx = Transaction::new(Action::Withdrawal, 1, 4, Some(7.0));
+        let error = AccountManagerError::Account(AccountError::InsufficientFunds {
+            requested: 7.0,
+            available: 5.0,
+        });
+        rejects.write(&position, &tx, &error).unwrap();
+        rejects.flush().unwrap();
+        drop(rejects);
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"line\":3,\"record\":2,\"type\":\"withdrawal\",\"client\":\"1\",\"tx\":4,\"amount\":7.0,\
+             \"error\":{\"code\":\"E1001\",\"kind\":\"insufficient_funds\",\
+             \"message\":\"Insufficient funds. Requested 7 of 5.\",\"requested\":7.0,\"available\":5.0}}\n"
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::account::Account;
+use crate::pseudonym::Pseudonymizer;
+use crate::tenant::Tenants;
+use crate::types::{Transaction, TransactionId};
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid replay entry at line {line}: {source}")]
+    Serialization {
+        line: u64,
+        source: serde_json::Error,
+    },
+}
+
+pub type ReplayResult<T> = Result<T, ReplayError>;
+
+// A transaction as the books received it: parsed, screened, and due if it was
+// scheduled. Transactions the registry or the script refused never reached
+// the books and aren't recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayEntry {
+    pub sequence: u64,
+    pub tx: Transaction,
+    // The metadata isn't part of the serialized transaction.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ReplayEntry {
+    pub fn into_transaction(self) -> Transaction {
+        Transaction {
+            metadata: self.metadata,
+            ..self.tx
+        }
+    }
+}
+
+// Writes the transaction stream of a run, one JSON entry per line.
+pub struct ReplayRecorder<W: Write> {
+    writer: W,
+    sequence: u64,
+}
+
+impl<W: Write> ReplayRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            sequence: 0,
+        }
+    }
+
+    pub fn record(&mut self, tx: &Transaction) -> ReplayResult<()> {
+        let entry = ReplayEntry {
+            sequence: self.sequence + 1,
+            tx: tx.clone(),
+            metadata: tx.metadata.clone(),
+        };
+        serde_json::to_writer(&mut self.writer, &entry).map_err(|source| {
+            ReplayError::Serialization {
+                line: entry.sequence,
+                source,
+            }
+        })?;
+        self.writer.write_all(b"\n")?;
+        self.sequence = entry.sequence;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl ReplayRecorder<BufWriter<File>> {
+    pub fn create(path: &Path) -> ReplayResult<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+pub fn read_replay<R: BufRead>(reader: R) -> impl Iterator<Item = ReplayResult<ReplayEntry>> {
+    (1..).zip(reader.lines()).map(|(line, text)| {
+        serde_json::from_str(&text?).map_err(|source| ReplayError::Serialization { line, source })
+    })
+}
+
+// The state around a transaction at a breakpoint.
+#[derive(Serialize)]
+struct Dump<'a> {
+    sequence: u64,
+    tx: serde_json::Value,
+    // "applied" or the kind of the rejection.
+    outcome: &'a str,
+    error: Option<String>,
+    before: Option<Account>,
+    after: Option<&'a Account>,
+}
+
+// The transaction with its client ids rendered by the pseudonymizer.
+fn dumped_transaction(
+    tx: &Transaction,
+    pseudonymizer: &Pseudonymizer,
+) -> serde_json::Result<serde_json::Value> {
+    let mut value = serde_json::to_value(tx)?;
+    if pseudonymizer.is_keyed() {
+        value["client"] = pseudonymizer.client(tx.client_id).into();
+        if let Some(counterparty) = tx.counterparty {
+            value["counterparty"] = pseudonymizer.client(counterparty).into();
+        }
+    }
+    Ok(value)
+}
+
+// Re-executes a recorded transaction stream on books with the same policy.
+// At every transaction with a breakpoint id, the transaction, its outcome and
+// the account of its client before and after it are dumped as a JSON line.
+#[derive(Clone, Default)]
+pub struct Replay {
+    breakpoints: HashSet<TransactionId>,
+    stop: bool,
+    pseudonymizer: Pseudonymizer,
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_breakpoints(mut self, tx_ids: impl IntoIterator<Item = TransactionId>) -> Self {
+        self.breakpoints.extend(tx_ids);
+        self
+    }
+
+    // Ends the replay after the first breakpoint, leaving the books as they
+    // were right after it.
+    pub fn stop_at_breakpoint(mut self) -> Self {
+        self.stop = true;
+        self
+    }
+
+    // Renders the client ids in the dumps.
+    pub fn with_pseudonymizer(mut self, pseudonymizer: Pseudonymizer) -> Self {
+        self.pseudonymizer = pseudonymizer;
+        self
+    }
+
+    // Returns the number of replayed transactions.
+    pub fn run<R: BufRead, W: Write>(
+        &self,
+        reader: R,
+        tenants: &mut Tenants,
+        mut dump: W,
+    ) -> ReplayResult<u64> {
+        let mut replayed = 0;
+        for entry in read_replay(reader) {
+            let entry = entry?;
+            let sequence = entry.sequence;
+            let tx = entry.into_transaction();
+            let account = |tenants: &Tenants| {
+                tenants
+                    .get(tx.tenant.as_deref().unwrap_or_default())
+                    .and_then(|account_manager| account_manager.account(tx.client_id))
+                    .cloned()
+            };
+            let breakpoint = self.breakpoints.contains(&tx.id);
+            let before = breakpoint.then(|| account(tenants)).flatten();
+            // Recorded transactions were due, whatever their execution time.
+            let result = tenants.process_scheduled(tx.clone());
+            replayed += 1;
+            if !breakpoint {
+                continue;
+            }
+            let after = account(tenants);
+            let serialization = |source| ReplayError::Serialization {
+                line: sequence,
+                source,
+            };
+            let dumped = Dump {
+                sequence,
+                tx: dumped_transaction(&tx, &self.pseudonymizer).map_err(serialization)?,
+                outcome: result
+                    .as_ref()
+                    .map_or_else(|err| err.kind(), |()| "applied"),
+                error: result
+                    .as_ref()
+                    .err()
+                    .map(|err| self.pseudonymizer.error(err)),
+                before,
+                after: after.as_ref(),
+            };
+            serde_json::to_writer(&mut dump, &dumped).map_err(serialization)?;
+            dump.write_all(b"\n")?;
+            if self.stop {
+                break;
+            }
+        }
+        dump.flush()?;
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+    use crate::types::Action;
+
+    fn recording() -> Vec<u8> {
+        let mut recorder = ReplayRecorder::new(Vec::new());
+        let mut deposit = Transaction::new(Action::Deposit, 1, 1, Some(5.0));
+        deposit
+            .metadata
+            .insert("review".to_string(), "large".to_string());
+        let txs = [
+            deposit,
+            Transaction::new(Action::Withdrawal, 1, 2, Some(2.0)),
+            Transaction::new(Action::Withdrawal, 1, 3, Some(9.0)),
+            Transaction::new(Action::Deposit, 2, 4, Some(1.0)),
+        ];
+        for tx in &txs {
+            assert!(recorder.record(tx).is_ok());
+        }
+        recorder.writer
+    }
+
+    #[test]
+    fn reads_back_the_recorded_transactions() {
+        let entries: Vec<_> = read_replay(recording().as_slice())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3].sequence, 4);
+        let deposit = entries[0].clone().into_transaction();
+        assert_eq!(deposit.metadata["review"], "large");
+
+        let invalid = "{\"sequence\":1}\n";
+        assert!(matches!(
+            read_replay(invalid.as_bytes()).next(),
+            Some(Err(ReplayError::Serialization { line: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn replay_dumps_the_account_at_breakpoints() {
+        let mut tenants = Tenants::with_policy(Policy::default());
+        let mut dump = Vec::new();
+        let replay = Replay::new().with_breakpoints([2, 3]);
+        let replayed = replay
+            .run(recording().as_slice(), &mut tenants, &mut dump)
+            .unwrap();
+        assert_eq!(replayed, 4);
+        assert_eq!(tenants.get("").unwrap().account(2).unwrap().total(), 1.0);
+
+        let dump = String::from_utf8(dump).unwrap();
+        let lines: Vec<serde_json::Value> = dump
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["outcome"], "applied");
+        assert_eq!(lines[0]["before"]["available"], 5.0);
+        assert_eq!(lines[0]["after"]["available"], 3.0);
+        assert_eq!(lines[1]["sequence"], 3);
+        assert_eq!(lines[1]["outcome"], "insufficient_funds");
+    }
+
+    #[test]
+    fn dumps_use_pseudonyms() {
+        let pseudonymizer = Pseudonymizer::with_key(b"secret");
+        let mut recorder = ReplayRecorder::new(Vec::new());
+        let txs = [
+            Transaction::new(Action::Deposit, 815, 1, Some(5.0)),
+            Transaction::new(Action::Dispute, 7, 1, None),
+        ];
+        for tx in &txs {
+            assert!(recorder.record(tx).is_ok());
+        }
+        let mut tenants = Tenants::with_policy(Policy::default());
+        let mut dump = Vec::new();
+        let replay = Replay::new()
+            .with_breakpoints([1])
+            .with_pseudonymizer(pseudonymizer.clone());
+        replay
+            .run(recorder.writer.as_slice(), &mut tenants, &mut dump)
+            .unwrap();
+
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(!dump.contains("815"));
+        let lines: Vec<serde_json::Value> = dump
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (client, owner) = (pseudonymizer.client(7), pseudonymizer.client(815));
+        assert_eq!(lines[0]["tx"]["client"], owner.as_str());
+        assert_eq!(lines[1]["tx"]["client"], client.as_str());
+        assert_eq!(lines[1]["outcome"], "unauthorized");
+        assert_eq!(
+            lines[1]["error"],
+            format!("Unauthorized. {client} can't modify transactions of {owner}.")
+        );
+    }
+
+    #[test]
+    fn replay_stops_at_the_first_breakpoint() {
+        let mut tenants = Tenants::with_policy(Policy::default());
+        let replay = Replay::new().with_breakpoints([2]).stop_at_breakpoint();
+        let replayed = replay
+            .run(recording().as_slice(), &mut tenants, io::sink())
+            .unwrap();
+        assert_eq!(replayed, 2);
+        assert_eq!(tenants.get("").unwrap().account(1).unwrap().total(), 3.0);
+        assert!(tenants.get("").unwrap().account(2).is_none());
+    }
+}
@@ -0,0 +1,493 @@
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use csv::{ReaderBuilder, Trim, WriterBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::amount::{format_amount, AmountFormat};
+use crate::pseudonym::Pseudonymizer;
+use crate::role::Role;
+use crate::tier::Tier;
+use crate::types::{ClientId, TenantId};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AccountRecord {
+    #[serde(default)]
+    pub tenant: Option<TenantId>,
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    #[serde(default)]
+    pub tx_count: u64,
+    #[serde(default)]
+    pub tier: Tier,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub receivable: f64,
+}
+
+impl AccountRecord {
+    pub fn new(client: ClientId, account: &Account) -> Self {
+        Self {
+            tenant: None,
+            client,
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+            tx_count: account.transactions(),
+            tier: Tier::default(),
+            role: account.role().unwrap_or_default(),
+            receivable: account.receivable(),
+        }
+    }
+
+    // `locked`, `held` if some funds are held, otherwise `active`.
+    pub fn state(&self) -> &'static str {
+        match (self.locked, self.held != 0.0) {
+            (true, _) => "locked",
+            (false, true) => "held",
+            (false, false) => "active",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportColumn {
+    Tenant,
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    TxCount,
+    State,
+    Tier,
+    Role,
+    Receivable,
+}
+
+impl FromStr for ReportColumn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tenant" => Ok(Self::Tenant),
+            "client" => Ok(Self::Client),
+            "available" => Ok(Self::Available),
+            "held" => Ok(Self::Held),
+            "total" => Ok(Self::Total),
+            "locked" => Ok(Self::Locked),
+            "tx_count" => Ok(Self::TxCount),
+            "state" => Ok(Self::State),
+            "tier" => Ok(Self::Tier),
+            "role" => Ok(Self::Role),
+            "receivable" => Ok(Self::Receivable),
+            _ => Err(format!(
+                "unknown column '{s}', expected tenant, client, available, held, total, locked, tx_count, state, tier, role or receivable"
+            )),
+        }
+    }
+}
+
+impl ReportColumn {
+    pub const DEFAULT: [ReportColumn; 5] = [
+        ReportColumn::Client,
+        ReportColumn::Available,
+        ReportColumn::Held,
+        ReportColumn::Total,
+        ReportColumn::Locked,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReportColumn::Tenant => "tenant",
+            ReportColumn::Client => "client",
+            ReportColumn::Available => "available",
+            ReportColumn::Held => "held",
+            ReportColumn::Total => "total",
+            ReportColumn::Locked => "locked",
+            ReportColumn::TxCount => "tx_count",
+            ReportColumn::State => "state",
+            ReportColumn::Tier => "tier",
+            ReportColumn::Role => "role",
+            ReportColumn::Receivable => "receivable",
+        }
+    }
+
+    fn is_amount(&self) -> bool {
+        matches!(
+            self,
+            ReportColumn::Available
+                | ReportColumn::Held
+                | ReportColumn::Total
+                | ReportColumn::Receivable
+        )
+    }
+
+    fn value(
+        &self,
+        record: &AccountRecord,
+        pseudonymizer: &Pseudonymizer,
+        amount: impl Fn(f64) -> String,
+    ) -> String {
+        match self {
+            ReportColumn::Tenant => record.tenant.clone().unwrap_or_default(),
+            ReportColumn::Client => pseudonymizer.client(record.client),
+            ReportColumn::Available => amount(record.available),
+            ReportColumn::Held => amount(record.held),
+            ReportColumn::Total => amount(record.total),
+            ReportColumn::Locked => record.locked.to_string(),
+            ReportColumn::TxCount => record.tx_count.to_string(),
+            ReportColumn::State => record.state().to_string(),
+            ReportColumn::Tier => record.tier.to_string(),
+            ReportColumn::Role => record.role.to_string(),
+            ReportColumn::Receivable => amount(record.receivable),
+        }
+    }
+}
+
+// The requested columns, or the default ones with a tenant column in front if
+// any record has a tenant.
+fn report_columns(
+    columns: &[ReportColumn],
+    with_tenant: impl FnOnce() -> bool,
+) -> Vec<ReportColumn> {
+    if !columns.is_empty() {
+        return columns.to_vec();
+    }
+    with_tenant()
+        .then_some(ReportColumn::Tenant)
+        .into_iter()
+        .chain(ReportColumn::DEFAULT)
+        .collect()
+}
+
+// Writes `columns` of the records, all default columns if it is empty.
+pub fn write_account_records<W: Write, I>(
+    writer: W,
+    records: I,
+    columns: &[ReportColumn],
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()>
+where
+    I: IntoIterator<Item = AccountRecord>,
+    I::IntoIter: Clone,
+{
+    // The header is written explicitly so that an empty report still has one.
+    // Finding tenants takes a first pass over the records instead of
+    // collecting them.
+    let records = records.into_iter();
+    let columns = report_columns(columns, || {
+        records.clone().any(|record| record.tenant.is_some())
+    });
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    writer.write_record(columns.iter().map(ReportColumn::name))?;
+    for record in records {
+        writer.write_record(columns.iter().map(|column| {
+            column.value(&record, pseudonymizer, |amount| {
+                format_amount(amount, places)
+            })
+        }))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReportFormat {
+    // Machine readable, always with plain amounts.
+    #[default]
+    Csv,
+    // An aligned table with currency formatted amounts for people.
+    Text,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "text" => Ok(Self::Text),
+            _ => Err(format!("unknown report format '{s}', expected csv or text")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrencyFormat {
+    pub currency: String,
+    // Written in front of the amount instead of the code after it, e.g. `$`.
+    pub symbol: Option<String>,
+    pub amounts: AmountFormat,
+    // The minor units of the currency.
+    pub places: u32,
+}
+
+impl Default for CurrencyFormat {
+    fn default() -> Self {
+        Self {
+            currency: "USD".to_string(),
+            symbol: None,
+            amounts: AmountFormat::DecimalPoint,
+            places: 4,
+        }
+    }
+}
+
+impl CurrencyFormat {
+    pub fn format(&self, amount: f64) -> String {
+        let formatted = self.amounts.format(amount, self.places as usize);
+        match &self.symbol {
+            Some(symbol) => match formatted.strip_prefix('-') {
+                Some(formatted) => format!("-{symbol}{formatted}"),
+                None => format!("{symbol}{formatted}"),
+            },
+            None => format!("{formatted} {}", self.currency),
+        }
+    }
+}
+
+pub fn write_account_table<W: Write>(
+    mut writer: W,
+    records: impl IntoIterator<Item = AccountRecord>,
+    columns: &[ReportColumn],
+    currency: &CurrencyFormat,
+    pseudonymizer: &Pseudonymizer,
+) -> io::Result<()> {
+    let records: Vec<_> = records.into_iter().collect();
+    let columns = report_columns(columns, || {
+        records.iter().any(|record| record.tenant.is_some())
+    });
+    let header: Vec<_> = columns
+        .iter()
+        .map(|column| column.name().to_string())
+        .collect();
+    let rows: Vec<Vec<_>> = records
+        .iter()
+        .map(|record| {
+            columns
+                .iter()
+                .map(|column| column.value(record, pseudonymizer, |amount| currency.format(amount)))
+                .collect()
+        })
+        .collect();
+
+    let mut widths = vec![0; columns.len()];
+    for row in rows.iter().chain([&header]) {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+    for row in [&header].into_iter().chain(&rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(&columns)
+            .zip(&widths)
+            .map(|((field, column), &width)| match column.is_amount() {
+                // Amounts are right aligned.
+                true => format!("{field:>width$}"),
+                false => format!("{field:<width$}"),
+            })
+            .collect();
+        writeln!(writer, "{}", line.join("  ").trim_end())?;
+    }
+    writer.flush()
+}
+
+// Accounts with plain numbers for embedders (wasm, ffi).
+#[derive(Serialize)]
+struct AccountView {
+    client: ClientId,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+}
+
+pub fn accounts_json<'a>(accounts: impl Iterator<Item = (&'a ClientId, &'a Account)>) -> String {
+    let accounts: Vec<_> = accounts
+        .map(|(client, account)| AccountView {
+            client: *client,
+            available: account.available(),
+            held: account.held(),
+            total: account.total(),
+            locked: account.locked(),
+        })
+        .collect();
+    serde_json::to_string(&accounts).expect("accounts serialize to JSON")
+}
+
+pub fn read_account_records<R: Read>(reader: R) -> csv::Result<Vec<AccountRecord>> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::{process_transaction, AccountManager};
+    use crate::types::{Action, Transaction};
+
+    #[test]
+    fn records_round_trip_with_four_decimal_places() {
+        let mut account = Account::new();
+        assert!(account.deposit(1.5).is_ok());
+        assert!(account.dispute(0.25).is_ok());
+        let record = AccountRecord::new(7, &account);
+
+        let mut output = Vec::new();
+        write_account_records(
+            &mut output,
+            vec![record.clone()],
+            &[],
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "client,available,held,total,locked\n7,1.2500,0.2500,1.5000,false\n"
+        );
+
+        let records = read_account_records(output.as_slice()).unwrap();
+        assert_eq!(records, vec![record]);
+    }
+
+    #[test]
+    fn amounts_have_the_decimal_places_they_are_written_with() {
+        let mut account = Account::new();
+        assert!(account.deposit(1234.5).is_ok());
+        let records = vec![AccountRecord::new(7, &account)];
+
+        let write = |places| {
+            let mut output = Vec::new();
+            write_account_records(
+                &mut output,
+                records.clone(),
+                &[],
+                places,
+                &Pseudonymizer::new(),
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert!(write(0).ends_with("7,1234,0,1234,false\n"));
+        assert!(write(3).ends_with("7,1234.500,0.000,1234.500,false\n"));
+
+        let currency = CurrencyFormat {
+            currency: "JPY".to_string(),
+            places: 0,
+            ..CurrencyFormat::default()
+        };
+        assert_eq!(currency.format(1234.5), "1,234 JPY");
+    }
+
+    #[test]
+    fn tenant_column_is_written_and_read_for_tenant_records() {
+        let record = AccountRecord {
+            tenant: Some("acme".to_string()),
+            ..AccountRecord::new(7, &Account::new())
+        };
+
+        let records = vec![AccountRecord::new(2, &Account::new()), record];
+
+        let mut output = Vec::new();
+        write_account_records(&mut output, records.clone(), &[], 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "tenant,client,available,held,total,locked\n\
+             ,2,0.0000,0.0000,0.0000,false\n\
+             acme,7,0.0000,0.0000,0.0000,false\n"
+        );
+        assert_eq!(read_account_records(output.as_slice()).unwrap(), records);
+    }
+
+    #[test]
+    fn text_report_formats_amounts_with_currency() {
+        let mut account = Account::new();
+        assert!(account.deposit(1234.5678).is_ok());
+        assert!(account.dispute(1000.0).is_ok());
+        let records = vec![
+            AccountRecord::new(7, &account),
+            AccountRecord::new(12, &Account::new()),
+        ];
+
+        let mut output = Vec::new();
+        let currency = CurrencyFormat::default();
+        write_account_table(
+            &mut output,
+            records.clone(),
+            &[],
+            &currency,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client     available            held           total  locked\n\
+             7       234.5678 USD  1,000.0000 USD  1,234.5678 USD  false\n\
+             12        0.0000 USD      0.0000 USD      0.0000 USD  false\n"
+        );
+
+        let currency = CurrencyFormat {
+            symbol: Some("€".to_string()),
+            amounts: AmountFormat::DecimalComma,
+            ..CurrencyFormat::default()
+        };
+        assert_eq!(currency.format(-1234.5), "-€1.234,5000");
+    }
+
+    #[test]
+    fn selected_columns_are_written_in_order() {
+        let mut account_manager = AccountManager::new();
+        let deposit = Transaction::new(Action::Deposit, 7, 1, Some(1.5));
+        assert!(process_transaction(&mut account_manager, deposit).is_ok());
+        let dispute = Transaction::new(Action::Dispute, 7, 1, None);
+        assert!(process_transaction(&mut account_manager, dispute).is_ok());
+        let records = vec![
+            AccountRecord::new(7, account_manager.account(7).unwrap()),
+            AccountRecord::new(8, &Account::new()),
+        ];
+        let columns = [
+            ReportColumn::State,
+            ReportColumn::Client,
+            ReportColumn::TxCount,
+            ReportColumn::Total,
+            ReportColumn::Tenant,
+        ];
+
+        let mut output = Vec::new();
+        write_account_records(&mut output, records, &columns, 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "state,client,tx_count,total,tenant\n\
+             held,7,2,1.5000,\n\
+             active,8,0,0.0000,\n"
+        );
+        assert_eq!(
+            "tx_count".parse::<ReportColumn>(),
+            Ok(ReportColumn::TxCount)
+        );
+        assert!("balance".parse::<ReportColumn>().is_err());
+    }
+
+    #[test]
+    fn header_is_written_without_records() {
+        let mut output = Vec::new();
+        write_account_records(&mut output, Vec::new(), &[], 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n"
+        );
+    }
+}
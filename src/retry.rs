@@ -0,0 +1,89 @@
+use std::io;
+use std::time::Duration;
+
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(200);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// How failed reads of network sources are repeated: up to `retries` times,
+// with a delay starting at `backoff` that doubles up to `max_backoff`. The
+// readers resume from the offset they got to, so nothing is read twice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Fails on the first error.
+    pub fn none() -> Self {
+        Self {
+            retries: 0,
+            ..Self::default()
+        }
+    }
+
+    // The delays before each retry.
+    pub fn delays(&self) -> impl Iterator<Item = Duration> {
+        let (backoff, max_backoff) = (self.backoff, self.max_backoff);
+        (0..self.retries).map(move |retry| {
+            backoff
+                .checked_mul(1 << retry.min(31))
+                .map_or(max_backoff, |delay| delay.min(max_backoff))
+        })
+    }
+}
+
+// Errors of the request itself, e.g. a missing object or denied access, fail
+// again on retry. Connection, server and timeout errors may not.
+pub fn is_transient(err: &io::Error) -> bool {
+    !matches!(
+        err.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::Unsupported
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5),
+        };
+        let delays: Vec<_> = policy.delays().map(|delay| delay.as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        assert_eq!(RetryPolicy::none().delays().count(), 0);
+
+        let many = RetryPolicy {
+            retries: 100,
+            ..policy
+        };
+        assert_eq!(many.delays().last(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn request_errors_are_not_transient() {
+        assert!(!is_transient(&io::ErrorKind::NotFound.into()));
+        assert!(is_transient(&io::ErrorKind::ConnectionReset.into()));
+        assert!(is_transient(&io::ErrorKind::UnexpectedEof.into()));
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::iter;
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::amount::ReportAmount;
+use crate::ledger::{JournalEntry, Ledger};
+use crate::period::ClosedPeriod;
+use crate::pseudonym::Pseudonymizer;
+use crate::types::ClientId;
+
+// The money a client moved in and out during a batch. Moves between the
+// client's own accounts (disputes and resolves of deposits) don't move money
+// and are left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settlement {
+    // Set when settling per period.
+    pub period: Option<u32>,
+    pub client_id: ClientId,
+    pub inflows: f64,
+    pub outflows: f64,
+}
+
+impl Settlement {
+    // Positive if the business received more than it paid out.
+    pub fn net(&self) -> f64 {
+        self.inflows - self.outflows
+    }
+}
+
+// Nets the activity of every client in the ledger, ordered by client.
+// Released escrows move money from one client to another.
+pub fn settle(ledger: &Ledger) -> Vec<Settlement> {
+    net(ledger.entries(), None)
+}
+
+// Nets the activity of every client per period, ordered by period and client:
+// the closed periods and then the current one. The balances carried by
+// compacted periods (sequence 0) moved no money and are left out.
+pub fn settle_periods(ledger: &Ledger, closed: &[ClosedPeriod]) -> Vec<Settlement> {
+    let mut settlements = Vec::new();
+    let mut entries = ledger
+        .entries()
+        .iter()
+        .filter(|entry| entry.sequence > 0)
+        .peekable();
+    let current = (closed.len() as u32 + 1, u64::MAX);
+    let periods = closed
+        .iter()
+        .map(|period| (period.number, period.ledger_sequence));
+    for (number, last) in periods.chain([current]) {
+        let period = iter::from_fn(|| entries.next_if(|entry| entry.sequence <= last));
+        settlements.extend(net(period, Some(number)));
+    }
+    settlements
+}
+
+fn net<'a>(
+    entries: impl IntoIterator<Item = &'a JournalEntry>,
+    period: Option<u32>,
+) -> Vec<Settlement> {
+    fn settlement(
+        settlements: &mut BTreeMap<ClientId, Settlement>,
+        period: Option<u32>,
+        client_id: ClientId,
+    ) -> &mut Settlement {
+        settlements.entry(client_id).or_insert_with(|| Settlement {
+            period,
+            client_id,
+            ..Settlement::default()
+        })
+    }
+
+    let mut settlements = BTreeMap::new();
+    for entry in entries {
+        let (debited, credited) = (entry.debit.client_id(), entry.credit.client_id());
+        if debited == credited {
+            continue;
+        }
+        if let Some(client_id) = debited {
+            settlement(&mut settlements, period, client_id).outflows += entry.amount;
+        }
+        if let Some(client_id) = credited {
+            settlement(&mut settlements, period, client_id).inflows += entry.amount;
+        }
+    }
+    settlements.into_values().collect()
+}
+
+#[derive(Serialize)]
+struct SettlementRecord<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    period: Option<u32>,
+    client: String,
+    inflows: ReportAmount,
+    outflows: ReportAmount,
+    net: ReportAmount,
+    debit: &'a str,
+    credit: &'a str,
+    amount: ReportAmount,
+}
+
+// One settlement entry per client. A positive net is swept from cash to the
+// settlement account, a negative one is funded from it.
+pub fn write_settlements<W: Write>(
+    writer: W,
+    settlements: &[Settlement],
+    cash_account: &str,
+    settlement_account: &str,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    for settlement in settlements {
+        let net = settlement.net();
+        let (debit, credit) = match net < 0.0 {
+            true => (cash_account, settlement_account),
+            false => (settlement_account, cash_account),
+        };
+        writer.serialize(SettlementRecord {
+            period: settlement.period,
+            client: pseudonymizer.client(settlement.client_id),
+            inflows: ReportAmount::new(settlement.inflows, places),
+            outflows: ReportAmount::new(settlement.outflows, places),
+            net: ReportAmount::new(net, places),
+            debit,
+            credit,
+            amount: ReportAmount::new(net.abs(), places),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_manager::AccountManager;
+    use crate::ledger::LedgerAccount;
+    use crate::types::Action;
+
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::new();
+        let entries = [
+            (
+                Action::Deposit,
+                LedgerAccount::Cash,
+                LedgerAccount::CustomerAvailable(2),
+                5.0,
+            ),
+            (
+                Action::Deposit,
+                LedgerAccount::Cash,
+                LedgerAccount::CustomerAvailable(1),
+                3.0,
+            ),
+            (
+                Action::Withdrawal,
+                LedgerAccount::CustomerAvailable(1),
+                LedgerAccount::Cash,
+                1.0,
+            ),
+            (
+                Action::Dispute,
+                LedgerAccount::CustomerAvailable(2),
+                LedgerAccount::CustomerHeld(2),
+                5.0,
+            ),
+            (
+                Action::Chargeback,
+                LedgerAccount::ChargebackLoss,
+                LedgerAccount::Cash,
+                5.0,
+            ),
+            (
+                Action::Chargeback,
+                LedgerAccount::CustomerHeld(2),
+                LedgerAccount::ChargebackLoss,
+                5.0,
+            ),
+        ];
+        for (tx_id, (action, debit, credit, amount)) in (1..).zip(entries) {
+            ledger.post(tx_id, action, debit, credit, amount);
+        }
+        ledger
+    }
+
+    #[test]
+    fn nets_the_money_moved_per_client() {
+        assert_eq!(
+            settle(&ledger()),
+            vec![
+                Settlement {
+                    period: None,
+                    client_id: 1,
+                    inflows: 3.0,
+                    outflows: 1.0,
+                },
+                Settlement {
+                    period: None,
+                    client_id: 2,
+                    inflows: 5.0,
+                    outflows: 5.0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn released_escrows_move_money_between_clients() {
+        let mut ledger = Ledger::new();
+        ledger.post(
+            1,
+            Action::HoldInEscrow,
+            LedgerAccount::CustomerAvailable(1),
+            LedgerAccount::CustomerEscrow(1),
+            2.0,
+        );
+        ledger.post(
+            1,
+            Action::ReleaseEscrow,
+            LedgerAccount::CustomerEscrow(1),
+            LedgerAccount::CustomerAvailable(2),
+            2.0,
+        );
+        let settlements = settle(&ledger);
+        assert_eq!(settlements[0].net(), -2.0);
+        assert_eq!(settlements[1].net(), 2.0);
+    }
+
+    #[test]
+    fn nets_every_period_on_its_own() {
+        let mut account_manager = AccountManager::new();
+        account_manager.enable_ledger();
+        assert!(account_manager.deposit(1, 1, 3.0).is_ok());
+        account_manager.close_period(Some("2024-01".to_string()), None);
+        assert!(account_manager.withdraw(2, 1, 1.0).is_ok());
+        assert!(account_manager.deposit(3, 2, 2.0).is_ok());
+
+        let settlements = settle_periods(
+            account_manager.ledger().unwrap(),
+            account_manager.periods().closed(),
+        );
+        let nets: Vec<_> = settlements
+            .iter()
+            .map(|settlement| (settlement.period, settlement.client_id, settlement.net()))
+            .collect();
+        assert_eq!(
+            nets,
+            vec![(Some(1), 1, 3.0), (Some(2), 1, -1.0), (Some(2), 2, 2.0)]
+        );
+
+        let mut output = Vec::new();
+        write_settlements(
+            &mut output,
+            &settlements[..1],
+            "Assets:Bank",
+            "Assets:Settlement",
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "period,client,inflows,outflows,net,debit,credit,amount\n\
+             1,1,3.0000,0.0000,3.0000,Assets:Settlement,Assets:Bank,3.0000\n"
+        );
+    }
+
+    #[test]
+    fn writes_one_settlement_entry_per_client() {
+        let settlements = [
+            Settlement {
+                period: None,
+                client_id: 1,
+                inflows: 3.0,
+                outflows: 1.0,
+            },
+            Settlement {
+                period: None,
+                client_id: 2,
+                inflows: 0.0,
+                outflows: 2.5,
+            },
+        ];
+        let mut output = Vec::new();
+        write_settlements(
+            &mut output,
+            &settlements,
+            "Assets:Bank",
+            "Assets:Settlement",
+            4,
+            &Pseudonymizer::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,inflows,outflows,net,debit,credit,amount\n\
+             1,3.0000,1.0000,2.0000,Assets:Settlement,Assets:Bank,2.0000\n\
+             2,0.0000,2.5000,-2.5000,Assets:Bank,Assets:Settlement,2.5000\n"
+        );
+    }
+}
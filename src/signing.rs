@@ -0,0 +1,81 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("Invalid {what}: {message}")]
+    InvalidKey { what: &'static str, message: String },
+
+    #[error("Signature doesn't match the report")]
+    Mismatch,
+}
+
+pub type SigningResult<T> = Result<T, SigningError>;
+
+// Keys and signatures are exchanged as hex text so that they can be kept in
+// environment variables and small text files.
+fn decode<const N: usize>(what: &'static str, text: &str) -> SigningResult<[u8; N]> {
+    let invalid = |message: String| SigningError::InvalidKey { what, message };
+    let bytes = hex::decode(text.trim()).map_err(|err| invalid(err.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| invalid(format!("expected {N} bytes, got {}", bytes.len())))
+}
+
+pub fn parse_signing_key(text: &str) -> SigningResult<SigningKey> {
+    Ok(SigningKey::from_bytes(&decode("signing key", text)?))
+}
+
+pub fn parse_verifying_key(text: &str) -> SigningResult<VerifyingKey> {
+    VerifyingKey::from_bytes(&decode("public key", text)?).map_err(|err| SigningError::InvalidKey {
+        what: "public key",
+        message: err.to_string(),
+    })
+}
+
+pub fn public_key_hex(key: &SigningKey) -> String {
+    hex::encode(key.verifying_key().to_bytes())
+}
+
+pub fn sign(key: &SigningKey, report: &[u8]) -> String {
+    hex::encode(key.sign(report).to_bytes())
+}
+
+pub fn verify(key: &VerifyingKey, report: &[u8], signature: &str) -> SigningResult<()> {
+    let signature = Signature::from_bytes(&decode("signature", signature)?);
+    key.verify_strict(report, &signature)
+        .map_err(|_| SigningError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    #[test]
+    fn signature_verifies_only_the_signed_report() {
+        let key = parse_signing_key(SECRET).unwrap();
+        let public_key = parse_verifying_key(&public_key_hex(&key)).unwrap();
+        let report = b"client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n";
+        let signature = sign(&key, report);
+
+        assert!(verify(&public_key, report, &signature).is_ok());
+        assert!(matches!(
+            verify(&public_key, b"client,available\n", &signature),
+            Err(SigningError::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        assert!(matches!(
+            parse_signing_key("abcd"),
+            Err(SigningError::InvalidKey {
+                what: "signing key",
+                ..
+            })
+        ));
+        assert!(parse_signing_key("not hex").is_err());
+    }
+}
@@ -0,0 +1,1078 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::account_manager::AccountManagerError;
+use crate::auth::{ApiKeys, AuthError, Operation};
+use crate::checkpoint::{Checkpoint, CheckpointResult, SourceOffset};
+use crate::encryption::EncryptionKey;
+use crate::error_code::ErrorCode;
+use crate::fast_parse::RecordParser;
+use crate::history::BalancePoint;
+use crate::policy::Policy;
+use crate::pseudonym::Pseudonymizer;
+use crate::rate_limit::{RateLimitError, RateLimiter};
+use crate::shared::{SharedBackend, SharedBook, SharedError};
+use crate::stats::ProcessingStats;
+use crate::tenant::Tenants;
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+// The answer to every line, one JSON object per line.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Reply {
+    Applied {
+        tx: TransactionId,
+    },
+    Rejected {
+        tx: TransactionId,
+        error: AccountManagerError,
+    },
+    // The shared backend failed or doesn't support the transaction.
+    Failed {
+        tx: TransactionId,
+        code: ErrorCode,
+        kind: &'static str,
+        message: String,
+    },
+    Invalid {
+        code: ErrorCode,
+        message: String,
+    },
+    // Not applied, the client may send it again after the delay.
+    RateLimited {
+        tx: TransactionId,
+        code: ErrorCode,
+        http_status: u16,
+        retry_after_ms: u128,
+        message: String,
+    },
+    // The answer to the `auth KEY` line of a connection.
+    Authenticated {
+        key: String,
+    },
+    // A missing or unknown key, which ends the connection, or a key whose
+    // role doesn't allow the line.
+    Denied {
+        code: ErrorCode,
+        http_status: u16,
+        message: String,
+    },
+    // The answer to a `history CLIENT [TENANT]` line.
+    History {
+        client: ClientId,
+        transactions: Vec<BalancePoint>,
+    },
+}
+
+// The reply as it's written. With a keyed pseudonymizer the client ids of
+// rejections and histories are pseudonyms, like in the rejects file.
+struct ReplyPayload<'a> {
+    reply: &'a Reply,
+    pseudonymizer: &'a Pseudonymizer,
+}
+
+impl Serialize for ReplyPayload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pseudonymizer = self.pseudonymizer;
+        if !pseudonymizer.is_keyed() {
+            return self.reply.serialize(serializer);
+        }
+        match self.reply {
+            Reply::Rejected { tx, error } => {
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry("status", "rejected")?;
+                map.serialize_entry("tx", tx)?;
+                map.serialize_entry("error", &pseudonymizer.error_payload(error))?;
+                map.end()
+            }
+            Reply::History {
+                client,
+                transactions,
+            } => {
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry("status", "history")?;
+                map.serialize_entry("client", &pseudonymizer.client(*client))?;
+                map.serialize_entry("transactions", transactions)?;
+                map.end()
+            }
+            reply => reply.serialize(serializer),
+        }
+    }
+}
+
+fn write_reply<W: Write>(
+    writer: &mut W,
+    reply: &Reply,
+    pseudonymizer: &Pseudonymizer,
+) -> io::Result<()> {
+    serde_json::to_writer(
+        &mut *writer,
+        &ReplyPayload {
+            reply,
+            pseudonymizer,
+        },
+    )?;
+    writer.write_all(b"\n")
+}
+
+// The client and tenant of a history query, none for other lines.
+fn parse_history_query(line: &str) -> Option<Result<(ClientId, String), String>> {
+    let query = line.trim().strip_prefix("history ")?;
+    let mut words = query.split_whitespace();
+    let client = words.next().and_then(|client| client.parse().ok());
+    let tenant = words.next().unwrap_or_default().to_string();
+    Some(match (client, words.next()) {
+        (Some(client), None) => Ok((client, tenant)),
+        _ => Err(format!(
+            "invalid query 'history {query}', expected history CLIENT [TENANT]"
+        )),
+    })
+}
+
+// The erasure of an `erase CLIENT TX REASON` line, none for other lines. It's
+// an admin action of the default tenant, so the server needs
+// --allow-admin-actions.
+fn parse_erase_command(line: &str) -> Option<Result<Transaction, String>> {
+    let command = line.trim().strip_prefix("erase ")?;
+    let mut words = command.splitn(3, char::is_whitespace);
+    let client = words.next().and_then(|client| client.parse().ok());
+    let tx = words.next().and_then(|tx| tx.parse().ok());
+    let reason = words.next().map(str::trim).unwrap_or_default();
+    Some(match (client, tx) {
+        (Some(client), Some(tx)) => Ok(Transaction {
+            description: Some(reason.to_string()),
+            ..Transaction::new(Action::EraseClient, client, tx, None)
+        }),
+        _ => Err(format!(
+            "invalid command 'erase {command}', expected erase CLIENT TX REASON"
+        )),
+    })
+}
+
+enum Request {
+    History(ClientId, String),
+    Apply(Transaction),
+}
+
+impl Request {
+    // History queries read, erasures and other admin actions need an admin
+    // key, everything else submits.
+    fn operation(&self) -> Operation {
+        match self {
+            Request::History(..) => Operation::Read,
+            Request::Apply(tx) if tx.action.is_admin() => Operation::Admin,
+            Request::Apply(_) => Operation::Submit,
+        }
+    }
+}
+
+fn parse_request(parser: &RecordParser, line: &str) -> Result<Request, String> {
+    if let Some(query) = parse_history_query(line) {
+        return query.map(|(client_id, tenant)| Request::History(client_id, tenant));
+    }
+    parse_erase_command(line)
+        .unwrap_or_else(|| parse_line(parser, line.trim_end()))
+        .map(Request::Apply)
+}
+
+// The key of an `auth KEY` line.
+fn parse_auth_line(line: &str) -> Option<&str> {
+    line.trim().strip_prefix("auth ").map(str::trim)
+}
+
+fn denied(err: AuthError) -> Reply {
+    Reply::Denied {
+        code: err.code(),
+        http_status: err.status(),
+        message: err.to_string(),
+    }
+}
+
+// Lines starting with `{` are JSON objects, others CSV rows, both with the
+// columns of the parser.
+pub fn parse_line(parser: &RecordParser, line: &str) -> Result<Transaction, String> {
+    match line.trim_start().starts_with('{') {
+        true => parser.parse_json_row(line),
+        false => parser.parse_row(line.as_bytes()),
+    }
+}
+
+// The books shared by all connections. Every transaction consumes the next
+// offset, so a checkpoint knows which were applied.
+//
+// With a shared backend the accounts and the tx cache live in the backend
+// instead, shared with the other instances; the tenants stay empty.
+pub struct Engine {
+    tenants: Tenants,
+    offset: u64,
+    stats: ProcessingStats,
+    checkpoint: Option<(PathBuf, String)>,
+    key: Option<EncryptionKey>,
+    rate_limiter: Option<RateLimiter>,
+    shared: Option<SharedBook<Box<dyn SharedBackend + Send + Sync>>>,
+    pseudonymizer: Pseudonymizer,
+}
+
+impl Engine {
+    pub fn new(tenants: Tenants) -> Self {
+        Self {
+            tenants,
+            offset: 0,
+            stats: ProcessingStats::new(),
+            checkpoint: None,
+            key: None,
+            rate_limiter: None,
+            shared: None,
+            pseudonymizer: Pseudonymizer::new(),
+        }
+    }
+
+    pub fn with_shared_backend(mut self, backend: Box<dyn SharedBackend + Send + Sync>) -> Self {
+        let policy = self.tenants.policy().clone();
+        self.shared = Some(SharedBook::with_policy(backend, policy));
+        self
+    }
+
+    pub fn shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    // Transactions of a client above the limit are answered with
+    // `rate_limited` before they reach the books.
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    // Renders the client ids in replies.
+    pub fn with_pseudonymizer(mut self, pseudonymizer: Pseudonymizer) -> Self {
+        self.pseudonymizer = pseudonymizer;
+        self
+    }
+
+    pub fn pseudonymizer(&self) -> &Pseudonymizer {
+        &self.pseudonymizer
+    }
+
+    // Resumes from the checkpoint of `source` at `path` if there is one, and
+    // saves it there whenever a connection closes, encrypted with the key if
+    // there is one.
+    pub fn with_checkpoint(
+        mut self,
+        path: &Path,
+        source: &str,
+        key: Option<EncryptionKey>,
+    ) -> CheckpointResult<Self> {
+        if let Some(checkpoint) = Checkpoint::load(path, source, key.as_ref())? {
+            let mut tenants = checkpoint.tenants;
+            tenants.set_policy(self.tenants.policy().clone());
+            if self.tenants.history_enabled() {
+                tenants.enable_history();
+            }
+            self.tenants = tenants;
+            self.offset = checkpoint.next.record;
+        }
+        self.checkpoint = Some((path.to_path_buf(), source.to_string()));
+        self.key = key;
+        Ok(self)
+    }
+
+    pub fn tenants(&self) -> &Tenants {
+        &self.tenants
+    }
+
+    pub fn stats(&self) -> &ProcessingStats {
+        &self.stats
+    }
+
+    // Applies to the transactions after it; the books are kept.
+    pub fn set_policy(&mut self, policy: Policy) {
+        if let Some(shared) = &mut self.shared {
+            shared.set_policy(policy.clone());
+        }
+        self.tenants.set_policy(policy);
+    }
+
+    pub fn apply(&mut self, mut tx: Transaction) -> Reply {
+        let id = tx.id;
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            let tenant = tx.tenant.as_deref().unwrap_or_default();
+            if let Err(err) = rate_limiter.check(tenant, tx.client_id, Instant::now()) {
+                self.stats.skip(err.kind());
+                return Reply::RateLimited {
+                    tx: id,
+                    code: err.code(),
+                    http_status: err.status(),
+                    retry_after_ms: match &err {
+                        RateLimitError::Exceeded { retry_after, .. } => retry_after.as_millis(),
+                        RateLimitError::InvalidConfig(_) => 0,
+                    },
+                    message: err.describe_with(|client_id| self.pseudonymizer.client(client_id)),
+                };
+            }
+        }
+        self.offset += 1;
+        let screened = self.tenants.screen(&mut tx);
+        let result = match (&self.shared, screened) {
+            (Some(shared), Ok(())) => match shared.process_transaction(tx) {
+                Ok(()) => Ok(()),
+                Err(SharedError::Rejected(err)) => Err(err),
+                Err(err) => {
+                    self.stats.skip(err.kind());
+                    return Reply::Failed {
+                        tx: id,
+                        code: err.code(),
+                        kind: err.kind(),
+                        message: err.to_string(),
+                    };
+                }
+            },
+            (None, Ok(())) => self.tenants.process_transaction_at(self.offset, tx),
+            (_, Err(err)) => Err(err),
+        };
+        self.stats.record(&result);
+        match result {
+            Ok(()) => Reply::Applied { tx: id },
+            Err(error) => Reply::Rejected { tx: id, error },
+        }
+    }
+
+    // The applied transactions of the client with the balances after them.
+    pub fn history(&self, tenant: &str, client_id: ClientId) -> Reply {
+        if !self.tenants.history_enabled() {
+            return Reply::Invalid {
+                code: ErrorCode::InvalidRow,
+                message: "the history isn't recorded, start the server with --history".to_string(),
+            };
+        }
+        let transactions = self
+            .tenants
+            .get(tenant)
+            .into_iter()
+            .flat_map(|account_manager| account_manager.history(client_id))
+            .cloned()
+            .collect();
+        Reply::History {
+            client: client_id,
+            transactions,
+        }
+    }
+
+    pub fn save(&self) -> CheckpointResult<()> {
+        let Some((path, source)) = &self.checkpoint else {
+            return Ok(());
+        };
+        let next = SourceOffset {
+            record: self.offset,
+            ..SourceOffset::default()
+        };
+        Checkpoint::save(path, source, next, &self.tenants, self.key.as_ref())
+    }
+}
+
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often the listener checks for a shutdown.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+// Lines are at most this long, with the line break. A longer one is answered
+// as invalid and closes the connection.
+pub const MAX_LINE: u64 = 64 * 1024;
+
+// Applies the lines of one connection in order and answers each of them.
+// Blank lines are skipped. Replies are flushed whenever no further line is
+// buffered, so clients waiting for them get them.
+//
+// With API keys the first line must be `auth KEY`; nothing else is parsed
+// before, and a missing or unknown key closes the connection. Every line is
+// then checked against the role of the key.
+pub fn serve_connection<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    parser: &RecordParser,
+    engine: &Mutex<Engine>,
+    keys: Option<&ApiKeys>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    let mut key: Option<String> = None;
+    let pseudonymizer = engine
+        .lock()
+        .expect("engine poisoned")
+        .pseudonymizer()
+        .clone();
+    loop {
+        line.clear();
+        let read = reader.by_ref().take(MAX_LINE).read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        // The rest of the line can't be told from the next one.
+        if read as u64 == MAX_LINE && !line.ends_with('\n') {
+            let reply = Reply::Invalid {
+                code: ErrorCode::InvalidRow,
+                message: format!("line longer than {MAX_LINE} bytes"),
+            };
+            write_reply(&mut writer, &reply, &pseudonymizer)?;
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match (keys, &key) {
+            (Some(keys), None) => {
+                let sent = parse_auth_line(&line);
+                match keys.authenticate(sent) {
+                    Ok(name) => {
+                        let reply = Reply::Authenticated {
+                            key: name.to_string(),
+                        };
+                        key = sent.map(str::to_string);
+                        reply
+                    }
+                    Err(err) => denied(err),
+                }
+            }
+            (keys, key) => match parse_request(parser, &line) {
+                Ok(request) => match keys.map_or(Ok(""), |keys| {
+                    keys.authorize(key.as_deref(), request.operation())
+                }) {
+                    Ok(_) => {
+                        let mut engine = engine.lock().expect("engine poisoned");
+                        match request {
+                            Request::History(client_id, tenant) => {
+                                engine.history(&tenant, client_id)
+                            }
+                            Request::Apply(tx) => engine.apply(tx),
+                        }
+                    }
+                    Err(err) => denied(err),
+                },
+                Err(message) => Reply::Invalid {
+                    code: ErrorCode::InvalidRow,
+                    message,
+                },
+            },
+        };
+        write_reply(&mut writer, &reply, &pseudonymizer)?;
+        if keys.is_some() && key.is_none() {
+            break;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()
+}
+
+// Accepts newline-delimited transactions from processes on the same host.
+// Every connection is served by its own thread; access is limited by the
+// permissions of the socket file and, with_api_keys, by API keys.
+//
+// Once the shutdown flag is set, e.g. on SIGTERM, no more connections are
+// accepted and the open ones stop reading. Lines already received are still
+// applied and answered, for at most the drain timeout, then the state is
+// saved and serve returns.
+pub struct SocketServer {
+    listener: UnixListener,
+    path: PathBuf,
+    shutdown: Arc<AtomicBool>,
+    drain_timeout: Duration,
+    keys: Option<Arc<ApiKeys>>,
+}
+
+struct Connection {
+    stream: UnixStream,
+    thread: JoinHandle<()>,
+}
+
+impl SocketServer {
+    // Replaces the socket file left behind by a previous server.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            keys: None,
+        })
+    }
+
+    // Connections must authenticate with one of the keys.
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        self.keys = Some(Arc::new(keys));
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Arc<AtomicBool>) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn serve(&self, parser: RecordParser, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+        let parser = Arc::new(parser);
+        let mut connections: Vec<Connection> = Vec::new();
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            stream.set_nonblocking(false)?;
+            let parser = Arc::clone(&parser);
+            let engine = Arc::clone(&engine);
+            let keys = self.keys.clone();
+            let served = stream.try_clone()?;
+            let thread = thread::spawn(move || {
+                if let Err(err) = serve_stream(served, &parser, &engine, keys.as_deref()) {
+                    eprintln!("Connection failed: {err}");
+                }
+                if let Err(err) = engine.lock().expect("engine poisoned").save() {
+                    eprintln!("Saving the checkpoint failed: {err}");
+                }
+            });
+            connections.retain(|connection| !connection.thread.is_finished());
+            connections.push(Connection { stream, thread });
+        }
+
+        for connection in &connections {
+            // Lines the connection already buffered are still applied.
+            connection.stream.shutdown(Shutdown::Read).ok();
+        }
+        let deadline = Instant::now() + self.drain_timeout;
+        while connections
+            .iter()
+            .any(|connection| !connection.thread.is_finished())
+        {
+            if Instant::now() >= deadline {
+                eprintln!(
+                    "gave up waiting for connections after {:?}",
+                    self.drain_timeout
+                );
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        engine
+            .lock()
+            .expect("engine poisoned")
+            .save()
+            .map_err(io::Error::other)
+    }
+}
+
+fn serve_stream(
+    stream: UnixStream,
+    parser: &RecordParser,
+    engine: &Mutex<Engine>,
+    keys: Option<&ApiKeys>,
+) -> io::Result<()> {
+    let reader = stream.try_clone()?;
+    let writer = BufWriter::new(stream.try_clone()?);
+    let result = serve_connection(reader, writer, parser, engine, keys);
+    // The server holds another handle, so close the connection explicitly.
+    stream.shutdown(Shutdown::Both).ok();
+    result
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use csv::ByteRecord;
+
+    use super::*;
+    use crate::amount::AmountFormat;
+    use crate::fast_parse::ActionAliases;
+    use crate::rate_limit::RateLimit;
+    use crate::shared::MemoryBackend;
+
+    fn parser() -> RecordParser {
+        let headers = ByteRecord::from(vec!["type", "client", "tx", "amount"]);
+        RecordParser::new(&headers, true).unwrap()
+    }
+
+    #[test]
+    fn answers_every_csv_and_json_line() {
+        let engine = Mutex::new(Engine::new(Tenants::with_policy(Policy::default())));
+        let input = "deposit,1,1,5.0\n\
+                     \n\
+                     {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":7.0}\n\
+                     deposit,x\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+
+        let lines: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines[0], r#"{"status":"applied","tx":1}"#);
+        assert!(lines[1].starts_with(
+            r#"{"status":"rejected","tx":2,"error":{"code":"E1001","kind":"insufficient_funds""#
+        ));
+        assert!(lines[2].starts_with(r#"{"status":"invalid","code":"E2002""#));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(engine.lock().unwrap().stats().processed(), 2);
+    }
+
+    #[test]
+    fn parses_json_lines_like_csv_rows() {
+        let parser = parser()
+            .with_action_aliases(ActionAliases::new().with_alias("pay_in", Action::Deposit))
+            .with_amount_format(AmountFormat::DecimalComma);
+        let tx = parse_line(
+            &parser,
+            r#"{"type":"pay_in","client":1,"tx":2,"amount":"1.234,5"}"#,
+        );
+        assert_eq!(
+            tx,
+            Ok(Transaction::new(Action::Deposit, 1, 2, Some(1234.5)))
+        );
+        assert_eq!(
+            parse_line(
+                &parser,
+                r#"{"type":"deposit","client":1,"tx":2,"amount":"0,1"}"#
+            ),
+            parse_line(&parser, "deposit,1,2,\"0,1\"")
+        );
+        assert!(parse_line(
+            &parser,
+            r#"{"type":"deposit","client":1,"tx":2,"memo":"x"}"#
+        )
+        .unwrap_err()
+        .contains("unknown key `memo`"));
+        assert!(parse_line(&parser, r#"{"type":"deposit","client":[1],"tx":2}"#).is_err());
+    }
+
+    #[test]
+    fn closes_connections_on_lines_above_the_limit() {
+        let engine = Mutex::new(Engine::new(Tenants::new()));
+        let mut input = "deposit,1,1,5.0\n".to_string();
+        input.push_str(&" ".repeat(MAX_LINE as usize));
+        input.push_str("\ndeposit,1,2,5.0\n");
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let lines: Vec<_> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("line longer than 65536 bytes"));
+        assert_eq!(engine.lock().unwrap().stats().processed(), 1);
+    }
+
+    #[test]
+    fn answers_history_queries() {
+        let engine = Mutex::new(Engine::new(Tenants::new()));
+        let input = "deposit,815,1,5.0\nhistory 815\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("start the server with --history"));
+
+        let mut tenants = Tenants::new();
+        tenants.enable_history();
+        let engine = Mutex::new(Engine::new(tenants));
+        let input = "deposit,815,1,5.0\n\
+                     withdrawal,815,2,2.0\n\
+                     deposit,7,3,1.0\n\
+                     history 815\n\
+                     history 815 shop\n\
+                     history x\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let history = &replies[3];
+        assert_eq!(history["status"], "history");
+        assert_eq!(history["transactions"].as_array().unwrap().len(), 2);
+        assert_eq!(history["transactions"][1]["action"], "withdrawal");
+        assert_eq!(history["transactions"][1]["available"], 3.0);
+        assert_eq!(replies[4]["transactions"], serde_json::json!([]));
+        assert_eq!(replies[5]["status"], "invalid");
+    }
+
+    #[test]
+    fn pseudonymizes_client_ids_in_replies() {
+        let pseudonymizer = Pseudonymizer::with_key(b"secret");
+        let mut tenants = Tenants::new();
+        tenants.enable_history();
+        let limit = RateLimit {
+            per_second: 1.0,
+            burst: 1,
+        };
+        let engine = Mutex::new(
+            Engine::new(tenants)
+                .with_rate_limiter(RateLimiter::new(limit).unwrap())
+                .with_pseudonymizer(pseudonymizer.clone()),
+        );
+        let input = "deposit,815,1,5.0\n\
+                     dispute,7,1,\n\
+                     deposit,7,2,1.0\n\
+                     history 815\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("815"));
+        assert!(!output.contains("client 7"));
+        let replies: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let (client, owner) = (pseudonymizer.client(7), pseudonymizer.client(815));
+        assert_eq!(replies[1]["error"]["kind"], "unauthorized");
+        assert_eq!(replies[1]["error"]["client"], client.as_str());
+        assert_eq!(replies[1]["error"]["owner"], owner.as_str());
+        assert_eq!(
+            replies[1]["error"]["message"],
+            format!("Unauthorized. {client} can't modify transactions of {owner}.")
+        );
+        assert_eq!(replies[2]["status"], "rate_limited");
+        assert!(replies[2]["message"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("Client {client} is rate limited")));
+        assert_eq!(replies[3]["client"], owner.as_str());
+        assert_eq!(replies[3]["transactions"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn erases_clients_on_admin_commands() {
+        let policy = Policy {
+            allow_admin_actions: true,
+            ..Policy::default()
+        };
+        let engine = Mutex::new(Engine::new(Tenants::with_policy(policy)));
+        let input = "deposit,815,1,5.0\n\
+                     withdrawal,815,2,5.0\n\
+                     erase 815 3\n\
+                     erase 815 3 Request 42\n\
+                     erase x 4 Request 43\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies[2]["error"]["kind"], "missing_reason");
+        assert_eq!(replies[3]["status"], "applied");
+        assert_eq!(replies[4]["status"], "invalid");
+        let engine = engine.lock().unwrap();
+        assert!(engine.tenants().get("").unwrap().account(815).is_none());
+
+        let engine = Mutex::new(Engine::new(Tenants::new()));
+        let mut output = Vec::new();
+        serve_connection(
+            &b"erase 1 1 Request 44\n"[..],
+            &mut output,
+            &parser(),
+            &engine,
+            None,
+        )
+        .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("admin_action_refused"));
+    }
+
+    #[test]
+    fn rejects_transactions_above_the_rate_limit() {
+        let limit = RateLimit {
+            per_second: 0.001,
+            burst: 2,
+        };
+        let engine =
+            Engine::new(Tenants::new()).with_rate_limiter(RateLimiter::new(limit).unwrap());
+        let engine = Mutex::new(engine);
+        let input = "deposit,1,1,1.0\n\
+                     deposit,1,2,1.0\n\
+                     deposit,1,3,1.0\n\
+                     deposit,2,4,1.0\n";
+        let mut output = Vec::new();
+        serve_connection(input.as_bytes(), &mut output, &parser(), &engine, None).unwrap();
+        let replies: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(replies[1]["status"], "applied");
+        assert_eq!(replies[2]["status"], "rate_limited");
+        assert_eq!(replies[2]["code"], "E1124");
+        assert_eq!(replies[2]["http_status"], 429);
+        assert!(replies[2]["retry_after_ms"].as_u64().unwrap() > 0);
+        assert_eq!(replies[3]["status"], "applied");
+
+        let engine = engine.lock().unwrap();
+        assert_eq!(
+            (engine.stats().processed(), engine.stats().rejected()),
+            (4, 1)
+        );
+        assert_eq!(
+            engine
+                .tenants()
+                .get("")
+                .unwrap()
+                .account(1)
+                .unwrap()
+                .total(),
+            2.0
+        );
+    }
+
+    #[test]
+    fn encrypts_the_checkpoint_with_the_key() {
+        let path = env::temp_dir().join(format!(
+            "accounting-demo-engine-{}.checkpoint",
+            std::process::id()
+        ));
+        let key = || Some(EncryptionKey::from_hex(&"42".repeat(32)).unwrap());
+        let mut engine = Engine::new(Tenants::new())
+            .with_checkpoint(&path, "unix:test.sock", key())
+            .unwrap();
+        assert_eq!(
+            engine.apply(Transaction::new(Action::Deposit, 1, 1, Some(5.0))),
+            Reply::Applied { tx: 1 }
+        );
+        assert!(engine.save().is_ok());
+
+        let without_key =
+            Engine::new(Tenants::new()).with_checkpoint(&path, "unix:test.sock", None);
+        let resumed = Engine::new(Tenants::new()).with_checkpoint(&path, "unix:test.sock", key());
+        fs::remove_file(&path).unwrap();
+        assert!(without_key.is_err());
+        let resumed = resumed.unwrap();
+        assert_eq!(
+            resumed
+                .tenants()
+                .get("")
+                .unwrap()
+                .account(1)
+                .unwrap()
+                .total(),
+            5.0
+        );
+    }
+
+    #[test]
+    fn rate_limits_the_clients_of_each_tenant() {
+        let limit = RateLimit {
+            per_second: 0.001,
+            burst: 1,
+        };
+        let mut engine =
+            Engine::new(Tenants::new()).with_rate_limiter(RateLimiter::new(limit).unwrap());
+        let deposit = |tenant: &str, tx| Transaction {
+            tenant: Some(tenant.to_string()),
+            ..Transaction::new(Action::Deposit, 1, tx, Some(1.0))
+        };
+        assert_eq!(engine.apply(deposit("shop", 1)), Reply::Applied { tx: 1 });
+        assert_eq!(engine.apply(deposit("bank", 2)), Reply::Applied { tx: 2 });
+        assert!(matches!(
+            engine.apply(deposit("shop", 3)),
+            Reply::RateLimited { tx: 3, .. }
+        ));
+        assert!(matches!(
+            engine.apply(deposit("bank", 4)),
+            Reply::RateLimited { tx: 4, .. }
+        ));
+    }
+
+    #[test]
+    fn checks_api_keys_and_their_roles() {
+        let keys = ApiKeys::read(
+            "name,key,role\n\
+             partner,s3cret,submit\n\
+             ops,r00t,admin\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let policy = Policy {
+            allow_admin_actions: true,
+            ..Policy::default()
+        };
+        let engine = Mutex::new(Engine::new(Tenants::with_policy(policy)));
+        let serve = |input: &str| {
+            let mut output = Vec::new();
+            serve_connection(
+                input.as_bytes(),
+                &mut output,
+                &parser(),
+                &engine,
+                Some(&keys),
+            )
+            .unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<Vec<serde_json::Value>>()
+        };
+
+        let replies = serve("deposit,1,1,5.0\ndeposit,1,2,5.0\n");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0]["status"], "denied");
+        assert_eq!(replies[0]["http_status"], 401);
+        assert_eq!(serve("auth guess\n")[0]["code"], "E1125");
+
+        let replies = serve("auth s3cret\ndeposit,1,1,5.0\nhistory 1\nfreeze,1,2,\n");
+        assert_eq!(
+            replies[0],
+            serde_json::json!({"status": "authenticated", "key": "partner"})
+        );
+        assert_eq!(replies[1]["status"], "applied");
+        assert_eq!(replies[2]["http_status"], 403);
+        assert_eq!(replies[3]["code"], "E1126");
+        assert!(!engine
+            .lock()
+            .unwrap()
+            .tenants()
+            .get("")
+            .unwrap()
+            .account(1)
+            .unwrap()
+            .locked());
+
+        let replies = serve("auth r00t\nfreeze,1,3,\n");
+        assert_eq!(replies[1]["status"], "applied");
+        assert!(engine
+            .lock()
+            .unwrap()
+            .tenants()
+            .get("")
+            .unwrap()
+            .account(1)
+            .unwrap()
+            .locked());
+    }
+
+    #[test]
+    fn instances_share_the_books_of_a_backend() {
+        let backend = Arc::new(MemoryBackend::new());
+        let instance = || {
+            let backend: Box<dyn SharedBackend + Send + Sync> = Box::new(Arc::clone(&backend));
+            Mutex::new(Engine::new(Tenants::new()).with_shared_backend(backend))
+        };
+        let (first, second) = (instance(), instance());
+        let serve = |engine: &Mutex<Engine>, input: &str| {
+            let mut output = Vec::new();
+            serve_connection(input.as_bytes(), &mut output, &parser(), engine, None).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<Vec<serde_json::Value>>()
+        };
+        serve(&first, "deposit,1,1,5.0\n");
+        let replies = serve(
+            &second,
+            "withdrawal,1,2,2.0\ndispute,2,1,\nhold_in_escrow,1,3,1.0\n",
+        );
+        assert_eq!(replies[0]["status"], "applied");
+        assert_eq!(replies[1]["error"]["kind"], "unauthorized");
+        assert_eq!(replies[2]["status"], "failed");
+        assert_eq!(replies[2]["kind"], "unsupported");
+        let replies = serve(&first, "withdrawal,1,4,3.0\nwithdrawal,1,5,0.5\n");
+        assert_eq!(replies[0]["status"], "applied");
+        assert_eq!(replies[1]["error"]["kind"], "insufficient_funds");
+        assert!(first.lock().unwrap().tenants().get("").is_none());
+    }
+
+    #[test]
+    fn serves_connections_on_the_socket() {
+        let path = env::temp_dir().join(format!("accounting-demo-{}.sock", std::process::id()));
+        let server = SocketServer::bind(&path).unwrap();
+        let engine = Arc::new(Mutex::new(Engine::new(Tenants::new())));
+        let served = Arc::clone(&engine);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = server.with_shutdown(Arc::clone(&shutdown));
+        let serving = thread::spawn(move || server.serve(parser(), served));
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"deposit,1,1,2.5\nwithdrawal,1,2,9.0\n")
+            .unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let replies: Vec<_> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0], r#"{"status":"applied","tx":1}"#);
+        assert!(replies[1].contains(r#""status":"rejected""#));
+
+        shutdown.store(true, Ordering::Relaxed);
+        serving.join().unwrap().unwrap();
+        let engine = engine.lock().unwrap();
+        let account = engine.tenants().get("").unwrap().account(1).unwrap();
+        assert_eq!(account.available(), 2.5);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn drains_open_connections_on_shutdown() {
+        let path =
+            env::temp_dir().join(format!("accounting-demo-drain-{}.sock", std::process::id()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = SocketServer::bind(&path)
+            .unwrap()
+            .with_shutdown(Arc::clone(&shutdown))
+            .with_drain_timeout(Duration::from_secs(5));
+        let engine = Arc::new(Mutex::new(Engine::new(Tenants::new())));
+        let served = Arc::clone(&engine);
+        let serving = thread::spawn(move || server.serve(parser(), served));
+
+        // The client keeps its connection open; the server still answers and
+        // then closes it on shutdown.
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"deposit,1,1,4.0\n").unwrap();
+        let mut replies = BufReader::new(stream.try_clone().unwrap()).lines();
+        assert_eq!(
+            replies.next().unwrap().unwrap(),
+            r#"{"status":"applied","tx":1}"#
+        );
+
+        let started = Instant::now();
+        shutdown.store(true, Ordering::Relaxed);
+        serving.join().unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(replies.next().is_none());
+        assert_eq!(engine.lock().unwrap().stats().processed(), 1);
+    }
+}
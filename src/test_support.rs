@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use csv::{ByteRecord, Position, ReaderBuilder, Trim};
+use thiserror::Error;
+
+use crate::fast_parse::{FastParseError, RecordParser};
+use crate::pseudonym::Pseudonymizer;
+use crate::report::{write_account_records, AccountRecord};
+use crate::tenant::Tenants;
+
+#[derive(Error, Debug)]
+pub enum FixtureError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+
+    #[error("{0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("Invalid header: {0}")]
+    InvalidHeader(#[from] FastParseError),
+
+    #[error("Invalid row at line {line}: {message}")]
+    InvalidRow { line: u64, message: String },
+
+    #[error("Fixture {name} has no {name}.expected.csv, regenerate the expectations")]
+    MissingExpectation { name: String },
+}
+
+pub type FixtureResult<T> = Result<T, FixtureError>;
+
+const INPUT_SUFFIX: &str = ".csv";
+const EXPECTED_SUFFIX: &str = ".expected.csv";
+
+// Processes a transactions CSV with the default policy and returns the report
+// of the accounts, ordered by tenant and client. Rejected transactions are
+// part of the scenario, rows that don't parse are errors of the fixture.
+pub fn run_scenario<R: Read>(input: R) -> FixtureResult<String> {
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(input);
+    let parser = RecordParser::new(reader.byte_headers()?, false)?;
+    let mut tenants = Tenants::new();
+    let mut record = ByteRecord::new();
+    while reader.read_byte_record(&mut record)? {
+        let position = record.position().cloned().unwrap_or_else(Position::new);
+        let tx = parser
+            .parse(&record)
+            .map_err(|err| FixtureError::InvalidRow {
+                line: position.line(),
+                message: err.to_string(),
+            })?;
+        // Scheduled transactions become due like in a run of the CLI.
+        if let Some(now) = tx.timestamp {
+            tenants.advance_clock(now);
+            while let Some(due) = tenants.next_due() {
+                let _ = tenants.process_scheduled(due);
+            }
+        }
+        let _ = tenants.process_transaction_at(position.byte(), tx);
+    }
+
+    let mut records: Vec<_> = tenants
+        .iter_accounts()
+        .map(|(tenant, client_id, account)| AccountRecord {
+            tenant: (!tenant.is_empty()).then(|| tenant.clone()),
+            ..AccountRecord::new(*client_id, account)
+        })
+        .collect();
+    records.sort_by(|a, b| (&a.tenant, a.client).cmp(&(&b.tenant, b.client)));
+    let mut report = Vec::new();
+    write_account_records(&mut report, records, &[], 4, &Pseudonymizer::new())?;
+    Ok(String::from_utf8(report).expect("reports are UTF-8"))
+}
+
+// A scenario of a fixtures directory: NAME.csv with the transactions and
+// NAME.expected.csv with the report they result in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixture {
+    pub name: String,
+    pub input: PathBuf,
+    pub expected: PathBuf,
+}
+
+impl Fixture {
+    pub fn run(&self) -> FixtureResult<String> {
+        run_scenario(File::open(&self.input)?)
+    }
+
+    // None if the report is the expected one.
+    pub fn check(&self) -> FixtureResult<Option<Mismatch>> {
+        let expected = match fs::read_to_string(&self.expected) {
+            Ok(expected) => expected,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(FixtureError::MissingExpectation {
+                    name: self.name.clone(),
+                });
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let actual = self.run()?;
+        Ok((actual != expected).then(|| Mismatch {
+            name: self.name.clone(),
+            expected,
+            actual,
+        }))
+    }
+
+    // Writes the current report as the expectation.
+    pub fn regenerate(&self) -> FixtureResult<()> {
+        fs::write(&self.expected, self.run()?)?;
+        Ok(())
+    }
+}
+
+// The fixtures of a directory, ordered by name.
+pub fn fixtures(dir: &Path) -> FixtureResult<Vec<Fixture>> {
+    let mut fixtures = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let input = entry?.path();
+        let Some(file_name) = input.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if file_name.ends_with(EXPECTED_SUFFIX) {
+            continue;
+        }
+        let Some(name) = file_name.strip_suffix(INPUT_SUFFIX) else {
+            continue;
+        };
+        fixtures.push(Fixture {
+            name: name.to_string(),
+            expected: dir.join(format!("{name}{EXPECTED_SUFFIX}")),
+            input,
+        });
+    }
+    fixtures.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(fixtures)
+}
+
+// A fixture whose report differs from the expected one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub name: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+// The name and the lines only in the expected (-) or the actual report (+).
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.name)?;
+        for line in self.expected.lines() {
+            if !self.actual.lines().any(|actual| actual == line) {
+                writeln!(f, "-{line}")?;
+            }
+        }
+        for line in self.actual.lines() {
+            if !self.expected.lines().any(|expected| expected == line) {
+                writeln!(f, "+{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_report_the_accounts_in_order() {
+        let input = "type, client, tx, amount, tenant\n\
+                     deposit, 2, 1, 2.0, \n\
+                     deposit, 1, 2, 1.0, \n\
+                     withdrawal, 1, 3, 5.0, \n\
+                     deposit, 1, 4, 3.0, shop\n";
+        let report = run_scenario(input.as_bytes()).unwrap();
+        assert_eq!(
+            report,
+            "tenant,client,available,held,total,locked\n\
+             ,1,1.0000,0.0000,1.0000,false\n\
+             ,2,2.0000,0.0000,2.0000,false\n\
+             shop,1,3.0000,0.0000,3.0000,false\n"
+        );
+
+        let invalid = "type,client,tx,amount\nsend,1,1,1.0\n";
+        assert!(matches!(
+            run_scenario(invalid.as_bytes()),
+            Err(FixtureError::InvalidRow { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn mismatches_show_the_differing_lines() {
+        let mismatch = Mismatch {
+            name: "dispute".to_string(),
+            expected: "client,total\n1,2.0000\n".to_string(),
+            actual: "client,total\n1,1.0000\n".to_string(),
+        };
+        assert_eq!(mismatch.to_string(), "dispute:\n-1,2.0000\n+1,1.0000\n");
+    }
+}
@@ -0,0 +1,253 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::io::Write;
+
+use csv::WriterBuilder;
+use serde::Serialize;
+
+use crate::amount::ReportAmount;
+use crate::pseudonym::Pseudonymizer;
+use crate::tenant::Tenants;
+use crate::types::{Action, ClientId, TenantId, Transaction};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TopMetric {
+    Deposits,
+    Withdrawals,
+    Disputed,
+}
+
+impl TopMetric {
+    pub const ALL: [TopMetric; 3] = [Self::Deposits, Self::Withdrawals, Self::Disputed];
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Volumes {
+    deposits: f64,
+    withdrawals: f64,
+    disputed: f64,
+}
+
+impl Volumes {
+    fn get(&self, metric: TopMetric) -> f64 {
+        match metric {
+            TopMetric::Deposits => self.deposits,
+            TopMetric::Withdrawals => self.withdrawals,
+            TopMetric::Disputed => self.disputed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopEntry {
+    pub metric: TopMetric,
+    pub rank: usize,
+    pub tenant: TenantId,
+    pub client: ClientId,
+    pub amount: f64,
+}
+
+// Ordered by amount, ties rank the lower tenant and client id first.
+struct Ranked<'a> {
+    amount: f64,
+    key: &'a (TenantId, ClientId),
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.amount
+            .total_cmp(&other.amount)
+            .then_with(|| other.key.cmp(self.key))
+    }
+}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked<'_> {}
+
+// The deposit, withdrawal and disputed volume of every account, summed up
+// while the rows are applied, so the top accounts need no second pass over
+// the input. Only covers the rows of this run, not those before a checkpoint.
+#[derive(Debug, Clone, Default)]
+pub struct TopAccounts {
+    count: usize,
+    volumes: HashMap<(TenantId, ClientId), Volumes>,
+}
+
+impl TopAccounts {
+    pub fn new(count: usize) -> Self {
+        Self {
+            count,
+            volumes: HashMap::new(),
+        }
+    }
+
+    // Counts an applied transaction. Disputes count with the amount they
+    // held, disputes that held nothing don't count.
+    pub fn record(&mut self, tx: &Transaction, tenants: &Tenants) {
+        let tenant = tx.tenant.as_deref().unwrap_or_default();
+        let amount = match tx.action {
+            Action::Deposit | Action::Withdrawal => tx.amount.unwrap_or_default(),
+            Action::Dispute => {
+                let case = tenants
+                    .get(tenant)
+                    .and_then(|account_manager| account_manager.dispute_cases().open_case(tx.id));
+                match case {
+                    Some(case) => case.amount,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        let volumes = self
+            .volumes
+            .entry((tenant.to_string(), tx.client_id))
+            .or_default();
+        match tx.action {
+            Action::Deposit => volumes.deposits += amount,
+            Action::Withdrawal => volumes.withdrawals += amount,
+            _ => volumes.disputed += amount,
+        }
+    }
+
+    // The accounts with the largest volume, largest first. A bounded heap
+    // keeps the best `count` accounts seen so far.
+    pub fn top(&self, metric: TopMetric) -> Vec<TopEntry> {
+        let mut heap = BinaryHeap::with_capacity(self.count + 1);
+        for (key, volumes) in &self.volumes {
+            let amount = volumes.get(metric);
+            if amount <= 0.0 {
+                continue;
+            }
+            heap.push(Reverse(Ranked { amount, key }));
+            if heap.len() > self.count {
+                heap.pop();
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .enumerate()
+            .map(|(index, Reverse(ranked))| TopEntry {
+                metric,
+                rank: index + 1,
+                tenant: ranked.key.0.clone(),
+                client: ranked.key.1,
+                amount: ranked.amount,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct TopRecord<'a> {
+    metric: TopMetric,
+    rank: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    client: String,
+    amount: ReportAmount,
+}
+
+// The top accounts of every metric, with the tenant column if any account has
+// a tenant.
+pub fn write_top<W: Write>(
+    writer: W,
+    top: &TopAccounts,
+    places: u32,
+    pseudonymizer: &Pseudonymizer,
+) -> csv::Result<()> {
+    let entries: Vec<_> = TopMetric::ALL
+        .into_iter()
+        .flat_map(|metric| top.top(metric))
+        .collect();
+    let with_tenant = entries.iter().any(|entry| !entry.tenant.is_empty());
+    let mut writer = WriterBuilder::new().has_headers(false).from_writer(writer);
+    writer.write_field("metric")?;
+    writer.write_field("rank")?;
+    if with_tenant {
+        writer.write_field("tenant")?;
+    }
+    writer.write_record(["client", "amount"])?;
+    for entry in &entries {
+        writer.serialize(TopRecord {
+            metric: entry.metric,
+            rank: entry.rank,
+            tenant: with_tenant.then_some(entry.tenant.as_str()),
+            client: pseudonymizer.client(entry.client),
+            amount: ReportAmount::new(entry.amount, places),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::Policy;
+
+    fn apply(tenants: &mut Tenants, top: &mut TopAccounts, tx: Transaction) {
+        if tenants.process_scheduled(tx.clone()).is_ok() {
+            top.record(&tx, tenants);
+        }
+    }
+
+    #[test]
+    fn keeps_the_largest_volumes_per_metric() {
+        let mut tenants = Tenants::with_policy(Policy::default());
+        let mut top = TopAccounts::new(2);
+        let rows = [
+            Transaction::new(Action::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(Action::Deposit, 2, 2, Some(3.0)),
+            Transaction::new(Action::Deposit, 3, 3, Some(3.0)),
+            Transaction::new(Action::Deposit, 2, 4, Some(4.0)),
+            Transaction::new(Action::Withdrawal, 3, 5, Some(1.0)),
+            Transaction::new(Action::Withdrawal, 1, 6, Some(100.0)),
+            Transaction::new(Action::Dispute, 2, 4, None),
+        ];
+        for tx in rows {
+            apply(&mut tenants, &mut top, tx);
+        }
+
+        let ranked = |metric| -> Vec<_> {
+            top.top(metric)
+                .into_iter()
+                .map(|entry| (entry.rank, entry.client, entry.amount))
+                .collect()
+        };
+        assert_eq!(ranked(TopMetric::Deposits), vec![(1, 2, 7.0), (2, 1, 5.0)]);
+        // The rejected withdrawal of client 1 doesn't count.
+        assert_eq!(ranked(TopMetric::Withdrawals), vec![(1, 3, 1.0)]);
+        assert_eq!(ranked(TopMetric::Disputed), vec![(1, 2, 4.0)]);
+    }
+
+    #[test]
+    fn ties_rank_the_lower_client_first() {
+        let mut tenants = Tenants::with_policy(Policy::default());
+        let mut top = TopAccounts::new(2);
+        for (client_id, tx_id) in [(3, 1), (2, 2), (1, 3)] {
+            let deposit = Transaction::new(Action::Deposit, client_id, tx_id, Some(1.0));
+            apply(&mut tenants, &mut top, deposit);
+        }
+
+        let mut output = Vec::new();
+        write_top(&mut output, &top, 4, &Pseudonymizer::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "metric,rank,client,amount\n\
+             deposits,1,1,1.0000\n\
+             deposits,2,2,1.0000\n"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use csv::Writer;
+use serde::Serialize;
+
+use crate::types::{Action, ClientId, RecordError, TenantId, Transaction, TransactionId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    InvalidHeader,
+    UnparsableRow,
+    MissingAmount,
+    DuplicateTx,
+    UnknownTx,
+}
+
+// A problem of the input file. Header findings are at line 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub line: u64,
+    pub kind: FindingKind,
+    pub client: Option<ClientId>,
+    pub tx: Option<TransactionId>,
+    // The column of an unparsable field.
+    pub field: Option<String>,
+    pub message: String,
+}
+
+// Which transactions a row refers to instead of creating one.
+fn referenced(action: &Action) -> Option<&'static [Action]> {
+    match action {
+        Action::Dispute | Action::Resolve | Action::Chargeback => {
+            Some(&[Action::Deposit, Action::Withdrawal])
+        }
+        Action::ReleaseEscrow | Action::RefundEscrow => Some(&[Action::HoldInEscrow]),
+        _ => None,
+    }
+}
+
+// Rows that create a transaction, they need an amount.
+fn creates_tx(action: &Action) -> bool {
+    matches!(
+        action,
+        Action::Deposit | Action::Withdrawal | Action::HoldInEscrow | Action::Adjustment
+    )
+}
+
+// Checks the rows of a file in order without applying them, so only problems
+// visible in the file itself are found. Tx ids are scoped by tenant.
+#[derive(Debug, Default)]
+pub struct Validator {
+    seen: HashMap<(Option<TenantId>, TransactionId), Action>,
+    findings: Vec<Finding>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn invalid_header(&mut self, message: String) {
+        self.push(1, FindingKind::InvalidHeader, None, message);
+    }
+
+    pub fn unparsable_row(&mut self, line: u64, err: &RecordError) {
+        self.findings.push(Finding {
+            line,
+            kind: FindingKind::UnparsableRow,
+            client: None,
+            tx: None,
+            field: err.field().map(str::to_string),
+            message: err.to_string(),
+        });
+    }
+
+    pub fn check(&mut self, line: u64, tx: &Transaction) {
+        let action = tx.action.as_str();
+        if creates_tx(&tx.action) && tx.amount.is_none() {
+            let message = format!("{action} {} has no amount", tx.id);
+            self.push(line, FindingKind::MissingAmount, Some(tx), message);
+        }
+        let key = (tx.tenant.clone(), tx.id);
+        match (referenced(&tx.action), self.seen.get(&key)) {
+            (Some(expected), Some(found)) if !expected.contains(found) => {
+                let message = format!("{action} references tx {}, a {}", tx.id, found.as_str());
+                self.push(line, FindingKind::UnknownTx, Some(tx), message);
+            }
+            (Some(_), None) => {
+                let message = format!("{action} references unknown tx {}", tx.id);
+                self.push(line, FindingKind::UnknownTx, Some(tx), message);
+            }
+            (None, Some(found)) if creates_tx(&tx.action) => {
+                let message = format!("tx {} was already used by a {}", tx.id, found.as_str());
+                self.push(line, FindingKind::DuplicateTx, Some(tx), message);
+            }
+            (None, None) if creates_tx(&tx.action) => {
+                self.seen.insert(key, tx.action.clone());
+            }
+            _ => {}
+        }
+    }
+
+    fn push(&mut self, line: u64, kind: FindingKind, tx: Option<&Transaction>, message: String) {
+        self.findings.push(Finding {
+            line,
+            kind,
+            client: tx.map(|tx| tx.client_id),
+            tx: tx.map(|tx| tx.id),
+            field: None,
+            message,
+        });
+    }
+}
+
+pub fn write_findings<W: Write>(writer: W, findings: &[Finding]) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    for finding in findings {
+        writer.serialize(finding)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::AmountParseError;
+
+    #[test]
+    fn finds_missing_amounts_duplicates_and_unknown_references() {
+        let mut validator = Validator::new();
+        let rows = [
+            Transaction::new(Action::Deposit, 1, 1, Some(2.0)),
+            Transaction::new(Action::Withdrawal, 1, 2, None),
+            Transaction::new(Action::Deposit, 2, 1, Some(1.0)),
+            Transaction {
+                tenant: Some("acme".to_string()),
+                ..Transaction::new(Action::Deposit, 2, 1, Some(1.0))
+            },
+            Transaction::new(Action::Dispute, 1, 1, None),
+            Transaction::new(Action::Dispute, 1, 9, None),
+            Transaction::new(Action::ReleaseEscrow, 1, 2, None),
+        ];
+        for (line, tx) in (2..).zip(&rows) {
+            validator.check(line, tx);
+        }
+        let malformed = RecordError::Amount(AmountParseError::Malformed("x".to_string()));
+        validator.unparsable_row(9, &malformed);
+
+        let findings: Vec<_> = validator
+            .findings()
+            .iter()
+            .map(|finding| (finding.line, finding.kind, finding.tx))
+            .collect();
+        assert_eq!(
+            findings,
+            vec![
+                (3, FindingKind::MissingAmount, Some(2)),
+                (4, FindingKind::DuplicateTx, Some(1)),
+                (7, FindingKind::UnknownTx, Some(9)),
+                (8, FindingKind::UnknownTx, Some(2)),
+                (9, FindingKind::UnparsableRow, None),
+            ]
+        );
+        assert_eq!(validator.findings()[4].field.as_deref(), Some("amount"));
+    }
+
+    #[test]
+    fn writes_one_row_per_finding() {
+        let mut validator = Validator::new();
+        validator.invalid_header("missing column `type`".to_string());
+        validator.check(2, &Transaction::new(Action::Resolve, 3, 4, None));
+
+        let mut output = Vec::new();
+        write_findings(&mut output, validator.findings()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "line,kind,client,tx,field,message\n\
+             1,invalid_header,,,,missing column `type`\n\
+             2,unknown_tx,3,4,,resolve references unknown tx 4\n"
+        );
+    }
+}
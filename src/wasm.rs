@@ -0,0 +1,82 @@
+use wasm_bindgen::prelude::*;
+
+use crate::account_manager::{process_transaction, AccountManager};
+use crate::report::accounts_json;
+use crate::types::{Action, ClientId, Transaction, TransactionId};
+
+// The engine for JavaScript, e.g. to step through dispute chains in a browser:
+//
+//   const engine = new Engine();
+//   engine.submit("deposit", 1, 1, 2.0);
+//   engine.submit("dispute", 1, 1);
+//   JSON.parse(engine.accountsJson());
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Engine {
+    account_manager: AccountManager,
+}
+
+#[wasm_bindgen]
+impl Engine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Throws an Error with the rejection reason if the transaction isn't applied.
+    pub fn submit(
+        &mut self,
+        action: &str,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<f64>,
+    ) -> Result<(), JsError> {
+        self.apply(action, client, tx, amount)
+            .map_err(|message| JsError::new(&message))
+    }
+
+    #[wasm_bindgen(js_name = accountsJson)]
+    pub fn accounts_json(&self) -> String {
+        accounts_json(self.account_manager.iter_accounts())
+    }
+}
+
+impl Engine {
+    fn apply(
+        &mut self,
+        action: &str,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<f64>,
+    ) -> Result<(), String> {
+        let action: Action = action.parse()?;
+        let tx = Transaction::new(action, client, tx, amount);
+        process_transaction(&mut self.account_manager, tx).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submitted_transactions_show_up_in_accounts() {
+        let mut engine = Engine::new();
+        assert!(engine.apply("deposit", 1, 1, Some(2.0)).is_ok());
+        assert!(engine.apply("dispute", 1, 1, None).is_ok());
+        assert_eq!(
+            engine.accounts_json(),
+            r#"[{"client":1,"available":0.0,"held":2.0,"total":2.0,"locked":false}]"#
+        );
+    }
+
+    #[test]
+    fn rejections_carry_the_reason() {
+        let mut engine = Engine::new();
+        assert_eq!(
+            engine.apply("refund", 1, 1, Some(2.0)),
+            Err("unknown transaction type 'refund'".to_string())
+        );
+        assert!(engine.apply("withdrawal", 1, 2, Some(1.0)).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+use std::path::Path;
+
+use accounting_cli::test_support::fixtures;
+
+#[test]
+fn fixtures_match_their_expectations() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+    let fixtures = fixtures(&dir).unwrap();
+    assert!(!fixtures.is_empty());
+
+    let mismatches: Vec<String> = fixtures
+        .iter()
+        .filter_map(|fixture| fixture.check().unwrap())
+        .map(|mismatch| mismatch.to_string())
+        .collect();
+    assert!(
+        mismatches.is_empty(),
+        "reports differ, regenerate the expectations if intended:\n{}",
+        mismatches.join("\n")
+    );
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn run(input: &Path, registry: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_accounting-demo"))
+        .arg("--processed-registry")
+        .arg(registry)
+        .arg(input)
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn deferred_rows_are_registered_once_they_are_applied() {
+    let dir = std::env::temp_dir().join(format!("processed-registry-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let registry = dir.join("processed.bin");
+    let first = dir.join("first.csv");
+    fs::write(
+        &first,
+        "type,client,tx,amount,timestamp,execute_at\n\
+         deposit,1,1,1.0,100,\n\
+         deposit,1,2,5.0,100,200\n",
+    )
+    .unwrap();
+    let second = dir.join("second.csv");
+    fs::write(
+        &second,
+        "type,client,tx,amount,timestamp,execute_at\n\
+         deposit,1,2,5.0,300,200\n\
+         deposit,1,3,1.0,400,\n",
+    )
+    .unwrap();
+
+    // The scheduled deposit isn't due in the first run, so the second one
+    // applies it.
+    assert!(run(&first, &registry).contains("1,1.0000,0.0000,1.0000,false"));
+    assert!(run(&second, &registry).contains("1,6.0000,0.0000,6.0000,false"));
+    // Applied once, so a third run skips it.
+    let third = dir.join("third.csv");
+    fs::write(
+        &third,
+        "type,client,tx,amount,timestamp,execute_at\n\
+         deposit,1,2,5.0,500,200\n\
+         deposit,1,4,1.0,600,\n",
+    )
+    .unwrap();
+    assert!(run(&third, &registry).contains("1,1.0000,0.0000,1.0000,false"));
+    fs::remove_dir_all(&dir).unwrap();
+}